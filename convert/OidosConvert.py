@@ -7,6 +7,7 @@ import struct
 import ctypes
 import math
 import datetime
+import base64
 
 TOTAL_SEMITONES = 120
 SAMPLERATE = 44100
@@ -48,23 +49,77 @@ class Instrument:
 			 "dummy",
 			 "q_decaydiff", "q_decaylow", "q_harmonicity", "q_sharpness", "q_width",
 			 "q_f_low", "q_fs_low", "q_fsw_low", "q_f_high", "q_fs_high", "q_fsw_high",
-			 "q_gain", "q_attack", "q_release"
+			 "q_gain", "q_attack", "q_release",
+			 "velsharpness", "velfilter", "veldecay", "vellayers", "refpitch",
+			 "hold", "envdecay", "sustain", "attackcurve", "decaycurve", "releasecurve",
+			 "decaytrack", "filtertrack", "trackpivot", "swell", "swellslope", "swellspread",
+			 "formant1", "fwidth1", "fgain1", "formant2", "fwidth2", "fgain2", "formantsweep",
+			 "q_formant", "q_fwidth", "q_fgain", "stiffness", "q_stiffness"
 	]
 
-	def __init__(self, number, name, params, legacy):
+	def __init__(self, number, name, params, legacy, tuning = None):
 		if legacy:
 			# Duplicate filter sweep parameter
 			params = params[:11] + [params[13]] + params[11:17] + [0.0] + params[20:27] + [params[29]] + params[27:33]
 
 		names = Instrument.NAMES
+		# Ignore read-only diagnostic parameters. Songs from before the velocity
+		# parameters have the diagnostics, which are zero, in their place.
+		params = (params + [0.0] * len(names))[:len(names)]
 		self.number = number
 		self.name = name
 		self.params = params
 		for i,p in enumerate(self.params):
 			self.__dict__[names[i]] = p
+		self.tuning = tuning if tuning is not None else dict()
 		self.volume = Volume(1.0, 1.0)
 		self.maxsamples = 0
 		self.title = "%02X|%s" % (self.number, self.name)
+		self.layer_numbers = dict()
+
+	def tuningOffset(self, tone):
+		# Same as tuned_tone in the synth, relative to the equal-tempered tone
+		refpitch = 440.0 if self.refpitch == 0.0 else 415.0 + self.refpitch * 50.0
+		return self.tuning.get(tone, tone) - tone + 12 * math.log(refpitch / 440.0, 2)
+
+	def usesEnvelope(self):
+		return any(p != 0.0 for p in [self.hold, self.sustain, self.attackcurve, self.decaycurve, self.releasecurve])
+
+	def usesKeytrack(self):
+		return self.decaytrack != 0.0 or self.filtertrack != 0.0
+
+	def usesSwell(self):
+		return self.swell != 0.0
+
+	def usesFormants(self):
+		return self.fwidth1 != 0.0 or self.fwidth2 != 0.0
+
+	def usesStiffness(self):
+		return self.stiffness != 0.0
+
+	def velocityLayers(self):
+		return max(1, int(math.floor(0.5 + self.vellayers * 16)))
+
+	def velocityLayer(self, velocity):
+		layers = self.velocityLayers()
+		return min(velocity * layers // 128, layers - 1)
+
+	def layerInstrument(self, number, layer):
+		# Same as layer_values in the synth
+		layers = self.velocityLayers()
+		velocity = ((layer + 1) * 128 - 1) // layers
+		softness = 1.0 - velocity / 127.0
+		names = Instrument.NAMES
+		params = list(self.params)
+		params[names.index("sharpness")] = max(0.0, self.sharpness - self.velsharpness * 24.0 / (5 * 20 * math.log10(2)) * softness)
+		params[names.index("filterhigh")] = max(0.0, self.filterhigh - self.velfilter * 60.0 / (2 * TOTAL_SEMITONES) * softness)
+		if self.veldecay > 0.0:
+			scale = max(0.01, 1.0 - self.veldecay * softness)
+			params[names.index("decaylow")] = math.pow(self.decaylow, 1.0 / scale)
+			params[names.index("decayhigh")] = math.pow(self.decayhigh, 1.0 / scale)
+		instrument = Instrument(number, "%s v%d" % (self.name, velocity), params, False, self.tuning)
+		instrument.volume = self.volume
+		return instrument
 
 
 class Reverb:
@@ -169,7 +224,20 @@ def quantize(value, level):
 	q = i2f(i)
 	return q
 
-def makeParamBlock(inst, uses_panning):
+def envelopeCurve(value):
+	# Zero means a linear stage
+	return 0.0 if value == 0.0 else value * 2 - 1
+
+def formantPeak(inst, center, width, gain):
+	# Center, inverse width and gain minus one, turned off at zero width
+	center = quantize(center * 127, inst.q_formant)
+	if width == 0.0:
+		return [center, 0.0, 0.0]
+	slope = quantize(1 / (width * 48), inst.q_fwidth)
+	gain = quantize(math.pow(10, (gain * 2 - 1) * 24 / 20) - 1, inst.q_fgain)
+	return [center, slope, gain]
+
+def makeParamBlock(inst, uses_panning, uses_envelope, uses_keytrack, uses_swell, uses_formants, uses_stiffness):
 	modes = max(1, math.floor(0.5 + inst.modes * 100))
 	fat = max(1, math.floor(0.5 + inst.fat * 100))
 	seed = math.floor(0.5 + inst.seed * 100)
@@ -177,6 +245,7 @@ def makeParamBlock(inst, uses_panning):
 	decaydiff = inst.decayhigh - inst.decaylow
 	decaylow = inst.decaylow
 	harmonicity = inst.harmonicity * 2 - 1
+	stiffness = math.pow(inst.stiffness, 4) * 0.1
 	sharpness = inst.sharpness * 5 - 4
 	width = 100 * math.pow(inst.width, 5)
 
@@ -194,6 +263,7 @@ def makeParamBlock(inst, uses_panning):
 	decaydiff = quantize(decaydiff, inst.q_decaydiff)
 	decaylow = quantize(decaylow, inst.q_decaylow)
 	harmonicity = quantize(harmonicity, inst.q_harmonicity)
+	stiffness = quantize(stiffness, inst.q_stiffness)
 	sharpness = quantize(sharpness, inst.q_sharpness)
 	width = quantize(width, inst.q_width)
 	filterlow = quantize(filterlow, inst.q_f_low)
@@ -206,12 +276,47 @@ def makeParamBlock(inst, uses_panning):
 	attack = quantize(attack, inst.q_attack)
 	release = quantize(release, inst.q_release)
 
+	hold = inst.hold * inst.hold * SAMPLERATE
+	envdecay = 2.0 if inst.envdecay == 0.0 else 1 / (inst.envdecay * inst.envdecay * 10) / SAMPLERATE
+	sustain = 1.0 - inst.sustain
+	decaystart = 1.0 / attack + hold
+
+	trackpivot = math.floor(0.5 + inst.trackpivot * 127)
+	# The player raises tracked decay factors to a power through their logarithm,
+	# which needs them to be above zero. Untracked decay factors are left as they are.
+	if inst.decaytrack != 0.0 and min(decaylow, decaylow + decaydiff) <= 0.0:
+		raise InputException("Instrument '%s' uses decay key tracking with a zero decay time" % inst.title)
+	# The decay is longest at the lowest tone
+	lowesttone = min(t + inst.tuningOffset(t) for t in inst.tones) if inst.tones else 0.0
+	decayscale = math.pow(2.0, (lowesttone - trackpivot) * inst.decaytrack / 12.0)
+
+	# Zero words make the partials start at full level
+	if inst.swell == 0.0:
+		swell, swellslope, swellspread = 0.0, 0.0, 0.0
+	else:
+		swell = 1 / (inst.swell * inst.swell * 2) / SAMPLERATE
+		swellslope = inst.swellslope
+		swellspread = inst.swellspread * 12
+
+	# The values of the two peaks are interleaved
+	formant1 = formantPeak(inst, inst.formant1, inst.fwidth1, inst.fgain1)
+	formant2 = formantPeak(inst, inst.formant2, inst.fwidth2, inst.fgain2)
+	formants = [v for pair in zip(formant1, formant2) for v in pair]
+	formantsweep = 0.0 if inst.formantsweep == 0.0 else math.pow(inst.formantsweep * 2 - 1, 3) * 120 / SAMPLERATE
+
 	maxdecay = max(decaylow, decaylow + decaydiff)
 	releasetime = inst.maxtime * SAMPLERATE + 1.0 / -release if release != 0.0 else float('inf')
-	decaytime = (math.log(0.01, maxdecay)) * 4096 if maxdecay < 1.0 else float('inf')
-	if math.isinf(releasetime) and math.isinf(decaytime):
+	if maxdecay <= 0.0:
+		# Silent right away, as in the synth
+		decaytime = 0.0
+	else:
+		decaytime = (math.log(0.01, maxdecay)) * 4096 / decayscale if maxdecay < 1.0 else float('inf')
+	envelopetime = decaystart + 1.0 / envdecay if sustain == 0.0 else float('inf')
+	if math.isinf(releasetime) and math.isinf(decaytime) and math.isinf(envelopetime):
 		raise InputException("Instrument '%s' has infinite duration" % inst.title)
-	inst.maxsamples = int(min(releasetime, decaytime) + 65535) & -65536
+	inst.maxsamples = int(min(releasetime, decaytime, envelopetime) + 65535) & -65536
+	# The player computes the decay state from the number of samples left of the sound
+	decaybase = 1.0 + (decaystart - inst.maxsamples) * envdecay
 
 	left_volume = inst.volume.left * inst.velocity_quantum * 128
 	right_volume = inst.volume.right * inst.velocity_quantum * 128
@@ -224,7 +329,13 @@ def makeParamBlock(inst, uses_panning):
 			decaydiff, decaylow, harmonicity, sharpness, width,
 			filterlow, filterhigh, fslopelow, fslopehigh, fsweeplow, fsweephigh,
 			gain, inst.maxsamples, release, attack,
-			volume] + ([pan] if uses_panning else [])
+			volume] + ([pan] if uses_panning else []) + \
+			([envelopeCurve(inst.releasecurve), envelopeCurve(inst.attackcurve),
+			  decaybase, envdecay, envelopeCurve(inst.decaycurve), sustain] if uses_envelope else []) + \
+			([trackpivot, inst.decaytrack, inst.filtertrack] if uses_keytrack else []) + \
+			([swell, swellslope, swellspread] if uses_swell else []) + \
+			(formants + [formantsweep] if uses_formants else []) + \
+			([stiffness] if uses_stiffness else [])
 
 
 class Track:
@@ -352,6 +463,12 @@ class Music:
 			instr.velocity_quantum = quantum
 
 		# Calculate longest sample
+		self.uses_envelope = any(instr.usesEnvelope() for instr in self.instruments)
+		self.uses_keytrack = any(instr.usesKeytrack() for instr in self.instruments)
+		self.uses_swell = any(instr.usesSwell() for instr in self.instruments)
+		self.uses_formants = any(instr.usesFormants() for instr in self.instruments)
+		self.uses_stiffness = any(instr.usesStiffness() for instr in self.instruments)
+		self.uses_tuning = False
 		self.max_maxsamples = 0
 		self.max_total_samples = 0
 		self.end_of_sound = 0
@@ -375,12 +492,15 @@ class Music:
 			for i,t in enumerate(instr.tones):
 				instr.tonemap[t] = i
 
-			instr.paramblock = makeParamBlock(instr, self.uses_panning)
+			instr.paramblock = makeParamBlock(instr, self.uses_panning, self.uses_envelope, self.uses_keytrack, self.uses_swell, self.uses_formants, self.uses_stiffness)
 
 			instr.end_of_sound = instr.latest_note * ticklength * SAMPLERATE + instr.maxsamples
 			if instr.number in with_reverb:
 				instr.end_of_sound += reverb.halftime * 10 * SAMPLERATE
 
+			if any(f2i(instr.tuningOffset(tone)) != 0 for tone in instr.tones):
+				self.uses_tuning = True
+
 			self.max_maxsamples = max(self.max_maxsamples, instr.maxsamples)
 			self.max_total_samples = max(self.max_total_samples, instr.maxsamples * len(instr.tones))
 			self.end_of_sound = max(self.end_of_sound, instr.end_of_sound)
@@ -481,6 +601,18 @@ class Music:
 
 		if self.uses_panning:
 			self.out += "\n%define USES_PANNING\n"
+		if self.uses_tuning:
+			self.out += "\n%define USES_TUNING\n"
+		if self.uses_envelope:
+			self.out += "\n%define USES_ENVELOPE\n"
+		if self.uses_keytrack:
+			self.out += "\n%define USES_KEYTRACK\n"
+		if self.uses_swell:
+			self.out += "\n%define USES_SWELL\n"
+		if self.uses_formants:
+			self.out += "\n%define USES_FORMANTS\n"
+		if self.uses_stiffness:
+			self.out += "\n%define USES_STIFFNESS\n"
 
 		# Instrument parameters
 		self.out += "\n\n\tSECTION_DATA(iparam) align=4\n"
@@ -515,6 +647,15 @@ class Music:
 				first = False
 			self.out += "%d\n" % (-129 + instr.columns)
 
+		# Tuning offsets of the tones
+		if self.uses_tuning:
+			self.out += "\n\n\tSECTION_DATA(ituning) align=4\n"
+			self.out += "\nInstrumentTuning:\n"
+			for instr in self.instruments:
+				self.label(".i%02d" % instr.number)
+				self.comment(instr.title)
+				self.out += "\tdd\t" + ",".join("0x%08X" % f2i(instr.tuningOffset(tone)) for tone in instr.tones) + "\n"
+
 		# Track data
 		self.out += "\n\n\tSECTION_DATA(trdata) align=1\n"
 		self.out += "\nTrackData:\n"
@@ -648,6 +789,31 @@ def filterTractNotes(tname, column, notes, instr):
 
 	return notes2
 
+def splitLayers(tname, column, notes, instruments, instr):
+	# An instrument with velocity layers is split into one instrument per layer used
+	inst = instruments[instr]
+	if inst.velocityLayers() == 1:
+		return [(instr, notes)]
+
+	result = []
+	for layer in sorted(set(inst.velocityLayer(n.velocity) for n in notes if not n.off)):
+		if layer not in inst.layer_numbers:
+			inst.layer_numbers[layer] = len(instruments)
+			instruments.append(inst.layerInstrument(len(instruments), layer))
+		layer_notes = []
+		off = False
+		for n in notes:
+			if n.off or inst.velocityLayer(n.velocity) != layer:
+				if not off:
+					layer_notes.append(Note(tname, column, n.line, n.songpos, n.pat, n.patline, "OFF", 0, 127))
+					off = True
+			else:
+				layer_notes.append(n)
+				off = False
+		result.append((inst.layer_numbers[layer], layer_notes))
+
+	return result
+
 def pickupReverb(xdevices, reverb, tname, ticklength):
 	xplugin = xdevices.AudioPluginDevice
 	if len(xplugin) > 1:
@@ -667,6 +833,18 @@ def pickupReverb(xdevices, reverb, tname, ticklength):
 
 	return reverb
 
+def readTuning(xplugin):
+	# The synth stores its state as a chunk: the patch text followed by
+	# a "tune <key> <tone>" line for each key not at its equal-tempered tone.
+	tuning = dict()
+	chunk = str(xplugin.ParameterChunk).strip()
+	if chunk:
+		for line in base64.b64decode(chunk).split("\n"):
+			words = line.split("#")[0].split()
+			if len(words) == 3 and words[0] == "tune":
+				tuning[int(words[1])] = float(words[2])
+	return tuning
+
 def makeTracks(xsong, ticklength):
 	instruments = []
 	reverb_tracks = []
@@ -676,8 +854,11 @@ def makeTracks(xsong, ticklength):
 	for ii,xinst in enumerate(xsong.Instruments.Instrument):
 		params = [float(v) for v in instplugins(xinst).PluginDevice.Parameters.Parameter.Value]
 		if params:
-			legacy = str(instplugins(xinst).PluginDevice.PluginIdentifier) == "MetaSynth"
-			instrument = Instrument(ii, str(xinst.Name), params, legacy)
+			plugin_id = str(instplugins(xinst).PluginDevice.PluginIdentifier)
+			if plugin_id == "OidosNoise":
+				raise InputException("Instrument '%02X|%s' uses OidosNoise, which the player does not support yet" % (ii, str(xinst.Name)))
+			legacy = plugin_id == "MetaSynth"
+			instrument = Instrument(ii, str(xinst.Name), params, legacy, readTuning(instplugins(xinst).PluginDevice))
 			instrument.volume = makeVolume(instplugins(xinst).Volume)
 			instruments.append(instrument)
 			
@@ -746,11 +927,12 @@ def makeTracks(xsong, ticklength):
 
 			for instr in track_instrs:
 				instr_notes = filterTractNotes(tname, column, notes, instr)
-				track = Track(tr, column, tname, instr, instr_notes, volume)
-				if isactive(xdevices.AudioPluginDevice):
-					reverb_tracks.append(track)
-				else:
-					non_reverb_tracks.append(track)
+				for layer_instr,layer_notes in splitLayers(tname, column, instr_notes, instruments, instr):
+					track = Track(tr, column, tname, layer_instr, layer_notes, volume)
+					if isactive(xdevices.AudioPluginDevice):
+						reverb_tracks.append(track)
+					else:
+						non_reverb_tracks.append(track)
 	
 		reverb = pickupReverb(xdevices, reverb, tname, ticklength)
 
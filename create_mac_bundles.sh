@@ -55,6 +55,61 @@ echo "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
 # move the provided library to the correct location
 cp "synth/target/x86_64-apple-darwin/release/libOidos.dylib" "Oidos.vst/Contents/MacOS/Oidos"
 
+# Build noise synth
+cd synth
+cargo build --release --features noise --target-dir=target/noise --target=x86_64-apple-darwin
+cd ..
+
+# Make the bundle folder
+mkdir -p "OidosNoise.vst/Contents/MacOS"
+
+# Create the PkgInfo
+echo "BNDL????" > "OidosNoise.vst/Contents/PkgInfo"
+
+#build the Info.Plist
+echo "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">
+<plist version=\"1.0\">
+<dict>
+    <key>CFBundleDevelopmentRegion</key>
+    <string>English</string>
+
+    <key>CFBundleExecutable</key>
+    <string>OidosNoise</string>
+
+    <key>CFBundleGetInfoString</key>
+    <string>vst</string>
+
+    <key>CFBundleIconFile</key>
+    <string></string>
+
+    <key>CFBundleIdentifier</key>
+    <string>com.rust-vst2.OidosNoise</string>
+
+    <key>CFBundleInfoDictionaryVersion</key>
+    <string>6.0</string>
+
+    <key>CFBundleName</key>
+    <string>OidosNoise</string>
+
+    <key>CFBundlePackageType</key>
+    <string>BNDL</string>
+
+    <key>CFBundleVersion</key>
+    <string>1.0</string>
+
+    <key>CFBundleSignature</key>
+    <string>5275</string>
+
+    <key>CSResourcesFileMapped</key>
+    <string></string>
+
+</dict>
+</plist>" > "OidosNoise.vst/Contents/Info.plist"
+
+# move the provided library to the correct location
+cp "synth/target/noise/x86_64-apple-darwin/release/libOidos.dylib" "OidosNoise.vst/Contents/MacOS/OidosNoise"
+
 # Build reverb
 cd reverb
 cargo build --release --target=x86_64-apple-darwin
@@ -110,4 +165,4 @@ echo "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
 # move the provided library to the correct location
 cp "reverb/target/x86_64-apple-darwin/release/libOidosReverb.dylib" "OidosReverb.vst/Contents/MacOS/OidosReverb"
 
-echo "Created bundle Oidos.vst and OidosReverb.vst"
+echo "Created bundles Oidos.vst, OidosNoise.vst and OidosReverb.vst"
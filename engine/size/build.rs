@@ -0,0 +1,4 @@
+fn main() {
+	// The binary provides its own entry point
+	println!("cargo:rustc-link-arg-bins=-nostartfiles");
+}
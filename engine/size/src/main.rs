@@ -0,0 +1,133 @@
+// Minimal player built on the engine, writing the music as a WAV file to stdout.
+// Used for measuring the code size of the engine. Linux x86-64 only.
+#![no_std]
+#![no_main]
+#![allow(non_snake_case)]
+
+use core::arch::{asm, naked_asm};
+use core::panic::PanicInfo;
+
+use OidosEngine::generator::Partial;
+use OidosEngine::random::{fill_random_data, RANDOM_DATA_SIZE};
+use OidosEngine::reverb::{ReverbParams, DELAY_BUFFER_SIZE};
+use OidosEngine::sequencer::{generate_music, Buffers, Song};
+
+
+const TOTAL_SAMPLES: usize = 32768;
+const MAX_TOTAL_INSTRUMENT_SAMPLES: usize = 32768;
+const MAX_PARTIALS: usize = 6;
+
+static INSTRUMENT_PARAMS: [u32; 42] = [
+	3, 2, 20, 12, 0x3F000000, 0x3F7FF000, 0x3F000000, 0xBE800000, 0x3DCCCCCD, 0xC1A00000, 0x42200000,
+	0x3E800000, 0xBE000000, 0x3A000000, 0xBA000000, 0x40800000, 16384, 0xB8800000, 0x3C000000, 0x45000000, 0x3E800000,
+	2, 2, 10, 3, 0x00000000, 0x3F7FF000, 0x3F800000, 0x00000000, 0x3DCCCCCD, 0xC2F00000, 0x42F00000,
+	0x3F800000, 0xBF800000, 0x00000000, 0x00000000, 0x40000000, 16384, 0xB8800000, 0x3C000000, 0x457A0000, 0xBF000000
+];
+static INSTRUMENT_TONES: [u8; 6] = [60, 4, 0x80, 36, 7, 0x81];
+static TRACK_DATA: [u8; 16] = [3, 0, 1, 1, 2, 0x80, 0, 0, 2, 1, 1, 0x80, 0, 1, 1, 0x80];
+static NOTE_LENGTHS: [u8; 13] = [4, 4, 8, 0, 2, 2, 1, 1, 0, 1, 0xFF, 20, 0];
+static NOTE_SAMPLES: [u8; 12] = [1, 2, 0, 0, 1, 0, 2, 0, 0, 0, 1, 0];
+
+static SONG: Song = Song {
+	samples_per_tick: 2000,
+	total_samples: TOTAL_SAMPLES,
+	num_tracks_with_reverb: 1,
+	num_tracks_without_reverb: 1,
+	uses_panning: true,
+	uses_envelope: false,
+	uses_keytrack: false,
+	uses_swell: false,
+	uses_formants: false,
+	uses_stiffness: false,
+	reverb: Some(ReverbParams {
+		num_delays: 6,
+		min_delay: 300,
+		max_delay: 2000,
+		add_delay: 100,
+		seed: 4000,
+		max_decay: 0.8,
+		decay_mul: 0.9999,
+		filter_high: 0.5,
+		filter_low: 0.01,
+		dampen_high: 0.75,
+		dampen_low: 0.01,
+		volume_left: 0.25,
+		volume_right: 0.125
+	}),
+	instrument_params: &INSTRUMENT_PARAMS,
+	instrument_tones: &INSTRUMENT_TONES,
+	instrument_tuning: &[],
+	track_data: &TRACK_DATA,
+	note_lengths: &NOTE_LENGTHS,
+	note_samples: &NOTE_SAMPLES
+};
+
+#[repr(C)]
+struct WavFile {
+	header: [u32; 11],
+	music: [[i16; 2]; TOTAL_SAMPLES]
+}
+
+static mut RANDOM: [u32; RANDOM_DATA_SIZE] = [0; RANDOM_DATA_SIZE];
+static mut PARTIALS: [Partial; MAX_PARTIALS] = [Partial::EMPTY; MAX_PARTIALS];
+static mut TONES: [f64; MAX_TOTAL_INSTRUMENT_SAMPLES] = [0.0; MAX_TOTAL_INSTRUMENT_SAMPLES];
+static mut MIXING: [[f64; 2]; TOTAL_SAMPLES] = [[0.0; 2]; TOTAL_SAMPLES];
+static mut REVERB: [[f64; 2]; TOTAL_SAMPLES] = [[0.0; 2]; TOTAL_SAMPLES];
+static mut DELAY: [f64; DELAY_BUFFER_SIZE] = [0.0; DELAY_BUFFER_SIZE];
+static mut WAV_FILE: WavFile = WavFile {
+	header: [
+		0x46464952, 36 + TOTAL_SAMPLES as u32 * 4, 0x45564157, 0x20746D66, 16, 1 | 2 << 16,
+		44100, 44100 * 4, 4 | 16 << 16, 0x61746164, TOTAL_SAMPLES as u32 * 4
+	],
+	music: [[0; 2]; TOTAL_SAMPLES]
+};
+
+unsafe fn syscall3(number: usize, a: usize, b: usize, c: usize) -> usize {
+	let result;
+	asm!("syscall", inlateout("rax") number => result, in("rdi") a, in("rsi") b, in("rdx") c,
+		lateout("rcx") _, lateout("r11") _, options(nostack));
+	result
+}
+
+unsafe fn exit(code: usize) -> ! {
+	asm!("syscall", in("rax") 60, in("rdi") code, options(noreturn, nostack))
+}
+
+unsafe extern "C" fn main() -> ! {
+	let random = &mut *core::ptr::addr_of_mut!(RANDOM);
+	fill_random_data(random);
+	let mut buffers = Buffers {
+		random,
+		partials: &mut *core::ptr::addr_of_mut!(PARTIALS),
+		tones: &mut *core::ptr::addr_of_mut!(TONES),
+		mixing: &mut *core::ptr::addr_of_mut!(MIXING),
+		reverb: &mut *core::ptr::addr_of_mut!(REVERB),
+		delay: &mut *core::ptr::addr_of_mut!(DELAY)
+	};
+	let wav_file = &mut *core::ptr::addr_of_mut!(WAV_FILE);
+	if generate_music(&SONG, &mut buffers, &mut wav_file.music).is_err() {
+		exit(1);
+	}
+	let size = core::mem::size_of::<WavFile>();
+	let mut written = 0;
+	while written < size {
+		let result = syscall3(1, 1, wav_file as *const WavFile as usize + written, size - written) as isize;
+		if result <= 0 {
+			exit(1);
+		}
+		written += result as usize;
+	}
+	exit(0)
+}
+
+/// Entry point. Aligns the stack, which is not done by the kernel.
+#[unsafe(naked)]
+#[no_mangle]
+unsafe extern "C" fn _start() -> ! {
+	naked_asm!("and rsp, -16", "call {}", sym main)
+}
+
+#[panic_handler]
+fn panic(_: &PanicInfo) -> ! {
+	unsafe { exit(101) }
+}
@@ -0,0 +1,258 @@
+// Calculating the sound of an instrument at a tone
+
+use libm::{cos, exp2, log2, pow, rint, sin, sqrt};
+
+use crate::random::random_value;
+
+
+/// Angular frequency of tone 0, as stored in the player
+pub const BASE_FREQ: f32 = 0.002_329_707_9;
+/// Offset of the random values for the swell of a partial from the one for its width
+const SWELL_RANDOM_OFFSET: usize = 0x10000;
+
+/// Parameter block of an instrument, in the layout used by the player.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstrumentParams {
+	pub modes: u32,
+	pub fat: u32,
+	pub seed: u32,
+	pub overtones: u32,
+	pub decaydiff: f32,
+	pub decaylow: f32,
+	pub harmonicity: f32,
+	pub sharpness: f32,
+	pub width: f32,
+	pub filterlow: f32,
+	pub filterhigh: f32,
+	pub fslopelow: f32,
+	pub fslopehigh: f32,
+	pub fsweeplow: f32,
+	pub fsweephigh: f32,
+	pub gain: f32,
+	pub maxsamples: u32,
+	pub release: f32,
+	pub attack: f32,
+	pub volume: f32,
+	/// Zero if the music does not use panning
+	pub panning: f32,
+	/// Envelope curves and decay stage, which are linear and without decay
+	/// if the music does not use envelopes
+	pub release_curve: f32,
+	pub attack_curve: f32,
+	pub decay_base: f32,
+	pub decay: f32,
+	pub decay_curve: f32,
+	pub sustain: f32,
+	/// Key tracking relative to the pivot tone, which is
+	/// without effect if the music does not use key tracking
+	pub track_pivot: f32,
+	pub decay_track: f32,
+	pub filter_track: f32,
+	/// Swell rate of the partials, with its slope and random spread,
+	/// where a zero rate, as when the music does not use swell, means no swell
+	pub swell: f32,
+	pub swell_slope: f32,
+	pub swell_spread: f32,
+	/// Center, inverse width and gain minus one of the formant peaks, with their movement
+	/// per sample, which are without effect if the music does not use formants
+	pub formants: [(f32, f32, f32); 2],
+	pub formant_sweep: f32,
+	/// Inharmonicity coefficient of the partial frequencies,
+	/// zero if the music does not use stiffness
+	pub stiffness: f32
+}
+
+/// Number of words in a parameter block without and with panning
+pub const PARAMS_WORDS: usize = 20;
+pub const PARAMS_WORDS_PANNING: usize = 21;
+/// Number of words added to a parameter block if the music uses envelopes
+pub const PARAMS_WORDS_ENVELOPE: usize = 6;
+/// Number of words added to a parameter block if the music uses key tracking
+pub const PARAMS_WORDS_KEYTRACK: usize = 3;
+/// Number of words added to a parameter block if the music uses swell
+pub const PARAMS_WORDS_SWELL: usize = 3;
+/// Number of words added to a parameter block if the music uses formants
+pub const PARAMS_WORDS_FORMANTS: usize = 7;
+/// Number of words added to a parameter block if the music uses stiffness
+pub const PARAMS_WORDS_STIFFNESS: usize = 1;
+
+/// Bend a linear envelope stage, as done by the player.
+fn envelope_curve(x: f64, curve: f32) -> f64 {
+	x * (1.0 + curve as f64 * (x - 1.0))
+}
+
+impl InstrumentParams {
+	/// Read a parameter block. The envelope words follow the panning word, if present, the key
+	/// tracking words follow the envelope words, the swell words follow the key tracking words,
+	/// the formant words, with the values of the two peaks interleaved, follow the swell words,
+	/// and the stiffness word follows the formant words.
+	pub fn from_words(w: &[u32], uses_panning: bool, uses_envelope: bool, uses_keytrack: bool, uses_swell: bool,
+	                  uses_formants: bool, uses_stiffness: bool) -> InstrumentParams {
+		let f = |i: usize| f32::from_bits(w[i]);
+		let envelope = if uses_panning { PARAMS_WORDS_PANNING } else { PARAMS_WORDS };
+		let keytrack = if uses_envelope { envelope + PARAMS_WORDS_ENVELOPE } else { envelope };
+		let swell = if uses_keytrack { keytrack + PARAMS_WORDS_KEYTRACK } else { keytrack };
+		let e = |i: usize, default: f32| if uses_envelope { f(envelope + i) } else { default };
+		let k = |i: usize| if uses_keytrack { f(keytrack + i) } else { 0.0 };
+		let formants = if uses_swell { swell + PARAMS_WORDS_SWELL } else { swell };
+		let s = |i: usize| if uses_swell { f(swell + i) } else { 0.0 };
+		let stiffness = if uses_formants { formants + PARAMS_WORDS_FORMANTS } else { formants };
+		let r = |i: usize| if uses_formants { f(formants + i) } else { 0.0 };
+		InstrumentParams {
+			modes: w[0],
+			fat: w[1],
+			seed: w[2],
+			overtones: w[3],
+			decaydiff: f(4),
+			decaylow: f(5),
+			harmonicity: f(6),
+			sharpness: f(7),
+			width: f(8),
+			filterlow: f(9),
+			filterhigh: f(10),
+			fslopelow: f(11),
+			fslopehigh: f(12),
+			fsweeplow: f(13),
+			fsweephigh: f(14),
+			gain: f(15),
+			maxsamples: w[16],
+			release: f(17),
+			attack: f(18),
+			volume: f(19),
+			panning: if uses_panning { f(20) } else { 0.0 },
+			release_curve: e(0, 0.0),
+			attack_curve: e(1, 0.0),
+			decay_base: e(2, 1.0),
+			decay: e(3, 0.0),
+			decay_curve: e(4, 0.0),
+			sustain: e(5, 1.0),
+			track_pivot: k(0),
+			decay_track: k(1),
+			filter_track: k(2),
+			swell: s(0),
+			swell_slope: s(1),
+			swell_spread: s(2),
+			formants: [(r(0), r(2), r(4)), (r(1), r(3), r(5))],
+			formant_sweep: r(6),
+			stiffness: if uses_stiffness { f(stiffness) } else { 0.0 }
+		}
+	}
+
+	/// Level of the attack and release for the states of their linear ramps.
+	#[inline]
+	pub fn attack_release_level(&self, release_state: f64, attack_state: f64) -> f64 {
+		let release = envelope_curve(release_state.clamp(0.0, 1.0), self.release_curve);
+		let attack = envelope_curve(attack_state.clamp(0.0, 1.0), self.attack_curve);
+		release.min(attack)
+	}
+
+	/// Level of the hold, decay and sustain stages at a sample of the sound of a tone.
+	#[inline]
+	pub fn decay_level(&self, sample: usize) -> f64 {
+		let state = self.decay_base as f64 + (self.maxsamples as usize - sample) as f64 * self.decay as f64;
+		let sustain = self.sustain as f64;
+		sustain + (1.0 - sustain) * envelope_curve(state.clamp(0.0, 1.0), self.decay_curve)
+	}
+
+	/// Number of partials in the sound
+	pub fn partials(&self) -> usize {
+		(self.modes * self.fat) as usize
+	}
+}
+
+/// State of one partial while generating.
+#[derive(Clone, Copy, Default)]
+pub struct Partial {
+	step: (f64, f64),
+	state: (f64, f64),
+	filter: (f64, f64),
+	/// Swell level and its increase per sample
+	swell: (f64, f64),
+	/// Ramps of the formant peaks, their increases per sample and the gains minus one
+	formants: [(f64, f64, f64); 2]
+}
+
+impl Partial {
+	/// Initial value for statically allocated partial buffers
+	pub const EMPTY: Partial = Partial { step: (0.0, 0.0), state: (0.0, 0.0), filter: (0.0, 0.0), swell: (0.0, 0.0),
+	                                    formants: [(0.0, 0.0, 0.0); 2] };
+}
+
+/// Calculate the sound of an instrument at a tone, including any tuning offset and the decay
+/// stage of the envelope, into `out`, as done by `MakeInstrument` in the player. The sound is
+/// mono. `partials` must hold at least `params.partials()` entries, and `out` is usually
+/// `params.maxsamples` long.
+pub fn make_tone(p: &InstrumentParams, tone: f64, random: &[u32], partials: &mut [Partial], out: &mut [f64]) {
+	let partials = &mut partials[..p.partials()];
+	// Key tracking of the decay and filter, relative to the pivot tone
+	let decay_scale = exp2((tone - p.track_pivot as f64) * p.decay_track as f64 / 12.0);
+	let filter_offset = (tone - p.track_pivot as f64) * p.filter_track as f64;
+	for m in 0..p.modes as usize {
+		let mut random_index = m * 256 + p.seed as usize;
+		let mut getrandom = || {
+			random_index += 1;
+			random_value(random, random_index - 1)
+		};
+
+		let subtone = getrandom().abs();
+		let reltone = subtone * p.overtones as f64;
+		let ampmul = pow(subtone * p.decaydiff as f64 + p.decaylow as f64, decay_scale / 4096.0);
+
+		let relfreq = exp2(reltone / 12.0);
+		let relfreq = relfreq + (rint(relfreq) - relfreq) * p.harmonicity as f64;
+		let relfreq = relfreq * sqrt(1.0 + p.stiffness as f64 * relfreq * relfreq);
+		let reltone = log2(relfreq) * 12.0;
+		let mamp = exp2(reltone * p.sharpness as f64 / 12.0) * getrandom();
+
+		for (f, partial) in partials[m * p.fat as usize..(m + 1) * p.fat as usize].iter_mut().enumerate() {
+			let swell_random = random_value(random, m * 256 + p.seed as usize + 2 + f * 2 + SWELL_RANDOM_OFFSET);
+			let prel = getrandom() * p.width as f64 + reltone;
+			let angle = exp2((prel + tone) / 12.0) * BASE_FREQ as f64;
+			let phase = core::f64::consts::PI * getrandom();
+			*partial = Partial {
+				step: (cos(angle) * ampmul, sin(angle) * ampmul),
+				state: (cos(phase) * mamp, sin(phase) * mamp),
+				filter: (
+					(prel + filter_offset - p.filterlow as f64) * p.fslopelow as f64 + 1.0,
+					(prel + filter_offset - p.filterhigh as f64) * p.fslopehigh as f64 + 1.0
+				),
+				swell: if p.swell == 0.0 {
+					(1.0, 0.0)
+				} else {
+					let swell_tone = prel * p.swell_slope as f64 + swell_random * p.swell_spread as f64;
+					(0.0, p.swell as f64 * exp2(-swell_tone / 12.0))
+				},
+				formants: p.formants.map(|(center, slope, gain)| (
+					1.0 - (prel + tone - center as f64) * slope as f64,
+					p.formant_sweep as f64 * slope as f64,
+					gain as f64
+				))
+			};
+		}
+	}
+
+	let n = partials.len() as f64;
+	let gain = p.gain as f64;
+	let filter_add = (
+		p.fslopelow as f64 * p.fsweeplow as f64,
+		p.fslopehigh as f64 * p.fsweephigh as f64
+	);
+	for (t, sample) in out.iter_mut().enumerate() {
+		let mut sum = 0.0;
+		for partial in partials.iter_mut() {
+			let (x1, y1) = partial.step;
+			let (x2, y2) = partial.state;
+			partial.state = (x1 * x2 - y1 * y2, y1 * x2 + x1 * y2);
+			let mut factor = partial.filter.0.min(partial.filter.1).min(partial.swell.0).clamp(0.0, 1.0);
+			for formant in &mut partial.formants {
+				factor *= 1.0 + formant.2 * formant.0.min(2.0 - formant.0).clamp(0.0, 1.0);
+				formant.0 += formant.1;
+			}
+			sum += partial.state.0 * factor;
+			partial.filter.0 += filter_add.0;
+			partial.filter.1 += filter_add.1;
+			partial.swell.0 += partial.swell.1;
+		}
+		*sample = sum / sqrt(((gain - 1.0) * sum * sum + n) / gain) * p.decay_level(t);
+	}
+}
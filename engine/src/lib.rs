@@ -0,0 +1,42 @@
+// Oidos generator, reverb and sequencer without std, for size-constrained intros
+#![no_std]
+#![allow(non_snake_case)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub mod generator;
+pub mod noise;
+pub mod random;
+pub mod reverb;
+pub mod sequencer;
+
+#[cfg(test)]
+extern crate std;
+
+/// Build the minimal player in the `size` directory and report the size of its code.
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn test_text_size() {
+	use std::fs;
+	use std::println;
+	use std::process::Command;
+
+	let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/size");
+	let status = Command::new(env!("CARGO")).args(["build", "--release"]).current_dir(dir).status().unwrap();
+	assert!(status.success());
+	let elf = fs::read(std::format!("{}/target/release/OidosEngineSize", dir)).unwrap();
+
+	let u16_at = |pos: usize| u16::from_le_bytes([elf[pos], elf[pos + 1]]) as usize;
+	let u32_at = |pos: usize| u32::from_le_bytes([elf[pos], elf[pos + 1], elf[pos + 2], elf[pos + 3]]) as usize;
+	let u64_at = |pos: usize| u32_at(pos) | u32_at(pos + 4) << 32;
+	let (shoff, shentsize, shnum, shstrndx) = (u64_at(0x28), u16_at(0x3A), u16_at(0x3C), u16_at(0x3E));
+	let strtab = u64_at(shoff + shstrndx * shentsize + 0x18);
+	let text_size = (0..shnum).map(|i| shoff + i * shentsize).find(|&header| {
+		let name = strtab + u32_at(header);
+		elf[name..].starts_with(b".text\0")
+	}).map(|header| u64_at(header + 0x20)).unwrap();
+
+	println!("Size of .text in minimal player: {} bytes", text_size);
+	assert!(text_size < 16384);
+}
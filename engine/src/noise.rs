@@ -0,0 +1,104 @@
+// Calculating the sound of an OidosNoise instrument at a tone
+
+use libm::{exp2, pow, sqrt, tan};
+
+use crate::generator::BASE_FREQ;
+use crate::random::random_value;
+
+
+/// Highest filter frequency, in radians per sample, keeping the filter clear of the Nyquist frequency
+const MAX_FILTER_FREQ: f64 = 0.98 * core::f64::consts::PI;
+
+/// Parameter block of an OidosNoise instrument, in the layout written by `make_noise_param_block`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoiseParams {
+	/// Start of the noise in the random data
+	pub random_offset: u32,
+	/// Distance of the filter from the played tone, in semitones
+	pub filter: f32,
+	pub damping: f32,
+	/// Mix of the band pass output with the low pass (negative) or high pass (positive) output
+	pub shape: f32,
+	/// Start of the pitch envelope of the filter, in semitones
+	pub pitchenv: f32,
+	/// Decay factors of the pitch envelope and the amplitude over 4096 samples
+	pub pitchdecay: f32,
+	pub decay: f32,
+	pub gain: f32,
+	pub maxsamples: u32,
+	pub release: f32,
+	pub attack: f32,
+	pub volume: f32,
+	/// Zero if the music does not use panning
+	pub panning: f32
+}
+
+/// Number of words in a noise parameter block without and with panning
+pub const NOISE_PARAMS_WORDS: usize = 12;
+pub const NOISE_PARAMS_WORDS_PANNING: usize = 13;
+
+impl NoiseParams {
+	/// Read a noise parameter block.
+	pub fn from_words(w: &[u32], uses_panning: bool) -> NoiseParams {
+		let f = |i: usize| f32::from_bits(w[i]);
+		NoiseParams {
+			random_offset: w[0],
+			filter: f(1),
+			damping: f(2),
+			shape: f(3),
+			pitchenv: f(4),
+			pitchdecay: f(5),
+			decay: f(6),
+			gain: f(7),
+			maxsamples: w[8],
+			release: f(9),
+			attack: f(10),
+			volume: f(11),
+			panning: if uses_panning { f(12) } else { 0.0 }
+		}
+	}
+}
+
+/// Calculate the sound of a noise instrument at a tone, including any tuning offset, into `out`,
+/// which is usually `params.maxsamples` long. The noise runs through a state variable filter
+/// following the pitch envelope, and the sound is mono.
+pub fn make_noise_tone(p: &NoiseParams, tone: f64, random: &[u32], out: &mut [f64]) {
+	let pitch_mul = pow(p.pitchdecay as f64, 1.0 / 4096.0);
+	let amp_mul = pow(p.decay as f64, 1.0 / 4096.0);
+	let k = p.damping as f64;
+	let shape = p.shape as f64;
+	let gain = p.gain as f64;
+	let filter_tone = tone + p.filter as f64;
+
+	let (mut ic1, mut ic2) = (0.0, 0.0);
+	let mut pitch = p.pitchenv as f64;
+	let mut amp = 1.0;
+	for (t, sample) in out.iter_mut().enumerate() {
+		let x = random_value(random, (p.random_offset as usize + t) % random.len());
+
+		// Trapezoidal state variable filter, stable at all frequencies
+		let freq = (BASE_FREQ as f64 * exp2((filter_tone + pitch) / 12.0)).min(MAX_FILTER_FREQ);
+		let g = tan(freq / 2.0);
+		let a1 = 1.0 / (1.0 + g * (g + k));
+		let a2 = g * a1;
+		let a3 = g * a2;
+		let v3 = x - ic2;
+		let v1 = a1 * ic1 + a2 * v3;
+		let v2 = ic2 + a2 * ic1 + a3 * v3;
+		ic1 = 2.0 * v1 - ic1;
+		ic2 = 2.0 * v2 - ic2;
+		pitch *= pitch_mul;
+
+		// The band pass output is scaled to unit gain at the filter frequency
+		let (low, band, high) = (v2, k * v1, x - k * v1 - v2);
+		let filtered = if shape < 0.0 {
+			band + (low - band) * -shape
+		} else {
+			band + (high - band) * shape
+		};
+
+		let s = filtered * amp;
+		amp *= amp_mul;
+		*sample = s * sqrt(gain / (1.0 + (gain - 1.0) * s * s));
+	}
+}
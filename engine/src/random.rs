@@ -0,0 +1,33 @@
+// The random data used by the player
+
+pub const NOISESIZE: usize = 64;
+/// Number of words in the random data
+pub const RANDOM_DATA_SIZE: usize = NOISESIZE * NOISESIZE * NOISESIZE;
+
+/// Fill a block of random data in the same way as `Oidos_FillRandomData`.
+/// The block must hold `RANDOM_DATA_SIZE` words to be usable by the generator.
+pub fn fill_random_data(data: &mut [u32]) {
+	let mut state: [u32; 4] = [0x6F15AAF2, 0x4E89D208, 0x9548B49A, 0x9C4FD335];
+	for word in data {
+		let mut r = 0u32;
+		for s in 0..3 {
+			state[s] = state[s].rotate_right(state[s]).wrapping_add(state[s + 1]);
+			r ^= state[s];
+		}
+		*word = r;
+	}
+}
+
+/// A random word scaled to the -1 to 1 range.
+#[inline]
+pub fn random_value(random: &[u32], index: usize) -> f64 {
+	random[index] as i32 as f64 * (1.0 / 2147483648.0)
+}
+
+#[test]
+fn test_random_data() {
+	let mut random = [0u32; RANDOM_DATA_SIZE];
+	fill_random_data(&mut random);
+	assert_eq!(random[0], 0xCAADAA7B);
+	assert_eq!(random[RANDOM_DATA_SIZE - 1], 0xB08A4BA7);
+}
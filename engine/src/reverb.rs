@@ -0,0 +1,68 @@
+// The reverb of the player
+
+
+/// Size of the delay buffer used by the player, in samples
+pub const DELAY_BUFFER_SIZE: usize = 25600;
+
+/// Reverb settings, as given by the `REVERB_` defines of the converted music.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReverbParams {
+	pub num_delays: u32,
+	pub min_delay: u32,
+	pub max_delay: u32,
+	pub add_delay: u32,
+	pub seed: u32,
+	pub max_decay: f32,
+	pub decay_mul: f32,
+	pub filter_high: f32,
+	pub filter_low: f32,
+	pub dampen_high: f32,
+	pub dampen_low: f32,
+	pub volume_left: f32,
+	pub volume_right: f32
+}
+
+/// Low-pass filter step, with the denormal avoidance of the player.
+#[inline]
+fn low_pass(x: f64, state: &mut f64, param: f32) -> f64 {
+	let y = (x - *state) * param as f64 + *state;
+	*state = (y + 1.0) - 1.0;
+	*state
+}
+
+/// Add the reverb of `input` to `output`, as done in `Oidos_GenerateMusic`.
+/// `delay_buffer` must hold at least `max_delay` samples. Its contents are ignored.
+pub fn apply_reverb(reverb: &ReverbParams, input: &[[f64; 2]], output: &mut [[f64; 2]], delay_buffer: &mut [f64], random: &[u32]) {
+	for d in delay_buffer.iter_mut() {
+		*d = 0.0;
+	}
+	let mut state = [0f64; 4];
+	let mut feedback = reverb.max_decay as f64;
+	let mut remaining = reverb.num_delays as u64;
+	let mut channel = 0;
+	let mut volume = reverb.volume_left as f64;
+	let add_delay = reverb.add_delay as usize;
+	for delay in (1..=reverb.max_delay).rev() {
+		let r = random[(reverb.seed + delay) as usize];
+		if ((delay.wrapping_sub(reverb.min_delay) as u64 * r as u64) >> 32) < remaining {
+			let delay_buffer = &mut delay_buffer[..delay as usize];
+			for (i, x) in input.iter().map(|s| s[channel]).enumerate() {
+				let slot = &mut delay_buffer[i % delay as usize];
+				let input_filtered = low_pass(x, &mut state[0], reverb.filter_high) - low_pass(x, &mut state[1], reverb.filter_low);
+				let delayed = *slot;
+				let echo_filtered = low_pass(delayed, &mut state[2], reverb.dampen_high) - low_pass(delayed, &mut state[3], reverb.dampen_low);
+				if let Some(out) = output.get_mut(i + add_delay) {
+					out[channel] += delayed * volume;
+				}
+				*slot = input_filtered + echo_filtered * feedback;
+			}
+			if reverb.volume_left != reverb.volume_right {
+				volume = if channel == 0 { reverb.volume_right } else { reverb.volume_left } as f64;
+			}
+			channel ^= 1;
+			remaining -= 1;
+		}
+		feedback *= reverb.decay_mul as f64;
+	}
+}
+
@@ -0,0 +1,518 @@
+// Playing the music data of the player
+
+use libm::rint;
+
+use crate::generator::{make_tone, InstrumentParams, Partial, PARAMS_WORDS, PARAMS_WORDS_ENVELOPE,
+                       PARAMS_WORDS_FORMANTS, PARAMS_WORDS_KEYTRACK, PARAMS_WORDS_PANNING, PARAMS_WORDS_STIFFNESS,
+                       PARAMS_WORDS_SWELL};
+use crate::random::RANDOM_DATA_SIZE;
+use crate::reverb::{apply_reverb, ReverbParams};
+
+#[cfg(feature = "alloc")] use crate::reverb::DELAY_BUFFER_SIZE;
+#[cfg(feature = "alloc")] use alloc::vec;
+#[cfg(feature = "alloc")] use alloc::vec::Vec;
+
+
+pub type Error = &'static str;
+
+const AMP_MAX: f64 = 32767.0;
+/// Maximum number of distinct tone and velocity combinations in a track column
+const MAX_TAVS: usize = 128;
+
+/// The music, in the data layout of the converted music file. The slices are the
+/// contents of the data sections of the same names.
+pub struct Song<'a> {
+	pub samples_per_tick: usize,
+	pub total_samples: usize,
+	pub num_tracks_with_reverb: usize,
+	pub num_tracks_without_reverb: usize,
+	pub uses_panning: bool,
+	/// Whether `USES_ENVELOPE` is defined
+	pub uses_envelope: bool,
+	/// Whether `USES_KEYTRACK` is defined
+	pub uses_keytrack: bool,
+	/// Whether `USES_SWELL` is defined
+	pub uses_swell: bool,
+	/// Whether `USES_FORMANTS` is defined
+	pub uses_formants: bool,
+	/// Whether `USES_STIFFNESS` is defined
+	pub uses_stiffness: bool,
+	/// Must be present if there are tracks with reverb
+	pub reverb: Option<ReverbParams>,
+	/// `iparam` section
+	pub instrument_params: &'a [u32],
+	/// `itones` section
+	pub instrument_tones: &'a [u8],
+	/// `ituning` section, empty if the music does not use tuning
+	pub instrument_tuning: &'a [f32],
+	/// `trdata` section
+	pub track_data: &'a [u8],
+	/// `notelen` section
+	pub note_lengths: &'a [u8],
+	/// `notesamp` section
+	pub note_samples: &'a [u8]
+}
+
+/// Buffers used while generating the music, provided by the caller.
+pub struct Buffers<'a> {
+	/// Random data filled by `fill_random_data`, `RANDOM_DATA_SIZE` words
+	pub random: &'a [u32],
+	/// At least `Song::max_partials` entries
+	pub partials: &'a mut [Partial],
+	/// Sounds of the tones of one instrument, at least `Song::max_instrument_samples` samples
+	pub tones: &'a mut [f64],
+	/// `total_samples` samples
+	pub mixing: &'a mut [[f64; 2]],
+	/// `total_samples` samples, empty if there are no tracks with reverb
+	pub reverb: &'a mut [[f64; 2]],
+	/// `DELAY_BUFFER_SIZE` samples, empty if there are no tracks with reverb
+	pub delay: &'a mut [f64]
+}
+
+/// Sequential reader of a data section, like the data pointers of the player.
+struct Stream<'a> {
+	data: &'a [u8],
+	pos: usize
+}
+
+impl<'a> Stream<'a> {
+	fn new(data: &'a [u8]) -> Stream<'a> {
+		Stream { data, pos: 0 }
+	}
+
+	#[inline]
+	fn byte(&mut self) -> Result<u8, Error> {
+		let byte = *self.data.get(self.pos).ok_or("Music data ends prematurely")?;
+		self.pos += 1;
+		Ok(byte)
+	}
+}
+
+impl<'a> Song<'a> {
+	fn num_instruments(&self) -> usize {
+		self.num_tracks_with_reverb + self.num_tracks_without_reverb
+	}
+
+	fn params(&self, instrument: usize) -> Result<InstrumentParams, Error> {
+		let mut words = if self.uses_panning { PARAMS_WORDS_PANNING } else { PARAMS_WORDS };
+		if self.uses_envelope {
+			words += PARAMS_WORDS_ENVELOPE;
+		}
+		if self.uses_keytrack {
+			words += PARAMS_WORDS_KEYTRACK;
+		}
+		if self.uses_swell {
+			words += PARAMS_WORDS_SWELL;
+		}
+		if self.uses_formants {
+			words += PARAMS_WORDS_FORMANTS;
+		}
+		if self.uses_stiffness {
+			words += PARAMS_WORDS_STIFFNESS;
+		}
+		self.instrument_params.get(instrument * words..(instrument + 1) * words)
+			.map(|w| InstrumentParams::from_words(w, self.uses_panning, self.uses_envelope, self.uses_keytrack,
+			                                      self.uses_swell, self.uses_formants, self.uses_stiffness))
+			.ok_or("Instrument parameters end prematurely")
+	}
+
+	/// The largest number of partials of any instrument.
+	pub fn max_partials(&self) -> Result<usize, Error> {
+		let mut max = 0;
+		for i in 0..self.num_instruments() {
+			max = max.max(self.params(i)?.partials());
+		}
+		Ok(max)
+	}
+
+	/// The largest number of samples of all tones of any instrument
+	/// (`MAX_TOTAL_INSTRUMENT_SAMPLES` in the converted music).
+	pub fn max_instrument_samples(&self) -> Result<usize, Error> {
+		let mut tones = Stream::new(self.instrument_tones);
+		let mut max = 0;
+		for i in 0..self.num_instruments() {
+			let mut tone = 0i32;
+			let mut count = 0;
+			loop {
+				tone += tones.byte()? as i8 as i32;
+				if tone < 0 {
+					break;
+				}
+				count += 1;
+			}
+			max = max.max(count * self.params(i)?.maxsamples as usize);
+		}
+		Ok(max)
+	}
+}
+
+fn mix_reverb(song: &Song, buffers: &mut Buffers) -> Result<(), Error> {
+	let reverb = song.reverb.as_ref().ok_or("Missing reverb parameters")?;
+	if buffers.reverb.len() < song.total_samples || buffers.delay.len() < reverb.max_delay as usize {
+		return Err("Reverb buffers too small");
+	}
+	apply_reverb(reverb, &buffers.mixing[..song.total_samples], buffers.reverb, buffers.delay, buffers.random);
+	Ok(())
+}
+
+/// Generate the music into `out`, as done by `Oidos_GenerateMusic`.
+/// `out` receives up to `total_samples` stereo samples.
+pub fn generate_music(song: &Song, buffers: &mut Buffers, out: &mut [[i16; 2]]) -> Result<(), Error> {
+	if buffers.random.len() < RANDOM_DATA_SIZE || buffers.mixing.len() < song.total_samples {
+		return Err("Buffers too small");
+	}
+	for s in buffers.mixing.iter_mut().chain(buffers.reverb.iter_mut()) {
+		*s = [0.0; 2];
+	}
+
+	let mut tones = Stream::new(song.instrument_tones);
+	let mut tovel = Stream::new(song.track_data);
+	let mut lengths = Stream::new(song.note_lengths);
+	let mut notes = Stream::new(song.note_samples);
+	let mut tuning = song.instrument_tuning.iter();
+	let mixing_end = song.total_samples;
+	for i in 0..song.num_instruments() {
+		if i == song.num_tracks_with_reverb && i > 0 {
+			mix_reverb(song, buffers)?;
+		}
+		let p = song.params(i)?;
+		let maxsamples = p.maxsamples as usize;
+		if p.partials() > buffers.partials.len() {
+			return Err("Partial buffer too small");
+		}
+
+		// Tones are delta coded, terminated by a byte making the sum negative
+		let mut tone = 0i32;
+		let mut num_tones = 0;
+		let mut terminator;
+		loop {
+			terminator = tones.byte()? as i8;
+			tone += terminator as i32;
+			if tone < 0 {
+				break;
+			}
+			let offset = if song.instrument_tuning.is_empty() {
+				0.0
+			} else {
+				*tuning.next().ok_or("Tuning data ends prematurely")?
+			};
+			let sound = buffers.tones.get_mut(num_tones * maxsamples..(num_tones + 1) * maxsamples).ok_or("Tone buffer too small")?;
+			make_tone(&p, tone as f64 + offset as f64, buffers.random, buffers.partials, sound);
+			num_tones += 1;
+		}
+
+		// The terminator is counted down once for each column
+		loop {
+			let singular_length = tovel.byte()? as usize;
+			let mut tavs = [(0usize, 0u8); MAX_TAVS];
+			let mut num_tavs = 0;
+			let mut tone_index = 0u8;
+			loop {
+				tone_index = tone_index.wrapping_add(tovel.byte()?);
+				if (tone_index as i8) < 0 {
+					break;
+				}
+				if tone_index as usize >= num_tones || num_tavs == MAX_TAVS {
+					return Err("Illegal tone index");
+				}
+				tavs[num_tavs] = (tone_index as usize, tovel.byte()?);
+				num_tavs += 1;
+			}
+
+			let mut position = 0;
+			loop {
+				let mut length = lengths.byte()? as usize;
+				if length >= 0x80 {
+					length = ((!length & 0xFF) << 8) | lengths.byte()? as usize;
+				}
+				let length = length * song.samples_per_tick;
+				let sample = notes.byte()? as i8;
+				if sample > 0 {
+					let (tone_index, note_volume) = *tavs[..num_tavs].get(sample as usize - 1).ok_or("Illegal note")?;
+					let note_length = if singular_length != 0 { singular_length * song.samples_per_tick } else { length };
+					let volume = p.volume as f64 * note_volume as f64;
+					let volume = [volume * (1.0 - p.panning as f64), volume * (1.0 + p.panning as f64)];
+					let release = p.release as f64;
+					let attack = p.attack as f64;
+					let mut release_state = 1.0 - note_length as f64 * release;
+					let mut attack_state = 0.0;
+					let sound = &buffers.tones[tone_index * maxsamples..(tone_index + 1) * maxsamples];
+					for (s, out) in sound.iter().zip(buffers.mixing[position.min(mixing_end)..mixing_end].iter_mut()) {
+						let envelope = p.attack_release_level(release_state, attack_state);
+						out[0] += s * volume[0] * envelope;
+						out[1] += s * volume[1] * envelope;
+						release_state += release;
+						attack_state += attack;
+					}
+				}
+				position += length;
+				if length == 0 {
+					break;
+				}
+			}
+
+			terminator = terminator.wrapping_sub(1);
+			if terminator >= 0 {
+				break;
+			}
+		}
+	}
+	if song.num_tracks_without_reverb == 0 && song.num_tracks_with_reverb > 0 {
+		mix_reverb(song, buffers)?;
+	}
+
+	let has_reverb = song.num_tracks_with_reverb > 0;
+	for (i, out) in out.iter_mut().take(song.total_samples).enumerate() {
+		let m = buffers.mixing[i];
+		let r = if has_reverb { buffers.reverb[i] } else { [0.0; 2] };
+		let sample = |c: usize| rint((m[c] + r[c]).clamp(-AMP_MAX, AMP_MAX)) as i16;
+		*out = [sample(0), sample(1)];
+	}
+	Ok(())
+}
+
+/// Generate the music with buffers allocated as needed.
+#[cfg(feature = "alloc")]
+pub fn render_music(song: &Song, random: &[u32]) -> Result<Vec<[i16; 2]>, Error> {
+	let reverb_samples = if song.num_tracks_with_reverb > 0 { song.total_samples } else { 0 };
+	let mut partials = vec![Partial::default(); song.max_partials()?];
+	let mut tones = vec![0f64; song.max_instrument_samples()?];
+	let mut mixing = vec![[0f64; 2]; song.total_samples];
+	let mut reverb = vec![[0f64; 2]; reverb_samples];
+	let mut delay = vec![0f64; if reverb_samples > 0 { DELAY_BUFFER_SIZE } else { 0 }];
+	let mut out = vec![[0i16; 2]; song.total_samples];
+	generate_music(song, &mut Buffers {
+		random,
+		partials: &mut partials,
+		tones: &mut tones,
+		mixing: &mut mixing,
+		reverb: &mut reverb,
+		delay: &mut delay
+	}, &mut out)?;
+	Ok(out)
+}
+
+#[cfg(test)]
+const TEST_MUSIC: &str = "\
+%define MUSIC_LENGTH 12
+%define TOTAL_SAMPLES 32768
+%define MAX_TOTAL_INSTRUMENT_SAMPLES 32768
+%define USES_PANNING
+
+%define SAMPLES_PER_TICK 2000
+%define TICKS_PER_SECOND 22.050000000
+
+%define NUM_TRACKS_WITH_REVERB 1
+%define NUM_TRACKS_WITHOUT_REVERB 1
+
+%define REVERB_NUM_DELAYS   6
+%define REVERB_MIN_DELAY    300
+%define REVERB_MAX_DELAY    2000
+%define REVERB_ADD_DELAY    100
+%define REVERB_RANDOMSEED   4000
+%define REVERB_MAX_DECAY    0.800000000
+%define REVERB_DECAY_MUL    0.999900000
+%define REVERB_FILTER_HIGH  0x3F000000 ; 0.500000000
+%define REVERB_FILTER_LOW   0x3C23D70A ; 0.010000000
+%define REVERB_DAMPEN_HIGH  0x3F400000 ; 0.750000000
+%define REVERB_DAMPEN_LOW   0x3C23D70A ; 0.010000000
+%define REVERB_VOLUME_LEFT  0x3E800000 ; 0.250000000
+%define REVERB_VOLUME_RIGHT 0x3E000000 ; 0.125000000
+
+	SECTION_DATA(iparam) align=4
+
+InstrumentParams:
+.i00:
+	dd	3,2,20,12,0x3F000000,0x3F7FF000,0x3F000000,0xBE800000,0x3DCCCCCD,0xC1A00000,0x42200000,0x3E800000,0xBE000000,0x3A000000,0xBA000000,0x40800000,16384,0xB8800000,0x3C000000,0x45000000,0x3E800000
+.i01:
+	dd	2,2,10,3,0x00000000,0x3F7FF000,0x3F800000,0x00000000,0x3DCCCCCD,0xC2F00000,0x42F00000,0x3F800000,0xBF800000,0x00000000,0x00000000,0x40000000,16384,0xB8800000,0x3C000000,0x457A0000,0xBF000000
+
+	SECTION_DATA(itones) align=1
+
+InstrumentTones:
+.i00:
+	db	60,4,-128
+.i01:
+	db	36,7,-127
+
+	SECTION_DATA(trdata) align=1
+
+TrackData:
+.t_Pad_1_1:
+	db	3,0,1,1,2,-128
+.t_Bass_1_0:
+	db	0,0,2,1,1,-128
+.t_Bass_2_0:
+	db	0,1,1,-128
+
+	SECTION_DATA(notelen) align=1
+
+NoteLengths:
+L_Pad_1_1:
+	db	4,4,8
+	db	0
+L_Bass_1_0:
+	db	2,2,1,1
+	db	0
+L_Bass_2_0:
+	db	1,-1,20
+	db	0
+
+	SECTION_DATA(notesamp) align=1
+
+NoteSamples:
+S_Pad_1_1:
+	db	1,2,0,0
+S_Bass_1_0:
+	db	1,0,2,0,0
+S_Bass_2_0:
+	db	0,1,0
+";
+
+#[test]
+fn test_generate_music() {
+	use crate::random::fill_random_data;
+	use OidosMusic::asm::parse_music;
+	use OidosMusic::player::decode_music;
+	use OidosMusic::render::render_music;
+	extern crate std;
+	use std::format;
+	use std::string::String;
+	use std::vec;
+	use std::vec::Vec;
+
+	/// Generate the music with an optional feature defined and its words added to the parameters
+	/// of each instrument, and compare it with the music rendered by OidosMusic from the same data.
+	fn generate_with_words(song: &Song, buffers: &mut Buffers, define: &str, extra: &[u32]) -> Vec<[i16; 2]> {
+		let hex: String = extra.iter().map(|w| format!(",0x{:08X}", w)).collect();
+		let asm = parse_music(&TEST_MUSIC.replace("%define USES_PANNING", &format!("%define USES_PANNING\n%define {}", define))
+			.replace(",0x3E800000\n.i01:", &format!(",0x3E800000{}\n.i01:", hex))
+			.replace(",0xBF000000\n\n", &format!(",0xBF000000{}\n\n", hex))).unwrap();
+		let expected = render_music(&decode_music(&asm).unwrap(), &|_| true);
+		let words: Vec<u32> = song.instrument_params.chunks(PARAMS_WORDS_PANNING).flat_map(|w| w.iter().chain(extra).copied()).collect();
+		let song = Song {
+			uses_envelope: define == "USES_ENVELOPE",
+			uses_keytrack: define == "USES_KEYTRACK",
+			uses_swell: define == "USES_SWELL",
+			uses_formants: define == "USES_FORMANTS",
+			uses_stiffness: define == "USES_STIFFNESS",
+			instrument_params: &words,
+			..*song
+		};
+		let mut out = vec![[0i16; 2]; 32768];
+		generate_music(&song, buffers, &mut out).unwrap();
+		assert!(out.iter().zip(&expected).all(|(a, b)| (a[0] - b[0]).abs() <= 1 && (a[1] - b[1]).abs() <= 1));
+		out
+	}
+
+	let asm = parse_music(TEST_MUSIC).unwrap();
+	let expected = render_music(&decode_music(&asm).unwrap(), &|_| true);
+
+	let words: Vec<u32> = asm.section("iparam").unwrap().bytes().chunks(4)
+		.map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+	let bytes = |name: &str| asm.section(name).unwrap().bytes();
+	let (tones, track_data, note_lengths, note_samples) = (bytes("itones"), bytes("trdata"), bytes("notelen"), bytes("notesamp"));
+	let float = |name: &str| f32::from_bits(u32::from_str_radix(&asm.define(name).unwrap()[2..10], 16).unwrap());
+	let song = Song {
+		samples_per_tick: 2000,
+		total_samples: 32768,
+		num_tracks_with_reverb: 1,
+		num_tracks_without_reverb: 1,
+		uses_panning: true,
+		uses_envelope: false,
+		uses_keytrack: false,
+		uses_swell: false,
+		uses_formants: false,
+		uses_stiffness: false,
+		reverb: Some(ReverbParams {
+			num_delays: 6,
+			min_delay: 300,
+			max_delay: 2000,
+			add_delay: 100,
+			seed: 4000,
+			max_decay: 0.8,
+			decay_mul: 0.9999,
+			filter_high: float("REVERB_FILTER_HIGH"),
+			filter_low: float("REVERB_FILTER_LOW"),
+			dampen_high: float("REVERB_DAMPEN_HIGH"),
+			dampen_low: float("REVERB_DAMPEN_LOW"),
+			volume_left: float("REVERB_VOLUME_LEFT"),
+			volume_right: float("REVERB_VOLUME_RIGHT")
+		}),
+		instrument_params: &words,
+		instrument_tones: &tones,
+		instrument_tuning: &[],
+		track_data: &track_data,
+		note_lengths: &note_lengths,
+		note_samples: &note_samples
+	};
+	assert_eq!(song.max_partials(), Ok(6));
+	assert_eq!(song.max_instrument_samples(), Ok(32768));
+
+	let mut random = vec![0u32; RANDOM_DATA_SIZE];
+	fill_random_data(&mut random);
+	let mut partials = vec![Partial::default(); 6];
+	let mut tones = vec![0f64; 32768];
+	let mut mixing = vec![[0f64; 2]; 32768];
+	let mut reverb = vec![[0f64; 2]; 32768];
+	let mut delay = vec![0f64; crate::reverb::DELAY_BUFFER_SIZE];
+	let mut out = vec![[0i16; 2]; 32768];
+	let mut buffers = Buffers {
+		random: &random,
+		partials: &mut partials,
+		tones: &mut tones,
+		mixing: &mut mixing,
+		reverb: &mut reverb,
+		delay: &mut delay
+	};
+	generate_music(&song, &mut buffers, &mut out).unwrap();
+	assert!(out.iter().any(|s| s[0] != s[1]));
+	assert!(out.iter().zip(&expected).all(|(a, b)| (a[0] - b[0]).abs() <= 1 && (a[1] - b[1]).abs() <= 1));
+
+	// Buffers are cleared, so generating again gives the same result
+	let mut again = vec![[0i16; 2]; 32768];
+	generate_music(&song, &mut buffers, &mut again).unwrap();
+	assert_eq!(out, again);
+
+	// Tuning offsets are added to the tones in the order they are made
+	let tuned_asm = parse_music(&(TEST_MUSIC.replace("%define USES_PANNING", "%define USES_PANNING\n%define USES_TUNING")
+		+ "\n\tSECTION_DATA(ituning) align=4\n\nInstrumentTuning:\n\tdd\t0x3E800000,0x00000000,0xBF000000,0x3F000000\n")).unwrap();
+	let tuned_expected = render_music(&decode_music(&tuned_asm).unwrap(), &|_| true);
+	let tuning = [0.25, 0.0, -0.5, 0.5];
+	let tuned_song = Song { instrument_tuning: &tuning, ..song };
+	let mut tuned = vec![[0i16; 2]; 32768];
+	generate_music(&tuned_song, &mut buffers, &mut tuned).unwrap();
+	assert!(tuned != out);
+	assert!(tuned.iter().zip(&tuned_expected).all(|(a, b)| (a[0] - b[0]).abs() <= 1 && (a[1] - b[1]).abs() <= 1));
+	let short = Song { instrument_tuning: &tuning[..3], ..song };
+	assert_eq!(generate_music(&short, &mut buffers, &mut tuned), Err("Tuning data ends prematurely"));
+
+	// Envelope words follow the panning word of each instrument. The decay
+	// goes from sample 1024 to a quarter level over 1024 samples.
+	let shaped = generate_with_words(&song, &mut buffers, "USES_ENVELOPE", &[0x3F000000, 0xBF000000, 0xC1600000, 0x3A800000, 0x3E800000, 0x3E800000]);
+	assert!(shaped != out);
+
+	// Key tracking words follow the panning word without envelopes. The decay halves
+	// for every octave above tone 60, and the filters stay half way behind the tone.
+	let tracked = generate_with_words(&song, &mut buffers, "USES_KEYTRACK", &[0x42700000, 0x3F800000, 0x3F000000]);
+	assert!(tracked != out);
+
+	// Swell words follow the panning word without envelopes and key tracking. The partials
+	// swell over 1024 samples, twice as long for every octave above the tone.
+	let swelling = generate_with_words(&song, &mut buffers, "USES_SWELL", &[0x3A800000, 0x3F800000, 0x40C00000]);
+	assert!(swelling != out);
+
+	// Formant words follow the panning word without envelopes, key tracking and swell. A peak
+	// doubling the partials at tone 72 falls off over 8 semitones and moves up slowly.
+	let formants = [0x42900000, 0, 0x3E000000, 0, 0x3F800000, 0, 0x38000000];
+	let shaped = generate_with_words(&song, &mut buffers, "USES_FORMANTS", &formants);
+	assert!(shaped != out);
+
+	// The stiffness word follows the panning word without the other optional words
+	let stretched = generate_with_words(&song, &mut buffers, "USES_STIFFNESS", &[0x3BCC0000]);
+	assert!(stretched != out);
+
+	let mut small = vec![0f64; 16384];
+	buffers.tones = &mut small;
+	assert_eq!(generate_music(&song, &mut buffers, &mut out), Err("Tone buffer too small"));
+}
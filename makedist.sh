@@ -7,6 +7,8 @@ rm -rf dist/Oidos
 cd synth
 cargo build --release
 cargo build --release --target=i686-pc-windows-msvc
+cargo build --release --features noise --target-dir=target/noise
+cargo build --release --features noise --target-dir=target/noise --target=i686-pc-windows-msvc
 cd ..
 
 # Build reverb
@@ -23,10 +25,12 @@ cd ..
 # copy VSTs
 mkdir -p $DIST/vst/Windows32
 cp synth/target/i686-pc-windows-msvc/release/Oidos.dll $DIST/vst/Windows32/
+cp synth/target/noise/i686-pc-windows-msvc/release/Oidos.dll $DIST/vst/Windows32/OidosNoise.dll
 cp reverb/target/i686-pc-windows-msvc/release/OidosReverb.dll $DIST/vst/Windows32/
 
 mkdir -p $DIST/vst/Windows64
 cp synth/target/release/Oidos.dll $DIST/vst/Windows64/
+cp synth/target/noise/release/Oidos.dll $DIST/vst/Windows64/OidosNoise.dll
 cp reverb/target/release/OidosReverb.dll $DIST/vst/Windows64/
 
 # Copy converter
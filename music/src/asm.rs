@@ -0,0 +1,225 @@
+// Reading the music data written by the converter
+
+/// What a block of music data belongs to.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Owner {
+	/// Instrument with the given number
+	Instrument(u32),
+	/// Track (one column of one track for one instrument) with the given label name
+	Track(String),
+	Other
+}
+
+/// Data following a label in a data section.
+pub struct Block {
+	pub label: String,
+	/// Comment next to the label, naming the instrument or track
+	pub title: Option<String>,
+	pub bytes: Vec<u8>
+}
+
+impl Block {
+	pub fn owner(&self) -> Owner {
+		let label = &self.label;
+		if let Some(Ok(number)) = label.strip_prefix(".i").map(|n| n.parse::<u32>()) {
+			return Owner::Instrument(number);
+		}
+		for prefix in &[".t_", "L_", "S_"] {
+			if let Some(name) = label.strip_prefix(prefix) {
+				return Owner::Track(name.to_string());
+			}
+		}
+		Owner::Other
+	}
+}
+
+pub struct Section {
+	/// Name given to SECTION_DATA, such as `iparam`
+	pub name: String,
+	pub blocks: Vec<Block>
+}
+
+impl Section {
+	pub fn bytes(&self) -> Vec<u8> {
+		self.blocks.iter().flat_map(|b| b.bytes.iter().cloned()).collect()
+	}
+}
+
+/// Contents of a music file written by the converter.
+pub struct MusicAsm {
+	/// `%define` names and values, in order
+	pub defines: Vec<(String, String)>,
+	pub sections: Vec<Section>
+}
+
+impl MusicAsm {
+	pub fn define(&self, name: &str) -> Option<&str> {
+		self.defines.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+	}
+
+	pub fn section(&self, name: &str) -> Option<&Section> {
+		self.sections.iter().find(|s| s.name == name)
+	}
+
+	/// Title of an instrument or track, as given in the comments of the data.
+	pub fn title(&self, owner: &Owner) -> Option<&str> {
+		self.sections.iter()
+			.flat_map(|s| s.blocks.iter())
+			.find(|b| &b.owner() == owner && b.title.is_some())
+			.and_then(|b| b.title.as_deref())
+	}
+}
+
+fn parse_values(text: &str, line_number: usize) -> Result<Vec<i64>, String> {
+	text.split(',').map(|v| {
+		let v = v.trim();
+		let parsed = if v.starts_with("0x") || v.starts_with("0X") {
+			i64::from_str_radix(&v[2..], 16)
+		} else {
+			v.parse::<i64>()
+		};
+		parsed.map_err(|_| format!("Line {}: Illegal value '{}'", line_number, v))
+	}).collect()
+}
+
+/// Parse the output of the converter. Only the data sections and defines are read.
+pub fn parse_music(text: &str) -> Result<MusicAsm, String> {
+	let mut defines = Vec::new();
+	let mut sections: Vec<Section> = Vec::new();
+	let mut pending_comment: Option<String> = None;
+
+	for (line_index, line) in text.lines().enumerate() {
+		let line_number = line_index + 1;
+		let (code, comment) = match line.find(';') {
+			Some(pos) => (line[..pos].trim(), Some(line[pos + 1..].trim())),
+			None => (line.trim(), None)
+		};
+
+		if code.is_empty() {
+			if let Some(comment) = comment {
+				// A comment directly before or after a label titles the block
+				if let Some(block) = sections.last_mut().and_then(|s| s.blocks.last_mut()) {
+					if block.title.is_none() && block.bytes.is_empty() {
+						block.title = Some(comment.to_string());
+					}
+				}
+				pending_comment = Some(comment.to_string());
+			}
+			continue;
+		}
+
+		if let Some(define) = code.strip_prefix("%define") {
+			let mut words = define.trim().splitn(2, char::is_whitespace);
+			let name = words.next().unwrap_or("").to_string();
+			let value = words.next().unwrap_or("").trim().to_string();
+			defines.push((name, value));
+		} else if code.starts_with("SECTION_DATA(") {
+			let end = code.find(')').ok_or(format!("Line {}: Malformed section", line_number))?;
+			sections.push(Section {
+				name: code["SECTION_DATA(".len()..end].to_string(),
+				blocks: Vec::new()
+			});
+		} else if let Some(label) = code.strip_suffix(':') {
+			let section = sections.last_mut().ok_or(format!("Line {}: Label outside section", line_number))?;
+			section.blocks.push(Block {
+				label: label.to_string(),
+				title: pending_comment.take(),
+				bytes: Vec::new()
+			});
+		} else {
+			let block = sections.last_mut().and_then(|s| s.blocks.last_mut())
+				.ok_or(format!("Line {}: Data outside labelled section", line_number))?;
+			let mut words = code.splitn(2, char::is_whitespace);
+			let directive = words.next().unwrap();
+			let values = parse_values(words.next().unwrap_or(""), line_number)?;
+			match directive {
+				"db" => block.bytes.extend(values.iter().map(|&v| v as u8)),
+				"dd" => block.bytes.extend(values.iter().flat_map(|&v| (v as u32).to_le_bytes().to_vec())),
+				_ => return Err(format!("Line {}: Unknown directive '{}'", line_number, directive))
+			}
+			pending_comment = None;
+		}
+	}
+
+	Ok(MusicAsm {
+		defines,
+		sections
+	})
+}
+
+#[cfg(test)]
+pub const TEST_MUSIC: &str = "\
+; Music converted from test.xrns 2024-01-01 12:00:00
+
+%define MUSIC_LENGTH 64
+%define TOTAL_SAMPLES 393216
+%define USES_PANNING
+
+
+	SECTION_DATA(iparam) align=4
+
+InstrumentParams:
+.i00:
+	; 00|Bass
+	dd	40,10,50,27,0,0x3F800000,0x3F800000,0x3EFFFFFC,0x3EE8A11F,0xC2F00000,0x42F00000,0x3F800000,0xBF800000,0,0,0x3F800000,131072,0xB83E37C6,0x39BE37C6,0x46800000,0x3F000000
+
+
+	SECTION_DATA(itones) align=1
+
+InstrumentTones:
+.i00:
+	; 00|Bass
+	db	36,5,-128
+
+
+	SECTION_DATA(trdata) align=1
+
+TrackData:
+.t_Bass_0_0:
+	; Bass
+	db	0,0,4,1,2,-128
+
+	SECTION_DATA(notelen) align=1
+
+NoteLengths:
+	; Bass
+L_Bass_0_0:
+	; Position 0, pattern 0
+	db	4,4,-2,0
+	db	0
+
+	SECTION_DATA(notesamp) align=1
+
+NoteSamples:
+	; Bass
+S_Bass_0_0:
+	; Position 0, pattern 0
+	db	1,2,0
+";
+
+#[test]
+fn test_parse_music() {
+	let music = parse_music(TEST_MUSIC).unwrap();
+	assert_eq!(music.define("MUSIC_LENGTH"), Some("64"));
+	assert_eq!(music.define("USES_PANNING"), Some(""));
+	assert_eq!(music.sections.len(), 5);
+
+	let params = music.section("iparam").unwrap();
+	let block = params.blocks.iter().find(|b| b.owner() == Owner::Instrument(0)).unwrap();
+	assert_eq!(block.bytes.len(), 21 * 4);
+	assert_eq!(&block.bytes[0..4], &[40, 0, 0, 0]);
+	assert_eq!(&block.bytes[20..24], &[0x00, 0x00, 0x80, 0x3F]);
+
+	assert_eq!(music.section("itones").unwrap().bytes(), vec![36, 5, 128]);
+	assert_eq!(music.section("notelen").unwrap().bytes(), vec![4, 4, 254, 0, 0]);
+	let owner = Owner::Track("Bass_0_0".to_string());
+	assert_eq!(music.title(&Owner::Instrument(0)), Some("00|Bass"));
+	assert_eq!(music.title(&owner), Some("Bass"));
+	let samples = music.section("notesamp").unwrap();
+	let block = samples.blocks.iter().find(|b| !b.bytes.is_empty()).unwrap();
+	assert_eq!(block.owner(), owner);
+	assert_eq!(block.title.as_ref().unwrap(), "Bass");
+
+	assert!(parse_music("\tdb\t1\n").is_err());
+	assert!(parse_music("\tSECTION_DATA(x) align=1\nx:\n\tdb\tfoo\n").is_err());
+}
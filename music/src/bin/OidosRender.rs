@@ -0,0 +1,97 @@
+// Render music converted for the Oidos player to a WAV file
+#![allow(non_snake_case)]
+
+use std::env;
+use std::fs;
+use std::process::exit;
+use std::time::Instant;
+
+use OidosMusic::asm::parse_music;
+use OidosMusic::player::{decode_music, note_name, PlayerMusic};
+use OidosMusic::render::{render_music, write_wav};
+use OidosMusic::stream::StreamingRenderer;
+
+
+fn print_tone_usage(music: &PlayerMusic) {
+	for instrument in &music.instruments {
+		let title = instrument.title.clone().unwrap_or(format!("{:02}", instrument.number));
+		let samples = instrument.tones.len() * instrument.params.maxsamples as usize;
+		println!("{}{}: {} tones, {} samples ({:.1} MB)", title, if instrument.reverb { " (reverb)" } else { "" },
+			instrument.tones.len(), samples, (samples * 16) as f64 / (1024.0 * 1024.0));
+		for usage in instrument.tone_usage() {
+			let volumes: Vec<String> = usage.volumes.iter().map(|v| v.to_string()).collect();
+			println!("  {:<4} {:4} notes, velocities {}", note_name(usage.tone), usage.notes, volumes.join(","));
+		}
+		for column in &instrument.columns {
+			println!("  Track {:<30} {:4} notes", column.title.as_ref().unwrap_or(&column.name), column.notes.len());
+		}
+	}
+}
+
+/// Render with the streaming renderer and report how soon playback could have started
+/// without catching up with the rendering.
+fn render_streaming(music: PlayerMusic, threads: usize) -> Vec<[i16; 2]> {
+	let start = Instant::now();
+	let renderer = StreamingRenderer::new(music, threads);
+	let mut rendered = 0;
+	let mut playback_start = 0f64;
+	while rendered < renderer.total_samples() {
+		renderer.wait_for(rendered + 1);
+		// Samples from the previous point up to here became available now
+		playback_start = playback_start.max(start.elapsed().as_secs_f64() - rendered as f64 / 44100.0);
+		rendered = renderer.rendered_samples();
+	}
+	println!("Rendered in {:.2} seconds. Playback could start after {:.2} seconds.",
+		start.elapsed().as_secs_f64(), playback_start);
+	renderer.finish()
+}
+
+fn main() {
+	let mut args: Vec<String> = env::args().skip(1).collect();
+	let mut solo = Vec::new();
+	let mut threads = None;
+	while args.len() > 2 && (args[0] == "-solo" || args[0] == "-threads") {
+		let value = args.remove(1);
+		if args.remove(0) == "-solo" {
+			solo.push(value);
+		} else {
+			threads = value.parse::<usize>().ok().filter(|&t| t > 0);
+			if threads.is_none() {
+				eprintln!("Error: Illegal number of threads '{}'", value);
+				exit(2);
+			}
+		}
+	}
+	if args.len() != 2 || (threads.is_some() && !solo.is_empty()) {
+		eprintln!("Usage: OidosRender [-solo <track>]... <converted music file> <output wav file>");
+		eprintln!("       OidosRender -threads <count> <converted music file> <output wav file>");
+		exit(2);
+	}
+
+	let music = match fs::read_to_string(&args[0]).map_err(|e| e.to_string())
+			.and_then(|text| parse_music(&text))
+			.and_then(|asm| decode_music(&asm)) {
+		Ok(music) => music,
+		Err(message) => {
+			eprintln!("Error: {}", message);
+			exit(1);
+		}
+	};
+	let columns: Vec<&str> = music.instruments.iter().flat_map(|i| i.columns.iter().map(|c| c.name.as_str())).collect();
+	if let Some(unknown) = solo.iter().find(|s| !columns.iter().any(|c| c.starts_with(s.as_str()))) {
+		eprintln!("Error: No track matches '{}'. Tracks are: {}", unknown, columns.join(" "));
+		exit(1);
+	}
+
+	print_tone_usage(&music);
+	let samples = match threads {
+		Some(threads) => render_streaming(music, threads),
+		// A solo name selects all tracks (columns) starting with it
+		None => render_music(&music, &|name| solo.is_empty() || solo.iter().any(|s| name.starts_with(s.as_str())))
+	};
+	if let Err(e) = write_wav(&args[1], &samples) {
+		eprintln!("Error: {}: {}", args[1], e);
+		exit(1);
+	}
+	println!("Wrote {:.1} seconds to {}", samples.len() as f64 / 44100.0, args[1]);
+}
@@ -0,0 +1,49 @@
+// Estimate the compressed size of music converted for the Oidos player
+#![allow(non_snake_case)]
+
+use std::env;
+use std::fs;
+use std::process::exit;
+
+use OidosMusic::asm::{parse_music, Owner};
+use OidosMusic::size::{estimate_size, Size};
+
+
+fn print_size(name: &str, size: &Size) {
+	println!("  {:<40} {:6} {:9.1}", name, size.raw, size.compressed);
+}
+
+fn main() {
+	let args: Vec<String> = env::args().skip(1).collect();
+	if args.len() != 1 {
+		eprintln!("Usage: OidosSize <converted music file>");
+		exit(2);
+	}
+	let music = match fs::read_to_string(&args[0]).map_err(|e| e.to_string()).and_then(|text| parse_music(&text)) {
+		Ok(music) => music,
+		Err(message) => {
+			eprintln!("Error: {}", message);
+			exit(1);
+		}
+	};
+
+	let report = estimate_size(&music);
+	println!("  {:<40} {:>6} {:>9}", "", "Raw", "Estimate");
+	println!("Sections:");
+	for (name, size) in &report.sections {
+		print_size(name, size);
+	}
+	println!("Instruments:");
+	for (owner, size) in &report.owners {
+		if let Owner::Instrument(number) = owner {
+			print_size(music.title(owner).unwrap_or(&format!("{:02}", number)), size);
+		}
+	}
+	println!("Tracks:");
+	for (owner, size) in &report.owners {
+		if let Owner::Track(name) = owner {
+			print_size(music.title(owner).unwrap_or(name), size);
+		}
+	}
+	print_size("Total", &report.total);
+}
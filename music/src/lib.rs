@@ -0,0 +1,9 @@
+// Tools working on music converted for the Oidos player
+#![allow(non_snake_case)]
+
+pub mod asm;
+pub mod size;
+pub mod player;
+pub mod render;
+pub mod stream;
+pub mod sync;
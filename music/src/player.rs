@@ -0,0 +1,534 @@
+// Decoding the music data the way the player reads it
+
+pub use OidosEngine::generator::InstrumentParams;
+pub use OidosEngine::reverb::ReverbParams;
+
+use crate::asm::MusicAsm;
+#[cfg(test)] use crate::asm::parse_music;
+
+
+/// A note as played by the player.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PlayerNote {
+	/// Start of the note, in samples
+	pub start: usize,
+	/// Time from the start of the note to the start of the release, in samples
+	pub length: usize,
+	/// Index into the tones of the instrument
+	pub tone_index: usize,
+	/// Velocity in units of the velocity quantum of the instrument
+	pub volume: u8
+}
+
+/// One column of one track, played by one instrument.
+pub struct PlayerColumn {
+	/// Name of the track label, as in `Owner::Track`
+	pub name: String,
+	pub title: Option<String>,
+	pub notes: Vec<PlayerNote>
+}
+
+pub struct PlayerInstrument {
+	/// Number given in the instrument label
+	pub number: u32,
+	pub title: Option<String>,
+	pub params: InstrumentParams,
+	/// The tones for which the sound is precalculated, in order
+	pub tones: Vec<i32>,
+	/// Offset of each tone given by the tuning, in semitones. Zero if the music does not use tuning.
+	pub tuning: Vec<f32>,
+	pub columns: Vec<PlayerColumn>,
+	pub reverb: bool
+}
+
+/// The music as seen by the player.
+pub struct PlayerMusic {
+	pub music_length: u32,
+	pub total_samples: usize,
+	pub samples_per_tick: usize,
+	pub ticks_per_second: f64,
+	pub reverb: Option<ReverbParams>,
+	/// Instruments in the order played, those with reverb first
+	pub instruments: Vec<PlayerInstrument>
+}
+
+fn number_define<T: std::str::FromStr>(music: &MusicAsm, name: &str) -> Result<T, String> {
+	let value = music.define(name).ok_or(format!("Missing define {}", name))?;
+	value.parse::<T>().map_err(|_| format!("Illegal value '{}' for {}", value, name))
+}
+
+fn float_define(music: &MusicAsm, name: &str) -> Result<f32, String> {
+	let value = music.define(name).ok_or(format!("Missing define {}", name))?;
+	match value.strip_prefix("0x") {
+		Some(hex) => u32::from_str_radix(hex, 16).map(f32::from_bits).ok(),
+		None => value.parse::<f32>().ok()
+	}.ok_or(format!("Illegal value '{}' for {}", value, name))
+}
+
+/// Sequential reader of a data section, like the data pointers of the player.
+struct Stream {
+	bytes: Vec<u8>,
+	pos: usize,
+	name: &'static str
+}
+
+impl Stream {
+	fn new(music: &MusicAsm, name: &'static str) -> Result<Stream, String> {
+		let section = music.section(name).ok_or(format!("Missing section {}", name))?;
+		Ok(Stream { bytes: section.bytes(), pos: 0, name })
+	}
+
+	fn byte(&mut self) -> Result<u8, String> {
+		let byte = *self.bytes.get(self.pos).ok_or(format!("Section {} ends prematurely", self.name))?;
+		self.pos += 1;
+		Ok(byte)
+	}
+
+	fn word(&mut self) -> Result<u32, String> {
+		let mut word = 0;
+		for i in 0..4 {
+			word |= (self.byte()? as u32) << (i * 8);
+		}
+		Ok(word)
+	}
+}
+
+/// Labels of the blocks in a section with the given prefix, in order, with their titles.
+fn labels(music: &MusicAsm, section: &str, prefix: &str) -> Vec<(String, Option<String>)> {
+	music.section(section).map(|s| s.blocks.iter()
+		.filter_map(|b| b.label.strip_prefix(prefix).map(|name| (name.to_string(), b.title.clone())))
+		.collect()
+	).unwrap_or_default()
+}
+
+/// Decode the music data by following the data pointers in the same way as the player.
+pub fn decode_music(music: &MusicAsm) -> Result<PlayerMusic, String> {
+	let samples_per_tick: usize = number_define(music, "SAMPLES_PER_TICK")?;
+	let with_reverb: usize = number_define(music, "NUM_TRACKS_WITH_REVERB")?;
+	let without_reverb: usize = number_define(music, "NUM_TRACKS_WITHOUT_REVERB")?;
+	let uses_panning = music.define("USES_PANNING").is_some();
+	let uses_tuning = music.define("USES_TUNING").is_some();
+	let uses_envelope = music.define("USES_ENVELOPE").is_some();
+	let uses_keytrack = music.define("USES_KEYTRACK").is_some();
+	let uses_swell = music.define("USES_SWELL").is_some();
+	let uses_formants = music.define("USES_FORMANTS").is_some();
+	let uses_stiffness = music.define("USES_STIFFNESS").is_some();
+	let reverb = if with_reverb > 0 {
+		Some(ReverbParams {
+			num_delays: number_define(music, "REVERB_NUM_DELAYS")?,
+			min_delay: number_define(music, "REVERB_MIN_DELAY")?,
+			max_delay: number_define(music, "REVERB_MAX_DELAY")?,
+			add_delay: number_define(music, "REVERB_ADD_DELAY")?,
+			seed: number_define(music, "REVERB_RANDOMSEED")?,
+			max_decay: float_define(music, "REVERB_MAX_DECAY")?,
+			decay_mul: float_define(music, "REVERB_DECAY_MUL")?,
+			filter_high: float_define(music, "REVERB_FILTER_HIGH")?,
+			filter_low: float_define(music, "REVERB_FILTER_LOW")?,
+			dampen_high: float_define(music, "REVERB_DAMPEN_HIGH")?,
+			dampen_low: float_define(music, "REVERB_DAMPEN_LOW")?,
+			volume_left: float_define(music, "REVERB_VOLUME_LEFT")?,
+			volume_right: float_define(music, "REVERB_VOLUME_RIGHT")?
+		})
+	} else {
+		None
+	};
+
+	let mut params = Stream::new(music, "iparam")?;
+	let mut tones = Stream::new(music, "itones")?;
+	let mut tovel = Stream::new(music, "trdata")?;
+	let mut lengths = Stream::new(music, "notelen")?;
+	let mut notes = Stream::new(music, "notesamp")?;
+	let mut tuning = if uses_tuning { Some(Stream::new(music, "ituning")?) } else { None };
+	let instrument_labels = labels(music, "iparam", ".i");
+	let mut track_labels = labels(music, "trdata", ".t_").into_iter();
+
+	let mut instruments = Vec::new();
+	for i in 0..with_reverb + without_reverb {
+		let block_words = 20 + uses_panning as usize + if uses_envelope { 6 } else { 0 } + if uses_keytrack { 3 } else { 0 }
+			+ if uses_swell { 3 } else { 0 } + if uses_formants { 7 } else { 0 }
+			+ uses_stiffness as usize;
+		let words = (0..block_words).map(|_| params.word()).collect::<Result<Vec<u32>, String>>()?;
+		let (number, title) = match instrument_labels.get(i) {
+			Some((label, title)) => (label.parse::<u32>().unwrap_or(i as u32), title.clone()),
+			None => (i as u32, None)
+		};
+
+		// Tones are delta coded, terminated by a byte making the sum negative
+		let mut instrument_tones = Vec::new();
+		let mut tone = 0i32;
+		let mut terminator;
+		loop {
+			terminator = tones.byte()? as i8;
+			tone += terminator as i32;
+			if tone < 0 {
+				break;
+			}
+			instrument_tones.push(tone);
+		}
+		let instrument_tuning = instrument_tones.iter().map(|_| match tuning {
+			Some(ref mut tuning) => tuning.word().map(f32::from_bits),
+			None => Ok(0.0)
+		}).collect::<Result<Vec<f32>, String>>()?;
+
+		// The terminator is counted down once for each column
+		let mut columns = Vec::new();
+		loop {
+			// Delta decode the tone indices of the tones and velocities
+			let start = tovel.pos;
+			let singular_length = tovel.byte()? as usize;
+			let mut tavs: Vec<(usize, u8)> = Vec::new();
+			let mut tone_index = 0u8;
+			loop {
+				let delta = tovel.byte()?;
+				tone_index = tone_index.wrapping_add(delta);
+				if (tone_index as i8) < 0 {
+					break;
+				}
+				tavs.push((tone_index as usize, tovel.byte()?));
+			}
+			let (name, title) = track_labels.next().unwrap_or((format!("{}", start), None));
+
+			let mut column_notes = Vec::new();
+			let mut position = 0;
+			loop {
+				let mut length = lengths.byte()? as usize;
+				if length >= 0x80 {
+					length = ((!length & 0xFF) << 8) | lengths.byte()? as usize;
+				}
+				let length = length * samples_per_tick;
+				let sample = notes.byte()? as i8;
+				if sample > 0 {
+					let &(tone_index, volume) = tavs.get(sample as usize - 1)
+						.ok_or(format!("Track {}: Note refers to undefined tone", name))?;
+					column_notes.push(PlayerNote {
+						start: position,
+						length: if singular_length != 0 { singular_length * samples_per_tick } else { length },
+						tone_index,
+						volume
+					});
+				}
+				position += length;
+				if length == 0 {
+					break;
+				}
+			}
+			if let Some(note) = column_notes.iter().find(|n| n.tone_index >= instrument_tones.len()) {
+				return Err(format!("Track {}: Tone index {} out of range", name, note.tone_index));
+			}
+			columns.push(PlayerColumn { name, title, notes: column_notes });
+
+			terminator = terminator.wrapping_sub(1);
+			if terminator >= 0 {
+				break;
+			}
+		}
+
+		instruments.push(PlayerInstrument {
+			number,
+			title,
+			params: InstrumentParams::from_words(&words, uses_panning, uses_envelope, uses_keytrack, uses_swell, uses_formants, uses_stiffness),
+			tones: instrument_tones,
+			tuning: instrument_tuning,
+			columns,
+			reverb: i < with_reverb
+		});
+	}
+
+	Ok(PlayerMusic {
+		music_length: number_define(music, "MUSIC_LENGTH")?,
+		total_samples: number_define(music, "TOTAL_SAMPLES")?,
+		samples_per_tick,
+		ticks_per_second: number_define(music, "TICKS_PER_SECOND")?,
+		reverb,
+		instruments
+	})
+}
+
+const NOTE_NAMES: [&str; 12] = ["C-", "C#", "D-", "D#", "E-", "F-", "F#", "G-", "G#", "A-", "A#", "B-"];
+
+/// Name of a tone, as shown by the converter.
+pub fn note_name(tone: i32) -> String {
+	format!("{}{}", NOTE_NAMES[tone.rem_euclid(12) as usize], tone.div_euclid(12))
+}
+
+/// How much an instrument uses a tone.
+pub struct ToneUsage {
+	pub tone: i32,
+	/// Number of notes played with the tone
+	pub notes: usize,
+	/// Distinct velocities the tone is played with
+	pub volumes: Vec<u8>
+}
+
+impl PlayerInstrument {
+	/// Tone played for a precalculated tone, including the tuning offset.
+	pub fn tuned_tone(&self, tone_index: usize) -> f64 {
+		self.tones[tone_index] as f64 + self.tuning[tone_index] as f64
+	}
+
+	/// Usage of each precalculated tone by the notes of the instrument.
+	pub fn tone_usage(&self) -> Vec<ToneUsage> {
+		self.tones.iter().enumerate().map(|(index, &tone)| {
+			let notes: Vec<&PlayerNote> = self.columns.iter().flat_map(|c| c.notes.iter()).filter(|n| n.tone_index == index).collect();
+			let mut volumes: Vec<u8> = notes.iter().map(|n| n.volume).collect();
+			volumes.sort_unstable();
+			volumes.dedup();
+			ToneUsage {
+				tone,
+				notes: notes.len(),
+				volumes
+			}
+		}).collect()
+	}
+}
+
+#[cfg(test)]
+pub const TEST_PLAYER_MUSIC: &str = "\
+; Music converted from test.xrns 2024-01-01 12:00:00
+
+%define MUSIC_LENGTH 16
+%define TOTAL_SAMPLES 131072
+%define MAX_TOTAL_INSTRUMENT_SAMPLES 131072
+
+%define SAMPLES_PER_TICK 1000
+%define TICKS_PER_SECOND 44.100000000
+
+%define NUM_TRACKS_WITH_REVERB 1
+%define NUM_TRACKS_WITHOUT_REVERB 1
+
+%define REVERB_NUM_DELAYS   4
+%define REVERB_MIN_DELAY    256
+%define REVERB_MAX_DELAY    1024
+%define REVERB_ADD_DELAY    0
+%define REVERB_RANDOMSEED   2048
+%define REVERB_MAX_DECAY    0.900000000
+%define REVERB_DECAY_MUL    1.000100000
+%define REVERB_FILTER_HIGH  0x3F000000 ; 0.500000000
+%define REVERB_FILTER_LOW   0x3C23D70A ; 0.010000000
+%define REVERB_DAMPEN_HIGH  0x3F000000 ; 0.500000000
+%define REVERB_DAMPEN_LOW   0x3C23D70A ; 0.010000000
+%define REVERB_VOLUME_LEFT  0x3E800000 ; 0.250000000
+%define REVERB_VOLUME_RIGHT 0x3E000000 ; 0.125000000
+
+
+	SECTION_DATA(iparam) align=4
+
+InstrumentParams:
+.i01:
+	; 01|Pad
+	dd	1,1,0,0,0x00000000,0x3F800000,0x00000000,0x00000000,0x00000000,0xC2F00000,0x42F00000,0x00000000,0x00000000,0x00000000,0x00000000,0x3F800000,65536,0xBF800000,0x40000000,0x45FA0000
+.i00:
+	; 00|Bass
+	dd	2,2,10,3,0x00000000,0x3F7FF000,0x3F800000,0x00000000,0x3DCCCCCD,0xC2F00000,0x42F00000,0x3F800000,0xBF800000,0x00000000,0x00000000,0x40000000,65536,0xB8800000,0x3C000000,0x457A0000
+
+
+	SECTION_DATA(itones) align=1
+
+InstrumentTones:
+.i01:
+	; 01|Pad
+	db	60,-128
+.i00:
+	; 00|Bass
+	db	36,7,-127
+
+
+	SECTION_DATA(trdata) align=1
+
+TrackData:
+.t_Pad_1_1:
+	; Pad, column 1
+	db	4,0,1,-128
+.t_Bass_1_0:
+	; Bass, column 1
+	db	0,0,2,1,1,-128
+.t_Bass_2_0:
+	; Bass, column 2
+	db	0,1,1,-128
+
+	SECTION_DATA(notelen) align=1
+
+NoteLengths:
+	; Pad, column 1
+L_Pad_1_1:
+	; Position 0, pattern 0
+	db	8
+	db	0
+
+	; Bass, column 1
+L_Bass_1_0:
+	; Position 0, pattern 0
+	db	4,4,2,2
+	db	0
+
+	; Bass, column 2
+L_Bass_2_0:
+	; Position 0, pattern 0
+	db	2,-1,130
+	db	0
+
+	SECTION_DATA(notesamp) align=1
+
+NoteSamples:
+	; Pad, column 1
+S_Pad_1_1:
+	; Position 0, pattern 0
+	db	1,1
+	; Bass, column 1
+S_Bass_1_0:
+	; Position 0, pattern 0
+	db	1,0,2,0,0
+	; Bass, column 2
+S_Bass_2_0:
+	; Position 0, pattern 0
+	db	0,1,0
+";
+
+#[test]
+fn test_decode_music() {
+	let music = decode_music(&parse_music(TEST_PLAYER_MUSIC).unwrap()).unwrap();
+	assert_eq!(music.music_length, 16);
+	assert_eq!(music.samples_per_tick, 1000);
+	let reverb = music.reverb.as_ref().unwrap();
+	assert_eq!((reverb.num_delays, reverb.max_delay, reverb.seed), (4, 1024, 2048));
+	assert_eq!(reverb.volume_right, 0.125);
+	assert_eq!(reverb.max_decay, 0.9);
+
+	assert_eq!(music.instruments.len(), 2);
+	let pad = &music.instruments[0];
+	assert_eq!((pad.number, pad.title.as_deref(), pad.reverb), (1, Some("01|Pad"), true));
+	assert_eq!(pad.params.maxsamples, 65536);
+	assert_eq!(pad.params.attack, 2.0);
+	assert_eq!(pad.tones, vec![60]);
+	assert_eq!(pad.columns.len(), 1);
+	// Singular length: the release comes after 4 ticks, though the next note is 8 ticks later
+	assert_eq!(pad.columns[0].notes, vec![
+		PlayerNote { start: 0, length: 4000, tone_index: 0, volume: 1 },
+		PlayerNote { start: 8000, length: 4000, tone_index: 0, volume: 1 }
+	]);
+
+	let bass = &music.instruments[1];
+	assert!(!bass.reverb);
+	assert_eq!(bass.tones, vec![36, 43]);
+	assert_eq!(bass.columns.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), vec!["Bass_1_0", "Bass_2_0"]);
+	assert_eq!(bass.columns[0].notes, vec![
+		PlayerNote { start: 0, length: 4000, tone_index: 0, volume: 2 },
+		PlayerNote { start: 8000, length: 2000, tone_index: 1, volume: 1 }
+	]);
+	assert_eq!(bass.columns[1].notes, vec![PlayerNote { start: 2000, length: 130000, tone_index: 1, volume: 1 }]);
+	assert_eq!(bass.columns[1].title.as_deref(), Some("Bass, column 2"));
+
+	let usage = bass.tone_usage();
+	assert_eq!((usage[0].tone, usage[0].notes, usage[0].volumes.clone()), (36, 1, vec![2]));
+	assert_eq!((usage[1].tone, usage[1].notes, usage[1].volumes.clone()), (43, 2, vec![1]));
+	assert_eq!(note_name(43), "G-3");
+
+	let truncated = TEST_PLAYER_MUSIC.replace("\tdb\t0,1,0\n", "\tdb\t0,1\n");
+	assert!(decode_music(&parse_music(&truncated).unwrap()).is_err());
+}
+
+/// Decode the test music with the given optional features defined and the given words
+/// appended to the parameters of the pad and the bass.
+#[cfg(test)]
+fn decode_test_music(features: &[&str], pad: &[u32], bass: &[u32]) -> PlayerMusic {
+	let defines: String = features.iter().map(|f| format!("%define {}\n", f)).collect();
+	let words = |w: &[u32]| w.iter().map(|b| format!(",0x{:08X}", b)).collect::<String>();
+	let text = TEST_PLAYER_MUSIC.replace("%define NUM_TRACKS_WITH_REVERB", &(defines + "%define NUM_TRACKS_WITH_REVERB"))
+		.replace("0x40000000,0x45FA0000", &("0x40000000,0x45FA0000".to_string() + &words(pad)))
+		.replace("0x3C000000,0x457A0000", &("0x3C000000,0x457A0000".to_string() + &words(bass)));
+	decode_music(&parse_music(&text).unwrap()).unwrap()
+}
+
+#[test]
+fn test_decode_tuning() {
+	let music = decode_test_music(&[], &[], &[]);
+	assert_eq!(music.instruments[1].tuning, vec![0.0, 0.0]);
+	assert_eq!(music.instruments[1].tuned_tone(1), 43.0);
+
+	// One offset for each tone, in the order the player makes the tones
+	let tuned = TEST_PLAYER_MUSIC.replace("%define NUM_TRACKS_WITH_REVERB", "%define USES_TUNING\n%define NUM_TRACKS_WITH_REVERB")
+		+ "\n\tSECTION_DATA(ituning) align=4\n\nInstrumentTuning:\n\tdd\t0x3E800000,0x00000000,0xBF000000\n";
+	let music = decode_music(&parse_music(&tuned).unwrap()).unwrap();
+	assert_eq!(music.instruments[0].tuned_tone(0), 60.25);
+	assert_eq!(music.instruments[1].tuned_tone(1), 42.5);
+
+	let missing = tuned.replace(",0xBF000000", "");
+	assert!(decode_music(&parse_music(&missing).unwrap()).is_err());
+}
+
+#[test]
+fn test_decode_envelope() {
+	let music = decode_test_music(&[], &[], &[]);
+	assert_eq!((music.instruments[0].params.sustain, music.instruments[0].params.decay_level(1000)), (1.0, 1.0));
+
+	// The pad decays from sample 1024 to a quarter level over 1024 samples
+	let music = decode_test_music(&["USES_ENVELOPE"],
+		&[0x3F000000, 0xBF000000, 0xC2780000, 0x3A800000, 0, 0x3E800000],
+		&[0, 0, 0x3F800000, 0, 0, 0x3F800000]);
+	let pad = &music.instruments[0].params;
+	assert_eq!((pad.volume, pad.release_curve, pad.attack_curve), (8000.0, 0.5, -0.5));
+	assert_eq!([0, 1024, 1536, 2048, 4096].map(|t| pad.decay_level(t)), [1.0, 1.0, 0.625, 0.25, 0.25]);
+	assert_eq!(pad.attack_release_level(1.0, 0.5), 0.625);
+	let bass = &music.instruments[1].params;
+	assert_eq!(bass.decay_level(1000), 1.0);
+	assert_eq!(bass.attack_release_level(0.5, 2.0), 0.5);
+}
+
+#[test]
+fn test_decode_keytrack() {
+	let music = decode_test_music(&[], &[], &[]);
+	assert_eq!(music.instruments[0].params.decay_track, 0.0);
+
+	// Key tracking words follow the envelope words, if present
+	let music = decode_test_music(&["USES_ENVELOPE", "USES_KEYTRACK"],
+		&[0, 0, 0x3F800000, 0, 0, 0x3F800000, 0x42700000, 0x3F800000, 0x3F000000],
+		&[0, 0, 0x3F800000, 0, 0, 0x3F800000, 0, 0, 0]);
+	let pad = &music.instruments[0].params;
+	assert_eq!((pad.sustain, pad.track_pivot, pad.decay_track, pad.filter_track), (1.0, 60.0, 1.0, 0.5));
+	assert_eq!(music.instruments[1].params.track_pivot, 0.0);
+}
+
+#[test]
+fn test_decode_swell() {
+	let music = decode_test_music(&[], &[], &[]);
+	assert_eq!(music.instruments[0].params.swell, 0.0);
+
+	// Without envelope and key tracking words, the swell words follow the volume word
+	let music = decode_test_music(&["USES_SWELL"], &[0x38000000, 0x3F800000, 0x40C00000], &[0x40000000, 0, 0]);
+	let pad = &music.instruments[0].params;
+	assert_eq!((pad.swell, pad.swell_slope, pad.swell_spread, pad.track_pivot), (1.0 / 32768.0, 1.0, 6.0, 0.0));
+	assert_eq!(music.instruments[1].params.swell, 2.0);
+}
+
+#[test]
+fn test_decode_formants() {
+	let music = decode_test_music(&[], &[], &[]);
+	assert_eq!(music.instruments[0].params.formants, [(0.0, 0.0, 0.0); 2]);
+
+	// The formant words follow the swell words
+	let music = decode_test_music(&["USES_SWELL", "USES_FORMANTS"],
+		&[0x40000000, 0, 0, 0x42900000, 0x42700000, 0x3E000000, 0, 0x3F800000, 0, 0x38000000],
+		&[0x40000000, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+	let pad = &music.instruments[0].params;
+	assert_eq!(pad.swell, 2.0);
+	assert_eq!(pad.formants, [(72.0, 0.125, 1.0), (60.0, 0.0, 0.0)]);
+	assert_eq!(pad.formant_sweep, 1.0 / 32768.0);
+	assert_eq!(music.instruments[1].params.formants[0], (0.0, 0.0, 0.0));
+}
+
+#[test]
+fn test_decode_stiffness() {
+	let music = decode_test_music(&[], &[], &[]);
+	assert_eq!(music.instruments[0].params.stiffness, 0.0);
+
+	// Without the other optional words, the stiffness word follows the volume word
+	let music = decode_test_music(&["USES_STIFFNESS"], &[0x3BCC0000], &[0]);
+	assert_eq!(music.instruments[0].params.stiffness, f32::from_bits(0x3BCC0000));
+	assert_eq!(music.instruments[0].params.swell, 0.0);
+	assert_eq!(music.instruments[1].params.stiffness, 0.0);
+
+	// Without the define, a word following the block is not read as the stiffness
+	let mut words = [0u32; 21];
+	words[20] = 0x3BCC0000;
+	assert_eq!(InstrumentParams::from_words(&words, false, false, false, false, false, false).stiffness, 0.0);
+}
@@ -0,0 +1,222 @@
+// Rendering music the way the player does
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use OidosEngine::generator::{self, Partial};
+use OidosEngine::random::{fill_random_data, RANDOM_DATA_SIZE};
+use OidosEngine::reverb::{self, DELAY_BUFFER_SIZE};
+
+use crate::player::{InstrumentParams, PlayerInstrument, PlayerMusic, PlayerNote, ReverbParams};
+#[cfg(test)] use crate::asm::parse_music;
+#[cfg(test)] use crate::player::{decode_music, TEST_PLAYER_MUSIC};
+#[cfg(test)] use OidosEngine::generator::BASE_FREQ;
+
+
+const AMP_MAX: f64 = 32767.0;
+
+/// The random data used by the player (`Oidos_RandomData`).
+pub fn random_data() -> Vec<u32> {
+	let mut data = vec![0; RANDOM_DATA_SIZE];
+	fill_random_data(&mut data);
+	data
+}
+
+/// Calculate the sound of an instrument at a tone, including any tuning offset and the
+/// decay stage of the envelope, as done by `MakeInstrument` in the player. Both channels
+/// of the sound are the same.
+pub fn make_tone(p: &InstrumentParams, tone: f64, random: &[u32]) -> Vec<f64> {
+	let mut partials = vec![Partial::default(); p.partials()];
+	let mut sound = vec![0f64; p.maxsamples as usize];
+	generator::make_tone(p, tone, random, &mut partials, &mut sound);
+	sound
+}
+
+/// Volume and envelope of a note being mixed, as done by `MakeChannel` in the player.
+/// The envelope is advanced sample by sample, so the note can be mixed in parts.
+pub(crate) struct NoteMixer {
+	volume: [f64; 2],
+	params: InstrumentParams,
+	release_state: f64,
+	attack_state: f64,
+	/// Position in the sound of the next sample to mix
+	offset: usize
+}
+
+impl NoteMixer {
+	pub(crate) fn new(p: &InstrumentParams, note: &PlayerNote) -> NoteMixer {
+		let volume = p.volume as f64 * note.volume as f64;
+		let panning = p.panning as f64;
+		let release = p.release as f64;
+		NoteMixer {
+			volume: [volume * (1.0 - panning), volume * (1.0 + panning)],
+			params: *p,
+			release_state: 1.0 - note.length as f64 * release,
+			attack_state: 0.0,
+			offset: 0
+		}
+	}
+
+	/// Mix the sound of the note, placed at `start`, into `mixing` up to position `end`.
+	pub(crate) fn mix(&mut self, sound: &[f64], start: usize, end: usize, mixing: &mut [[f64; 2]]) {
+		let end = end.min(mixing.len()).max(start);
+		let remaining = sound.len().min(end - start).saturating_sub(self.offset);
+		let from = start + self.offset;
+		for (s, out) in sound[self.offset..].iter().zip(&mut mixing[from..from + remaining]) {
+			let envelope = self.params.attack_release_level(self.release_state, self.attack_state);
+			out[0] += s * self.volume[0] * envelope;
+			out[1] += s * self.volume[1] * envelope;
+			self.release_state += self.params.release as f64;
+			self.attack_state += self.params.attack as f64;
+		}
+		self.offset += remaining;
+	}
+}
+
+/// Mix the columns of an instrument into the mixing buffer, as done by `MakeChannel`
+/// in the player. Only columns accepted by `solo` are mixed.
+fn mix_instrument(instrument: &PlayerInstrument, mixing: &mut [[f64; 2]], random: &[u32], solo: &dyn Fn(&str) -> bool) {
+	let columns: Vec<_> = instrument.columns.iter().filter(|c| solo(&c.name)).collect();
+	if columns.is_empty() {
+		return;
+	}
+	let p = &instrument.params;
+	let sounds: Vec<Vec<f64>> = (0..instrument.tones.len()).map(|i| make_tone(p, instrument.tuned_tone(i), random)).collect();
+	for column in columns {
+		for note in &column.notes {
+			NoteMixer::new(p, note).mix(&sounds[note.tone_index], note.start, mixing.len(), mixing);
+		}
+	}
+}
+
+/// Apply the reverb to the mixed sound of the instruments with reverb, as done in
+/// `Oidos_GenerateMusic`. The filter states and the delay buffer are shared by all
+/// delay lines, like in the player.
+pub(crate) fn apply_reverb(reverb: &ReverbParams, input: &[[f64; 2]], output: &mut [[f64; 2]], random: &[u32]) {
+	let mut delay_buffer = vec![0f64; DELAY_BUFFER_SIZE];
+	reverb::apply_reverb(reverb, input, output, &mut delay_buffer, random);
+}
+
+/// Render the music the way the player does. Only columns (named as the track labels)
+/// accepted by `solo` are played. Returns interleaved stereo samples.
+pub fn render_music(music: &PlayerMusic, solo: &dyn Fn(&str) -> bool) -> Vec<[i16; 2]> {
+	let random = random_data();
+	let mut mixing = vec![[0f64; 2]; music.total_samples];
+	let mut reverb_buffer = vec![[0f64; 2]; music.total_samples];
+
+	for instrument in music.instruments.iter().filter(|i| i.reverb) {
+		mix_instrument(instrument, &mut mixing, &random, solo);
+	}
+	if let Some(ref reverb) = music.reverb {
+		apply_reverb(reverb, &mixing, &mut reverb_buffer, &random);
+	}
+	for instrument in music.instruments.iter().filter(|i| !i.reverb) {
+		mix_instrument(instrument, &mut mixing, &random, solo);
+	}
+
+	mixing.iter().zip(&reverb_buffer).map(|(m, r)| output_sample(m, r)).collect()
+}
+
+/// Final output sample from the mixing and reverb buffers.
+pub(crate) fn output_sample(m: &[f64; 2], r: &[f64; 2]) -> [i16; 2] {
+	let sample = |c: usize| (m[c] + r[c]).clamp(-AMP_MAX, AMP_MAX).round_ties_even() as i16;
+	[sample(0), sample(1)]
+}
+
+/// Write a 16-bit stereo WAV file at 44100 Hz, like `Oidos_WavFileHeader`.
+pub fn write_wav(filename: &str, samples: &[[i16; 2]]) -> io::Result<()> {
+	let mut out = BufWriter::new(File::create(filename)?);
+	let data_size = samples.len() as u32 * 4;
+	out.write_all(b"RIFF")?;
+	out.write_all(&(36 + data_size).to_le_bytes())?;
+	out.write_all(b"WAVEfmt ")?;
+	for value in &[16u32, 1 | 2 << 16, 44100, 44100 * 4, 4 | 16 << 16] {
+		out.write_all(&value.to_le_bytes())?;
+	}
+	out.write_all(b"data")?;
+	out.write_all(&data_size.to_le_bytes())?;
+	for sample in samples {
+		out.write_all(&sample[0].to_le_bytes())?;
+		out.write_all(&sample[1].to_le_bytes())?;
+	}
+	out.flush()
+}
+
+#[test]
+fn test_random_data() {
+	let random = random_data();
+	assert_eq!(random[0], 0xCAADAA7B);
+	assert_eq!(*random.last().unwrap(), 0xB08A4BA7);
+}
+
+#[test]
+fn test_make_tone() {
+	let music = decode_music(&parse_music(TEST_PLAYER_MUSIC).unwrap()).unwrap();
+	let random = random_data();
+
+	// A single undamped partial with unit gain is a pure sine at the frequency of the tone
+	let pad = &music.instruments[0].params;
+	let sound = make_tone(pad, 60.0, &random);
+	assert_eq!(sound.len(), 65536);
+	let getrandom = |i: usize| random[i] as i32 as f64 / 2147483648.0;
+	let amp = getrandom(1);
+	let phase = std::f64::consts::PI * getrandom(3);
+	let angle = 2f64.powf(5.0) * BASE_FREQ as f64;
+	for (i, s) in sound.iter().enumerate().step_by(1000) {
+		assert!((s - amp * (phase + angle * (i + 1) as f64).cos()).abs() < 1e-6);
+	}
+
+	// With swell, the partial rises linearly to full level, starting silent
+	let swell = InstrumentParams { swell: 1.0 / 1024.0, ..*pad };
+	let sound = make_tone(&swell, 60.0, &random);
+	for (i, s) in sound.iter().enumerate().step_by(100).take(20) {
+		let level = (i as f64 / 1024.0).min(1.0);
+		assert!((s - level * amp * (phase + angle * (i + 1) as f64).cos()).abs() < 1e-6);
+	}
+
+	// A formant peak centered on the partial scales it by its gain, moving away over time
+	let ptone = 60.0 + getrandom(2) * pad.width as f64;
+	let formant = InstrumentParams { formants: [(ptone as f32, 0.5, 1.0), (0.0, 0.0, 0.0)], formant_sweep: 1.0 / 1024.0, ..*pad };
+	let sound = make_tone(&formant, 60.0, &random);
+	for (i, s) in sound.iter().enumerate().step_by(100).take(20) {
+		let level = 2.0 - (i as f64 / 2048.0).min(1.0);
+		assert!((s - level * amp * (phase + angle * (i + 1) as f64).cos()).abs() < 1e-5);
+	}
+
+	// Stiffness stretches the frequency of the partial, which changes its amplitude by the sharpness
+	let stiff = InstrumentParams { stiffness: 0.21, ..*pad };
+	let sound = make_tone(&stiff, 60.0, &random);
+	let stretch = (1.0 + stiff.stiffness as f64).sqrt();
+	let stiff_amp = amp * stretch.powf(pad.sharpness as f64);
+	for (i, s) in sound.iter().enumerate().step_by(1000) {
+		assert!((s - stiff_amp * (phase + angle * stretch * (i + 1) as f64).cos()).abs() < 1e-6);
+	}
+
+	// Saturation keeps the sound of the gained instrument within the sqrt(gain) bound
+	let bass = &music.instruments[1].params;
+	let sound = make_tone(bass, 36.0, &random);
+	assert!(sound.iter().all(|s| s.abs() <= 2f64.sqrt()));
+	assert!(sound.iter().any(|&s| s != 0.0));
+}
+
+#[test]
+fn test_render_music() {
+	let music = decode_music(&parse_music(TEST_PLAYER_MUSIC).unwrap()).unwrap();
+	let full = render_music(&music, &|_| true);
+	assert_eq!(full.len(), 131072);
+
+	// The pad is released right away after 4 ticks, leaving only the reverb
+	let pad = render_music(&music, &|name| name == "Pad_1_1");
+	assert!(pad[100..4000].iter().any(|s| s[0] != 0));
+	// The reverb volumes differ between the channels
+	assert!(pad[1100..4000].iter().any(|s| s[0] != s[1]));
+	assert!(pad[4300..8000].iter().any(|s| s[0] != 0));
+	assert!(pad[70000..].iter().all(|s| s[0].abs() < 100));
+
+	// The bass has no reverb and is silent outside its notes
+	let bass = render_music(&music, &|name| name.starts_with("Bass"));
+	assert!(bass[1000..2000].iter().any(|s| s[0].abs() > 1000));
+	assert!(bass[70000..].iter().all(|s| s[0] == 0));
+	let none = render_music(&music, &|_| false);
+	assert!(none.iter().all(|s| *s == [0, 0]));
+}
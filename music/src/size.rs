@@ -0,0 +1,180 @@
+// Estimation of the compressed size of music data
+
+use std::collections::HashMap;
+
+use crate::asm::{MusicAsm, Owner};
+#[cfg(test)] use crate::asm::{parse_music, TEST_MUSIC};
+
+
+/// Context models, given as masks of the preceding bytes (bit 0 is the previous byte).
+/// Similar to the models tried by Crinkler, including sparse models picking up the
+/// structure of 4-byte parameters.
+const MODEL_MASKS: [u8; 12] = [0x00, 0x01, 0x03, 0x07, 0x0F, 0xFF, 0x02, 0x04, 0x08, 0x88, 0x05, 0x0A];
+const LEARNING_RATE: f32 = 0.02;
+const INITIAL_WEIGHT: f32 = 0.3;
+
+/// Order in which the converter writes the data sections
+pub const DATA_SECTIONS: [&str; 6] = ["iparam", "itones", "ituning", "trdata", "notelen", "notesamp"];
+
+fn stretch(p: f32) -> f32 {
+	(p / (1.0 - p)).ln()
+}
+
+fn squash(x: f32) -> f32 {
+	1.0 / (1.0 + (-x).exp())
+}
+
+/// Bitwise context mixing compressor model. Only the cost of the coded bits is
+/// computed; no output is produced.
+pub struct Estimator {
+	/// Bit counts for each model, indexed by hashed context
+	counts: Vec<HashMap<u64, (u32, u32)>>,
+	weights: Vec<f32>,
+	history: Vec<u8>
+}
+
+impl Default for Estimator {
+	fn default() -> Estimator {
+		Estimator {
+			counts: vec![HashMap::new(); MODEL_MASKS.len()],
+			weights: vec![INITIAL_WEIGHT; MODEL_MASKS.len()],
+			history: Vec::new()
+		}
+	}
+}
+
+impl Estimator {
+	fn context(&self, mask: u8, partial: u32) -> u64 {
+		let mut hash = mask as u64 ^ ((partial as u64) << 8);
+		for i in 0..8 {
+			if mask & (1 << i) != 0 {
+				let byte = self.history.len().checked_sub(i + 1).map(|pos| self.history[pos]).unwrap_or(0);
+				hash = hash.wrapping_mul(0x100000001B3).wrapping_add(byte as u64 + 1);
+			}
+		}
+		hash
+	}
+
+	/// Code a byte, returning its cost in bits.
+	pub fn code(&mut self, byte: u8) -> f32 {
+		let mut cost = 0.0;
+		// Bits coded so far of the current byte, with a leading 1
+		let mut partial = 1u32;
+		for bit_index in (0..8).rev() {
+			let bit = (byte >> bit_index) & 1;
+			let contexts: Vec<u64> = MODEL_MASKS.iter().map(|&mask| self.context(mask, partial)).collect();
+			let inputs: Vec<f32> = contexts.iter().zip(&self.counts).map(|(c, counts)| {
+				let (n0, n1) = counts.get(c).cloned().unwrap_or((0, 0));
+				stretch((n1 as f32 + 0.4) / ((n0 + n1) as f32 + 0.8))
+			}).collect();
+			let p = squash(inputs.iter().zip(&self.weights).map(|(x, w)| x * w).sum::<f32>()).clamp(1e-6, 1.0 - 1e-6);
+			cost -= if bit == 1 { p.log2() } else { (1.0 - p).log2() };
+
+			let error = bit as f32 - p;
+			for (w, x) in self.weights.iter_mut().zip(&inputs) {
+				*w += LEARNING_RATE * error * x;
+			}
+			for (c, counts) in contexts.iter().zip(&mut self.counts) {
+				let (n0, n1) = counts.entry(*c).or_insert((0, 0));
+				// Nonstationary counting: the opposite count is halved
+				if bit == 1 {
+					*n1 += 1;
+					if *n0 > 2 { *n0 = *n0 / 2 + 1; }
+				} else {
+					*n0 += 1;
+					if *n1 > 2 { *n1 = *n1 / 2 + 1; }
+				}
+			}
+			partial = (partial << 1) | bit as u32;
+		}
+		self.history.push(byte);
+		cost
+	}
+}
+
+/// Raw and estimated compressed size of a part of the music data, in bytes.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Size {
+	pub raw: usize,
+	pub compressed: f32
+}
+
+impl Size {
+	fn add(&mut self, compressed_bits: f32) {
+		self.raw += 1;
+		self.compressed += compressed_bits / 8.0;
+	}
+}
+
+pub struct SizeReport {
+	/// Size of each data section, in the order written by the converter
+	pub sections: Vec<(String, Size)>,
+	/// Size of the data of each instrument and track, summed over all sections
+	pub owners: Vec<(Owner, Size)>,
+	pub total: Size
+}
+
+/// Estimate the compressed size of the data sections of the music, coding them as
+/// one stream in the order written by the converter.
+pub fn estimate_size(music: &MusicAsm) -> SizeReport {
+	let mut estimator = Estimator::default();
+	let mut sections = Vec::new();
+	let mut owners: HashMap<Owner, Size> = HashMap::new();
+	let mut total = Size::default();
+	for &name in &DATA_SECTIONS {
+		let section = match music.section(name) {
+			Some(section) => section,
+			None => continue
+		};
+		let mut size = Size::default();
+		for block in &section.blocks {
+			let owner = owners.entry(block.owner()).or_default();
+			for &byte in &block.bytes {
+				let bits = estimator.code(byte);
+				size.add(bits);
+				owner.add(bits);
+				total.add(bits);
+			}
+		}
+		sections.push((name.to_string(), size));
+	}
+	let mut owners: Vec<(Owner, Size)> = owners.into_iter().filter(|(_, size)| size.raw > 0).collect();
+	owners.sort_by(|a, b| a.0.cmp(&b.0));
+	SizeReport {
+		sections,
+		owners,
+		total
+	}
+}
+
+#[test]
+fn test_estimator() {
+	let mut estimator = Estimator::default();
+	let zeros: f32 = (0..1000).map(|_| estimator.code(0)).sum();
+	assert!(zeros / 8.0 < 20.0);
+
+	// Pseudorandom data does not compress
+	let mut estimator = Estimator::default();
+	let mut state = 12345u32;
+	let noise: f32 = (0..1000).map(|_| {
+		state = state.wrapping_mul(1103515245).wrapping_add(12345);
+		estimator.code((state >> 16) as u8)
+	}).sum();
+	assert!(noise / 8.0 > 950.0);
+}
+
+#[test]
+fn test_estimate_size() {
+	let music = parse_music(TEST_MUSIC).unwrap();
+	let report = estimate_size(&music);
+	assert_eq!(report.sections.len(), 5);
+	assert_eq!(report.total.raw, 84 + 3 + 6 + 5 + 3);
+	let section_sum: f32 = report.sections.iter().map(|(_, s)| s.compressed).sum();
+	let owner_sum: f32 = report.owners.iter().map(|(_, s)| s.compressed).sum();
+	assert!((section_sum - report.total.compressed).abs() < 0.01);
+	assert!((owner_sum - report.total.compressed).abs() < 0.01);
+	assert!(report.total.compressed < report.total.raw as f32);
+	assert_eq!(report.owners.len(), 2);
+	assert_eq!(report.owners[0].1.raw, 84 + 3);
+	assert_eq!(report.owners[1].0, Owner::Track("Bass_0_0".to_string()));
+}
@@ -0,0 +1,339 @@
+// Rendering music incrementally ahead of playback
+
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::player::PlayerMusic;
+use crate::render::{apply_reverb, make_tone, output_sample, random_data, NoteMixer};
+#[cfg(test)] use crate::asm::parse_music;
+#[cfg(test)] use crate::player::{decode_music, TEST_PLAYER_MUSIC};
+#[cfg(test)] use crate::render::render_music;
+
+
+/// A tone of an instrument to be calculated.
+#[derive(Clone, Copy)]
+struct Job {
+	instrument: usize,
+	tone_index: usize,
+	/// Position of the first note using the tone
+	first_needed: usize
+}
+
+struct State {
+	/// Calculated sounds, by instrument and tone index. Dropped once all notes
+	/// using them have been mixed.
+	sounds: Vec<Vec<Option<Arc<Vec<f64>>>>>,
+	/// Tones of instruments with reverb not yet calculated
+	reverb_tones_left: usize,
+	reverb_done: bool,
+	/// Unfinished tones of instruments without reverb, as positions of their first notes
+	pending: Vec<usize>,
+	mixing: Vec<[f64; 2]>,
+	reverb: Vec<[f64; 2]>,
+	/// Mixing progress of each note, by instrument, column and note
+	notes: Vec<Vec<Vec<NoteMixer>>>,
+	/// Position up to which the instruments without reverb are mixed
+	mixed_until: usize,
+	output: Vec<[i16; 2]>
+}
+
+struct Shared {
+	music: PlayerMusic,
+	random: Vec<u32>,
+	/// Jobs in priority order, taken from the front
+	jobs: Mutex<Vec<Job>>,
+	state: Mutex<State>,
+	/// Number of samples from the start that are rendered
+	rendered: AtomicUsize,
+	/// Set if a worker panicked, so its tone will never be calculated
+	failed: AtomicBool,
+	progress: Condvar
+}
+
+/// Marks the rendering as failed when a worker panics, waking up any waiting readers.
+struct FailureGuard<'a>(&'a Shared);
+
+impl Drop for FailureGuard<'_> {
+	fn drop(&mut self) {
+		if thread::panicking() {
+			self.0.failed.store(true, Ordering::Release);
+			// Notify under the lock, so a reader cannot miss it between checking and waiting
+			let _state = self.0.state.lock();
+			self.0.progress.notify_all();
+		}
+	}
+}
+
+/// Renders music in worker threads, producing the output from the start of the music
+/// while the rest is still being calculated. The output is identical to `render_music`.
+///
+/// Tones are calculated in order of when they are first needed. Since the reverb
+/// depends on the whole of the music going into it, the instruments with reverb are
+/// calculated first, and no output is available until the reverb is done.
+pub struct StreamingRenderer {
+	shared: Arc<Shared>,
+	workers: Vec<JoinHandle<()>>
+}
+
+impl Shared {
+	fn work(&self) {
+		let _failure = FailureGuard(self);
+		loop {
+			let job = {
+				let mut jobs = self.jobs.lock().unwrap();
+				if jobs.is_empty() {
+					return;
+				}
+				jobs.remove(0)
+			};
+			let instrument = &self.music.instruments[job.instrument];
+			let sound = make_tone(&instrument.params, instrument.tuned_tone(job.tone_index), &self.random);
+
+			let mut state = self.state.lock().unwrap();
+			state.sounds[job.instrument][job.tone_index] = Some(Arc::new(sound));
+			if instrument.reverb {
+				state.reverb_tones_left -= 1;
+				if state.reverb_tones_left == 0 {
+					state = self.mix_reverb(state);
+				}
+			} else if let Some(index) = state.pending.iter().position(|&p| p == job.first_needed) {
+				state.pending.swap_remove(index);
+			}
+			self.advance(&mut state);
+		}
+	}
+
+	/// Mix the instruments with reverb and apply the reverb. The lock is released
+	/// while the reverb is calculated.
+	fn mix_reverb<'a>(&'a self, mut state: std::sync::MutexGuard<'a, State>) -> std::sync::MutexGuard<'a, State> {
+		let mut mixing = mem::take(&mut state.mixing);
+		let mut reverb = mem::take(&mut state.reverb);
+		let sounds = state.sounds.clone();
+		let total = mixing.len();
+		for (i, instrument) in self.music.instruments.iter().enumerate().filter(|(_, i)| i.reverb) {
+			for (c, column) in instrument.columns.iter().enumerate() {
+				for (n, note) in column.notes.iter().enumerate() {
+					let sound = sounds[i][note.tone_index].as_ref().unwrap();
+					state.notes[i][c][n].mix(sound, note.start, total, &mut mixing);
+				}
+			}
+			state.sounds[i].clear();
+		}
+		drop(state);
+
+		if let Some(ref settings) = self.music.reverb {
+			apply_reverb(settings, &mixing, &mut reverb, &self.random);
+		}
+
+		let mut state = self.state.lock().unwrap();
+		state.mixing = mixing;
+		state.reverb = reverb;
+		state.reverb_done = true;
+		state
+	}
+
+	/// Mix the instruments without reverb up to the first position needing a tone
+	/// not yet calculated, and produce the output up to there.
+	fn advance(&self, state: &mut State) {
+		if !state.reverb_done {
+			return;
+		}
+		let total = state.mixing.len();
+		let end = state.pending.iter().copied().min().unwrap_or(total).min(total);
+		let start = state.mixed_until;
+		if end <= start {
+			return;
+		}
+		let State { ref mut sounds, ref mut mixing, ref mut notes, .. } = *state;
+		for (i, instrument) in self.music.instruments.iter().enumerate().filter(|(_, i)| !i.reverb) {
+			for (c, column) in instrument.columns.iter().enumerate() {
+				for (n, note) in column.notes.iter().enumerate().filter(|(_, n)| n.start < end) {
+					if let Some(sound) = sounds[i][note.tone_index].as_ref() {
+						notes[i][c][n].mix(sound, note.start, end, mixing);
+					}
+				}
+			}
+			// Drop sounds whose notes are all mixed
+			let maxsamples = instrument.params.maxsamples as usize;
+			for (tone_index, sound) in sounds[i].iter_mut().enumerate() {
+				let last_end = instrument.columns.iter().flat_map(|c| c.notes.iter())
+					.filter(|n| n.tone_index == tone_index)
+					.map(|n| n.start + maxsamples)
+					.max().unwrap_or(0);
+				if sound.is_some() && last_end <= end {
+					*sound = None;
+				}
+			}
+		}
+
+		for p in start..end {
+			state.output[p] = output_sample(&state.mixing[p], &state.reverb[p]);
+		}
+		state.mixed_until = end;
+		self.rendered.store(end, Ordering::Release);
+		self.progress.notify_all();
+	}
+}
+
+/// All tones of the music in the order they are to be calculated.
+fn schedule(music: &PlayerMusic) -> Vec<Job> {
+	let mut jobs = Vec::new();
+	for (i, instrument) in music.instruments.iter().enumerate() {
+		for tone_index in 0..instrument.tones.len() {
+			let first_needed = instrument.columns.iter().flat_map(|c| c.notes.iter())
+				.filter(|n| n.tone_index == tone_index)
+				.map(|n| n.start)
+				.min().unwrap_or(music.total_samples);
+			jobs.push(Job { instrument: i, tone_index, first_needed });
+		}
+	}
+	// Instruments with reverb are needed before anything can be output
+	jobs.sort_by_key(|job| (!music.instruments[job.instrument].reverb, job.first_needed));
+	jobs
+}
+
+impl StreamingRenderer {
+	/// Start rendering the music using the given number of worker threads.
+	pub fn new(music: PlayerMusic, threads: usize) -> StreamingRenderer {
+		let total = music.total_samples;
+		let jobs = schedule(&music);
+		let pending = jobs.iter().filter(|job| !music.instruments[job.instrument].reverb).map(|job| job.first_needed).collect();
+		let reverb_tones_left = jobs.iter().filter(|job| music.instruments[job.instrument].reverb).count();
+		let state = State {
+			sounds: music.instruments.iter().map(|i| vec![None; i.tones.len()]).collect(),
+			reverb_tones_left,
+			reverb_done: reverb_tones_left == 0,
+			pending,
+			mixing: vec![[0.0; 2]; total],
+			reverb: vec![[0.0; 2]; total],
+			notes: music.instruments.iter().map(|i| i.columns.iter().map(|c| {
+				c.notes.iter().map(|n| NoteMixer::new(&i.params, n)).collect()
+			}).collect()).collect(),
+			mixed_until: 0,
+			output: vec![[0; 2]; total]
+		};
+		let shared = Arc::new(Shared {
+			music,
+			random: random_data(),
+			jobs: Mutex::new(jobs),
+			state: Mutex::new(state),
+			rendered: AtomicUsize::new(0),
+			failed: AtomicBool::new(false),
+			progress: Condvar::new()
+		});
+		// Music without tones is complete right away
+		shared.advance(&mut shared.state.lock().unwrap());
+
+		let workers = (0..threads.max(1)).map(|_| {
+			let shared = shared.clone();
+			thread::spawn(move || shared.work())
+		}).collect();
+		StreamingRenderer { shared, workers }
+	}
+
+	pub fn total_samples(&self) -> usize {
+		self.shared.music.total_samples
+	}
+
+	/// Number of samples from the start of the music that are rendered.
+	pub fn rendered_samples(&self) -> usize {
+		self.shared.rendered.load(Ordering::Acquire)
+	}
+
+	/// How far rendering is ahead of the play position, in samples.
+	/// Zero if playback has caught up with the rendering.
+	pub fn samples_ahead(&self, position: usize) -> usize {
+		self.rendered_samples().saturating_sub(position)
+	}
+
+	pub fn is_finished(&self) -> bool {
+		self.rendered_samples() >= self.total_samples()
+	}
+
+	/// Copy rendered samples starting at `position` into `out`.
+	/// Returns the number of samples copied, which is less than the length of `out`
+	/// if rendering has not got that far.
+	pub fn read(&self, position: usize, out: &mut [[i16; 2]]) -> usize {
+		let state = self.shared.state.lock().unwrap();
+		let available = state.mixed_until.saturating_sub(position).min(out.len());
+		if available == 0 {
+			return 0;
+		}
+		out[..available].copy_from_slice(&state.output[position..position + available]);
+		available
+	}
+
+	/// Wait until at least `samples` samples (or the whole music) are rendered.
+	/// Panics if a worker thread panicked before getting that far.
+	pub fn wait_for(&self, samples: usize) {
+		let samples = samples.min(self.total_samples());
+		let mut state = self.shared.state.lock().unwrap();
+		while state.mixed_until < samples {
+			assert!(!self.shared.failed.load(Ordering::Acquire), "Rendering failed in a worker thread");
+			state = self.shared.progress.wait(state).unwrap();
+		}
+	}
+
+	/// Wait until the whole music is rendered and return it.
+	pub fn finish(self) -> Vec<[i16; 2]> {
+		for worker in self.workers {
+			worker.join().unwrap();
+		}
+		let mut state = self.shared.state.lock().unwrap();
+		mem::take(&mut state.output)
+	}
+}
+
+#[test]
+fn test_streaming_renderer() {
+	let music = decode_music(&parse_music(TEST_PLAYER_MUSIC).unwrap()).unwrap();
+	let expected = render_music(&music, &|_| true);
+
+	for &threads in &[1, 3] {
+		let music = decode_music(&parse_music(TEST_PLAYER_MUSIC).unwrap()).unwrap();
+		let renderer = StreamingRenderer::new(music, threads);
+		renderer.wait_for(10000);
+		assert!(renderer.rendered_samples() >= 10000);
+		assert_eq!(renderer.samples_ahead(4000), renderer.rendered_samples() - 4000);
+		let mut start = [[0i16; 2]; 10000];
+		assert_eq!(renderer.read(0, &mut start), 10000);
+		assert_eq!(&start[..], &expected[..10000]);
+
+		let output = renderer.finish();
+		assert_eq!(output, expected);
+	}
+
+	// The reverb comes first, then the tones by when they are first needed
+	let music = decode_music(&parse_music(TEST_PLAYER_MUSIC).unwrap()).unwrap();
+	let order: Vec<_> = schedule(&music).iter().map(|job| (job.instrument, job.tone_index, job.first_needed)).collect();
+	assert_eq!(order, vec![(0, 0, 0), (1, 0, 0), (1, 1, 2000)]);
+
+	// Without reverb, output is produced as the tones are calculated
+	let mut music = music;
+	music.instruments.remove(0);
+	music.reverb = None;
+	let renderer = StreamingRenderer::new(music, 1);
+	let mut rendered = 0;
+	while !renderer.is_finished() {
+		renderer.wait_for(rendered + 1);
+		assert!(renderer.rendered_samples() > rendered);
+		assert!(renderer.rendered_samples() == 2000 || renderer.is_finished());
+		rendered = renderer.rendered_samples();
+	}
+	assert_eq!(renderer.finish().len(), 131072);
+
+	// Reading past the end gives nothing
+	let music = decode_music(&parse_music(TEST_PLAYER_MUSIC).unwrap()).unwrap();
+	let renderer = StreamingRenderer::new(music, 1);
+	renderer.wait_for(renderer.total_samples());
+	assert_eq!(renderer.read(renderer.total_samples() + 10, &mut [[0i16; 2]; 100]), 0);
+
+	// A failing worker is reported rather than waited for
+	let mut music = decode_music(&parse_music(TEST_PLAYER_MUSIC).unwrap()).unwrap();
+	music.instruments[1].params.seed = u32::MAX;
+	let renderer = StreamingRenderer::new(music, 1);
+	let waited = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| renderer.wait_for(renderer.total_samples())));
+	assert!(waited.is_err());
+}
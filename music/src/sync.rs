@@ -0,0 +1,164 @@
+// Synchronizing visuals to the music
+
+use crate::player::{PlayerMusic, PlayerNote};
+#[cfg(test)] use crate::asm::parse_music;
+#[cfg(test)] use crate::player::{decode_music, TEST_PLAYER_MUSIC};
+
+
+/// A note sounding at the queried position.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ActiveNote {
+	/// Index of the column in the instrument
+	pub column: usize,
+	pub tone: i32,
+	/// Velocity in units of the velocity quantum of the instrument
+	pub volume: u8,
+	/// Start of the note, in ticks
+	pub start: f32,
+	/// Envelope of the note, 0 to 1
+	pub envelope: f32
+}
+
+struct SyncNote {
+	column: usize,
+	note: PlayerNote,
+	/// Position where the note stops sounding, in samples
+	end: usize
+}
+
+/// Answers questions about what the music is playing at a given position, using
+/// the same timing as the player. Positions are in ticks, as returned by
+/// `Oidos_GetPosition`, and may be fractional.
+pub struct MusicSync<'a> {
+	music: &'a PlayerMusic,
+	/// Notes of each instrument sorted by start
+	notes: Vec<Vec<SyncNote>>,
+	/// Longest note of each instrument, in samples
+	max_duration: Vec<usize>
+}
+
+impl<'a> MusicSync<'a> {
+	pub fn new(music: &'a PlayerMusic) -> MusicSync<'a> {
+		let notes: Vec<Vec<SyncNote>> = music.instruments.iter().map(|instrument| {
+			let p = &instrument.params;
+			let mut notes: Vec<SyncNote> = instrument.columns.iter().enumerate().flat_map(|(column, c)| {
+				c.notes.iter().map(move |&note| {
+					// The release reaches zero 1/release samples after the release point
+					let release_end = if p.release < 0.0 {
+						note.length as f64 - 1.0 / p.release as f64
+					} else {
+						f64::INFINITY
+					};
+					let duration = (p.maxsamples as f64).min(release_end.ceil()) as usize;
+					SyncNote { column, note, end: note.start + duration }
+				})
+			}).collect();
+			notes.sort_by_key(|n| n.note.start);
+			notes
+		}).collect();
+		let max_duration = notes.iter().map(|n| n.iter().map(|n| n.end - n.note.start).max().unwrap_or(0)).collect();
+		MusicSync { music, notes, max_duration }
+	}
+
+	/// Index of the instrument with the given number, title or name.
+	/// The title is the number and name, as in `01|Pad`.
+	pub fn instrument(&self, name: &str) -> Option<usize> {
+		self.music.instruments.iter().position(|i| {
+			name.parse::<u32>() == Ok(i.number) || i.title.as_deref().is_some_and(|title| {
+				title == name || title.split_once('|').is_some_and(|(_, n)| n == name)
+			})
+		})
+	}
+
+	fn sample(&self, position: f32) -> f64 {
+		position as f64 * self.music.samples_per_tick as f64
+	}
+
+	/// Convert a time in seconds to a position in ticks.
+	pub fn ticks(&self, seconds: f32) -> f32 {
+		(seconds as f64 * self.music.ticks_per_second) as f32
+	}
+
+	/// The notes of an instrument sounding at the position, in order of their start.
+	pub fn active_notes(&self, instrument: usize, position: f32) -> Vec<ActiveNote> {
+		let sample = self.sample(position);
+		if sample < 0.0 {
+			return vec![];
+		}
+		let notes = &self.notes[instrument];
+		let first = notes.partition_point(|n| (n.note.start + self.max_duration[instrument]) as f64 <= sample);
+		let last = notes.partition_point(|n| n.note.start as f64 <= sample);
+		let p = &self.music.instruments[instrument].params;
+		notes[first..last].iter().filter(|n| sample < n.end as f64).map(|n| {
+			// Envelope as in the player, at the start of the current sample
+			let offset = (sample - n.note.start as f64).floor();
+			let release = 1.0 - (n.note.length as f64 - offset) * p.release as f64;
+			let attack = offset * p.attack as f64;
+			ActiveNote {
+				column: n.column,
+				tone: self.music.instruments[instrument].tones[n.note.tone_index],
+				volume: n.note.volume,
+				start: (n.note.start as f64 / self.music.samples_per_tick as f64) as f32,
+				envelope: (p.attack_release_level(release, attack) * p.decay_level(offset as usize)) as f32
+			}
+		}).collect()
+	}
+
+	/// The combined volume of the notes of an instrument at the position, as applied
+	/// by the player to the sounds of the tones: the instrument volume times the
+	/// velocity and envelope of each note.
+	pub fn envelope_level(&self, instrument: usize, position: f32) -> f32 {
+		let volume = self.music.instruments[instrument].params.volume;
+		self.active_notes(instrument, position).iter().map(|n| n.envelope * n.volume as f32 * volume).sum()
+	}
+
+	/// Ticks since the last note started in the track, or `None` if no note has been
+	/// played yet. The track is given by its name in the converted music, and all of its
+	/// columns count. Names of columns, like `Bass_2_0`, also work.
+	pub fn time_since_hit(&self, track: &str, position: f32) -> Option<f32> {
+		let sample = self.sample(position);
+		self.music.instruments.iter().flat_map(|i| i.columns.iter())
+			.filter(|c| c.name == track || c.name.starts_with(&format!("{}_", track)))
+			.filter_map(|c| {
+				let count = c.notes.partition_point(|n| n.start as f64 <= sample);
+				count.checked_sub(1).map(|last| c.notes[last].start)
+			})
+			.max()
+			.map(|start| ((sample - start as f64) / self.music.samples_per_tick as f64) as f32)
+	}
+}
+
+#[test]
+fn test_music_sync() {
+	let music = decode_music(&parse_music(TEST_PLAYER_MUSIC).unwrap()).unwrap();
+	let sync = MusicSync::new(&music);
+	let pad = sync.instrument("Pad").unwrap();
+	assert_eq!(sync.instrument("01|Pad"), Some(pad));
+	let bass = sync.instrument("0").unwrap();
+	assert_eq!((pad, bass), (0, 1));
+	assert_eq!(sync.instrument("Drums"), None);
+
+	// The pad is released at 4 ticks and is silent one sample later
+	let notes = sync.active_notes(pad, 2.0);
+	assert_eq!(notes, vec![ActiveNote { column: 0, tone: 60, volume: 1, start: 0.0, envelope: 1.0 }]);
+	assert_eq!(sync.active_notes(pad, 4.0005)[0].envelope, 1.0);
+	assert!(sync.active_notes(pad, 4.5).is_empty());
+	assert_eq!(sync.active_notes(pad, 9.0)[0].start, 8.0);
+	assert!(sync.active_notes(pad, -1.0).is_empty());
+
+	// The bass attack takes 128 samples
+	let attack = sync.active_notes(bass, 0.064)[0].envelope;
+	assert_eq!(attack, 0.5);
+	assert_eq!(sync.envelope_level(bass, 0.064), 0.5 * 2.0 * 4000.0);
+	// Both columns play at 9 ticks
+	let notes = sync.active_notes(bass, 9.0);
+	assert_eq!(notes.iter().map(|n| (n.column, n.tone)).collect::<Vec<_>>(), vec![(0, 36), (1, 43), (0, 43)]);
+	assert!(notes[0].envelope < notes[1].envelope);
+
+	assert_eq!(sync.time_since_hit("Bass", 5.0), Some(3.0));
+	assert_eq!(sync.time_since_hit("Bass_1", 5.0), Some(5.0));
+	assert_eq!(sync.time_since_hit("Bass_1_0", 9.5), Some(1.5));
+	assert_eq!(sync.time_since_hit("Pad", -0.5), None);
+	assert_eq!(sync.time_since_hit("Lead", 5.0), None);
+	assert_eq!(sync.ticks(1.0), 44.1);
+}
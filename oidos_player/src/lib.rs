@@ -0,0 +1,102 @@
+// Safe interface to the Oidos player
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::slice;
+use std::sync::Once;
+
+use oidos_player_sys as sys;
+
+pub use crate::sys::{Sample, MUSIC_ASM, NOISESIZE};
+
+static FILL_RANDOM_DATA: Once = Once::new();
+static GENERATE_MUSIC: Once = Once::new();
+#[cfg(windows)]
+static START_MUSIC: Once = Once::new();
+
+/// The block of random data used by Oidos, filled on first use.
+/// Can also be useful as a 3D noise texture.
+pub fn random_data() -> &'static [u32] {
+	FILL_RANDOM_DATA.call_once(|| unsafe { sys::Oidos_FillRandomData() });
+	// The data is only written by Oidos_FillRandomData, which has finished.
+	unsafe { &*std::ptr::addr_of!(sys::Oidos_RandomData) }
+}
+
+/// The tick rate of the music.
+pub fn ticks_per_second() -> f32 {
+	unsafe { sys::Oidos_TicksPerSecond }
+}
+
+/// The length of the music in ticks.
+pub fn music_length() -> u32 {
+	unsafe { sys::Oidos_MusicLength }
+}
+
+/// The WAV file header for the music, 44 bytes as 32-bit words.
+pub fn wav_header() -> &'static [u32; sys::WAV_HEADER_WORDS] {
+	unsafe { &sys::Oidos_WavFileHeader }
+}
+
+/// The music, once it has been generated.
+pub struct Music {
+	_private: ()
+}
+
+impl Music {
+	/// Generate the whole music. This takes a while the first time it is called.
+	/// Subsequent calls return immediately.
+	pub fn generate() -> Music {
+		random_data();
+		GENERATE_MUSIC.call_once(|| unsafe { sys::Oidos_GenerateMusic() });
+		Music { _private: () }
+	}
+
+	/// The generated music as 16-bit stereo samples at 44100 Hz.
+	pub fn buffer(&self) -> &'static [Sample] {
+		let length = wav_header()[10] as usize / std::mem::size_of::<Sample>();
+		// The buffer is only written by Oidos_GenerateMusic, which has finished.
+		unsafe { slice::from_raw_parts(std::ptr::addr_of!(sys::Oidos_MusicBuffer) as *const Sample, length) }
+	}
+
+	/// The length of the music in seconds.
+	pub fn duration(&self) -> f32 {
+		music_length() as f32 / ticks_per_second()
+	}
+
+	/// Write the music as a WAV file, like `dump_wav.c` does.
+	pub fn write_wav<W: Write>(&self, out: &mut W) -> io::Result<()> {
+		for word in wav_header() {
+			out.write_all(&word.to_le_bytes())?;
+		}
+		for sample in self.buffer() {
+			out.write_all(&sample.left.to_le_bytes())?;
+			out.write_all(&sample.right.to_le_bytes())?;
+		}
+		Ok(())
+	}
+
+	/// Write the music to a WAV file.
+	pub fn save_wav<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+		let mut out = BufWriter::new(File::create(path)?);
+		self.write_wav(&mut out)?;
+		out.flush()
+	}
+
+	/// Start playing the music. Only the first call has any effect.
+	#[cfg(windows)]
+	pub fn start(&self) {
+		START_MUSIC.call_once(|| unsafe { sys::Oidos_StartMusic() });
+	}
+
+	/// How much of the music has currently been played, in music ticks.
+	/// Use this as the timer for the visuals. Zero until the music is started.
+	#[cfg(windows)]
+	pub fn position(&self) -> f32 {
+		if START_MUSIC.is_completed() {
+			unsafe { sys::Oidos_GetPosition() }
+		} else {
+			0.0
+		}
+	}
+}
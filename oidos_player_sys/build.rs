@@ -0,0 +1,47 @@
+extern crate nasm_rs;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Environment variable giving the converted music file to include in the player
+const MUSIC_VAR: &str = "OIDOS_MUSIC";
+
+fn main() {
+	let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+	let player_dir = manifest_dir.join("../player");
+	let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+	// Relative paths are relative to this crate. Default is music.asm in the player directory.
+	let music = match env::var_os(MUSIC_VAR) {
+		Some(path) => manifest_dir.join(path),
+		None => player_dir.join("music.asm")
+	};
+	println!("cargo:rerun-if-env-changed={}", MUSIC_VAR);
+	println!("cargo:rerun-if-changed={}", music.display());
+
+	// Assemble a copy of the player with the chosen music, like easy_exe does
+	for file in &["oidos.asm", "random.asm", "platform.inc"] {
+		let source = player_dir.join(file);
+		println!("cargo:rerun-if-changed={}", source.display());
+		fs::copy(&source, out_dir.join(file)).unwrap_or_else(|e| panic!("{}: {}", source.display(), e));
+	}
+	fs::copy(&music, out_dir.join("music.asm")).unwrap_or_else(|e|
+		panic!("{}: {} (set {} to the converted music file)", music.display(), e, MUSIC_VAR));
+
+	let mut build = nasm_rs::Build::new();
+	build.file(out_dir.join("oidos.asm"));
+	build.file(out_dir.join("random.asm"));
+	build.include(&out_dir);
+
+	#[cfg(not(target_env = "msvc"))]
+	build.compile("liboidos.a");
+
+	#[cfg(target_env = "msvc")]
+	build.compile("oidos.lib");
+
+	println!("cargo:rustc-link-lib=static=oidos");
+	if env::var("CARGO_CFG_TARGET_OS").unwrap() == "windows" {
+		println!("cargo:rustc-link-lib=dylib=winmm");
+	}
+}
@@ -0,0 +1,54 @@
+// Raw bindings to the Oidos player. See player/oidos.h for documentation.
+#![allow(non_upper_case_globals)]
+#![no_std]
+
+/// Size of each dimension of the random data
+pub const NOISESIZE: usize = 64;
+
+/// Size of the WAV file header, in 32-bit words
+pub const WAV_HEADER_WORDS: usize = 11;
+
+/// A stereo sample of the music buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Sample {
+	pub left: i16,
+	pub right: i16
+}
+
+#[link(name = "oidos")]
+extern "C" {
+	/// Fill the block of random data used by Oidos.
+	/// Must be called before `Oidos_GenerateMusic`.
+	pub fn Oidos_FillRandomData();
+
+	/// Generate the whole music into the music buffer.
+	pub fn Oidos_GenerateMusic();
+
+	/// Play the music.
+	#[cfg(windows)]
+	pub fn Oidos_StartMusic();
+
+	/// How much of the music has currently been played, in music ticks.
+	#[cfg(windows)]
+	pub fn Oidos_GetPosition() -> f32;
+
+	/// Buffer containing the music. The length in bytes is `Oidos_WavFileHeader[10]`.
+	pub static mut Oidos_MusicBuffer: [Sample; 0];
+
+	/// The tick rate of the music.
+	pub static Oidos_TicksPerSecond: f32;
+
+	/// The length of the music in ticks.
+	pub static Oidos_MusicLength: u32;
+
+	/// WAV file header to write before the music buffer.
+	pub static Oidos_WavFileHeader: [u32; WAV_HEADER_WORDS];
+
+	/// Block of random data used by Oidos.
+	pub static mut Oidos_RandomData: [u32; NOISESIZE * NOISESIZE * NOISESIZE];
+}
+
+/// The converted music file the player is built with, for use with tools
+/// reading the music data, such as the sync API of the `OidosMusic` crate.
+pub const MUSIC_ASM: &str = include_str!(concat!(env!("OUT_DIR"), "/music.asm"));
@@ -38,6 +38,19 @@ extern PUBLIC_SYM(Oidos_RandomData)
 
 %define SAMPLE_RATE 44100
 %define BASE_FREQ 0.00232970791933 ; 440/((2^(1/12))^(9+12*4))/44100*(2*3.14159265358979)
+%define SWELL_RANDOM_OFFSET 0x10000
+
+; Number of 16-byte slots for each partial: step, state, filter, swell and formants
+%assign PARTIAL_SLOTS 3
+%ifdef USES_SWELL
+%assign PARTIAL_SLOTS PARTIAL_SLOTS+1
+%define FORMANT_OFFSET 32
+%else
+%define FORMANT_OFFSET 16
+%endif
+%ifdef USES_FORMANTS
+%assign PARTIAL_SLOTS PARTIAL_SLOTS+1
+%endif
 
 
 ;; ********** Public variables **********
@@ -100,7 +113,7 @@ WaveTime:
 SECTION_BSS(freqarr) align=16
 PartialArray:
 .align16:
-	resq	10000*3*2
+	resq	10000*PARTIAL_SLOTS*2
 
 SECTION_BSS(sampbuf) align=16
 SampleBuffer:
@@ -151,6 +164,36 @@ struc params
 %ifdef USES_PANNING
 	p_panning:		resd	1
 %endif
+%ifdef USES_ENVELOPE
+	p_releasecurve:	resd	1
+	p_attackcurve:	resd	1
+	p_decaybase:	resd	1
+	p_decay:		resd	1
+	p_decaycurve:	resd	1
+	p_sustain:		resd	1
+%endif
+%ifdef USES_KEYTRACK
+	p_trackpivot:	resd	1
+	p_decaytrack:	resd	1
+	p_filtertrack:	resd	1
+%endif
+%ifdef USES_SWELL
+	p_swell:		resd	1
+	p_swellslope:	resd	1
+	p_swellspread:	resd	1
+%endif
+%ifdef USES_FORMANTS
+	p_formant1:		resd	1
+	p_formant2:		resd	1
+	p_fslope1:		resd	1
+	p_fslope2:		resd	1
+	p_fgain1:		resd	1
+	p_fgain2:		resd	1
+	p_formantsweep:	resd	1
+%endif
+%ifdef USES_STIFFNESS
+	p_stiffness:	resd	1
+%endif
 
 
 ;; ********** Internal constants and tables **********
@@ -159,6 +202,11 @@ SECTION_DATA(base) align=16
 baseptr:
 
 c_oneone:		dq		1.0,1.0
+%ifdef USES_FORMANTS
+c_twotwo:		dq		2.0,2.0
+FormantAdd:		dq		0.0,0.0
+FormantGain:	dq		0.0,0.0
+%endif
 c_twelve:		dd		12
 c_randscale:	dd		0x30000000	; 2^-31
 c_ampmax:		dd		32767.0
@@ -179,6 +227,13 @@ TovelPtr:		dd	TrackData
 LengthPtr:		dd	NoteLengths
 NotePtr:		dd	NoteSamples
 MixingPtr:		dd	0
+%ifdef USES_TUNING
+TuningPtr:		dd	InstrumentTuning
+%endif
+%ifdef USES_KEYTRACK
+DecayScale:		dq	0.0
+FilterOffset:	dq	0.0
+%endif
 
 %if NUM_TRACKS_WITH_REVERB > 0
 ReverbState:
@@ -242,6 +297,29 @@ SECTION_TEXT(makeinst) align=1
 MakeInstrument:
 	mov				PARAMS, [BASE + ParamsPtr]
 
+%ifdef USES_KEYTRACK
+	; Key tracking relative to the pivot tone
+	fld				st0
+	fsub			dword [PARAMS + p_trackpivot]
+	fld				st0
+	fmul			dword [PARAMS + p_filtertrack]
+	fstp			qword [BASE + FilterOffset]
+	fmul			dword [PARAMS + p_decaytrack]
+	TONE2FREQ
+	fstp			qword [BASE + DecayScale]
+%endif
+
+%ifdef USES_FORMANTS
+	; Movement and gain of the formant peaks
+	cvtps2pd		xmm0, [PARAMS + p_fslope1]
+	cvtss2sd		xmm1, [PARAMS + p_formantsweep]
+	unpcklpd		xmm1, xmm1
+	mulpd			xmm0, xmm1
+	movapd			[BASE + FormantAdd], xmm0
+	cvtps2pd		xmm0, [PARAMS + p_fgain1]
+	movapd			[BASE + FormantGain], xmm0
+%endif
+
 	; tone
 
 	mov				ARRAY, PartialArray
@@ -273,6 +351,18 @@ MakeInstrument:
 	add				PARAMS, byte 4
 	fadd			dword [PARAMS]		; decaylow
 	add				PARAMS, byte 4
+%ifdef USES_KEYTRACK
+	; Raise decay to the power of the key tracking scale.
+	; Skipped without decay tracking, since the decay may be zero.
+	cmp				dword [PARAMS + p_decaytrack - p_harmonicity], byte 0
+	je				.untracked
+	fld				qword [BASE + DecayScale]
+	fxch			st1
+	fyl2x
+	fimul			dword [BASE + c_twelve]
+	TONE2FREQ
+.untracked:
+%endif
 %rep 12
 	fsqrt
 %endrep
@@ -286,6 +376,16 @@ MakeInstrument:
 	fmul			dword [PARAMS]		; harmonicity
 	add				PARAMS, byte 4
 	faddp			st1
+%ifdef USES_STIFFNESS
+	; Stretch like the overtones of a stiff string
+	fld				st0
+	fmul			st0, st0
+	fmul			dword [PARAMS + p_stiffness - p_sharpness]
+	fld1
+	faddp			st1
+	fsqrt
+	fmulp			st1
+%endif
 	FREQ2TONE
 	; reltone2, ampmul, tone
 
@@ -331,7 +431,48 @@ MakeInstrument:
 	fstp			qword [ARRAY]
 	add				ARRAY, byte 8
 
+%ifdef USES_SWELL
+	; Swell value, stored after the filter value
+	fld				st0
+	fmul			dword [PARAMS + p_swellslope - p_filterlow]
+	fild			dword [RANDOM + SWELL_RANDOM_OFFSET*4 - 8]
+	fmul			dword [BASE + c_randscale]
+	fmul			dword [PARAMS + p_swellspread - p_filterlow]
+	faddp			st1
+	fchs
+	TONE2FREQ
+	fmul			dword [PARAMS + p_swell - p_filterlow]
+	fstp			qword [ARRAY + 24]
+	; Partials start silent, or at full level without swell
+	fldz
+	fld1
+	cmp				dword [PARAMS + p_swell - p_filterlow], byte 0
+	fcmovne			st0, st1
+	fstp			qword [ARRAY + 16]
+	fstp			st0
+%endif
+
+%ifdef USES_FORMANTS
+	; Formant values, stored after the filter and swell values
+	fld				st0
+	fadd			st0, st5
+	fld				st0
+	fsub			dword [PARAMS + p_formant1 - p_filterlow]
+	fmul			dword [PARAMS + p_fslope1 - p_filterlow]
+	fld1
+	fsubrp			st1
+	fstp			qword [ARRAY + FORMANT_OFFSET]
+	fsub			dword [PARAMS + p_formant2 - p_filterlow]
+	fmul			dword [PARAMS + p_fslope2 - p_filterlow]
+	fld1
+	fsubrp			st1
+	fstp			qword [ARRAY + FORMANT_OFFSET + 8]
+%endif
+
 	; Filter value
+%ifdef USES_KEYTRACK
+	fadd			qword [BASE + FilterOffset]
+%endif
 	fld				st0
 	fsub			dword [PARAMS]		; filterlow
 	add				PARAMS, byte 8
@@ -350,6 +491,12 @@ MakeInstrument:
 	faddp			st1
 	fstp			qword [ARRAY]
 	add				ARRAY, byte 8
+%ifdef USES_SWELL
+	add				ARRAY, byte 16
+%endif
+%ifdef USES_FORMANTS
+	add				ARRAY, byte 16
+%endif
 
 	pop				PARAMS
 	loop			.partialloop
@@ -408,17 +555,52 @@ MakeInstrument:
 	pshufd			xmm3, xmm1, 0xEE
 	pshufd			xmm2, xmm1, 0x44
 	minpd			xmm2, xmm3
+%ifdef USES_SWELL
+	minsd			xmm2, [ARRAY + 16]	; swell
+%endif
 	xorpd			xmm3, xmm3
 	maxpd			xmm2, xmm3
 	minpd			xmm2, [BASE + c_oneone]
 
-	mulpd			xmm0, xmm2
-	addpd			xmm4, xmm0
-
 	addpd			xmm1, xmm7
 	movapd			[ARRAY], xmm1
 	add				ARRAY, byte 16
+%ifdef USES_SWELL
+	movapd			xmm3, [ARRAY]		; [swelladd, swell]
+	pshufd			xmm1, xmm3, 0xEE
+	addsd			xmm3, xmm1
+	movapd			[ARRAY], xmm3
+	add				ARRAY, byte 16
+%endif
+%ifdef USES_FORMANTS
+	; Formant peaks
+	movapd			xmm3, [ARRAY]		; [formant2, formant1]
+	movapd			xmm1, xmm3
+	addpd			xmm1, [BASE + FormantAdd]
+	movapd			[ARRAY], xmm1
+	add				ARRAY, byte 16
+	movapd			xmm1, [BASE + c_twotwo]
+	subpd			xmm1, xmm3
+	minpd			xmm3, xmm1
+	xorpd			xmm1, xmm1
+	maxpd			xmm3, xmm1
+	minpd			xmm3, [BASE + c_oneone]
+	mulpd			xmm3, [BASE + FormantGain]
+	addpd			xmm3, [BASE + c_oneone]
+	mulsd			xmm2, xmm3
+	unpckhpd		xmm3, xmm3
+	mulsd			xmm2, xmm3
+%endif
+
+	mulpd			xmm0, xmm2
+	addpd			xmm4, xmm0
+%ifdef USES_FORMANTS
+	; Too far for loop
+	dec				ecx
+	jne near		.decay
+%else
 	loop			.decay
+%endif
 
 	; Gain
 	movsd			xmm1, xmm6
@@ -430,6 +612,34 @@ MakeInstrument:
 	sqrtsd			xmm1, xmm1
 	divsd			xmm4, xmm1
 
+%ifdef USES_ENVELOPE
+	; Hold, decay and sustain
+	cvtsi2sd		xmm0, eax			; samples left
+	cvtss2sd		xmm1, [PARAMS + p_decay - p_maxsamples]
+	mulsd			xmm0, xmm1
+	cvtss2sd		xmm1, [PARAMS + p_decaybase - p_maxsamples]
+	addsd			xmm0, xmm1
+	xorpd			xmm1, xmm1
+	maxsd			xmm0, xmm1
+	minsd			xmm0, [BASE + c_oneone]
+
+	; Decay curve
+	cvtss2sd		xmm2, [PARAMS + p_decaycurve - p_maxsamples]
+	movsd			xmm1, xmm0
+	subsd			xmm1, [BASE + c_oneone]
+	mulsd			xmm1, xmm2
+	addsd			xmm1, [BASE + c_oneone]
+	mulsd			xmm0, xmm1
+
+	; Sustain level
+	cvtss2sd		xmm2, [PARAMS + p_sustain - p_maxsamples]
+	movsd			xmm1, [BASE + c_oneone]
+	subsd			xmm1, xmm2
+	mulsd			xmm0, xmm1
+	addsd			xmm0, xmm2
+	mulsd			xmm4, xmm0
+%endif
+
 	unpcklpd		xmm4, xmm4
 	movapd			[SAMPLE], xmm4
 	add				SAMPLE, byte 16
@@ -454,6 +664,12 @@ MakeChannel:
 	add				[esp], eax
 	js				.tonesdone
 	fild			dword [esp]
+%ifdef USES_TUNING
+	; Tuning offset of tone
+	mov				eax, [BASE + TuningPtr]
+	fadd			dword [eax]
+	add				dword [BASE + TuningPtr], byte 4
+%endif
 	call			MakeInstrument
 	jmp				.tonesloop
 .tonesdone:
@@ -533,6 +749,32 @@ MakeChannel:
 	mulpd			xmm5, xmm1
 %endif
 
+%ifdef USES_ENVELOPE
+	; Attack/release curves
+	cvtps2pd		xmm6, [PARAMS]		; releasecurve, attackcurve
+	add				PARAMS, byte p_sustain + 4 - p_releasecurve
+%endif
+
+%ifdef USES_KEYTRACK
+	; Key tracking is included in the sound
+	add				PARAMS, byte p_filtertrack + 4 - p_trackpivot
+%endif
+
+%ifdef USES_SWELL
+	; Swell is included in the sound
+	add				PARAMS, byte p_swellspread + 4 - p_swell
+%endif
+
+%ifdef USES_FORMANTS
+	; Formants are included in the sound
+	add				PARAMS, byte p_formantsweep + 4 - p_formant1
+%endif
+
+%ifdef USES_STIFFNESS
+	; Stiffness is included in the sound
+	add				PARAMS, byte 4
+%endif
+
 	; Find sample
 	movzx			SAMPLESRC, byte [TOVEL+eax*2+1]
 	imul			SAMPLESRC, ecx
@@ -552,6 +794,7 @@ MakeChannel:
 
 	; XMM1 = Attack/release state
 	; XMM5 = Volume
+	; XMM6 = Attack/release curves
 	; XMM7 = Attack/release add
 
 .mixingloop:
@@ -561,12 +804,26 @@ MakeChannel:
 
 	mulpd			xmm0, xmm5			; Volume
 
+%ifdef USES_ENVELOPE
+	xorpd			xmm3, xmm3
+	maxpd			xmm3, xmm1
+	minpd			xmm3, [BASE + c_oneone]
+	movapd			xmm2, xmm3
+	subpd			xmm2, [BASE + c_oneone]
+	mulpd			xmm2, xmm6
+	addpd			xmm2, [BASE + c_oneone]
+	mulpd			xmm3, xmm2			; Curved release and attack
+	pshufd			xmm2, xmm3, 0x44
+	pshufd			xmm3, xmm3, 0xEE
+	minpd			xmm2, xmm3
+%else
 	pshufd			xmm3, xmm1, 0xEE
 	pshufd			xmm2, xmm1, 0x44
 	minpd			xmm2, xmm3
 	xorpd			xmm3, xmm3
 	maxpd			xmm2, xmm3
 	minpd			xmm2, [BASE + c_oneone]
+%endif
 
 	mulpd			xmm0, xmm2			; Attack and release
 	addpd			xmm4, xmm0
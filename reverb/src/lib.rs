@@ -1,13 +1,14 @@
 // We want the DLL to be called OidosReverb
 #![allow(non_snake_case)]
 
-#[macro_use]
+#[cfg_attr(feature = "plugin", macro_use)]
 extern crate vst;
 
 use std::cmp::Ordering;
 use std::sync::Arc;
 
 use vst::buffer::AudioBuffer;
+use vst::host::HostBuffer;
 use vst::plugin::{Category, Info, Plugin, PluginParameters};
 use vst::util::ParameterTransfer;
 
@@ -17,6 +18,21 @@ const NBUFS: usize = 200;
 const NOISESIZE: usize = 64;
 const NPARAMS: usize = 20;
 
+pub const PARAMETER_NAMES: [&str; NPARAMS] = [
+	"mix", "pan", "delaymin", "delaymax", "delayadd",
+	"halftime", "filterlow", "filterhigh", "dampenlow", "dampenhigh",
+	"n", "seed", "-", "--", "---",
+	"q_mixpan", "q_flow", "q_fhigh", "q_dlow", "q_dhigh"
+];
+pub const DEFAULT_VALUES: [f32; NPARAMS] = [
+	0.1,  0.5,  0.07, 0.13, 0.0,
+	0.5,  0.1,  0.6,  0.1,  0.7,
+	0.32, 0.32, 0.0,  0.0,  0.0,
+	0.0,  0.0,  0.0,  0.0,  0.0
+];
+/// Indices of the quantization parameters
+pub const QUANTIZATION_PARAMETERS: std::ops::Range<usize> = 15..20;
+
 pub struct OidosRandomData {
 	data: Vec<u32>
 }
@@ -68,6 +84,130 @@ fn quantize(value: f32, level: f32) -> f32 {
 }
 
 
+fn parse_number(text: &str) -> Option<f32> {
+	let word = text.split_whitespace().next()?;
+	word.parse::<f32>().ok()
+		.or_else(|| word.trim_end_matches(|c: char| c.is_alphabetic()).parse::<f32>().ok())
+		.filter(|v| !v.is_nan())
+}
+
+fn parse_pan(text: &str) -> Option<f32> {
+	let words: Vec<&str> = text.split_whitespace().collect();
+	match words.as_slice() {
+		[center] if center.eq_ignore_ascii_case("center") || center.eq_ignore_ascii_case("c") => Some(0.5),
+		[amount, side] => {
+			let amount = amount.parse::<f32>().ok()? / 100.0;
+			match side.to_ascii_uppercase().as_str() {
+				"L" => Some(0.5 - amount),
+				"R" => Some(0.5 + amount),
+				_ => None
+			}
+		},
+		_ => parse_number(text)
+	}
+}
+
+// Mix and optional pan shown by q_mixpan, or the number shown by another quantization parameter
+fn parse_quantized_text(text: &str) -> Option<(f32, Option<f32>)> {
+	let mut words = text.split_whitespace();
+	let number = parse_number(words.next()?)?;
+	let rest = words.collect::<Vec<_>>().join(" ");
+	Some((number, if rest.is_empty() { None } else { Some(parse_pan(&rest)?) }))
+}
+
+// Quantization parameters show the quantized value of their parameter. A typed value picks
+// the level showing the nearest value, the coarsest one if several are equally near.
+// The number of bits to round off can be typed as `12 bits`.
+fn parse_quantization_text(index: usize, text: &str, param_values: &[f32; NPARAMS]) -> Option<f32> {
+	let level = |bits: f32| ((bits.round() + 0.5) / 31.0).clamp(0.0, 1.0);
+	let trimmed = text.trim();
+	if let Some(bits) = trimmed.strip_suffix("bits").or_else(|| trimmed.strip_suffix("bit")) {
+		return parse_number(bits).map(level);
+	}
+	let (number, pan) = parse_quantized_text(text)?;
+	let mut values = *param_values;
+	let mut nearest: Option<(f32, f32)> = None;
+	for bits in 0..32 {
+		values[index] = level(bits as f32);
+		let (shown_number, shown_pan) = match parse_quantized_text(&parameter_text(index, &values)) {
+			Some(shown) => shown,
+			None => continue
+		};
+		let pan_distance = match (pan, shown_pan) {
+			(Some(pan), Some(shown_pan)) => (shown_pan - pan).abs(),
+			_ => 0.0
+		};
+		let distance = (shown_number - number).abs() + pan_distance;
+		if nearest.is_none_or(|(d, _)| distance <= d) {
+			nearest = Some((distance, values[index]));
+		}
+	}
+	nearest.map(|(_, value)| value)
+}
+
+// Inverse of get_parameter_text
+fn parse_parameter_text(index: usize, text: &str, param_values: &[f32; NPARAMS]) -> Option<f32> {
+	let value = match index {
+		0/* mix */              => parse_number(text)? / 10.0,
+		1/* pan */              => parse_pan(text)?,
+		2 ..= 4/* delays */     => (parse_number(text)? / 1000.0 / DELAY_STEP).round() / 100.0,
+		5/* halftime */         => parse_number(text)?,
+		6 ..= 9/* filters */    => parse_number(text)?.max(0.0).sqrt(),
+		10/* n */               => (parse_number(text)? / 2.0).round() / 100.0,
+		11/* seed */            => parse_number(text)?.round() / 100.0,
+		15 ..= 19/* q params */ => parse_quantization_text(index, text, param_values)?,
+		_ => return None
+	};
+	Some(value.clamp(0.0, 1.0))
+}
+
+#[test]
+fn test_parse_parameter_text() {
+	let parse = |index, text| parse_parameter_text(index, text, &DEFAULT_VALUES);
+	assert_eq!(parse(0, "1.0"), Some(0.1));
+	assert_eq!(parse(1, "Center"), Some(0.5));
+	assert_eq!(parse(1, "20 L"), Some(0.3));
+	assert_eq!(parse(1, "20 R"), Some(0.7));
+	assert_eq!(p100(parse(2, "41 ms").unwrap()), 7);
+	assert_eq!(parse(6, "0.25"), Some(0.5));
+	assert_eq!(p100(parse(10, "200").unwrap()), 100);
+	assert_eq!(p100(parse(11, "42").unwrap()), 42);
+	assert_eq!(((parse(16, "12 bits").unwrap() * 31.0).floor()), 12.0);
+	assert_eq!(parse(12, "1"), None);
+	assert_eq!(parse(0, "loud"), None);
+	assert_eq!(parse(15, "loud"), None);
+}
+
+#[test]
+fn test_parameter_text_roundtrip() {
+	let plugin = OidosReverbPlugin::default();
+	let params = &plugin.param_transfer;
+	for index in (0..NPARAMS).filter(|&i| !PARAMETER_NAMES[i].starts_with('-')) {
+		for &value in &[DEFAULT_VALUES[index], 0.13, 0.5, 0.71] {
+			// Parsing a displayed value gives a value with the same display
+			params.set_parameter(index as i32, value);
+			let text = params.get_parameter_text(index as i32);
+			assert!(params.string_to_parameter(index as i32, text.clone()), "{} {}", PARAMETER_NAMES[index], text);
+			assert_eq!(params.get_parameter_text(index as i32), text, "{} {}", PARAMETER_NAMES[index], value);
+		}
+		params.set_parameter(index as i32, DEFAULT_VALUES[index]);
+	}
+}
+
+#[test]
+fn test_parse_quantization_text() {
+	// A typed value picks the coarsest level showing the nearest value
+	let mut values = DEFAULT_VALUES;
+	values[6] = 0.3;
+	let unquantized = parameter_text(16, &values);
+	values[16] = parse_parameter_text(16, "20 bits", &values).unwrap();
+	let text = parameter_text(16, &values);
+	assert_ne!(text, unquantized);
+	values[16] = parse_parameter_text(16, &text, &values).unwrap();
+	assert_eq!(parameter_text(16, &values), text);
+	assert!((values[16] * 31.0).floor() >= 20.0);
+}
+
 struct OidosReverbParameters {
 	nbufs: usize,
 	delaymin: usize,
@@ -144,12 +284,7 @@ struct OidosReverbParameterTransfer {
 
 impl Default for OidosReverbPlugin {
 	fn default() -> OidosReverbPlugin {
-		let param_values = vec![
-			0.1,  0.5,  0.07, 0.13, 0.0,
-			0.5,  0.1,  0.6,  0.1,  0.7,
-			0.32, 0.32, 0.0,  0.0,  0.0,
-			0.0,  0.0,  0.0,  0.0,  0.0
-		];
+		let param_values = DEFAULT_VALUES.to_vec();
 		let param_transfer = Arc::new(OidosReverbParameterTransfer {
 			transfer: ParameterTransfer::new(NPARAMS),
 		});
@@ -177,12 +312,7 @@ impl Default for OidosReverbPlugin {
 
 impl PluginParameters for OidosReverbParameterTransfer {
 	fn get_parameter_name(&self, index: i32) -> String {
-		[
-			"mix", "pan", "delaymin", "delaymax", "delayadd",
-			"halftime", "filterlow", "filterhigh", "dampenlow", "dampenhigh",
-			"n", "seed", "-", "--", "---",
-			"q_mixpan", "q_flow", "q_fhigh", "q_dlow", "q_dhigh"
-		][index as usize].to_string()
+		PARAMETER_NAMES[index as usize].to_string()
 	}
 
 	fn get_parameter(&self, index: i32) -> f32 {
@@ -194,45 +324,16 @@ impl PluginParameters for OidosReverbParameterTransfer {
 	}
 
 	fn get_parameter_text(&self, index: i32) -> String {
-		let pantext = |pan: f32| -> String {
-			match pan.partial_cmp(&0.5) {
-				Some(Ordering::Equal)   => format!("Center"),
-				Some(Ordering::Less)    => format!("{:.0} L", (0.5 - pan) * 100.0),
-				Some(Ordering::Greater) => format!("{:.0} R", (pan - 0.5) * 100.0),
-				None                    => format!("?")
-			}
-		};
+		parameter_text(index as usize, &self.parameter_values())
+	}
 
-		let mut param_values = [0f32; NPARAMS];
-		for index in 0..NPARAMS {
-			param_values[index] = self.get_parameter(index as i32);
-		}
-		let p = OidosReverbParameters::make(&param_values);
-		match index {
-			0/* mix */        => format!("{:.1}", param_values[0] * 10.0),
-			1/* pan */        => pantext(param_values[1]),
-			2/* delaymin */   => format!("{:.0}", 1000.0 * (p.delaymin as f32 / BASE_SAMPLE_RATE)),
-			3/* delaymax */   => format!("{:.0}", 1000.0 * (p.delaymax as f32 / BASE_SAMPLE_RATE)),
-			4/* delayadd */   => format!("{:.0}", 1000.0 * (p.delayadd as f32 / BASE_SAMPLE_RATE)),
-			5/* halftime */   => format!("{:.2}", param_values[5]),
-			6/* filterlow */  => format!("{:.4}", param_values[6].powi(2)),
-			7/* filterhigh */ => format!("{:.4}", param_values[7].powi(2)),
-			8/* dampenlow */  => format!("{:.4}", param_values[8].powi(2)),
-			9/* dampenhigh */ => format!("{:.4}", param_values[9].powi(2)),
-			10/* n */         => format!("{}", p.nbufs),
-			11/* seed */      => format!("{}", p.seed / 2048),
-
-			15/* q_mixpan */  => {
-				let mix = ((p.volumes[0].powi(2) + p.volumes[1].powi(2)) / 2.0 * p.nbufs as f32).sqrt();
-				let pan = 1.0 / ((p.volumes[0] / p.volumes[1]).powi(2) + 1.0);
-				format!("{:.1} {}", mix, pantext(pan))
+	fn string_to_parameter(&self, index: i32, text: String) -> bool {
+		match parse_parameter_text(index as usize, &text, &self.parameter_values()) {
+			Some(value) => {
+				self.set_parameter(index, value);
+				true
 			},
-			16/* q_flow */    => format!("{:.4}", p.filterlow),
-			17/* q_fhigh */   => format!("{:.4}", p.filterhigh),
-			18/* q_dlow */    => format!("{:.4}", p.dampenlow),
-			19/* q_dhigh */   => format!("{:.4}", p.dampenhigh),
-
-			_ => format!("-")
+			None => false
 		}
 	}
 
@@ -245,6 +346,55 @@ impl PluginParameters for OidosReverbParameterTransfer {
 	}
 }
 
+impl OidosReverbParameterTransfer {
+	fn parameter_values(&self) -> [f32; NPARAMS] {
+		let mut param_values = [0f32; NPARAMS];
+		for index in 0..NPARAMS {
+			param_values[index] = self.get_parameter(index as i32);
+		}
+		param_values
+	}
+}
+
+fn parameter_text(index: usize, param_values: &[f32; NPARAMS]) -> String {
+	let pantext = |pan: f32| -> String {
+		match pan.partial_cmp(&0.5) {
+			Some(Ordering::Equal)   => format!("Center"),
+			Some(Ordering::Less)    => format!("{:.0} L", (0.5 - pan) * 100.0),
+			Some(Ordering::Greater) => format!("{:.0} R", (pan - 0.5) * 100.0),
+			None                    => format!("?")
+		}
+	};
+
+	let p = OidosReverbParameters::make(param_values);
+	match index {
+		0/* mix */        => format!("{:.1}", param_values[0] * 10.0),
+		1/* pan */        => pantext(param_values[1]),
+		2/* delaymin */   => format!("{:.0}", 1000.0 * (p.delaymin as f32 / BASE_SAMPLE_RATE)),
+		3/* delaymax */   => format!("{:.0}", 1000.0 * (p.delaymax as f32 / BASE_SAMPLE_RATE)),
+		4/* delayadd */   => format!("{:.0}", 1000.0 * (p.delayadd as f32 / BASE_SAMPLE_RATE)),
+		5/* halftime */   => format!("{:.2}", param_values[5]),
+		6/* filterlow */  => format!("{:.4}", param_values[6].powi(2)),
+		7/* filterhigh */ => format!("{:.4}", param_values[7].powi(2)),
+		8/* dampenlow */  => format!("{:.4}", param_values[8].powi(2)),
+		9/* dampenhigh */ => format!("{:.4}", param_values[9].powi(2)),
+		10/* n */         => format!("{}", p.nbufs),
+		11/* seed */      => format!("{}", p.seed / 2048),
+
+		15/* q_mixpan */  => {
+			let mix = ((p.volumes[0].powi(2) + p.volumes[1].powi(2)) / 2.0 * p.nbufs as f32).sqrt();
+			let pan = 1.0 / ((p.volumes[0] / p.volumes[1]).powi(2) + 1.0);
+			format!("{:.1} {}", mix, pantext(pan))
+		},
+		16/* q_flow */    => format!("{:.4}", p.filterlow),
+		17/* q_fhigh */   => format!("{:.4}", p.filterhigh),
+		18/* q_dlow */    => format!("{:.4}", p.dampenlow),
+		19/* q_dhigh */   => format!("{:.4}", p.dampenhigh),
+
+		_ => format!("-")
+	}
+}
+
 impl Plugin for OidosReverbPlugin {
 	fn get_info(&self) -> Info {
 		Info {
@@ -311,7 +461,7 @@ impl Plugin for OidosReverbPlugin {
 				let c = b & 1;
 				for i in 0..size {
 					// Extract delayed signal
-					let out_index = (self.buffer_index + i - scaled_delay - scaled_delayadd) & (self.buffer_size - 1);
+					let out_index = (self.buffer_index + i).wrapping_sub(scaled_delay + scaled_delayadd) & (self.buffer_size - 1);
 					let out = self.delay_buffers[b][out_index];
 					outputs[c][i] += out as f32 * p.volumes[c];
 
@@ -322,7 +472,7 @@ impl Plugin for OidosReverbPlugin {
 					let f_input = f_input_high - f_input_low;
 
 					// Filter echo
-					let echo_index = (self.buffer_index + i - scaled_delay) & (self.buffer_size - 1);
+					let echo_index = (self.buffer_index + i).wrapping_sub(scaled_delay) & (self.buffer_size - 1);
 					let echo = self.delay_buffers[b][echo_index];
 					let f_echo_low = filter(&mut self.dhstate[b], echo, scaled_dampenlow);
 					let f_echo_high = filter(&mut self.dlstate[b], echo, scaled_dampenhigh);
@@ -343,10 +493,80 @@ impl Plugin for OidosReverbPlugin {
 	}
 }
 
+/// Render the response of the reverb to an impulse in both channels, including
+/// the dry signal, for parameter values as used by the plugin.
+pub fn render_impulse_response(values: &[f32], length: usize) -> Vec<[f32; 2]> {
+	const BLOCK_SIZE: usize = 1024;
+	let mut plugin = OidosReverbPlugin::default();
+	for (index, &value) in values.iter().enumerate() {
+		plugin.param_transfer.set_parameter(index as i32, value);
+	}
+
+	let mut response = Vec::with_capacity(length);
+	let mut host_buffer = HostBuffer::new(2, 2);
+	let mut input = vec![0f32; BLOCK_SIZE];
+	input[0] = 1.0;
+	while response.len() < length {
+		let mut left = vec![0f32; BLOCK_SIZE];
+		let mut right = vec![0f32; BLOCK_SIZE];
+		{
+			let mut buffer = host_buffer.bind(&[&input, &input], &mut [&mut left, &mut right]);
+			plugin.process(&mut buffer);
+		}
+		response.extend(left.iter().zip(&right).map(|(&l, &r)| [l, r]).take(length - response.len()));
+		input[0] = 0.0;
+	}
+	response
+}
+
+/// Parse a patch with one `name value` line per parameter, as written by `format_patch`.
+/// Parameters not mentioned get their default values. Text after `#` is ignored.
+pub fn parse_patch(text: &str) -> Result<Vec<f32>, String> {
+	let mut values = DEFAULT_VALUES.to_vec();
+	for (line_index, line) in text.lines().enumerate() {
+		let line = line.split('#').next().unwrap().trim();
+		if line.is_empty() {
+			continue;
+		}
+		let mut words = line.split_whitespace();
+		let name = words.next().unwrap();
+		let index = match PARAMETER_NAMES.iter().position(|&n| n == name) {
+			Some(index) => index,
+			None => return Err(format!("Line {}: Unknown parameter '{}'", line_index + 1, name))
+		};
+		let value = match (words.next().map(|w| w.parse::<f32>()), words.next()) {
+			(Some(Ok(value)), None) if (0.0..=1.0).contains(&value) => value,
+			_ => return Err(format!("Line {}: Expected a single number from 0 to 1 after '{}'", line_index + 1, name))
+		};
+		values[index] = value;
+	}
+	Ok(values)
+}
+
+/// Write parameter values as a patch text, skipping the unused parameters.
+pub fn format_patch(values: &[f32]) -> String {
+	let mut text = String::new();
+	for (name, value) in PARAMETER_NAMES.iter().zip(values) {
+		if !name.starts_with('-') {
+			text += &format!("{} {}\n", name, value);
+		}
+	}
+	text
+}
+
+#[test]
+fn test_patch_roundtrip() {
+	let values: Vec<f32> = (0..NPARAMS).map(|i| if PARAMETER_NAMES[i].starts_with('-') { 0.0 } else { i as f32 / 32.0 }).collect();
+	assert_eq!(parse_patch(&format_patch(&values)), Ok(values));
+	assert_eq!(parse_patch("").unwrap(), DEFAULT_VALUES.to_vec());
+	assert!(parse_patch("mix 1.5").is_err());
+}
+
 fn filter(state: &mut f64, value: f64, strength: f32) -> f64 {
 	let filtered = *state + (value - *state) * strength as f64;
 	*state = filtered;
 	filtered
 }
 
+#[cfg(feature = "plugin")]
 plugin_main!(OidosReverbPlugin);
@@ -1,35 +1,48 @@
+use oidos_player::Music;
 
-use std::io::{stdout, Write};
-use std::thread::sleep;
-use std::time::Duration;
+#[cfg(windows)]
+fn play(music: &Music) {
+	use std::io::{stdout, Write};
+	use std::thread::sleep;
+	use std::time::Duration;
+	use OidosMusic::asm::parse_music;
+	use OidosMusic::player::decode_music;
+	use OidosMusic::sync::MusicSync;
 
-#[link(name = "oidos")]
-extern "C" {
-	fn Oidos_FillRandomData();
-	fn Oidos_GenerateMusic();
-	fn Oidos_StartMusic();
-	fn Oidos_GetPosition() -> f32;
-	static Oidos_TicksPerSecond: f32;
-	static Oidos_MusicLength: u32;
-}
+	// Show which instruments are playing, using the music data the player is built with
+	let data = decode_music(&parse_music(oidos_player::MUSIC_ASM).unwrap()).unwrap();
+	let sync = MusicSync::new(&data);
 
-fn main() {
-    println!("Calculating music...");
-	unsafe {
-		Oidos_FillRandomData();
-		Oidos_GenerateMusic();
-		Oidos_StartMusic();
-	}
-    println!();
-	let length = unsafe { Oidos_MusicLength as f32 / Oidos_TicksPerSecond } as u32;
+	music.start();
+	let ticks_per_second = oidos_player::ticks_per_second();
+	let length = music.duration() as u32;
 	loop {
-		let time = unsafe { Oidos_GetPosition() / Oidos_TicksPerSecond };
-		let seconds = time.floor() as u32;
+		let position = music.position();
+		let seconds = (position / ticks_per_second).floor() as u32;
 		if seconds > length { break; }
-		print!("\rPlaying {}:{:02} / {}:{:02}",
-			seconds / 60, seconds % 60, length / 60, length % 60);
+		let playing: String = (0..data.instruments.len())
+			.map(|i| if sync.envelope_level(i, position) > 0.0 { '#' } else { '.' })
+			.collect();
+		print!("\rPlaying {}:{:02} / {}:{:02} {}",
+			seconds / 60, seconds % 60, length / 60, length % 60, playing);
 		stdout().flush().ok();
-		sleep(Duration::from_millis(100));
+		sleep(Duration::from_millis(20));
 	}
-    println!();
+	println!();
+}
+
+// The player has no playback on other platforms, so write the music to a file.
+#[cfg(not(windows))]
+fn play(music: &Music) {
+	match music.save_wav("music.wav") {
+		Ok(()) => println!("Wrote music.wav"),
+		Err(e) => eprintln!("Error writing music.wav: {}", e)
+	}
+}
+
+fn main() {
+	println!("Calculating music...");
+	let music = Music::generate();
+	println!();
+	play(&music);
 }
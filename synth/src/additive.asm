@@ -30,6 +30,7 @@ global _additive_core_avx
 section .rodata align=32
 c_zero:		dq	0.0, 0.0, 0.0, 0.0
 c_one:		dq	1.0, 1.0, 1.0, 1.0
+c_two:		dq	2.0, 2.0, 2.0, 2.0
 
 
 ; Register assignment
@@ -39,7 +40,72 @@ c_one:		dq	1.0, 1.0, 1.0, 1.0
 %define STEP_IM r(cx)
 %define FILTER_LOW r(ax)
 %define FILTER_HIGH r(bx)
-%define COUNT r(bp)
+%define SHAPE r(bp)
+
+; Stack locations of the count argument, which is replaced by the end of the
+; partials to free a register, and the shape argument
+%if __BITS__ == 32
+%define COUNT_ARG [esp + 5*4 + 6*4 + 2*8 + 0*4]
+%define SHAPE_ARG [esp + 5*4 + 6*4 + 2*8 + 1*4]
+%define STATE_END COUNT_ARG
+%elif WINDOWS
+%define COUNT_ARG [rsp + 5*8 + 32 + 2*8 + 2*8 + 0*8]
+%define SHAPE_ARG [rsp + 5*8 + 32 + 2*8 + 2*8 + 1*8]
+%define STATE_END [rsp + 2*16 + 5*8 + 32 + 2*8 + 2*8 + 0*8]
+%else
+%define COUNT_ARG [rsp + 5*8 + 0*8]
+%define SHAPE_ARG [rsp + 5*8 + 1*8]
+%define STATE_END COUNT_ARG
+%endif
+
+; Layout of the shape block for each pair of partials, two values per entry
+%define SHAPE_LEVEL 0*16
+%define SHAPE_ADD 1*16
+%define SHAPE_FORMANT1 2*16
+%define SHAPE_FORMANT2 5*16
+%define SHAPE_SIZE 8*16
+
+; Formant peak offsets of the ramp, its increase and the gain minus one
+%define FORMANT_RAMP 0*16
+%define FORMANT_ADD 1*16
+%define FORMANT_GAIN 2*16
+
+; Multiply oscillator by the formant peak at the given shape offset
+%macro FORMANT_SSE2 1
+	movupd			xmm4, [SHAPE + %1 + FORMANT_RAMP]
+	movupd			xmm5, [SHAPE + %1 + FORMANT_ADD]
+	addpd			xmm5, xmm4
+	movupd			[SHAPE + %1 + FORMANT_RAMP], xmm5
+	movapd			xmm5, [c_two]
+	subpd			xmm5, xmm4
+	minpd			xmm4, xmm5
+	maxpd			xmm4, [c_zero]
+	minpd			xmm4, [c_one]
+	movupd			xmm5, [SHAPE + %1 + FORMANT_GAIN]
+	mulpd			xmm4, xmm5
+	addpd			xmm4, [c_one]
+	mulpd			xmm2, xmm4
+%endmacro
+
+%macro FORMANT_AVX 1
+	vmovupd			xmm4, [SHAPE + %1 + FORMANT_RAMP]
+	vinsertf128		ymm4, ymm4, [SHAPE + SHAPE_SIZE + %1 + FORMANT_RAMP], 1
+	vmovupd			xmm5, [SHAPE + %1 + FORMANT_ADD]
+	vinsertf128		ymm5, ymm5, [SHAPE + SHAPE_SIZE + %1 + FORMANT_ADD], 1
+	vaddpd			ymm5, ymm5, ymm4
+	vmovupd			[SHAPE + %1 + FORMANT_RAMP], xmm5
+	vextractf128	[SHAPE + SHAPE_SIZE + %1 + FORMANT_RAMP], ymm5, 1
+	vmovapd			ymm5, [c_two]
+	vsubpd			ymm5, ymm5, ymm4
+	vminpd			ymm4, ymm4, ymm5
+	vmaxpd			ymm4, ymm4, [c_zero]
+	vminpd			ymm4, ymm4, [c_one]
+	vmovupd			xmm5, [SHAPE + %1 + FORMANT_GAIN]
+	vinsertf128		ymm5, ymm5, [SHAPE + SHAPE_SIZE + %1 + FORMANT_GAIN], 1
+	vmulpd			ymm4, ymm4, ymm5
+	vaddpd			ymm4, ymm4, [c_one]
+	vmulpd			ymm2, ymm2, ymm4
+%endmacro
 
 ; Argument to ENTRY and EXIT macros to specify encoding
 %define LEGACY(i) i
@@ -63,8 +129,6 @@ c_one:		dq	1.0, 1.0, 1.0, 1.0
 
 	%1(movsd)		xmm0,        [esp + 5*4 + 6*4 + 0*8]
 	%1(movsd)		xmm1,        [esp + 5*4 + 6*4 + 1*8]
-
-	mov				COUNT,       [esp + 5*4 + 6*4 + 2*8 + 0*4]
 %elif WINDOWS
 	mov				STATE_RE,    rcx
 	mov				STATE_IM,    rdx
@@ -75,15 +139,17 @@ c_one:		dq	1.0, 1.0, 1.0, 1.0
 
 	%1(movsd)		xmm0,        [rsp + 5*8 + 32 + 2*8 + 0*8]
 	%1(movsd)		xmm1,        [rsp + 5*8 + 32 + 2*8 + 1*8]
-
-	mov				COUNT,       [rsp + 5*8 + 32 + 2*8 + 2*8 + 0*8]
 %else
 	mov				FILTER_LOW,  r8
 	mov				FILTER_HIGH, r9
-
-	mov				COUNT,       [rsp + 5*8 + 0*8]
 %endif
 
+	; Replace count by end of partials
+	mov				SHAPE,       COUNT_ARG
+	lea				SHAPE,       [STATE_RE + SHAPE*8]
+	mov				COUNT_ARG,   SHAPE
+	mov				SHAPE,       SHAPE_ARG
+
 %if WINDOWS && __BITS__ == 64
 	; Save float registers
 	sub				rsp, 2*16
@@ -179,9 +245,20 @@ _additive_core_sse2:
 	addpd			xmm5, xmm7
 	movupd			[FILTER_LOW], xmm4
 	movupd			[FILTER_HIGH], xmm5
+
+	; Update swell
+	movupd			xmm4, [SHAPE + SHAPE_LEVEL]
+	movupd			xmm5, [SHAPE + SHAPE_ADD]
+	minpd			xmm3, xmm4
+	addpd			xmm4, xmm5
+	movupd			[SHAPE + SHAPE_LEVEL], xmm4
 	maxpd			xmm3, [c_zero]
 	minpd			xmm3, [c_one]
 
+	; Apply formants
+	FORMANT_SSE2	SHAPE_FORMANT1
+	FORMANT_SSE2	SHAPE_FORMANT2
+
 	; Accumulate filtered oscillator
 	mulpd			xmm2, xmm3
 	addpd			xmm0, xmm2
@@ -193,9 +270,10 @@ _additive_core_sse2:
 	add				STEP_IM, 16
 	add				FILTER_LOW, 16
 	add				FILTER_HIGH, 16
+	add				SHAPE, SHAPE_SIZE
 
-	sub				COUNT, 2
-	ja				.loop
+	cmp				STATE_RE, STATE_END
+	jb				.loop
 
 	; Final summation
 	movapd			xmm1, xmm0
@@ -240,9 +318,23 @@ _additive_core_avx:
 	vaddpd			ymm5, ymm5, ymm7
 	vmovupd			[FILTER_LOW], ymm4
 	vmovupd			[FILTER_HIGH], ymm5
+
+	; Update swell
+	vmovupd			xmm4, [SHAPE + SHAPE_LEVEL]
+	vinsertf128		ymm4, ymm4, [SHAPE + SHAPE_SIZE + SHAPE_LEVEL], 1
+	vmovupd			xmm5, [SHAPE + SHAPE_ADD]
+	vinsertf128		ymm5, ymm5, [SHAPE + SHAPE_SIZE + SHAPE_ADD], 1
+	vminpd			ymm3, ymm3, ymm4
+	vaddpd			ymm4, ymm4, ymm5
+	vmovupd			[SHAPE + SHAPE_LEVEL], xmm4
+	vextractf128	[SHAPE + SHAPE_SIZE + SHAPE_LEVEL], ymm4, 1
 	vmaxpd			ymm3, ymm3, [c_zero]
 	vminpd			ymm3, ymm3, [c_one]
 
+	; Apply formants
+	FORMANT_AVX		SHAPE_FORMANT1
+	FORMANT_AVX		SHAPE_FORMANT2
+
 	; Accumulate filtered oscillator
 	vmulpd			ymm2, ymm2, ymm3
 	vaddpd			ymm0, ymm0, ymm2
@@ -254,9 +346,10 @@ _additive_core_avx:
 	add				STEP_IM, 32
 	add				FILTER_LOW, 32
 	add				FILTER_HIGH, 32
+	add				SHAPE, 2*SHAPE_SIZE
 
-	sub				COUNT, 4
-	ja				.loop
+	cmp				STATE_RE, STATE_END
+	jb				.loop
 
 	; Final summation
 	vextractf128	xmm1, ymm0, 1
@@ -0,0 +1,58 @@
+// Export music from a MIDI file and saved plugin states to a player music file
+#![allow(non_snake_case)]
+
+extern crate Oidos;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::exit;
+
+use Oidos::export::{export_music, parse_description, parse_instrument_state, parse_plugin_state};
+use Oidos::midi::parse_midi;
+use Oidos::oidos_player::{REVERB_DEFAULT_VALUES, REVERB_PARAMETER_NAMES};
+
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+	let mut data = Vec::new();
+	File::open(path).and_then(|mut f| f.read_to_end(&mut data)).map_err(|e| format!("{}: {}", path.display(), e))?;
+	Ok(data)
+}
+
+fn run(input: &str, output: &str) -> Result<(), String> {
+	let text = String::from_utf8(read_file(Path::new(input))?).map_err(|e| e.to_string())?;
+	let description = parse_description(&text).map_err(|e| format!("{}: {}", input, e))?;
+	// Files are relative to the description
+	let dir = Path::new(input).parent().unwrap_or(Path::new(""));
+
+	let midi = parse_midi(&read_file(&dir.join(&description.midi))?).map_err(|e| format!("{}: {}", description.midi, e))?;
+	let mut instruments = Vec::new();
+	for instr in &description.instruments {
+		let state = parse_instrument_state(&read_file(&dir.join(&instr.state))?).map_err(|e| format!("{}: {}", instr.state, e))?;
+		instruments.push(state);
+	}
+	let reverb = match description.reverb {
+		Some(ref file) => Some(parse_plugin_state(&read_file(&dir.join(file))?, &REVERB_PARAMETER_NAMES, &REVERB_DEFAULT_VALUES)
+			.map_err(|e| format!("{}: {}", file, e))?),
+		None => None
+	};
+
+	let music = export_music(&description, &midi, &instruments, reverb.as_ref().map(|r| &r[..]), &description.midi)?;
+	File::create(output).and_then(|mut f| f.write_all(music.as_bytes())).map_err(|e| e.to_string())?;
+	println!("Wrote file {}", output);
+	Ok(())
+}
+
+fn main() {
+	let args: Vec<String> = env::args().skip(1).collect();
+	if args.len() != 2 {
+		eprintln!("Usage: OidosExport <export description file> <output asm file>");
+		exit(2);
+	}
+
+	if let Err(message) = run(&args[0], &args[1]) {
+		eprintln!("Error: {}", message);
+		exit(1);
+	}
+}
@@ -0,0 +1,133 @@
+// Search for Oidos parameters matching a sound in a WAV file
+#![allow(non_snake_case)]
+
+extern crate Oidos;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::process::exit;
+
+use Oidos::fit::{fit, FitSettings};
+use Oidos::generate::SoundParameters;
+use Oidos::oidos_generate::{OidosRandomData, OidosSoundGenerator, OidosSoundParameters};
+use Oidos::patch::{format_patch, parse_patch};
+use Oidos::render::{read_wav, render_note, write_wav};
+
+
+struct Options {
+	tone: u8,
+	population: usize,
+	generations: usize,
+	seed: u32,
+	keep: usize,
+	locks: Vec<String>,
+	start: Option<String>,
+	target: String,
+	prefix: String
+}
+
+fn usage() -> ! {
+	eprintln!("Usage: OidosFit [-tone <0-127>] [-population <n>] [-generations <n>] [-seed <number>]");
+	eprintln!("                [-keep <n>] [-lock <name,name,...>] [-start <patch file>] <WAV file> <output prefix>");
+	exit(2);
+}
+
+fn parse_option<T: std::str::FromStr>(name: &str, value: Option<String>) -> T {
+	match value.as_ref().map(|v| v.parse::<T>()) {
+		Some(Ok(v)) => v,
+		_ => {
+			eprintln!("Illegal value for {}", name);
+			usage();
+		}
+	}
+}
+
+fn parse_options() -> Options {
+	let mut options = Options {
+		tone: 60,
+		population: 32,
+		generations: 50,
+		seed: 1,
+		keep: 4,
+		locks: Vec::new(),
+		start: None,
+		target: String::new(),
+		prefix: String::new()
+	};
+	let mut files = Vec::new();
+	let mut args = env::args().skip(1);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"-tone"        => options.tone = parse_option(&arg, args.next()),
+			"-population"  => options.population = parse_option(&arg, args.next()),
+			"-generations" => options.generations = parse_option(&arg, args.next()),
+			"-seed"        => options.seed = parse_option(&arg, args.next()),
+			"-keep"        => options.keep = parse_option(&arg, args.next()),
+			"-lock"        => options.locks.extend(parse_option::<String>(&arg, args.next()).split(',').map(|s| s.to_string())),
+			"-start"       => options.start = Some(parse_option(&arg, args.next())),
+			_ if arg.starts_with('-') => usage(),
+			_ => files.push(arg)
+		}
+	}
+	if files.len() != 2 || options.tone >= 128 {
+		usage();
+	}
+	options.prefix = files.pop().unwrap();
+	options.target = files.pop().unwrap();
+	options
+}
+
+fn run(options: &Options) -> Result<(), String> {
+	let (target, sample_rate) = read_wav(&options.target)?;
+	let start = match options.start {
+		Some(ref filename) => {
+			let mut text = String::new();
+			File::open(filename).and_then(|mut f| f.read_to_string(&mut text)).map_err(|e| e.to_string())?;
+			parse_patch::<OidosSoundParameters>(&text)?
+		},
+		None => OidosSoundParameters::default_values()
+	};
+
+	let schema = OidosSoundParameters::schema();
+	let mut locks = vec![false; start.len()];
+	for name in &options.locks {
+		match schema.iter().position(|desc| desc.name == name) {
+			Some(index) => locks[index] = true,
+			None => return Err(format!("Unknown parameter '{}'", name))
+		}
+	}
+
+	let settings = FitSettings {
+		tone: options.tone,
+		population: options.population,
+		generations: options.generations,
+		locks: locks,
+		seed: options.seed
+	};
+	let random = OidosRandomData::default();
+	let sample_rate = sample_rate as f32;
+	let result = fit::<OidosSoundGenerator, _>(&target, sample_rate, &start, &settings, &random, |generation, best| {
+		println!("Generation {}: best distance {:.2}", generation + 1, best.score);
+	});
+
+	let length = target.len();
+	for (i, candidate) in result.iter().take(options.keep).enumerate() {
+		let name = format!("{}_{}", options.prefix, i + 1);
+		File::create(format!("{}.patch", name))
+			.and_then(|mut f| f.write_all(format_patch::<OidosSoundParameters>(&candidate.values).as_bytes()))
+			.map_err(|e| e.to_string())?;
+		let samples = render_note::<OidosSoundGenerator>(&candidate.values, options.tone, 127, length, length, sample_rate, &random);
+		write_wav(&format!("{}.wav", name), &samples, sample_rate as u32).map_err(|e| e.to_string())?;
+		println!("{}: distance {:.2}", name, candidate.score);
+	}
+	Ok(())
+}
+
+fn main() {
+	let options = parse_options();
+	if let Err(message) = run(&options) {
+		eprintln!("Error: {}", message);
+		exit(1);
+	}
+}
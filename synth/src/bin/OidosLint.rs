@@ -0,0 +1,76 @@
+// Check Oidos patches for problems that would show up in the player
+#![allow(non_snake_case)]
+
+extern crate Oidos;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::process::exit;
+
+use Oidos::generate::SoundParameters;
+use Oidos::oidos_generate::{OidosRandomData, OidosSoundParameters};
+use Oidos::oidos_lint::{diagnose, note_name};
+use Oidos::patch::parse_patch;
+
+
+fn read_patch(filename: &str) -> Result<OidosSoundParameters, String> {
+	let mut text = String::new();
+	File::open(filename).and_then(|mut f| f.read_to_string(&mut text)).map_err(|e| e.to_string())?;
+	let values = parse_patch::<OidosSoundParameters>(&text)?;
+	Ok(OidosSoundParameters::build(&values, 44100.0))
+}
+
+fn main() {
+	let mut args: Vec<String> = env::args().skip(1).collect();
+	let mut tone = 60u8;
+	if args.len() >= 2 && args[0] == "-tone" {
+		tone = match args[1].parse::<u8>() {
+			Ok(t) if t < 128 => t,
+			_ => {
+				eprintln!("Illegal tone '{}'", args[1]);
+				exit(2);
+			}
+		};
+		args.drain(0..2);
+	}
+	if args.is_empty() {
+		eprintln!("Usage: OidosLint [-tone <0-127>] <patch file>...");
+		exit(2);
+	}
+
+	let random = OidosRandomData::default();
+	let mut problems = false;
+	for filename in &args {
+		println!("{}", filename);
+		let param = match read_patch(filename) {
+			Ok(param) => param,
+			Err(message) => {
+				println!(" Error: {}", message);
+				problems = true;
+				continue;
+			}
+		};
+
+		let diag = diagnose(&param, tone, &random);
+		match diag.duration {
+			Some(d) => println!(" Duration:    {:.2} s", d),
+			None    => println!(" Duration:    infinite")
+		}
+		println!(" Partials:    {}", diag.partials);
+		println!(" Aliasing:    {} at {}", diag.aliasing_partials, note_name(tone));
+		println!(" Peak level:  {:+.1} dB", diag.peak_level);
+		println!(" Saturation:  {:.1} dB", diag.saturation);
+		if let Some(t) = diag.precalc_time {
+			println!(" Precalc:     {:.2} s per tone", t);
+		}
+		for warning in diag.warnings() {
+			println!(" Warning: {}", warning);
+			problems = true;
+		}
+	}
+
+	if problems {
+		exit(1);
+	}
+}
@@ -0,0 +1,149 @@
+// Render a patch as a multisample: WAV files for a range of keys and velocities plus an SFZ file
+#![allow(non_snake_case)]
+
+extern crate Oidos;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::process::exit;
+
+use Oidos::export::parse_instrument_state;
+use Oidos::generate::{layer_velocity, SoundParameters};
+use Oidos::oidos_generate::{OidosRandomData, OidosSoundGenerator, OidosSoundParameters};
+use Oidos::render::{render_velocity_layers, trim_silence, write_wav};
+
+
+struct Options {
+	low: u8,
+	high: u8,
+	step: u8,
+	/// Velocities to render, or the top velocity of each velocity layer of the patch if empty
+	velocities: Vec<u8>,
+	hold: f32,
+	max_length: f32,
+	sample_rate: u32,
+	patch: String,
+	output: String
+}
+
+fn usage() -> ! {
+	eprintln!("Usage: OidosMultisample [-keys <low>-<high>] [-step <n>] [-velocities <v,v,...>] [-hold <seconds>]");
+	eprintln!("                        [-length <seconds>] [-rate <sample rate>] <patch or preset file> <output directory>");
+	exit(2);
+}
+
+fn parse_option<T: std::str::FromStr>(name: &str, value: Option<String>) -> T {
+	match value.as_ref().map(|v| v.parse::<T>()) {
+		Some(Ok(v)) => v,
+		_ => {
+			eprintln!("Illegal value for {}", name);
+			usage();
+		}
+	}
+}
+
+fn parse_options() -> Options {
+	let mut options = Options {
+		low: 36,
+		high: 96,
+		step: 3,
+		velocities: Vec::new(),
+		hold: 1.0,
+		max_length: 30.0,
+		sample_rate: 44100,
+		patch: String::new(),
+		output: String::new()
+	};
+	let mut files = Vec::new();
+	let mut args = env::args().skip(1);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"-keys" => {
+				let keys: String = parse_option(&arg, args.next());
+				match keys.split_once('-').map(|(l, h)| (l.parse::<u8>(), h.parse::<u8>())) {
+					Some((Ok(low), Ok(high))) => { options.low = low; options.high = high; },
+					_ => usage()
+				}
+			},
+			"-step"       => options.step = parse_option(&arg, args.next()),
+			"-velocities" => options.velocities = parse_option::<String>(&arg, args.next()).split(',')
+				.map(|v| parse_option(&arg, Some(v.to_string()))).collect(),
+			"-hold"       => options.hold = parse_option(&arg, args.next()),
+			"-length"     => options.max_length = parse_option(&arg, args.next()),
+			"-rate"       => options.sample_rate = parse_option(&arg, args.next()),
+			_ if arg.starts_with('-') => usage(),
+			_ => files.push(arg)
+		}
+	}
+	options.velocities.sort();
+	options.velocities.dedup();
+	if files.len() != 2 || options.low > options.high || options.high >= 128 || options.step == 0
+			|| options.velocities.iter().any(|&v| v == 0 || v >= 128) {
+		usage();
+	}
+	options.output = files.pop().unwrap();
+	options.patch = files.pop().unwrap();
+	options
+}
+
+/// Keys to sample, each with the range of keys it plays: up to halfway to the neighbouring
+/// sampled keys, with the outermost samples covering the rest of the keyboard.
+fn key_ranges(low: u8, high: u8, step: u8) -> Vec<(u8, u8, u8)> {
+	let keys: Vec<u8> = (low..=high).step_by(step as usize).collect();
+	keys.iter().enumerate().map(|(i, &key)| {
+		let lokey = if i == 0 { 0 } else { (keys[i - 1] + key) / 2 + 1 };
+		let hikey = if i == keys.len() - 1 { 127 } else { (key + keys[i + 1]) / 2 };
+		(key, lokey, hikey)
+	}).collect()
+}
+
+fn run(options: &Options) -> Result<(), String> {
+	let data = fs::read(&options.patch).map_err(|e| format!("{}: {}", options.patch, e))?;
+	let state = parse_instrument_state(&data)?;
+	let values = state.values;
+	let name = Path::new(&options.patch).file_stem().and_then(|s| s.to_str()).unwrap_or("oidos").to_string();
+	fs::create_dir_all(&options.output).map_err(|e| e.to_string())?;
+
+	let mut velocities = options.velocities.clone();
+	if velocities.is_empty() {
+		let layers = OidosSoundParameters::velocity_layers(&values);
+		velocities = (0..layers).map(|layer| layer_velocity(layer, layers)).collect();
+	}
+
+	let random = OidosRandomData::default();
+	let sample_rate = options.sample_rate as f32;
+	let hold = (options.hold * sample_rate) as usize;
+	let max_length = (options.max_length * sample_rate) as usize;
+	let mut sfz = String::new();
+	sfz += &format!("// {} rendered by OidosMultisample\n<group> loop_mode=no_loop\n", name);
+	for (key, lokey, hikey) in key_ranges(options.low, options.high, options.step) {
+		let layers = render_velocity_layers::<OidosSoundGenerator>(&values, &state.tuning, key, &velocities, hold, max_length, sample_rate, &random);
+		for (i, mut samples) in layers.into_iter().enumerate() {
+			let velocity = velocities[i];
+			let lovel = if i == 0 { 1 } else { velocities[i - 1] + 1 };
+			let hivel = if i == velocities.len() - 1 { 127 } else { velocity };
+			trim_silence(&mut samples);
+			let filename = format!("{}_{:03}_v{:03}.wav", name, key, velocity);
+			let path = Path::new(&options.output).join(&filename);
+			write_wav(path.to_str().unwrap(), &samples, options.sample_rate).map_err(|e| format!("{}: {}", filename, e))?;
+			sfz += &format!("<region> sample={} pitch_keycenter={} lokey={} hikey={} lovel={} hivel={}\n",
+				filename, key, lokey, hikey, lovel, hivel);
+			println!("{}: {:.2} seconds", filename, samples.len() as f32 / sample_rate);
+		}
+	}
+
+	let path = Path::new(&options.output).join(format!("{}.sfz", name));
+	File::create(&path).and_then(|mut f| f.write_all(sfz.as_bytes())).map_err(|e| e.to_string())?;
+	println!("Wrote {}", path.display());
+	Ok(())
+}
+
+fn main() {
+	let options = parse_options();
+	if let Err(message) = run(&options) {
+		eprintln!("Error: {}", message);
+		exit(1);
+	}
+}
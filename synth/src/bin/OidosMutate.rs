@@ -0,0 +1,120 @@
+// Render audition WAVs of random variations of an Oidos patch
+#![allow(non_snake_case)]
+
+extern crate Oidos;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::process::exit;
+
+use Oidos::generate::SoundParameters;
+use Oidos::mutate::Mutator;
+use Oidos::oidos_generate::{OidosRandomData, OidosSoundGenerator, OidosSoundParameters};
+use Oidos::patch::{format_patch, parse_patch};
+use Oidos::render::{render_note, write_wav};
+
+
+const SAMPLE_RATE: u32 = 44100;
+
+struct Options {
+	count: usize,
+	strength: f32,
+	seed: u32,
+	tone: u8,
+	length: f32,
+	locks: Vec<String>,
+	patch: String,
+	prefix: String
+}
+
+fn usage() -> ! {
+	eprintln!("Usage: OidosMutate [-n <count>] [-strength <0-1>] [-seed <number>] [-tone <0-127>]");
+	eprintln!("                   [-length <seconds>] [-lock <name,name,...>] <patch file> <output prefix>");
+	exit(2);
+}
+
+fn parse_option<T: std::str::FromStr>(name: &str, value: Option<String>) -> T {
+	match value.as_ref().map(|v| v.parse::<T>()) {
+		Some(Ok(v)) => v,
+		_ => {
+			eprintln!("Illegal value for {}", name);
+			usage();
+		}
+	}
+}
+
+fn parse_options() -> Options {
+	let mut options = Options {
+		count: 8,
+		strength: 0.2,
+		seed: 1,
+		tone: 60,
+		length: 1.0,
+		locks: Vec::new(),
+		patch: String::new(),
+		prefix: String::new()
+	};
+	let mut files = Vec::new();
+	let mut args = env::args().skip(1);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"-n"        => options.count = parse_option(&arg, args.next()),
+			"-strength" => options.strength = parse_option(&arg, args.next()),
+			"-seed"     => options.seed = parse_option(&arg, args.next()),
+			"-tone"     => options.tone = parse_option(&arg, args.next()),
+			"-length"   => options.length = parse_option(&arg, args.next()),
+			"-lock"     => options.locks.extend(parse_option::<String>(&arg, args.next()).split(',').map(|s| s.to_string())),
+			_ if arg.starts_with('-') => usage(),
+			_ => files.push(arg)
+		}
+	}
+	if files.len() != 2 || options.tone >= 128 || !(0.0..=1.0).contains(&options.strength) {
+		usage();
+	}
+	options.prefix = files.pop().unwrap();
+	options.patch = files.pop().unwrap();
+	options
+}
+
+fn run(options: &Options) -> Result<(), String> {
+	let mut text = String::new();
+	File::open(&options.patch).and_then(|mut f| f.read_to_string(&mut text)).map_err(|e| e.to_string())?;
+	let values = parse_patch::<OidosSoundParameters>(&text)?;
+
+	let schema = OidosSoundParameters::schema();
+	let mut locks = vec![false; values.len()];
+	for name in &options.locks {
+		match schema.iter().position(|desc| desc.name == name) {
+			Some(index) => locks[index] = true,
+			None => return Err(format!("Unknown parameter '{}'", name))
+		}
+	}
+
+	let random = OidosRandomData::default();
+	let mut mutator = Mutator::new(options.seed);
+	let hold = (options.length * SAMPLE_RATE as f32) as usize;
+	for i in 1..=options.count {
+		let mutated = match mutator.mutate_usable::<OidosSoundGenerator>(&values, &locks, options.strength,
+		                                                                  options.tone, SAMPLE_RATE as f32, &random) {
+			Some(mutated) => mutated,
+			None => return Err("No audible variation with finite duration found".to_string())
+		};
+		let name = format!("{}_{:02}", options.prefix, i);
+		File::create(format!("{}.patch", name))
+			.and_then(|mut f| f.write_all(format_patch::<OidosSoundParameters>(&mutated).as_bytes()))
+			.map_err(|e| e.to_string())?;
+		let samples = render_note::<OidosSoundGenerator>(&mutated, options.tone, 127, hold, usize::MAX, SAMPLE_RATE as f32, &random);
+		write_wav(&format!("{}.wav", name), &samples, SAMPLE_RATE).map_err(|e| e.to_string())?;
+		println!("{}: {:.2} s", name, samples.len() as f32 / SAMPLE_RATE as f32);
+	}
+	Ok(())
+}
+
+fn main() {
+	let options = parse_options();
+	if let Err(message) = run(&options) {
+		eprintln!("Error: {}", message);
+		exit(1);
+	}
+}
@@ -0,0 +1,65 @@
+// Print the player parameter blocks for Oidos or OidosNoise patches
+#![allow(non_snake_case)]
+
+extern crate Oidos;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::process::exit;
+
+use Oidos::noise_generate::NoiseSoundParameters;
+use Oidos::noise_player::make_noise_param_block;
+use Oidos::oidos_generate::OidosSoundParameters;
+use Oidos::oidos_player::{format_param_block, make_param_block, OidosInstrumentContext};
+use Oidos::patch::parse_patch;
+
+
+fn param_block(filename: &str, context: &OidosInstrumentContext, noise: bool) -> Result<String, String> {
+	let mut text = String::new();
+	File::open(filename).and_then(|mut f| f.read_to_string(&mut text)).map_err(|e| e.to_string())?;
+	let block = if noise {
+		make_noise_param_block(&parse_patch::<NoiseSoundParameters>(&text)?, context)?
+	} else {
+		make_param_block(&parse_patch::<OidosSoundParameters>(&text)?, context)?
+	};
+	Ok(format_param_block(&block))
+}
+
+fn main() {
+	let mut args: Vec<String> = env::args().skip(1).collect();
+	let mut context = OidosInstrumentContext::default();
+	let noise = !args.is_empty() && args[0] == "-noise";
+	if noise {
+		args.remove(0);
+	}
+	if args.len() >= 2 && args[0] == "-length" {
+		context.max_note_length = match args[1].parse::<f64>() {
+			Ok(l) if l >= 0.0 => l,
+			_ => {
+				eprintln!("Illegal note length '{}'", args[1]);
+				exit(2);
+			}
+		};
+		args.drain(0..2);
+	}
+	if args.is_empty() {
+		eprintln!("Usage: OidosParamBlock [-noise] [-length <longest note in seconds>] <patch file>...");
+		exit(2);
+	}
+
+	let mut failed = false;
+	for filename in &args {
+		match param_block(filename, &context, noise) {
+			Ok(block) => print!("\t; {}\n{}", filename, block),
+			Err(message) => {
+				eprintln!("{}: {}", filename, message);
+				failed = true;
+			}
+		}
+	}
+
+	if failed {
+		exit(1);
+	}
+}
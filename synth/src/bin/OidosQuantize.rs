@@ -0,0 +1,81 @@
+// Find the coarsest quantization of an Oidos patch that keeps the sound
+#![allow(non_snake_case)]
+
+extern crate Oidos;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::process::exit;
+
+use Oidos::generate::SoundParameters;
+use Oidos::oidos_generate::{OidosRandomData, OidosSoundGenerator, OidosSoundParameters};
+use Oidos::patch::{format_patch, parse_patch};
+use Oidos::quantization::optimize_quantization;
+
+
+const SAMPLE_RATE: f32 = 44100.0;
+
+fn usage() -> ! {
+	eprintln!("Usage: OidosQuantize [-tone <0-127>] [-length <seconds>] [-error <dB>] <patch file> <output patch file>");
+	exit(2);
+}
+
+fn parse_option<T: std::str::FromStr>(name: &str, value: Option<String>) -> T {
+	match value.as_ref().map(|v| v.parse::<T>()) {
+		Some(Ok(v)) => v,
+		_ => {
+			eprintln!("Illegal value for {}", name);
+			usage();
+		}
+	}
+}
+
+fn run(input: &str, output: &str, tone: u8, length: f32, max_error: f32) -> Result<(), String> {
+	let mut text = String::new();
+	File::open(input).and_then(|mut f| f.read_to_string(&mut text)).map_err(|e| e.to_string())?;
+	let values = parse_patch::<OidosSoundParameters>(&text)?;
+
+	let random = OidosRandomData::default();
+	let length = (length * SAMPLE_RATE) as usize;
+	let (quantized, levels) = optimize_quantization::<OidosSoundGenerator>(&values, tone, length, max_error, SAMPLE_RATE, &random);
+
+	let schema = OidosSoundParameters::schema();
+	let mut total = 0i32;
+	for level in &levels {
+		let saved = level.bits as i32 - level.original_bits as i32;
+		total += saved;
+		println!("{:<14} {:2} -> {:2} bits ({:+3})  error {:.2} dB",
+		         schema[level.index].name, level.original_bits, level.bits, saved, level.error);
+	}
+	println!("Total: {:+} bits", total);
+
+	File::create(output)
+		.and_then(|mut f| f.write_all(format_patch::<OidosSoundParameters>(&quantized).as_bytes()))
+		.map_err(|e| e.to_string())
+}
+
+fn main() {
+	let mut tone = 60u8;
+	let mut length = 1.0f32;
+	let mut max_error = 0.5f32;
+	let mut files = Vec::new();
+	let mut args = env::args().skip(1);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"-tone"   => tone = parse_option(&arg, args.next()),
+			"-length" => length = parse_option(&arg, args.next()),
+			"-error"  => max_error = parse_option(&arg, args.next()),
+			_ if arg.starts_with('-') => usage(),
+			_ => files.push(arg)
+		}
+	}
+	if files.len() != 2 || tone >= 128 {
+		usage();
+	}
+
+	if let Err(message) = run(&files[0], &files[1], tone, length, max_error) {
+		eprintln!("Error: {}", message);
+		exit(1);
+	}
+}
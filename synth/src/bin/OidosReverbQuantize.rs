@@ -0,0 +1,74 @@
+// Find the coarsest quantization of an OidosReverb patch that keeps the sound
+#![allow(non_snake_case)]
+
+extern crate Oidos;
+extern crate OidosReverb;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::process::exit;
+
+use Oidos::quantization::optimize_reverb_quantization;
+use OidosReverb::{format_patch, parse_patch, PARAMETER_NAMES};
+
+
+const SAMPLE_RATE: f32 = 44100.0;
+
+fn usage() -> ! {
+	eprintln!("Usage: OidosReverbQuantize [-length <seconds>] [-error <dB>] <patch file> <output patch file>");
+	exit(2);
+}
+
+fn parse_option<T: std::str::FromStr>(name: &str, value: Option<String>) -> T {
+	match value.as_ref().map(|v| v.parse::<T>()) {
+		Some(Ok(v)) => v,
+		_ => {
+			eprintln!("Illegal value for {}", name);
+			usage();
+		}
+	}
+}
+
+fn run(input: &str, output: &str, length: f32, max_error: f32) -> Result<(), String> {
+	let mut text = String::new();
+	File::open(input).and_then(|mut f| f.read_to_string(&mut text)).map_err(|e| e.to_string())?;
+	let values = parse_patch(&text)?;
+
+	let (quantized, levels) = optimize_reverb_quantization(&values, (length * SAMPLE_RATE) as usize, max_error);
+
+	let mut total = 0i32;
+	for level in &levels {
+		let saved = level.bits as i32 - level.original_bits as i32;
+		total += saved;
+		println!("{:<9} {:2} -> {:2} bits ({:+3})  error {:.2} dB", PARAMETER_NAMES[level.index], level.original_bits, level.bits, saved, level.error);
+	}
+	println!("Total: {:+} bits", total);
+
+	File::create(output)
+		.and_then(|mut f| f.write_all(format_patch(&quantized).as_bytes()))
+		.map_err(|e| e.to_string())
+}
+
+fn main() {
+	let mut length = 2.0f32;
+	let mut max_error = 0.5f32;
+	let mut files = Vec::new();
+	let mut args = env::args().skip(1);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"-length" => length = parse_option(&arg, args.next()),
+			"-error"  => max_error = parse_option(&arg, args.next()),
+			_ if arg.starts_with('-') => usage(),
+			_ => files.push(arg)
+		}
+	}
+	if files.len() != 2 {
+		usage();
+	}
+
+	if let Err(message) = run(&files[0], &files[1], length, max_error) {
+		eprintln!("Error: {}", message);
+		exit(1);
+	}
+}
@@ -0,0 +1,65 @@
+// Give a patch the tuning of a Scala scale and keyboard mapping
+#![allow(non_snake_case)]
+
+extern crate Oidos;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::exit;
+
+use Oidos::export::parse_instrument_state;
+use Oidos::oidos_generate::OidosSoundParameters;
+use Oidos::patch::{format_fxp_chunk, format_patch};
+use Oidos::tuning::Tuning;
+use Oidos::{OIDOS_UNIQUE_ID, OIDOS_VERSION};
+
+
+fn read_file(filename: &str) -> Result<Vec<u8>, String> {
+	let mut data = Vec::new();
+	File::open(filename).and_then(|mut f| f.read_to_end(&mut data)).map_err(|e| format!("{}: {}", filename, e))?;
+	Ok(data)
+}
+
+fn read_text(filename: &str) -> Result<String, String> {
+	// Scala files are often Latin-1 in their descriptions and comments
+	Ok(read_file(filename)?.iter().map(|&b| b as char).collect())
+}
+
+fn run(scl: &str, kbm: Option<&str>, input: &str, output: &str) -> Result<(), String> {
+	let kbm_text = match kbm {
+		Some(filename) => Some(read_text(filename)?),
+		None => None
+	};
+	let tuning = Tuning::from_scala(&read_text(scl)?, kbm_text.as_deref()).map_err(|e| format!("{}: {}", scl, e))?;
+	let state = parse_instrument_state(&read_file(input)?).map_err(|e| format!("{}: {}", input, e))?;
+
+	let text = format_patch::<OidosSoundParameters>(&state.values) + &tuning.format();
+	let path = Path::new(output);
+	let data = if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("fxp")) {
+		let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+		format_fxp_chunk(OIDOS_UNIQUE_ID as u32, OIDOS_VERSION as u32, name, text.as_bytes())
+	} else {
+		text.into_bytes()
+	};
+	File::create(output).and_then(|mut f| f.write_all(&data)).map_err(|e| e.to_string())?;
+	println!("Wrote file {}", output);
+	Ok(())
+}
+
+fn main() {
+	let args: Vec<String> = env::args().skip(1).collect();
+	let result = match args.len() {
+		3 => run(&args[0], None, &args[1], &args[2]),
+		4 => run(&args[0], Some(&args[1]), &args[2], &args[3]),
+		_ => {
+			eprintln!("Usage: OidosTuning <scl file> [<kbm file>] <patch or preset file> <output patch or .fxp file>");
+			exit(2);
+		}
+	};
+	if let Err(message) = result {
+		eprintln!("Error: {}", message);
+		exit(1);
+	}
+}
@@ -0,0 +1,181 @@
+// Plugin controls outside the patch which are saved with it: the randomizer settings,
+// the parameter locks and the morph snapshots and settings
+
+use generate::{ParameterGroup, SoundParameters};
+#[cfg(test)] use oidos_generate::{OidosSoundParameters, ATTACK, MODES, SEED};
+
+/// Strength of the mutations, by default
+pub const DEFAULT_MUTATION_STRENGTH: f32 = 0.2;
+/// Morph position where parameters with integer steps switch from snapshot A to B, by default
+pub const DEFAULT_MORPH_SWITCH: f32 = 0.5;
+
+/// First word of the line holding the mutation strength in a patch text
+const MUTATE_STRENGTH_KEYWORD: &str = "mutate_strength";
+/// First word of the lines naming a locked parameter in a patch text
+const LOCK_KEYWORD: &str = "lock";
+/// First word of the lines holding a snapshot parameter value in a patch text
+const SNAPSHOT_KEYWORD: &str = "snapshot";
+/// First word of the line holding the morph amount and switch point in a patch text
+const MORPH_KEYWORD: &str = "morph";
+/// Line telling that the morphed patch is playing
+const MORPHING_KEYWORD: &str = "morphing";
+const SNAPSHOT_NAMES: [&str; 2] = ["A", "B"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Controls {
+	pub mutation_strength: f32,
+	/// Whether each parameter is kept unchanged by mutations
+	pub locks: Vec<bool>,
+	pub snapshots: [Option<Vec<f32>>; 2],
+	pub morph_amount: f32,
+	pub morph_switch: f32,
+	/// Whether the morph of the snapshots replaces the current patch
+	pub morphing: bool
+}
+
+impl Default for Controls {
+	fn default() -> Controls {
+		Controls {
+			mutation_strength: DEFAULT_MUTATION_STRENGTH,
+			locks: Vec::new(),
+			snapshots: [None, None],
+			morph_amount: 0.0,
+			morph_switch: DEFAULT_MORPH_SWITCH,
+			morphing: false
+		}
+	}
+}
+
+impl Controls {
+	/// Lines to append to a patch text to save the controls. Controls in their
+	/// default state are left out.
+	pub fn format<S: SoundParameters>(&self) -> String {
+		let mut text = String::new();
+		if self.mutation_strength != DEFAULT_MUTATION_STRENGTH {
+			text += &format!("{} {}\n", MUTATE_STRENGTH_KEYWORD, self.mutation_strength);
+		}
+		for (desc, &locked) in S::schema().iter().zip(&self.locks) {
+			if locked {
+				text += &format!("{} {}\n", LOCK_KEYWORD, desc.name);
+			}
+		}
+		for (snapshot, values) in self.snapshots.iter().enumerate() {
+			if let Some(ref values) = *values {
+				for (desc, value) in S::schema().iter().zip(values) {
+					if desc.group != ParameterGroup::Unused {
+						text += &format!("{} {} {} {}\n", SNAPSHOT_KEYWORD, SNAPSHOT_NAMES[snapshot], desc.name, value);
+					}
+				}
+			}
+		}
+		if self.morph_amount != 0.0 || self.morph_switch != DEFAULT_MORPH_SWITCH {
+			text += &format!("{} {} {}\n", MORPH_KEYWORD, self.morph_amount, self.morph_switch);
+		}
+		if self.morphing {
+			text += &format!("{}\n", MORPHING_KEYWORD);
+		}
+		text
+	}
+
+	/// Separate the control lines written by `format` from the rest of a patch text for
+	/// a plugin with the given parameter names. Parameters missing from a snapshot get
+	/// the given default values. The control lines are blanked out to keep the line numbers.
+	pub fn split_patch(text: &str, names: &[&str], defaults: &[f32]) -> Result<(String, Controls), String> {
+		let mut controls = Controls {
+			locks: vec![false; names.len()],
+			.. Controls::default()
+		};
+		let mut rest = String::new();
+		for (line_index, line) in text.lines().enumerate() {
+			let words: Vec<&str> = line.split('#').next().unwrap().split_whitespace().collect();
+			let parse_value = |word: &str| word.parse::<f32>().ok().filter(|v| (0.0..=1.0).contains(v));
+			match words.as_slice() {
+				[keyword, ..] if *keyword == MUTATE_STRENGTH_KEYWORD => {
+					match words[1..].iter().map(|w| parse_value(w)).collect::<Option<Vec<f32>>>().as_deref() {
+						Some(&[strength]) => controls.mutation_strength = strength,
+						_ => return Err(format!("Line {}: Expected a strength from 0 to 1 after '{}'", line_index + 1, MUTATE_STRENGTH_KEYWORD))
+					}
+				},
+				[keyword, ..] if *keyword == LOCK_KEYWORD => {
+					let index = match words[1..] {
+						[name] => names.iter().position(|&n| n == name),
+						_ => None
+					};
+					match index {
+						Some(index) => controls.locks[index] = true,
+						None => return Err(format!("Line {}: Expected a parameter name after '{}'", line_index + 1, LOCK_KEYWORD))
+					}
+				},
+				[keyword, ..] if *keyword == SNAPSHOT_KEYWORD => {
+					let target = match words[1..] {
+						[snapshot, name, value] => SNAPSHOT_NAMES.iter().position(|&s| s == snapshot).and_then(|snapshot| {
+							Some((snapshot, names.iter().position(|&n| n == name)?, parse_value(value)?))
+						}),
+						_ => None
+					};
+					match target {
+						Some((snapshot, index, value)) => {
+							controls.snapshots[snapshot].get_or_insert_with(|| defaults.to_vec())[index] = value;
+						},
+						None => return Err(format!("Line {}: Expected A or B, a parameter name and a value from 0 to 1 after '{}'", line_index + 1, SNAPSHOT_KEYWORD))
+					}
+				},
+				[keyword, ..] if *keyword == MORPH_KEYWORD => {
+					match words[1..].iter().map(|w| parse_value(w)).collect::<Option<Vec<f32>>>().as_deref() {
+						Some(&[amount, switch]) => {
+							controls.morph_amount = amount;
+							controls.morph_switch = switch;
+						},
+						_ => return Err(format!("Line {}: Expected an amount and a switch point from 0 to 1 after '{}'", line_index + 1, MORPH_KEYWORD))
+					}
+				},
+				[keyword] if *keyword == MORPHING_KEYWORD => controls.morphing = true,
+				_ => {
+					rest += line;
+				}
+			}
+			rest += "\n";
+		}
+		Ok((rest, controls))
+	}
+}
+
+#[test]
+fn test_controls_patch_text() {
+	let names: Vec<&str> = OidosSoundParameters::schema().iter().map(|desc| desc.name).collect();
+	let defaults = OidosSoundParameters::default_values();
+	let mut a = defaults.clone();
+	a[ATTACK] = 0.2;
+	let mut b = defaults.clone();
+	b[SEED] = 0.9;
+	let mut locks = vec![false; names.len()];
+	locks[SEED] = true;
+	locks[MODES] = true;
+	let controls = Controls {
+		mutation_strength: 0.5,
+		locks: locks,
+		snapshots: [Some(a), Some(b)],
+		morph_amount: 0.25,
+		morph_switch: 0.75,
+		morphing: true
+	};
+	let text = format!("gain 0.5\n{}# Comment\n", controls.format::<OidosSoundParameters>());
+	let (rest, parsed) = Controls::split_patch(&text, &names, &defaults).unwrap();
+	assert_eq!(parsed, controls);
+	assert_eq!(rest.lines().count(), text.lines().count());
+	assert_eq!(rest.lines().filter(|l| !l.is_empty()).collect::<Vec<_>>(), vec!["gain 0.5", "# Comment"]);
+
+	assert_eq!(Controls::default().format::<OidosSoundParameters>(), "");
+	let (_, parsed) = Controls::split_patch("", &names, &defaults).unwrap();
+	assert_eq!(parsed.locks, vec![false; names.len()]);
+	assert_eq!(parsed.format::<OidosSoundParameters>(), "");
+	let (_, parsed) = Controls::split_patch("snapshot B seed 0.9\n", &names, &defaults).unwrap();
+	assert_eq!(parsed.snapshots[0], None);
+	assert_eq!(parsed.snapshots[1].as_ref().map(|b| b[SEED]), Some(0.9));
+	assert!(Controls::split_patch("snapshot C seed 0.9", &names, &defaults).is_err());
+	assert!(Controls::split_patch("snapshot A seeds 0.9", &names, &defaults).is_err());
+	assert!(Controls::split_patch("snapshot A seed 2", &names, &defaults).is_err());
+	assert!(Controls::split_patch("morph 0.5", &names, &defaults).is_err());
+	assert!(Controls::split_patch("lock seeds", &names, &defaults).is_err());
+	assert!(Controls::split_patch("mutate_strength", &names, &defaults).is_err());
+}
@@ -0,0 +1,1062 @@
+// Exporting music from a MIDI file and plugin states, in the same form as the converter
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use generate::{layer_velocity, velocity_layer, SoundParameters};
+use midi::{MidiEventKind, MidiFile};
+use oidos_generate::OidosSoundParameters;
+use oidos_player::{format_param_block, make_param_block, uses_envelope, uses_formants, uses_keytrack, uses_stiffness, uses_swell, OidosInstrumentContext, OidosReverbDefines, PlayerValue, BLOCK_MAXSAMPLES};
+use controls::Controls;
+use patch::{is_fxp_chunk, parse_fxp, parse_fxp_chunk, parse_named_values};
+use tuning::Tuning;
+#[cfg(test)] use generate::SoundGenerator;
+#[cfg(test)] use midi::{parse_midi, write_test_midi};
+#[cfg(test)] use oidos_generate::{OidosRandomData, OidosSoundGenerator, DECAYHIGH, DECAYLOW, DECAYTRACK, FGAIN2, FILTERTRACK, FWIDTH2, MODES, Q_RELEASE, REFPITCH, RELEASE, STIFFNESS, SUSTAIN, SWELL, SWELLSLOPE, SWELLSPREAD, VELLAYERS, VELSHARPNESS};
+#[cfg(test)] use oidos_player::REVERB_DEFAULT_VALUES;
+#[cfg(test)] use patch::format_fxp_chunk;
+#[cfg(test)] use OidosMusic::asm::{parse_music, MusicAsm};
+#[cfg(test)] use OidosMusic::player::{decode_music, InstrumentParams};
+#[cfg(test)] use OidosMusic::render::{make_tone, random_data};
+
+
+const SAMPLERATE: f64 = 44100.0;
+/// Number of beats in each position of the exported music
+const BEATS_PER_POSITION: u32 = 4;
+
+/// An instrument in the export description.
+pub struct InstrumentDescription {
+	pub name: String,
+	/// File containing the plugin state: a preset file or a patch text
+	pub state: String,
+	pub volume: f64
+}
+
+/// A selection of notes from the MIDI file, played by one instrument.
+pub struct TrackDescription {
+	/// Name or index of the MIDI track
+	pub track: String,
+	/// Channel (0-15) to take notes from, or all channels
+	pub channel: Option<u8>,
+	pub instrument: String,
+	pub reverb: bool,
+	pub volume: f64,
+	/// Panning from 0 (left) to 1 (right)
+	pub pan: f64
+}
+
+/// Description of how to export a MIDI file.
+pub struct Description {
+	pub midi: String,
+	pub lines_per_beat: u32,
+	/// File containing the reverb plugin state
+	pub reverb: Option<String>,
+	pub instruments: Vec<InstrumentDescription>,
+	pub tracks: Vec<TrackDescription>
+}
+
+/// Split a line into words. Words containing spaces can be quoted.
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+	let mut words = Vec::new();
+	let mut chars = line.chars().peekable();
+	while let Some(&c) = chars.peek() {
+		if c.is_whitespace() {
+			chars.next();
+		} else if c == '"' {
+			chars.next();
+			let word: String = chars.by_ref().take_while(|&c| c != '"').collect();
+			words.push(word);
+		} else {
+			let mut word = String::new();
+			while let Some(&c) = chars.peek() {
+				if c.is_whitespace() {
+					break;
+				}
+				word.push(c);
+				chars.next();
+			}
+			words.push(word);
+		}
+	}
+	if line.matches('"').count() % 2 == 1 {
+		return Err("Unterminated quote".to_string());
+	}
+	Ok(words)
+}
+
+fn parse_number<T: ::std::str::FromStr>(name: &str, value: Option<&String>) -> Result<T, String> {
+	value.and_then(|v| v.parse::<T>().ok()).ok_or(format!("Expected a number after '{}'", name))
+}
+
+/// Parse an export description. It consists of lines of the form
+///
+/// ```text
+/// midi <MIDI file>
+/// lines_per_beat <lines>
+/// reverb <reverb state file>
+/// instrument <name> <state file> [volume <volume>]
+/// track <MIDI track name or index> <instrument name> [channel <1-16>] [reverb] [volume <volume>] [pan <0-1>]
+/// ```
+///
+/// Text after `#` is ignored.
+pub fn parse_description(text: &str) -> Result<Description, String> {
+	let mut description = Description {
+		midi: String::new(),
+		lines_per_beat: 4,
+		reverb: None,
+		instruments: Vec::new(),
+		tracks: Vec::new()
+	};
+	for (line_index, line) in text.lines().enumerate() {
+		let error = |message: String| format!("Line {}: {}", line_index + 1, message);
+		let words = split_words(line.split('#').next().unwrap()).map_err(&error)?;
+		if words.is_empty() {
+			continue;
+		}
+		let keyword = words[0].as_str();
+		let arguments = match keyword {
+			"midi" | "reverb" | "lines_per_beat" => 2,
+			"instrument" | "track" => 3,
+			_ => return Err(error(format!("Unknown keyword '{}'", keyword)))
+		};
+		if words.len() < arguments {
+			return Err(error(format!("Missing arguments for '{}'", keyword)));
+		}
+
+		let mut volume = 1.0;
+		let mut pan = 0.5;
+		let mut channel = None;
+		let mut reverb = false;
+		let mut i = arguments;
+		while i < words.len() {
+			let option = words[i].as_str();
+			let value = words.get(i + 1);
+			match (keyword, option) {
+				("instrument", "volume") | ("track", "volume") => volume = parse_number(option, value).map_err(&error)?,
+				("track", "pan") => pan = parse_number(option, value).map_err(&error)?,
+				("track", "channel") => match parse_number::<u8>(option, value) {
+					Ok(c) if (1..=16).contains(&c) => channel = Some(c - 1),
+					_ => return Err(error("Expected a channel from 1 to 16".to_string()))
+				},
+				("track", "reverb") => {
+					reverb = true;
+					i += 1;
+					continue;
+				},
+				_ => return Err(error(format!("Unexpected '{}'", option)))
+			}
+			i += 2;
+		}
+		if !(0.0..=1.0).contains(&pan) {
+			return Err(error("Panning must be from 0 to 1".to_string()));
+		}
+
+		match keyword {
+			"midi" => description.midi = words[1].clone(),
+			"lines_per_beat" => match words[1].parse::<u32>() {
+				Ok(lpb) if lpb > 0 => description.lines_per_beat = lpb,
+				_ => return Err(error(format!("Illegal number of lines per beat '{}'", words[1])))
+			},
+			"reverb" => {
+				if description.reverb.is_some() {
+					return Err(error("Only one reverb can be used".to_string()));
+				}
+				description.reverb = Some(words[1].clone());
+			},
+			"instrument" => {
+				if description.instruments.iter().any(|instr| instr.name == words[1]) {
+					return Err(error(format!("Instrument '{}' is defined twice", words[1])));
+				}
+				description.instruments.push(InstrumentDescription {
+					name: words[1].clone(),
+					state: words[2].clone(),
+					volume: volume
+				});
+			},
+			_ => {
+				if !description.instruments.iter().any(|instr| instr.name == words[2]) {
+					return Err(error(format!("Undefined instrument '{}'", words[2])));
+				}
+				description.tracks.push(TrackDescription {
+					track: words[1].clone(),
+					channel: channel,
+					instrument: words[2].clone(),
+					reverb: reverb,
+					volume: volume,
+					pan: pan
+				});
+			}
+		}
+	}
+	if description.midi.is_empty() {
+		return Err("No MIDI file given".to_string());
+	}
+	Ok(description)
+}
+
+/// Saved state of an Oidos instrument.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InstrumentState {
+	pub values: Vec<f32>,
+	pub tuning: Tuning
+}
+
+impl From<Vec<f32>> for InstrumentState {
+	/// An instrument with the given parameter values and equal-tempered tuning.
+	fn from(values: Vec<f32>) -> InstrumentState {
+		InstrumentState {
+			values: values,
+			tuning: Tuning::default()
+		}
+	}
+}
+
+/// Text of a saved plugin state, either a VST preset file storing a chunk or a patch text.
+fn state_text(data: &[u8]) -> Result<String, String> {
+	let text = if is_fxp_chunk(data) { parse_fxp_chunk(data)? } else { data };
+	String::from_utf8(text.to_vec()).map_err(|_| "Plugin state is neither a preset nor a patch text".to_string())
+}
+
+/// Read a saved plugin state, either a VST preset file or a patch text.
+pub fn parse_plugin_state(data: &[u8], names: &[&str], defaults: &[f32]) -> Result<Vec<f32>, String> {
+	if data.starts_with(b"CcnK") && !is_fxp_chunk(data) {
+		parse_fxp(data, names.len())
+	} else {
+		let (text, _) = Controls::split_patch(&state_text(data)?, names, defaults)?;
+		parse_named_values(&text, names, defaults.to_vec())
+	}
+}
+
+/// Read a saved Oidos plugin state, including the tuning stored in preset chunks and patch texts.
+pub fn parse_instrument_state(data: &[u8]) -> Result<InstrumentState, String> {
+	let names: Vec<&str> = OidosSoundParameters::schema().iter().map(|desc| desc.name).collect();
+	let defaults = OidosSoundParameters::default_values();
+	if data.starts_with(b"CcnK") && !is_fxp_chunk(data) {
+		return parse_fxp(data, names.len()).map(InstrumentState::from);
+	}
+	let (text, tuning) = Tuning::split_patch(&state_text(data)?)?;
+	let (text, _) = Controls::split_patch(&text, &names, &defaults)?;
+	Ok(InstrumentState {
+		values: parse_named_values(&text, &names, defaults)?,
+		tuning: tuning
+	})
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Note {
+	line: u32,
+	off: bool,
+	tone: u8,
+	velocity: u8
+}
+
+impl Note {
+	fn off(line: u32) -> Note {
+		Note { line: line, off: true, tone: 0, velocity: 0 }
+	}
+}
+
+/// The notes of a column played in a velocity layer, with the other notes replaced by OFFs.
+fn layer_notes(notes: &[Note], layer: usize, layers: usize) -> Vec<Note> {
+	let mut result: Vec<Note> = Vec::new();
+	for n in notes {
+		let note = if n.off || velocity_layer(n.velocity, layers) != layer { Note::off(n.line) } else { *n };
+		if !(note.off && result.last().is_some_and(|prev| prev.off)) {
+			result.push(note);
+		}
+	}
+	result
+}
+
+fn make_volume(volume: f64, pan: f64) -> [f64; 2] {
+	[volume * (2.0 * (1.0 - pan)).sqrt(), volume * (2.0 * pan).sqrt()]
+}
+
+fn is_panned(volume: &[f64; 2]) -> bool {
+	volume[0] != volume[1]
+}
+
+struct Track {
+	title: String,
+	labelname: String,
+	column: usize,
+	instr: usize,
+	notes: Vec<Note>,
+	volume: [f64; 2],
+	/// Index into `tavs` for each note that is not an OFF
+	tav_index: Vec<Option<usize>>,
+	singular_length: Option<u32>,
+	/// Distinct tone and velocity pairs, sorted
+	tavs: Vec<(u8, u8)>,
+	latest_note: u32,
+	max_length: u32
+}
+
+impl Track {
+	fn new(name: &str, column: usize, instr: usize, notes: Vec<Note>, volume: [f64; 2]) -> Result<Track, String> {
+		let mut note_lengths = BTreeMap::new();
+		let mut latest_note = 0;
+		let mut max_length = 0;
+		for pair in notes.windows(2) {
+			if !pair[0].off {
+				let length = pair[1].line - pair[0].line;
+				latest_note = latest_note.max(pair[0].line);
+				max_length = max_length.max(length);
+				*note_lengths.entry(length).or_insert(0) += 1;
+			}
+		}
+		if notes.last().is_some_and(|n| !n.off) {
+			return Err(format!("Track '{}' column {}: Note not terminated", name, column));
+		}
+		let singular_length = match note_lengths.keys().collect::<Vec<_>>()[..] {
+			[&l] if l <= 255 => Some(l),
+			_ => None
+		};
+
+		let mut tavs: Vec<(u8, u8)> = notes.iter().filter(|n| !n.off).map(|n| (n.tone, n.velocity)).collect();
+		tavs.sort();
+		tavs.dedup();
+		let tav_index = notes.iter().map(|n| if n.off { None } else { tavs.iter().position(|&tav| tav == (n.tone, n.velocity)) }).collect();
+
+		Ok(Track {
+			title: format!("{}, column {}", name, column),
+			labelname: name.chars().filter(|&c| c.is_alphanumeric() || c == '_').collect(),
+			column: column,
+			instr: instr,
+			notes: notes,
+			volume: volume,
+			tav_index: tav_index,
+			singular_length: singular_length,
+			tavs: tavs,
+			latest_note: latest_note,
+			max_length: max_length
+		})
+	}
+}
+
+/// Extract the notes for a track description from a MIDI track, distributing
+/// overlapping notes onto columns so each column plays one note at a time.
+/// Returns the note list of each column, starting at line 0 and ending in an OFF.
+fn make_columns(midi: &MidiFile, track_index: usize, channel: Option<u8>, lines_per_beat: u32, name: &str) -> Result<Vec<Vec<Note>>, String> {
+	let to_line = |tick: u32| ((tick as u64 * lines_per_beat as u64 + midi.division as u64 / 2) / midi.division as u64) as u32;
+
+	// Pair note on and off events
+	let mut playing: Vec<(u8, u8, u32, u8)> = Vec::new();
+	let mut notes: Vec<(u32, u32, u8, u8)> = Vec::new();
+	for event in &midi.tracks[track_index].events {
+		match event.kind {
+			MidiEventKind::NoteOn { channel: c, key, velocity } if channel.is_none_or(|ch| ch == c) => {
+				playing.push((c, key, event.tick, velocity));
+			},
+			MidiEventKind::NoteOff { channel: c, key } if channel.is_none_or(|ch| ch == c) => {
+				if let Some(index) = playing.iter().position(|&(pc, pk, _, _)| pc == c && pk == key) {
+					let (_, _, start, velocity) = playing.remove(index);
+					let (start_line, end_line) = (to_line(start), to_line(event.tick));
+					if end_line <= start_line {
+						return Err(format!("Track '{}': Note at tick {} is shorter than a line (increase lines_per_beat)", name, start));
+					}
+					notes.push((start_line, end_line, key, velocity));
+				}
+			},
+			_ => {}
+		}
+	}
+	if let Some(&(_, _, start, _)) = playing.first() {
+		return Err(format!("Track '{}': Note at tick {} not terminated", name, start));
+	}
+	notes.sort();
+
+	let mut columns: Vec<Vec<(u32, u32, u8, u8)>> = Vec::new();
+	for note in notes {
+		match columns.iter_mut().find(|column| column.last().unwrap().1 <= note.0) {
+			Some(column) => column.push(note),
+			None => columns.push(vec![note])
+		}
+	}
+
+	Ok(columns.iter().map(|column| {
+		let mut notes = Vec::new();
+		let mut prev_end = None;
+		for &(start, end, tone, velocity) in column {
+			if prev_end.map_or(start != 0, |prev_end| prev_end < start) {
+				notes.push(Note::off(prev_end.unwrap_or(0)));
+			}
+			notes.push(Note { line: start, off: false, tone: tone, velocity: velocity });
+			prev_end = Some(end);
+		}
+		notes.push(Note::off(prev_end.unwrap()));
+		notes
+	}).collect())
+}
+
+fn find_midi_track(midi: &MidiFile, selector: &str) -> Result<usize, String> {
+	if let Some(index) = midi.tracks.iter().position(|t| t.name.as_ref().is_some_and(|n| n == selector)) {
+		return Ok(index);
+	}
+	match selector.parse::<usize>() {
+		Ok(index) if index < midi.tracks.len() => Ok(index),
+		_ => Err(format!("No MIDI track named '{}'", selector))
+	}
+}
+
+struct Instrument {
+	number: usize,
+	title: String,
+	columns: usize,
+	tones: Vec<u8>,
+	/// Offset of each tone given by the tuning, in semitones
+	tuning: Vec<f32>,
+	velocity_quantum: u32,
+	paramblock: Vec<PlayerValue>
+}
+
+fn dataline(out: &mut String, data: &[i32]) {
+	if !data.is_empty() {
+		let values: Vec<String> = data.iter().map(|d| d.to_string()).collect();
+		*out += &format!("\tdb\t{}\n", values.join(","));
+	}
+}
+
+/// Write the note lengths or note samples of the tracks, as done by `notelist` in the converter.
+fn notelist<F: Fn(&Track, usize, Option<usize>) -> Vec<i32>>(out: &mut String, tracks: &[Track], track_order: &[usize],
+                                                             position_lines: u32, datafunc: F, trackterm: &[i32], prefix: &str) {
+	for &ti in track_order {
+		let track = &tracks[ti];
+		*out += &format!("\t; {}\n", track.title);
+		*out += &format!("{}{}_{}_{}:\n", prefix, track.labelname, track.column, track.instr);
+		let mut prev_n: Option<usize> = None;
+		let mut pat_data = Vec::new();
+		for (i, n) in track.notes.iter().enumerate() {
+			if track.singular_length.is_some() && n.off && n.line > 0 {
+				continue;
+			}
+			let position = n.line / position_lines;
+			let new_position = match prev_n {
+				Some(p) => {
+					pat_data.extend(datafunc(track, p, Some(i)));
+					position != track.notes[p].line / position_lines
+				},
+				None => !n.off
+			};
+			if new_position {
+				dataline(out, &pat_data);
+				pat_data.clear();
+				*out += &format!("\t; Position {}, pattern {}\n", position, position);
+			}
+			prev_n = Some(i);
+		}
+		pat_data.extend(datafunc(track, prev_n.unwrap(), None));
+		dataline(out, &pat_data);
+		dataline(out, trackterm);
+		*out += "\n";
+	}
+}
+
+/// Export music from a MIDI file, producing the same assembly file as the converter
+/// produces for a Renoise song. `instruments` contains the plugin parameters for each
+/// instrument in the description, and `reverb` the parameters of the reverb.
+/// Each MIDI track selected in the description becomes a track, with overlapping
+/// notes placed in separate columns. Music positions are four beats long.
+/// An instrument with velocity layers becomes one player instrument for each
+/// layer used, playing the notes with velocities in that layer.
+pub fn export_music(description: &Description, midi: &MidiFile, instruments: &[InstrumentState],
+                    reverb: Option<&[f32]>, source: &str) -> Result<String, String> {
+	let lines_per_beat = description.lines_per_beat;
+	let ticklength = midi.tempo()? as f64 / 1e6 / lines_per_beat as f64;
+	let position_lines = lines_per_beat * BEATS_PER_POSITION;
+
+	let mut selected = Vec::new();
+	for desc in &description.tracks {
+		let track_index = find_midi_track(midi, &desc.track)?;
+		let mut name = midi.tracks[track_index].name.clone().unwrap_or(format!("Track {}", track_index));
+		if let Some(channel) = desc.channel {
+			name += &format!(" ch{}", channel + 1);
+		}
+		let number = description.instruments.iter().position(|i| i.name == desc.instrument)
+			.ok_or(format!("Undefined instrument '{}'", desc.instrument))?;
+		let columns = make_columns(midi, track_index, desc.channel, lines_per_beat, &name)?;
+		selected.push((desc, name, number, columns));
+	}
+
+	// Player instruments, as description instrument and velocity layer
+	let layers: Vec<usize> = instruments.iter().map(|state| OidosSoundParameters::velocity_layers(&state.values)).collect();
+	let mut variants: Vec<(usize, Option<usize>)> = Vec::new();
+	for (number, &instr_layers) in layers.iter().enumerate() {
+		if instr_layers == 1 {
+			variants.push((number, None));
+		} else {
+			let used: BTreeSet<usize> = selected.iter().filter(|s| s.2 == number)
+				.flat_map(|s| s.3.iter().flatten())
+				.filter(|n| !n.off)
+				.map(|n| velocity_layer(n.velocity, instr_layers))
+				.collect();
+			variants.extend(used.into_iter().map(|layer| (number, Some(layer))));
+		}
+	}
+
+	let mut reverb_tracks = Vec::new();
+	let mut non_reverb_tracks = Vec::new();
+	let mut labels = HashSet::new();
+	for (desc, name, number, columns) in selected {
+		let volume = make_volume(desc.volume, desc.pan);
+		for (c, notes) in columns.into_iter().enumerate() {
+			for (instr, &(_, layer)) in variants.iter().enumerate().filter(|(_, v)| v.0 == number) {
+				let notes = match layer {
+					Some(layer) => layer_notes(&notes, layer, layers[number]),
+					None => notes.clone()
+				};
+				if notes.iter().all(|n| n.off) {
+					continue;
+				}
+				let track = Track::new(&name, c + 1, instr, notes, volume)?;
+				if !labels.insert((track.labelname.clone(), track.column, instr)) {
+					return Err(format!("Track '{}' is exported more than once with instrument '{}'", track.title, desc.instrument));
+				}
+				if desc.reverb {
+					reverb_tracks.push(track);
+				} else {
+					non_reverb_tracks.push(track);
+				}
+			}
+		}
+	}
+	let title = |instr: usize| {
+		let (number, layer) = variants[instr];
+		let name = &description.instruments[number].name;
+		match layer {
+			Some(layer) => format!("{:02X}|{} v{}", instr, name, layer_velocity(layer, layers[number])),
+			None => format!("{:02X}|{}", instr, name)
+		}
+	};
+	let n_reverb_tracks = reverb_tracks.len();
+	let mut tracks = reverb_tracks;
+	tracks.extend(non_reverb_tracks);
+	let reverb = match reverb {
+		Some(values) => Some(OidosReverbDefines::new(values)),
+		None if n_reverb_tracks > 0 => return Err("Tracks use reverb, but no reverb is given".to_string()),
+		None => None
+	};
+
+	// Reorder instruments according to reverb
+	let with_reverb: HashSet<usize> = tracks[..n_reverb_tracks].iter().map(|t| t.instr).collect();
+	let without_reverb: HashSet<usize> = tracks[n_reverb_tracks..].iter().map(|t| t.instr).collect();
+	let mut instrument_order = Vec::new();
+	for instr in 0..variants.len() {
+		if with_reverb.contains(&instr) {
+			if without_reverb.contains(&instr) {
+				return Err(format!("Instrument '{}' is used both with and without reverb", title(instr)));
+			}
+			instrument_order.push(instr);
+		}
+	}
+	let n_reverb_instruments = instrument_order.len();
+	instrument_order.extend((0..variants.len()).filter(|n| without_reverb.contains(n)));
+
+	// Calculate track order, volumes and velocity quanta
+	let mut track_order = Vec::new();
+	let mut volumes = Vec::new();
+	let mut quanta = Vec::new();
+	for &number in &instrument_order {
+		let mut velocities = HashSet::new();
+		let mut volume: Option<[f64; 2]> = None;
+		for (ti, track) in tracks.iter().enumerate() {
+			if track.instr == number {
+				track_order.push(ti);
+				if volume.is_some_and(|v| v != track.volume) {
+					return Err(format!("Track '{}' has different volume/panning than previous tracks with same instrument", track.title));
+				}
+				volume = Some(track.volume);
+				velocities.extend(track.tavs.iter().map(|&(_, v)| v as u32));
+			}
+		}
+		let instr_volume = description.instruments[variants[number].0].volume;
+		let volume = volume.unwrap();
+		volumes.push([instr_volume * volume[0], instr_volume * volume[1]]);
+
+		let mut quantum = 128;
+		while quantum > 1 && !velocities.iter().all(|&v| v == 127 || v % quantum == 0) {
+			quantum /= 2;
+		}
+		quanta.push(quantum);
+	}
+	let uses_panning = volumes.iter().any(is_panned);
+	let uses_envelope = instrument_order.iter().any(|&number| uses_envelope(&instruments[variants[number].0].values));
+	let uses_keytrack = instrument_order.iter().any(|&number| uses_keytrack(&instruments[variants[number].0].values));
+	let uses_swell = instrument_order.iter().any(|&number| uses_swell(&instruments[variants[number].0].values));
+	let uses_formants = instrument_order.iter().any(|&number| uses_formants(&instruments[variants[number].0].values));
+	let uses_stiffness = instrument_order.iter().any(|&number| uses_stiffness(&instruments[variants[number].0].values));
+
+	// Calculate longest sample
+	let mut music_instruments = Vec::new();
+	let mut max_total_samples = 0;
+	let mut end_of_sound = 0.0f64;
+	for (i, &number) in instrument_order.iter().enumerate() {
+		let title = title(number);
+		let instr_tracks: Vec<&Track> = tracks.iter().filter(|t| t.instr == number).collect();
+		let max_length = instr_tracks.iter().map(|t| t.max_length).max().unwrap_or(0);
+		let latest_note = instr_tracks.iter().map(|t| t.latest_note).max().unwrap_or(0);
+		let mut tones: Vec<u8> = instr_tracks.iter().flat_map(|t| t.notes.iter().filter(|n| !n.off).map(|n| n.tone)).collect();
+		tones.sort();
+		tones.dedup();
+
+		let state = &instruments[variants[number].0];
+		let values = match variants[number].1 {
+			Some(layer) => OidosSoundParameters::layer_values(&state.values, layer),
+			None => state.values.clone()
+		};
+		let mut sound_params = OidosSoundParameters::build(&values, SAMPLERATE as f32);
+		sound_params.tune(&state.tuning);
+		let tuning = tones.iter().map(|&tone| (sound_params.tuned_tone(tone) - tone as f64) as f32).collect();
+
+		let context = OidosInstrumentContext {
+			max_note_length: max_length as f64 * ticklength,
+			volume: volumes[i],
+			velocity_quantum: quanta[i],
+			uses_panning: uses_panning,
+			uses_envelope: uses_envelope,
+			uses_keytrack: uses_keytrack,
+			uses_swell: uses_swell,
+			uses_formants: uses_formants,
+			uses_stiffness: uses_stiffness,
+			lowest_tone: tones.iter().map(|&tone| sound_params.tuned_tone(tone)).reduce(f64::min).unwrap_or(0.0)
+		};
+		let paramblock = make_param_block(&values, &context).map_err(|e| format!("Instrument '{}': {}", title, e))?;
+		let maxsamples = paramblock[BLOCK_MAXSAMPLES].to_bits() as f64;
+
+		let mut instr_end = latest_note as f64 * ticklength * SAMPLERATE + maxsamples;
+		if with_reverb.contains(&number) {
+			instr_end += reverb.as_ref().unwrap().halftime * 10.0 * SAMPLERATE;
+		}
+		max_total_samples = max_total_samples.max(maxsamples as u64 * tones.len() as u64);
+		end_of_sound = end_of_sound.max(instr_end);
+
+		music_instruments.push(Instrument {
+			number: number,
+			title: title,
+			columns: instr_tracks.len(),
+			tones: tones,
+			tuning: tuning,
+			velocity_quantum: quanta[i],
+			paramblock: paramblock
+		});
+	}
+
+	let length = midi.tracks.iter().map(|t| t.end).max().map_or(0, |end| {
+		(end as u64 * lines_per_beat as u64).div_ceil(midi.division as u64) as u32
+	}).max(tracks.iter().map(|t| t.notes.last().unwrap().line).max().unwrap_or(0));
+
+	// Write the music
+	let roundup = |v: f64| (v as i64 & -0x10000) + 0x10000;
+	let spt = (ticklength * SAMPLERATE) as i64;
+	let total_samples = (length as f64 * ticklength * SAMPLERATE).max(end_of_sound);
+
+	let mut out = String::new();
+	out += &format!("; Music converted from {}\n", source);
+	out += "\n";
+	out += &format!("%define MUSIC_LENGTH {}\n", length);
+	out += &format!("%define TOTAL_SAMPLES {}\n", roundup(total_samples));
+	out += &format!("%define MAX_TOTAL_INSTRUMENT_SAMPLES {}\n", roundup(max_total_samples as f64));
+	out += "\n";
+	out += &format!("%define SAMPLES_PER_TICK {}\n", spt);
+	out += &format!("%define TICKS_PER_SECOND {:.9}\n", 1.0 / ticklength);
+	out += "\n";
+	out += &format!("%define NUM_TRACKS_WITH_REVERB {}\n", n_reverb_instruments);
+	out += &format!("%define NUM_TRACKS_WITHOUT_REVERB {}\n", music_instruments.len() - n_reverb_instruments);
+	if n_reverb_instruments > 0 {
+		out += "\n";
+		out += &reverb.as_ref().unwrap().format();
+	}
+	if uses_panning {
+		out += "\n%define USES_PANNING\n";
+	}
+	let uses_tuning = music_instruments.iter().any(|instr| instr.tuning.iter().any(|&offset| offset != 0.0));
+	if uses_tuning {
+		out += "\n%define USES_TUNING\n";
+	}
+	if uses_envelope {
+		out += "\n%define USES_ENVELOPE\n";
+	}
+	if uses_keytrack {
+		out += "\n%define USES_KEYTRACK\n";
+	}
+	if uses_swell {
+		out += "\n%define USES_SWELL\n";
+	}
+	if uses_formants {
+		out += "\n%define USES_FORMANTS\n";
+	}
+	if uses_stiffness {
+		out += "\n%define USES_STIFFNESS\n";
+	}
+
+	// Instrument parameters
+	out += "\n\n\tSECTION_DATA(iparam) align=4\n";
+	out += "\nInstrumentParams:\n";
+	for instr in &music_instruments {
+		out += &format!(".i{:02}:\n", instr.number);
+		out += &format!("\t; {}\n", instr.title);
+		out += &format_param_block(&instr.paramblock);
+	}
+	out += "\n";
+
+	// Instrument tones
+	out += "\n\n\tSECTION_DATA(itones) align=1\n";
+	out += "\nInstrumentTones:\n";
+	for instr in &music_instruments {
+		out += &format!(".i{:02}:\n", instr.number);
+		out += &format!("\t; {}\n", instr.title);
+		out += "\tdb\t";
+		let mut prev_tone = 0;
+		for &tone in &instr.tones {
+			out += &format!("{},", tone as i32 - prev_tone);
+			prev_tone = tone as i32;
+		}
+		out += &format!("{}\n", -129 + instr.columns as i32);
+	}
+
+	// Tuning offsets of the tones
+	if uses_tuning {
+		out += "\n\n\tSECTION_DATA(ituning) align=4\n";
+		out += "\nInstrumentTuning:\n";
+		for instr in &music_instruments {
+			out += &format!(".i{:02}:\n", instr.number);
+			out += &format!("\t; {}\n", instr.title);
+			let offsets: Vec<PlayerValue> = instr.tuning.iter().map(|&offset| PlayerValue::Float(offset)).collect();
+			out += &format_param_block(&offsets);
+		}
+	}
+
+	// Track data
+	out += "\n\n\tSECTION_DATA(trdata) align=1\n";
+	out += "\nTrackData:\n";
+	for &ti in &track_order {
+		let track = &tracks[ti];
+		let instr = music_instruments.iter().find(|i| i.number == track.instr).unwrap();
+		out += &format!(".t_{}_{}_{}:\n", track.labelname, track.column, track.instr);
+		out += &format!("\t; {}\n", track.title);
+
+		// List tones and velocities
+		let mut tavdata = vec![track.singular_length.unwrap_or(0) as i32];
+		let mut prev_tone_id = 0;
+		for &(t, v) in &track.tavs {
+			let tone_id = instr.tones.iter().position(|&tone| tone == t).unwrap() as i32;
+			let quantum = instr.velocity_quantum as i32;
+			tavdata.extend(&[tone_id - prev_tone_id, (v as i32 + quantum / 2) / quantum]);
+			prev_tone_id = tone_id;
+		}
+		tavdata.push(-128);
+		dataline(&mut out, &tavdata);
+	}
+
+	// Lengths of notes
+	out += "\n\tSECTION_DATA(notelen) align=1\n";
+	out += "\nNoteLengths:\n";
+	notelist(&mut out, &tracks, &track_order, position_lines, |track, pn, n| {
+		match n {
+			Some(n) => {
+				let step = (track.notes[n].line - track.notes[pn].line) as i32;
+				if step > 127 { vec![-1 - (step >> 8), step & 255] } else { vec![step] }
+			},
+			None => vec![]
+		}
+	}, &[0], "L_");
+
+	// Samples for notes
+	out += "\n\tSECTION_DATA(notesamp) align=1\n";
+	out += "\nNoteSamples:\n";
+	notelist(&mut out, &tracks, &track_order, position_lines, |track, pn, _| {
+		match track.tav_index[pn] {
+			Some(index) => vec![1 + index as i32],
+			None => vec![0]
+		}
+	}, &[], "S_");
+
+	Ok(out)
+}
+
+#[cfg(test)]
+fn test_midi() -> MidiFile {
+	// 96 ticks per beat. The lead plays a chord of two notes.
+	parse_midi(&write_test_midi(96, &[
+		vec![(0, vec![0xFF, 0x51, 3, 0x07, 0xA1, 0x20]), (0, vec![0xFF, 0x2F, 0])],
+		vec![
+			(0, vec![0xFF, 0x03, 4, b'B', b'a', b's', b's']),
+			(0, vec![0x90, 36, 64]),
+			(96, vec![0x80, 36, 0]),
+			(96, vec![0x90, 41, 127]),
+			(96, vec![0x80, 41, 0]),
+			(96, vec![0xFF, 0x2F, 0])
+		],
+		vec![
+			(0, vec![0xFF, 0x03, 4, b'L', b'e', b'a', b'd']),
+			(48, vec![0x90, 60, 100]),
+			(0, vec![0x90, 64, 100]),
+			(432, vec![0x80, 60, 0]),
+			(0, vec![0x80, 64, 0]),
+			(0, vec![0xFF, 0x2F, 0])
+		]
+	])).unwrap()
+}
+
+/// Export the test song with the bass and the lead as separate instruments.
+#[cfg(test)]
+fn export_bass_and_lead(bass: Vec<f32>, lead: Vec<f32>) -> MusicAsm {
+	let description = parse_description("\
+		midi test.mid\n\
+		instrument Bass bass.txt\n\
+		instrument Lead lead.txt\n\
+		track Bass Bass\n\
+		track 2 Lead\n").unwrap();
+	let text = export_music(&description, &test_midi(), &[bass.into(), lead.into()], None, "test.mid").unwrap();
+	parse_music(&text).unwrap()
+}
+
+/// Export the test song with only the bass, changed from the default values, and check that the
+/// given feature is the only optional feature in use, with the given size of the parameter block.
+#[cfg(test)]
+fn export_bass_feature(changes: &[(usize, f32)], feature: &str, words: usize) -> InstrumentParams {
+	let description = parse_description("midi test.mid\ninstrument Bass bass.txt\ntrack Bass Bass\n").unwrap();
+	let mut bass = OidosSoundParameters::default_values();
+	for &(index, value) in changes {
+		bass[index] = value;
+	}
+	let text = export_music(&description, &test_midi(), &[bass.into()], None, "test.mid").unwrap();
+	let music = parse_music(&text).unwrap();
+	for &optional in &["USES_ENVELOPE", "USES_KEYTRACK", "USES_SWELL", "USES_FORMANTS", "USES_STIFFNESS"] {
+		assert_eq!(music.define(optional), if optional == feature { Some("") } else { None });
+	}
+	assert_eq!(music.section("iparam").unwrap().bytes().len(), words * 4);
+	decode_music(&music).unwrap().instruments.remove(0).params
+}
+
+#[test]
+fn test_parse_description() {
+	let description = parse_description("\
+		# Test song\n\
+		midi \"my song.mid\"\n\
+		lines_per_beat 8\n\
+		reverb hall.fxp\n\
+		instrument Bass bass.txt volume 0.5\n\
+		track 1 Bass channel 2 reverb pan 0.25 # Comment\n").unwrap();
+	assert_eq!(description.midi, "my song.mid");
+	assert_eq!(description.lines_per_beat, 8);
+	assert_eq!(description.reverb.as_ref().unwrap(), "hall.fxp");
+	assert_eq!(description.instruments[0].volume, 0.5);
+	let track = &description.tracks[0];
+	assert_eq!((track.track.as_str(), track.channel, track.reverb, track.pan), ("1", Some(1), true, 0.25));
+
+	assert!(parse_description("midi a.mid\nreverb a\nreverb b\n").is_err());
+	assert!(parse_description("midi a.mid\ntrack 1 Bass\n").is_err());
+	assert!(parse_description("midi a.mid\ninstrument Bass b.txt pan 0.5\n").is_err());
+	assert!(parse_description("lines_per_beat 4\n").is_err());
+}
+
+#[test]
+fn test_export_music() {
+	let midi = test_midi();
+	let mut bass = OidosSoundParameters::default_values();
+	bass[MODES] = 0.2;
+	let lead = OidosSoundParameters::default_values();
+	let description = parse_description("\
+		midi test.mid\n\
+		instrument Bass bass.txt\n\
+		instrument Lead lead.txt\n\
+		track Bass Bass\n\
+		track 2 Lead reverb pan 0.3\n").unwrap();
+	let text = export_music(&description, &midi, &[bass.clone().into(), lead.clone().into()], Some(&REVERB_DEFAULT_VALUES), "test.mid").unwrap();
+	let music = parse_music(&text).unwrap();
+
+	assert_eq!(music.define("MUSIC_LENGTH"), Some("20"));
+	assert_eq!(music.define("SAMPLES_PER_TICK"), Some("5512"));
+	assert_eq!(music.define("NUM_TRACKS_WITH_REVERB"), Some("1"));
+	assert_eq!(music.define("NUM_TRACKS_WITHOUT_REVERB"), Some("1"));
+	assert_eq!(music.define("REVERB_NUM_DELAYS"), Some("64"));
+	assert_eq!(music.define("USES_PANNING"), Some(""));
+	assert_eq!(music.define("USES_ENVELOPE"), None);
+
+	// The instrument with reverb comes first
+	let params = music.section("iparam").unwrap();
+	assert_eq!(params.blocks.iter().map(|b| b.label.as_str()).collect::<Vec<_>>(), vec!["InstrumentParams", ".i01", ".i00"]);
+	assert_eq!(params.blocks[1].title.as_ref().unwrap(), "01|Lead");
+	assert_eq!(params.blocks[2].bytes[0], 20);
+	assert_eq!(params.blocks[2].bytes.len(), 21 * 4);
+
+	// The lead plays two tones in two columns, the bass two tones in one column
+	assert_eq!(music.section("itones").unwrap().bytes(), vec![60, 4, (-127i8) as u8, 36, 5, (-128i8) as u8]);
+
+	// Singular lengths. The lead velocity is quantized to 4, the bass velocities to 64.
+	let trdata = music.section("trdata").unwrap();
+	let bytes = |label: &str| trdata.blocks.iter().find(|b| b.label == label).unwrap().bytes.clone();
+	assert_eq!(bytes(".t_Lead_1_1"), vec![18, 0, 25, 128]);
+	assert_eq!(bytes(".t_Lead_2_1"), vec![18, 1, 25, 128]);
+	assert_eq!(bytes(".t_Bass_1_0"), vec![4, 0, 1, 1, 2, 128]);
+
+	let notelen = music.section("notelen").unwrap();
+	let lengths = |label: &str| notelen.blocks.iter().find(|b| b.label == label).unwrap().bytes.clone();
+	assert_eq!(lengths("L_Lead_1_1"), vec![2, 0]);
+	assert_eq!(lengths("L_Bass_1_0"), vec![8, 0]);
+	let notesamp = music.section("notesamp").unwrap();
+	let samples = |label: &str| notesamp.blocks.iter().find(|b| b.label == label).unwrap().bytes.clone();
+	assert_eq!(samples("S_Lead_1_1"), vec![0, 1]);
+	assert_eq!(samples("S_Bass_1_0"), vec![1, 2]);
+
+	// Constraints
+	let both = parse_description("midi t\ninstrument Bass b\ntrack Bass Bass\ntrack Lead Bass reverb\n").unwrap();
+	assert!(export_music(&both, &midi, &[bass.clone().into()], Some(&REVERB_DEFAULT_VALUES), "").is_err());
+	let volumes = parse_description("midi t\ninstrument Bass b\ntrack Bass Bass\ntrack Lead Bass volume 0.5\n").unwrap();
+	assert!(export_music(&volumes, &midi, &[bass.clone().into()], None, "").is_err());
+	let no_reverb = parse_description("midi t\ninstrument Lead l\ntrack Lead Lead reverb\n").unwrap();
+	assert!(export_music(&no_reverb, &midi, &[lead.clone().into()], None, "").is_err());
+	let twice = parse_description("midi t\ninstrument Lead l\ntrack Lead Lead\ntrack 2 Lead\n").unwrap();
+	assert!(export_music(&twice, &midi, &[lead.into()], None, "").is_err());
+	let mut infinite = OidosSoundParameters::default_values();
+	infinite[DECAYLOW] = 1.0;
+	infinite[DECAYHIGH] = 1.0;
+	infinite[RELEASE] = 0.0;
+	infinite[Q_RELEASE] = 1.0;
+	let single = parse_description("midi t\ninstrument Lead l\ntrack Lead Lead\n").unwrap();
+	assert!(export_music(&single, &midi, &[infinite.into()], None, "").unwrap_err().contains("infinite"));
+}
+
+#[test]
+fn test_export_velocity_layers() {
+	let midi = test_midi();
+	let mut bass = OidosSoundParameters::default_values();
+	bass[MODES] = 0.2;
+	bass[VELLAYERS] = 4.0 / 16.0;
+	bass[VELSHARPNESS] = 1.0;
+	let lead = OidosSoundParameters::default_values();
+	let description = parse_description("\
+		midi test.mid\n\
+		instrument Bass bass.txt\n\
+		instrument Lead lead.txt\n\
+		track Bass Bass\n\
+		track 2 Lead\n").unwrap();
+	let text = export_music(&description, &midi, &[bass.clone().into(), lead.into()], None, "test.mid").unwrap();
+	let music = parse_music(&text).unwrap();
+
+	// The bass velocities 64 and 127 are in layers 2 and 3, each played by its own instrument
+	assert_eq!(music.define("NUM_TRACKS_WITHOUT_REVERB"), Some("3"));
+	let params = music.section("iparam").unwrap();
+	let titles: Vec<&str> = params.blocks[1..].iter().map(|b| b.title.as_ref().unwrap().as_str()).collect();
+	assert_eq!(titles, vec!["00|Bass v95", "01|Bass v127", "02|Lead"]);
+	let sharpness = |block: usize| f32::from_bits(u32::from_le_bytes([0, 1, 2, 3].map(|i| params.blocks[block].bytes[7 * 4 + i])));
+	assert!(sharpness(1) < sharpness(2));
+	assert_eq!(music.section("itones").unwrap().bytes(), vec![36, (-128i8) as u8, 41, (-128i8) as u8, 60, 4, (-127i8) as u8]);
+
+	// Each layer plays its own note, the top layer being off until its note
+	let notesamp = music.section("notesamp").unwrap();
+	let samples = |label: &str| notesamp.blocks.iter().find(|b| b.label == label).unwrap().bytes.clone();
+	assert_eq!(samples("S_Bass_1_0"), vec![1]);
+	assert_eq!(samples("S_Bass_1_1"), vec![0, 1]);
+}
+
+#[test]
+fn test_export_tuning() {
+	let midi = test_midi();
+	let description = parse_description("\
+		midi test.mid\n\
+		instrument Bass bass.txt\n\
+		instrument Lead lead.txt\n\
+		track Bass Bass\n\
+		track 2 Lead\n").unwrap();
+	let bass = InstrumentState::from(OidosSoundParameters::default_values());
+	let text = export_music(&description, &midi, &[bass.clone(), bass.clone()], None, "test.mid").unwrap();
+	assert_eq!(parse_music(&text).unwrap().define("USES_TUNING"), None);
+
+	let mut lead = bass.clone();
+	lead.tuning.set_tone(60, 60.25);
+	lead.values[REFPITCH] = 0.5;
+	let text = export_music(&description, &midi, &[bass, lead], None, "test.mid").unwrap();
+	let music = parse_music(&text).unwrap();
+	assert_eq!(music.define("USES_TUNING"), Some(""));
+	// One offset for each tone, in the order of the tones
+	let offsets: Vec<f32> = music.section("ituning").unwrap().bytes().chunks(4)
+		.map(|b| f32::from_bits(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))).collect();
+	assert_eq!(offsets, vec![0.0, 0.0, 0.25, 0.0]);
+
+	let state = parse_instrument_state(b"modes 0.5\ntune 60 60.25\n").unwrap();
+	assert_eq!(state.values[MODES], 0.5);
+	assert_eq!(state.tuning.tone(60), 60.25);
+	let preset = format_fxp_chunk(0x50D10, 2100, "Lead", b"tune 60 60.25\n");
+	assert_eq!(parse_instrument_state(&preset).unwrap().tuning.tone(60), 60.25);
+	// The morph controls saved with the patch are not part of it
+	let state = parse_instrument_state(b"modes 0.5\nsnapshot A modes 0.25\nmorph 0.5 0.5\n").unwrap();
+	assert_eq!(state.values[MODES], 0.5);
+}
+
+#[test]
+fn test_export_envelope() {
+	// A sustain level of half, reached at once after the attack
+	let params = export_bass_feature(&[(SUSTAIN, 0.5)], "USES_ENVELOPE", 26);
+	assert_eq!((params.decay, params.sustain), (2.0, 0.5));
+}
+
+#[test]
+fn test_export_keytrack() {
+	let params = export_bass_feature(&[(FILTERTRACK, 0.5)], "USES_KEYTRACK", 23);
+	assert_eq!((params.track_pivot, params.decay_track, params.filter_track), (60.0, 0.0, 0.5));
+}
+
+#[test]
+fn test_export_keytrack_zero_decay() {
+	let mut bass = OidosSoundParameters::default_values();
+	bass[DECAYTRACK] = 0.5;
+	// Without decay tracking, the lead may decay instantly
+	let mut lead = OidosSoundParameters::default_values();
+	lead[DECAYLOW] = 0.0;
+	lead[DECAYHIGH] = 0.0;
+	let music = export_bass_and_lead(bass, lead);
+	assert_eq!(music.define("USES_KEYTRACK"), Some(""));
+	let instruments = decode_music(&music).unwrap().instruments;
+	let lead = instruments.iter().find(|i| i.params.decaylow == 0.0).unwrap();
+	// The player skips the decay tracking when the word is exactly zero
+	assert_eq!(lead.params.decay_track.to_bits(), 0);
+	assert_eq!(lead.params.maxsamples, 0);
+}
+
+#[test]
+fn test_export_swell() {
+	let params = export_bass_feature(&[(SWELL, 0.5), (SWELLSPREAD, 0.25)], "USES_SWELL", 23);
+	assert_eq!((params.swell_slope, params.swell_spread), (0.0, 3.0));
+	assert!((params.swell * 22050.0 - 1.0).abs() < 1e-6);
+}
+
+#[test]
+fn test_export_swell_off() {
+	let mut bass = OidosSoundParameters::default_values();
+	bass[SWELL] = 0.5;
+	// The slope and spread of the lead have no effect without swell
+	let mut lead = OidosSoundParameters::default_values();
+	lead[SWELLSLOPE] = 1.0;
+	lead[SWELLSPREAD] = 0.5;
+	let music = export_bass_and_lead(bass, lead.clone());
+	assert_eq!(music.define("USES_SWELL"), Some(""));
+	let instruments = decode_music(&music).unwrap().instruments;
+	let player = instruments.iter().find(|i| i.params.swell == 0.0).unwrap();
+
+	// The player sound matches the plugin from the first sample
+	let tone = player.tones[0];
+	let sound = make_tone(&player.params, player.tuned_tone(0), &random_data());
+	let param = OidosSoundParameters::build(&lead, 44100.0);
+	let mut generator = OidosSoundGenerator::new(&param, tone as u8, 0, &OidosRandomData::default());
+	for &s in &sound[..1000] {
+		assert!((generator.produce_sample() as f64 - s).abs() < 1e-3);
+	}
+	assert!(sound[0] != 0.0);
+}
+
+#[test]
+fn test_export_formants() {
+	let params = export_bass_feature(&[(FWIDTH2, 0.25), (FGAIN2, 0.0)], "USES_FORMANTS", 27);
+	assert_eq!(params.formants[0], (63.5, 0.0, 0.0));
+	assert_eq!((params.formants[1].0, params.formants[1].1), (63.5, 1.0 / 12.0));
+	assert!((params.formants[1].2 - (10f32.powf(-24.0 / 20.0) - 1.0)).abs() < 1e-6);
+	assert_eq!(params.formant_sweep, 0.0);
+}
+
+#[test]
+fn test_export_stiffness() {
+	let params = export_bass_feature(&[(STIFFNESS, 0.5)], "USES_STIFFNESS", 21);
+	assert_eq!(params.stiffness, 0.1 / 16.0);
+}
@@ -0,0 +1,257 @@
+use std::f32::consts::PI;
+
+use generate::{Sample, SoundGenerator};
+use mutate::Mutator;
+use render::render_note;
+#[cfg(test)] use generate::SoundParameters;
+#[cfg(test)] use oidos_generate::{OidosRandomData, OidosSoundGenerator, OidosSoundParameters, DECAYHIGH, DECAYLOW, FAT, MODES};
+
+
+const FRAME_SIZE: usize = 1024;
+const N_BANDS: usize = 24;
+const LOWEST_FREQUENCY: f32 = 50.0;
+const HIGHEST_FREQUENCY: f32 = 16000.0;
+/// Levels below this are considered silence
+const FLOOR_DB: f32 = -90.0;
+/// Weight of the level envelope (the decay) relative to the spectral envelope
+const DECAY_WEIGHT: f32 = 1.0;
+
+/// Strength of the mutations in the first and last generations of the search
+const INITIAL_STRENGTH: f32 = 0.5;
+const FINAL_STRENGTH: f32 = 0.05;
+
+/// Spectral envelope of a sound over time: the level of a set of logarithmically
+/// spaced frequency bands for consecutive frames of the sound.
+pub struct SoundProfile {
+	/// Band levels in dB relative to the level of the frame
+	shapes: Vec<[f32; N_BANDS]>,
+	/// Total level of each frame in dB
+	levels: Vec<f32>
+}
+
+impl SoundProfile {
+	pub fn analyze(samples: &[Sample], sample_rate: f32) -> SoundProfile {
+		let nyquist = sample_rate / 2.0;
+		let top = HIGHEST_FREQUENCY.min(nyquist);
+		let band_of_bin = |bin: usize| -> Option<usize> {
+			let freq = bin as f32 * sample_rate / FRAME_SIZE as f32;
+			if freq < LOWEST_FREQUENCY || freq >= top {
+				return None;
+			}
+			Some(((freq / LOWEST_FREQUENCY).ln() / (top / LOWEST_FREQUENCY).ln() * N_BANDS as f32) as usize)
+		};
+		let window: Vec<f32> = (0..FRAME_SIZE).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FRAME_SIZE as f32).cos()).collect();
+
+		let mut shapes = Vec::new();
+		let mut levels = Vec::new();
+		for frame in samples.chunks(FRAME_SIZE) {
+			let mut re = vec![0f32; FRAME_SIZE];
+			let mut im = vec![0f32; FRAME_SIZE];
+			for (i, s) in frame.iter().enumerate() {
+				re[i] = (s.left + s.right) * 0.5 * window[i];
+			}
+			fft(&mut re, &mut im);
+
+			let mut energies = [0f32; N_BANDS];
+			for bin in 0..FRAME_SIZE / 2 {
+				if let Some(band) = band_of_bin(bin) {
+					energies[band] += re[bin] * re[bin] + im[bin] * im[bin];
+				}
+			}
+			let scale = 4.0 / (FRAME_SIZE * FRAME_SIZE) as f32;
+			let level = decibel(energies.iter().sum::<f32>() * scale);
+			let mut shape = [0f32; N_BANDS];
+			for band in 0..N_BANDS {
+				shape[band] = decibel(energies[band] * scale).max(level + FLOOR_DB) - level;
+			}
+			shapes.push(shape);
+			levels.push(level);
+		}
+		SoundProfile {
+			shapes: shapes,
+			levels: levels
+		}
+	}
+
+	/// Distance from this (target) profile to the profile of another sound. Frames
+	/// missing from the other sound count as silence. The spectral shape of a frame
+	/// counts less the quieter the frame is in the target.
+	pub fn distance(&self, other: &SoundProfile) -> f32 {
+		let mut sum = 0f32;
+		for (i, (shape, &level)) in self.shapes.iter().zip(&self.levels).enumerate() {
+			let other_level = other.levels.get(i).cloned().unwrap_or(FLOOR_DB);
+			let level_diff = level - other_level;
+			let shape_diff = match other.shapes.get(i) {
+				Some(other_shape) => shape.iter().zip(other_shape).map(|(a, b)| (a - b) * (a - b)).sum::<f32>() / N_BANDS as f32,
+				None => FLOOR_DB * FLOOR_DB
+			};
+			let weight = ((level - FLOOR_DB) / -FLOOR_DB).clamp(0.0, 1.0);
+			sum += shape_diff * weight + level_diff * level_diff * DECAY_WEIGHT;
+		}
+		sum / self.levels.len().max(1) as f32
+	}
+}
+
+fn decibel(energy: f32) -> f32 {
+	(10.0 * energy.log10()).max(FLOOR_DB)
+}
+
+/// In-place radix-2 FFT. The length must be a power of two.
+fn fft(re: &mut [f32], im: &mut [f32]) {
+	let n = re.len();
+	let mut j = 0;
+	for i in 1..n {
+		let mut bit = n >> 1;
+		while j & bit != 0 {
+			j ^= bit;
+			bit >>= 1;
+		}
+		j |= bit;
+		if i < j {
+			re.swap(i, j);
+			im.swap(i, j);
+		}
+	}
+	let mut len = 2;
+	while len <= n {
+		let angle = -2.0 * PI / len as f32;
+		for start in (0..n).step_by(len) {
+			for k in 0..len / 2 {
+				let (wr, wi) = ((angle * k as f32).cos(), (angle * k as f32).sin());
+				let (a, b) = (start + k, start + k + len / 2);
+				let tr = re[b] * wr - im[b] * wi;
+				let ti = re[b] * wi + im[b] * wr;
+				re[b] = re[a] - tr;
+				im[b] = im[a] - ti;
+				re[a] += tr;
+				im[a] += ti;
+			}
+		}
+		len <<= 1;
+	}
+}
+
+pub struct FitSettings {
+	/// Tone at which the candidates are played
+	pub tone: u8,
+	pub population: usize,
+	pub generations: usize,
+	/// Parameters kept at their starting values
+	pub locks: Vec<bool>,
+	pub seed: u32
+}
+
+#[derive(Clone)]
+pub struct Candidate {
+	pub values: Vec<f32>,
+	/// Distance to the target. Lower is better.
+	pub score: f32
+}
+
+fn evaluate<G: SoundGenerator>(values: Vec<f32>, target: &SoundProfile, length: usize, tone: u8,
+                               sample_rate: f32, global: &G::Global) -> Candidate {
+	let samples = render_note::<G>(&values, tone, 127, length, length, sample_rate, global);
+	let score = target.distance(&SoundProfile::analyze(&samples, sample_rate));
+	Candidate {
+		values: values,
+		// A sound which cannot be compared ranks last
+		score: if score.is_nan() { f32::INFINITY } else { score }
+	}
+}
+
+/// Search for parameters making the generator sound like the target sound, using
+/// a genetic search starting from the `start` patch. Each candidate is rendered
+/// for the length of the target and compared by spectral envelope and decay.
+/// `progress` is called with the best candidate after each generation.
+/// Returns the final population, best candidate first.
+pub fn fit<G: SoundGenerator, F: FnMut(usize, &Candidate)>(target: &[Sample], sample_rate: f32, start: &[f32],
+                                                           settings: &FitSettings, global: &G::Global, mut progress: F) -> Vec<Candidate> {
+	let profile = SoundProfile::analyze(target, sample_rate);
+	let length = target.len();
+	let population = settings.population.max(2);
+	let elite = (population / 4).max(1);
+	let mut mutator = Mutator::new(settings.seed);
+	let mutant = |mutator: &mut Mutator, values: &[f32], strength: f32| {
+		mutator.mutate_usable::<G>(values, &settings.locks, strength, settings.tone, sample_rate, global)
+	};
+
+	let mut candidates = vec![evaluate::<G>(start.to_vec(), &profile, length, settings.tone, sample_rate, global)];
+	while candidates.len() < population {
+		match mutant(&mut mutator, start, 1.0) {
+			Some(values) => candidates.push(evaluate::<G>(values, &profile, length, settings.tone, sample_rate, global)),
+			None => break
+		}
+	}
+	candidates.sort_by(|a, b| a.score.total_cmp(&b.score));
+
+	for generation in 0..settings.generations {
+		let t = generation as f32 / settings.generations.max(2).saturating_sub(1) as f32;
+		let strength = INITIAL_STRENGTH * (FINAL_STRENGTH / INITIAL_STRENGTH).powf(t);
+		candidates.truncate(elite);
+		let parents = candidates.len();
+		let mut children = Vec::new();
+		for i in 0..population - parents {
+			let a = &candidates[i % parents].values;
+			let b = &candidates[(i / parents + i + 1) % parents].values;
+			let child = mutator.crossover::<G::Parameters>(a, b, &settings.locks);
+			if let Some(values) = mutant(&mut mutator, &child, strength) {
+				children.push(evaluate::<G>(values, &profile, length, settings.tone, sample_rate, global));
+			}
+		}
+		candidates.extend(children);
+		candidates.sort_by(|a, b| a.score.total_cmp(&b.score));
+		progress(generation, &candidates[0]);
+	}
+	candidates
+}
+
+#[test]
+fn test_fft() {
+	let n = 64;
+	let mut re: Vec<f32> = (0..n).map(|i| (2.0 * PI * 5.0 * i as f32 / n as f32).cos()).collect();
+	let mut im = vec![0f32; n];
+	fft(&mut re, &mut im);
+	for k in 0..n {
+		let magnitude = (re[k] * re[k] + im[k] * im[k]).sqrt();
+		let expected = if k == 5 || k == n - 5 { n as f32 / 2.0 } else { 0.0 };
+		assert!((magnitude - expected).abs() < 0.01);
+	}
+}
+
+#[test]
+fn test_fit() {
+	let random = OidosRandomData::default();
+	let sample_rate = 22050.0;
+	let mut target_values = OidosSoundParameters::default_values();
+	target_values[MODES] = 0.05;
+	target_values[FAT] = 0.02;
+	target_values[DECAYLOW] = 0.9;
+	target_values[DECAYHIGH] = 0.85;
+	let length = 4 * FRAME_SIZE;
+	let target = render_note::<OidosSoundGenerator>(&target_values, 60, 127, length, length, sample_rate, &random);
+
+	let profile = SoundProfile::analyze(&target, sample_rate);
+	assert_eq!(profile.distance(&profile), 0.0);
+	assert!(profile.distance(&SoundProfile::analyze(&[], sample_rate)) > 0.0);
+
+	let mut start = target_values.clone();
+	start[DECAYLOW] = 0.5;
+	start[DECAYHIGH] = 0.5;
+	let mut locks = vec![false; start.len()];
+	locks[MODES] = true;
+	locks[FAT] = true;
+	let settings = FitSettings {
+		tone: 60,
+		population: 6,
+		generations: 3,
+		locks: locks,
+		seed: 1
+	};
+	let start_score = evaluate::<OidosSoundGenerator>(start.clone(), &profile, length, 60, sample_rate, &random).score;
+	let mut best_scores = Vec::new();
+	let result = fit::<OidosSoundGenerator, _>(&target, sample_rate, &start, &settings, &random, |_, best| best_scores.push(best.score));
+	assert_eq!(best_scores.len(), 3);
+	assert!(best_scores.windows(2).all(|w| w[1] <= w[0]));
+	assert!(result[0].score <= start_score);
+	assert_eq!(result[0].values[MODES], start[MODES]);
+}
@@ -1,5 +1,7 @@
 
-use std::ops::{Add, AddAssign, Index, Mul, MulAssign};
+use std::ops::{Add, AddAssign, Mul, MulAssign};
+
+use tuning::Tuning;
 
 #[derive(Clone, Copy)]
 pub struct Sample {
@@ -52,21 +54,284 @@ impl From<f32> for Sample {
 	}
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParameterGroup {
+	Partials,
+	Decay,
+	Filter,
+	Amplitude,
+	Velocity,
+	Tuning,
+	Quantization,
+	Unused
+}
+
+/// Description of a sound parameter. Parameter values are always in the 0-1 range
+/// used by the plugin, but are displayed mapped to `range` in `unit`.
+pub struct ParameterDescriptor {
+	/// Position of the parameter in the parameter list
+	pub index: usize,
+	pub name: &'static str,
+	pub default: f32,
+	/// Displayed values at parameter values 0 and 1
+	pub range: (f32, f32),
+	pub unit: &'static str,
+	/// Number of steps for parameters with integer values
+	pub steps: Option<u32>,
+	pub group: ParameterGroup,
+	/// Parameters whose displayed values depend on this one
+	pub influences: &'static [usize]
+}
+
+impl ParameterDescriptor {
+	/// Integer value of a parameter with integer steps, limited below by the range.
+	pub fn integer_value(&self, value: f32) -> u32 {
+		let steps = self.steps.unwrap_or(1) as f32;
+		(value * steps + 0.5).floor().max(self.range.0) as u32
+	}
+}
+
+/// Descriptor of a parameter, in the columns of a schema table.
+macro_rules! param {
+	($index:expr, $name:expr, $default:expr, $range:expr, $unit:expr, $steps:expr, $group:ident, $influences:expr) => {
+		$crate::generate::ParameterDescriptor {
+			index: $index,
+			name: $name,
+			default: $default,
+			range: $range,
+			unit: $unit,
+			steps: $steps,
+			group: $crate::generate::ParameterGroup::$group,
+			influences: $influences
+		}
+	}
+}
+
 pub trait SoundParameters {
-	fn names() -> &'static [&'static str];
-	fn default_value(name: &str) -> f32;
-	fn influence(name: &'static str) -> Vec<&'static str>;
-	fn display<P: Index<&'static str, Output = f32>>(&self, name: &'static str, p: &P) -> (String, String);
-	fn build<P: Index<&'static str, Output = f32>>(p: &P, sample_rate: f32) -> Self;
-	fn attack<P: Index<&'static str, Output = f32>>(p: &P, sample_rate: f32) -> f32;
-	fn release<P: Index<&'static str, Output = f32>>(p: &P, sample_rate: f32) -> f32;
+	fn schema() -> &'static [ParameterDescriptor];
+	fn display(&self, index: usize, p: &[f32]) -> String;
+	/// Displayed value of a parameter, disregarding quantization.
+	fn to_display(index: usize, value: f32) -> f32;
+	/// Parameter value for a displayed value. Inverse of `to_display`.
+	fn from_display(index: usize, display: f32) -> f32;
+	fn build(p: &[f32], sample_rate: f32) -> Self;
+	fn attack(p: &[f32], sample_rate: f32) -> f32;
+	fn release(p: &[f32], sample_rate: f32) -> f32;
+
+	/// Amplitude envelope of the notes, by default the linear attack and release.
+	fn envelope(p: &[f32], sample_rate: f32) -> Envelope {
+		Envelope::linear(Self::attack(p, sample_rate), Self::release(p, sample_rate))
+	}
+
+	/// Number of samples after which the sound can be cut off, or `None` if the sound never ends.
+	fn duration(&self) -> Option<usize>;
+
+	/// Number of velocity layers with distinct sounds.
+	fn velocity_layers(_p: &[f32]) -> usize {
+		1
+	}
+
+	/// Parameter values of the sound played in a velocity layer.
+	/// The top layer plays the patch itself.
+	fn layer_values(p: &[f32], _layer: usize) -> Vec<f32> {
+		p.to_vec()
+	}
+
+	/// Play the keys at the tones given by the tuning.
+	fn tune(&mut self, _tuning: &Tuning) {}
+
+	fn default_values() -> Vec<f32> {
+		Self::schema().iter().map(|desc| desc.default).collect()
+	}
+
+	/// Parse a parameter value typed in display units, such as `250 ms half` or `+3`.
+	fn parse(index: usize, text: &str) -> Option<f32> {
+		parse_number(text).map(|display| Self::from_display(index, display))
+	}
+
+	/// Parse a parameter value typed into a patch with the given values. A quantization
+	/// parameter shows the quantized value of the parameter it quantizes, so typing a value
+	/// picks the quantization level showing the nearest value, the coarsest one if several
+	/// are equally near. The number of bits to round off can be typed as `12 bits`.
+	fn parse_in_patch(index: usize, text: &str, p: &[f32], sample_rate: f32) -> Option<f32> where Self: Sized {
+		if Self::schema()[index].group != ParameterGroup::Quantization {
+			return Self::parse(index, text);
+		}
+		if let Some(bits) = parse_bits(text) {
+			return Some(Self::from_display(index, bits));
+		}
+		let target = parse_number(text)?;
+		let mut values = p.to_vec();
+		let mut nearest: Option<(f32, f32)> = None;
+		for bits in 0..32 {
+			values[index] = Self::from_display(index, bits as f32);
+			let shown = match parse_number(&Self::build(&values, sample_rate).display(index, &values)) {
+				Some(shown) => shown,
+				None => continue
+			};
+			// Infinite decay times are equally near to each other
+			let distance = if shown == target { 0.0 } else { (shown - target).abs() };
+			if nearest.is_none_or(|(d, _)| distance <= d) {
+				nearest = Some((distance, values[index]));
+			}
+		}
+		nearest.map(|(_, value)| value)
+	}
 }
 
 pub trait SoundGenerator {
 	type Parameters: SoundParameters + PartialEq + Clone + Send + Sync;
 	type Output: Default + Copy + Into<Sample>;
-	type Global: Default;
+	type Global: Default + Send + Sync;
 
 	fn new(param: &Self::Parameters, tone: u8, time: usize, global: &Self::Global) -> Self;
 	fn produce_sample(&mut self) -> Self::Output;
+
+	/// Names of read-only parameters displaying diagnostics about the sound.
+	fn diagnostic_names() -> &'static [&'static str];
+	/// Value and label texts of the diagnostic parameters for the sound played at the given tone.
+	fn diagnostics(param: &Self::Parameters, tone: u8, global: &Self::Global) -> Vec<(String, String)>;
+
+	/// Whether the sound played at the given tone is loud enough to be heard.
+	fn is_audible(param: &Self::Parameters, tone: u8, global: &Self::Global) -> bool;
+}
+
+/// Velocity layer playing notes of the given velocity when the velocities
+/// are split into `layers` ranges of equal size.
+pub fn velocity_layer(velocity: u8, layers: usize) -> usize {
+	(velocity as usize * layers / 128).min(layers - 1)
+}
+
+/// Highest velocity played by a velocity layer.
+pub fn layer_velocity(layer: usize, layers: usize) -> u8 {
+	(((layer + 1) * 128 - 1) / layers) as u8
+}
+
+/// Bend a linear envelope stage going between 0 and 1. A curve of 0 keeps the stage linear,
+/// 1 bends it into the square of the level (the attack starts slowly, the decay and release
+/// fall quickly at first) and -1 bends it the opposite way.
+pub fn envelope_curve(x: f32, curve: f32) -> f32 {
+	x * (1.0 + curve * (x - 1.0))
+}
+
+/// Amplitude envelope of a note: attack, hold, decay, sustain and release.
+/// Rates are the change in level per sample.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Envelope {
+	pub attack: f32,
+	pub attack_curve: f32,
+	/// Samples at full level after the attack
+	pub hold: f32,
+	pub decay: f32,
+	pub decay_curve: f32,
+	/// Level reached by the decay, 1 for no decay
+	pub sustain: f32,
+	pub release: f32,
+	pub release_curve: f32
+}
+
+impl Envelope {
+	/// Linear attack and release around a full sustain level.
+	pub fn linear(attack: f32, release: f32) -> Envelope {
+		Envelope {
+			attack: attack,
+			attack_curve: 0.0,
+			hold: 0.0,
+			decay: 2.0,
+			decay_curve: 0.0,
+			sustain: 1.0,
+			release: release,
+			release_curve: 0.0
+		}
+	}
+
+	/// Time at which the decay starts, in samples.
+	fn decay_start(&self) -> f32 {
+		1.0 / self.attack + self.hold
+	}
+
+	/// Level of the attack and release, the lower of the two, at a time after the start of the note.
+	pub fn attack_release_level(&self, time: usize, release_time: Option<usize>) -> f32 {
+		let attack = (time as f32 * self.attack).min(1.0);
+		let release = match release_time {
+			Some(t) if time >= t => (1.0 - (time - t) as f32 * self.release).max(0.0),
+			_ => 1.0
+		};
+		envelope_curve(attack, self.attack_curve).min(envelope_curve(release, self.release_curve))
+	}
+
+	/// Level of the hold, decay and sustain stages at a time after the start of the note.
+	pub fn decay_level(&self, time: usize) -> f32 {
+		let decay = (1.0 - (time as f32 - self.decay_start()) * self.decay).max(0.0).min(1.0);
+		self.sustain + (1.0 - self.sustain) * envelope_curve(decay, self.decay_curve)
+	}
+
+	/// Amplitude of a note at a time after its start, released at `release_time`.
+	pub fn amplitude(&self, time: usize, release_time: Option<usize>) -> f32 {
+		self.attack_release_level(time, release_time) * self.decay_level(time)
+	}
+
+	/// Whether the release of a note released at `release_time` has finished.
+	pub fn is_released(&self, time: usize, release_time: usize) -> bool {
+		time >= release_time && 1.0 - (time - release_time) as f32 * self.release <= 0.0
+	}
+
+	/// Time after which the envelope stays silent, if the sustain level is zero.
+	pub fn silence_time(&self) -> Option<f32> {
+		if self.sustain == 0.0 { Some(self.decay_start() + 1.0 / self.decay) } else { None }
+	}
+}
+
+/// Parse a number of bits typed as `12 bits` or `12bits`.
+pub fn parse_bits(text: &str) -> Option<f32> {
+	let text = text.trim();
+	let number = text.strip_suffix("bits").or_else(|| text.strip_suffix("bit"))?;
+	parse_number(number)
+}
+
+/// Parse the number at the start of a text, ignoring any unit after it.
+pub fn parse_number(text: &str) -> Option<f32> {
+	let word = text.split_whitespace().next()?;
+	let number = match word.parse::<f32>() {
+		Ok(value) => value,
+		// The unit may follow the number without a space
+		Err(_) => word.trim_end_matches(char::is_alphabetic).parse::<f32>().ok()?
+	};
+	if number.is_nan() { None } else { Some(number) }
+}
+
+#[test]
+fn test_parse_bits() {
+	assert_eq!(parse_bits("12 bits"), Some(12.0));
+	assert_eq!(parse_bits("1bit"), Some(1.0));
+	assert_eq!(parse_bits("12"), None);
+	assert_eq!(parse_bits("bits"), None);
+}
+
+#[test]
+fn test_velocity_layers() {
+	assert_eq!((1..128).map(|v| velocity_layer(v, 1)).max(), Some(0));
+	assert_eq!([1, 31, 32, 64, 127].iter().map(|&v| velocity_layer(v, 4)).collect::<Vec<_>>(), vec![0, 0, 1, 2, 3]);
+	assert_eq!((0..4).map(|l| layer_velocity(l, 4)).collect::<Vec<_>>(), vec![31, 63, 95, 127]);
+	for layers in 1..17 {
+		for layer in 0..layers {
+			assert_eq!(velocity_layer(layer_velocity(layer, layers), layers), layer);
+		}
+	}
+}
+
+#[test]
+fn test_envelope() {
+	let linear = Envelope::linear(0.25, 0.5);
+	assert_eq!((0..6).map(|t| linear.amplitude(t, Some(4))).collect::<Vec<_>>(), vec![0.0, 0.25, 0.5, 0.75, 1.0, 0.5]);
+	assert!(!linear.is_released(5, 4) && linear.is_released(6, 4));
+	assert_eq!(linear.silence_time(), None);
+
+	// Attack over 4 samples, hold for 2, decay over 4 samples to half level
+	let envelope = Envelope { hold: 2.0, decay: 0.25, sustain: 0.5, attack_curve: 1.0, decay_curve: -1.0, .. linear };
+	assert_eq!(envelope.amplitude(2, None), 0.25);
+	assert_eq!((4..12).map(|t| envelope.decay_level(t)).collect::<Vec<_>>(), vec![1.0, 1.0, 1.0, 0.96875, 0.875, 0.71875, 0.5, 0.5]);
+	assert_eq!(Envelope { sustain: 0.0, .. envelope }.silence_time(), Some(10.0));
+	assert_eq!(envelope_curve(0.5, 0.0), 0.5);
+	assert_eq!(envelope_curve(0.5, -1.0), 0.75);
 }
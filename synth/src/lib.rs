@@ -2,12 +2,29 @@
 #![allow(non_snake_case)]
 
 #[macro_use] extern crate vst;
+extern crate OidosReverb;
 #[cfg(test)] extern crate rand;
+#[cfg(test)] extern crate OidosMusic;
+#[cfg(test)] extern crate OidosEngine;
 
 mod cache;
-mod generate;
-mod oidos_generate;
+pub mod controls;
+pub mod export;
+pub mod fit;
+#[macro_use] pub mod generate;
+pub mod midi;
+pub mod morph;
+pub mod mutate;
+pub mod noise_generate;
+pub mod noise_player;
+pub mod oidos_generate;
+pub mod oidos_lint;
+pub mod oidos_player;
+pub mod patch;
+pub mod quantization;
+pub mod render;
 mod synth;
+pub mod tuning;
 
 #[cfg(test)] use rand::{thread_rng, Rng};
 
@@ -18,9 +35,18 @@ use vst::plugin::Info;
 #[cfg(test)] use vst::plugin::Plugin;
 
 use synth::{SynthInfo, SynthPlugin};
+use noise_generate::NoiseSoundGenerator;
 use oidos_generate::{OidosSoundGenerator};
 
 
+/// VST plugin ID of the Oidos synth, identifying its presets.
+pub const OIDOS_UNIQUE_ID: i32 = 0x50D10;
+/// Version of the Oidos synth, as reported to the host.
+pub const OIDOS_VERSION: i32 = 2100;
+/// VST plugin ID of the OidosNoise synth, identifying its presets.
+pub const NOISE_UNIQUE_ID: i32 = 0x50D1A;
+
+#[cfg_attr(feature = "noise", allow(dead_code))]
 struct OidosSynthInfo;
 
 impl SynthInfo for OidosSynthInfo {
@@ -28,17 +54,40 @@ impl SynthInfo for OidosSynthInfo {
 		Info {
 			name: "Oidos".to_string(),
 			vendor: "Loonies".to_string(),
-			unique_id: 0x50D10,
-			version: 2100,
+			unique_id: OIDOS_UNIQUE_ID,
+			version: OIDOS_VERSION,
 
 			.. Info::default()
 		}
 	}
 }
 
+#[cfg_attr(not(feature = "noise"), allow(dead_code))]
+struct NoiseSynthInfo;
+
+impl SynthInfo for NoiseSynthInfo {
+	fn get_info() -> Info {
+		Info {
+			name: "OidosNoise".to_string(),
+			vendor: "Loonies".to_string(),
+			unique_id: NOISE_UNIQUE_ID,
+			version: OIDOS_VERSION,
+
+			.. Info::default()
+		}
+	}
+}
+
+#[cfg_attr(feature = "noise", allow(dead_code))]
 type OidosPlugin = SynthPlugin<OidosSoundGenerator, OidosSynthInfo>;
+#[cfg_attr(not(feature = "noise"), allow(dead_code))]
+type NoisePlugin = SynthPlugin<NoiseSoundGenerator, NoiseSynthInfo>;
 
+// The library is built as either plugin, selected by the noise feature
+#[cfg(not(feature = "noise"))]
 plugin_main!(OidosPlugin);
+#[cfg(feature = "noise")]
+plugin_main!(NoisePlugin);
 
 
 #[test]
@@ -85,3 +134,156 @@ fn test_oidos_plugin() {
 		plugin.process(&mut buffer);
 	}
 }
+
+#[test]
+fn test_oidos_diagnostic_tone() {
+	let mut plugin = OidosPlugin::default();
+	plugin.set_sample_rate(44100.0);
+	let nump = plugin.get_info().parameters;
+	let params = plugin.get_parameter_object();
+	let aliasing = (0..nump).find(|&i| params.get_parameter_name(i) == "lint_aliasing").unwrap();
+	assert!(params.get_parameter_label(aliasing).starts_with(&format!("at {}", oidos_lint::note_name(60))));
+
+	let note_on = Event::Midi(MidiEvent {
+		data: [0x90u8, 81, 127],
+		delta_frames: 0,
+		live: true,
+		note_length: None,
+		note_offset: None,
+		detune: 0,
+		note_off_velocity: 0
+	});
+	let mut event_buffer = SendEventBuffer::new(1);
+	event_buffer.send_events_to_plugin(vec![note_on], &mut plugin);
+	let mut left = vec![0f32; 100];
+	let mut right = vec![0f32; 100];
+	let mut hostbuffer = HostBuffer::new(0, 2);
+	let mut buffer = hostbuffer.bind(&[&[]; 0], &mut [&mut left, &mut right]);
+	plugin.process(&mut buffer);
+
+	// The diagnostics follow the played tone once they are shown
+	assert!(params.get_parameter_label(aliasing).starts_with(&format!("at {}", oidos_lint::note_name(81))));
+}
+
+#[test]
+fn test_oidos_mutate() {
+	let mut plugin = OidosPlugin::default();
+	let nump = plugin.get_info().parameters;
+	let params = plugin.get_parameter_object();
+	let index_of = |name: &str| (0..nump).find(|&i| params.get_parameter_name(i) == name).unwrap();
+	let filterlow = index_of("filterlow");
+	params.set_parameter(index_of("lock_filterlow"), 1.0);
+
+	let before: Vec<f32> = (0..nump).map(|i| params.get_parameter(i)).collect();
+	params.set_parameter(index_of("mutate"), 1.0);
+	let after: Vec<f32> = (0..nump).map(|i| params.get_parameter(i)).collect();
+	assert!(before != after);
+	assert_eq!(before[filterlow as usize], after[filterlow as usize]);
+
+	// Holding the trigger does not mutate again
+	params.set_parameter(index_of("mutate"), 1.0);
+	assert_eq!((0..nump).map(|i| params.get_parameter(i)).collect::<Vec<f32>>(), after);
+
+	// The triggers and locks are not automated, and the locks and strength are saved with the patch
+	assert!(!params.can_be_automated(index_of("mutate")));
+	assert!(!params.can_be_automated(index_of("lock_filterlow")));
+	assert!(params.can_be_automated(index_of("mutate_strength")));
+	params.set_parameter(index_of("mutate_strength"), 0.5);
+	let mut loaded = OidosPlugin::default();
+	let loaded_params = loaded.get_parameter_object();
+	loaded_params.load_preset_data(&params.get_preset_data());
+	assert_eq!(loaded_params.get_parameter(index_of("lock_filterlow")), 1.0);
+	assert_eq!(loaded_params.get_parameter(index_of("mutate_strength")), 0.5);
+}
+
+#[test]
+fn test_oidos_morph() {
+	let mut plugin = OidosPlugin::default();
+	let nump = plugin.get_info().parameters;
+	let params = plugin.get_parameter_object();
+	let find = |name: &str| (0..nump).find(|&i| params.get_parameter_name(i) == name).unwrap();
+	let (attack, seed) = (find("attack"), find("seed"));
+
+	params.set_parameter(attack, 0.2);
+	params.set_parameter(find("snapshot_a"), 1.0);
+	params.set_parameter(attack, 0.6);
+	params.set_parameter(seed, 0.9);
+	params.set_parameter(find("snapshot_b"), 1.0);
+	assert_eq!(params.get_parameter_text(find("snapshot_b")), "Stored");
+	assert!(!params.can_be_automated(find("snapshot_b")));
+	assert!(!params.can_be_automated(find("commit_morph")));
+	assert!(params.can_be_automated(find("morph")));
+
+	params.set_parameter(find("morph_switch"), 0.75);
+	params.set_parameter(find("morph"), 0.5);
+	assert_eq!(params.get_parameter_text(attack), "200.0");
+	assert_eq!(params.get_parameter_text(seed), "50");
+	// The current patch is unchanged until the morph is committed
+	assert_eq!(params.get_parameter(attack), 0.6);
+
+	// The snapshots and the morph are saved with the patch
+	let mut loaded = OidosPlugin::default();
+	let loaded_params = loaded.get_parameter_object();
+	loaded_params.load_preset_data(&params.get_preset_data());
+	assert_eq!(loaded_params.get_parameter_text(find("snapshot_a")), "Stored");
+	assert_eq!(loaded_params.get_parameter(find("morph")), 0.5);
+	assert_eq!(loaded_params.get_parameter(find("morph_switch")), 0.75);
+	assert_eq!(loaded_params.get_parameter_text(attack), "200.0");
+	assert_eq!(loaded_params.get_parameter(attack), 0.6);
+
+	params.set_parameter(find("commit_morph"), 1.0);
+	assert_eq!(params.get_parameter_text(find("commit_morph")), "-");
+	assert!((params.get_parameter(attack) - 0.2f32.sqrt()).abs() < 1e-6);
+	assert_eq!(params.get_parameter(seed), 0.5);
+}
+
+#[test]
+fn test_oidos_preset_data() {
+	let mut plugin = OidosPlugin::default();
+	let params = plugin.get_parameter_object();
+	params.set_parameter(1, 0.25);
+	let data = params.get_preset_data();
+	assert!(String::from_utf8(data.clone()).unwrap().starts_with("seed 0.5\nmodes 0.25\n"));
+
+	let mut loaded = OidosPlugin::default();
+	let loaded_params = loaded.get_parameter_object();
+	let mut tuned = data.clone();
+	tuned.extend(b"tune 60 60.5\n");
+	loaded_params.load_preset_data(&tuned);
+	assert_eq!(loaded_params.get_parameter(1), 0.25);
+	assert_eq!(loaded_params.get_preset_data(), tuned);
+
+	// Data not understood leaves the state unchanged
+	loaded_params.load_preset_data(b"modes 2");
+	assert_eq!(loaded_params.get_preset_data(), tuned);
+}
+
+#[test]
+fn test_noise_plugin() {
+	let mut plugin = NoisePlugin::default();
+	plugin.set_sample_rate(44100.0);
+	assert_eq!(plugin.get_info().unique_id, NOISE_UNIQUE_ID);
+	let params = plugin.get_parameter_object();
+	assert_eq!(params.get_parameter_name(0), "seed");
+	assert_eq!(params.get_parameter_text(noise_generate::DECAY as i32), "80.0");
+
+	let note_on = Event::Midi(MidiEvent {
+		data: [0x90u8, 60, 127],
+		delta_frames: 0,
+		live: true,
+		note_length: None,
+		note_offset: None,
+		detune: 0,
+		note_off_velocity: 0
+	});
+	let mut event_buffer = SendEventBuffer::new(1);
+	event_buffer.send_events_to_plugin(vec![note_on], &mut plugin);
+
+	let mut left = vec![0f32; 1000];
+	let mut right = vec![0f32; 1000];
+	let mut hostbuffer = HostBuffer::new(0, 2);
+	let mut buffer = hostbuffer.bind(&[&[]; 0], &mut [&mut left, &mut right]);
+	plugin.process(&mut buffer);
+	assert!(left.iter().any(|&s| s != 0.0));
+	assert_eq!(left, right);
+}
@@ -0,0 +1,239 @@
+// Reading Standard MIDI Files
+
+/// Tempo used when a MIDI file has no tempo events, in microseconds per beat
+pub const DEFAULT_TEMPO: u32 = 500000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiEventKind {
+	NoteOn { channel: u8, key: u8, velocity: u8 },
+	NoteOff { channel: u8, key: u8 },
+	/// Microseconds per beat
+	Tempo(u32)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MidiEvent {
+	/// Absolute time in MIDI ticks
+	pub tick: u32,
+	pub kind: MidiEventKind
+}
+
+pub struct MidiTrack {
+	pub name: Option<String>,
+	/// Note and tempo events in time order. Other events are skipped.
+	pub events: Vec<MidiEvent>,
+	/// Time of the end of the track in MIDI ticks
+	pub end: u32
+}
+
+pub struct MidiFile {
+	/// MIDI ticks per beat
+	pub division: u16,
+	pub tracks: Vec<MidiTrack>
+}
+
+impl MidiFile {
+	/// The tempo of the music. Tempo changes are not supported by the player.
+	pub fn tempo(&self) -> Result<u32, String> {
+		let mut tempo = None;
+		for event in self.tracks.iter().flat_map(|t| t.events.iter()) {
+			if let MidiEventKind::Tempo(t) = event.kind {
+				if tempo.is_some_and(|prev| prev != t) {
+					return Err(format!("Tempo change at tick {} is not supported", event.tick));
+				}
+				tempo = Some(t);
+			}
+		}
+		Ok(tempo.unwrap_or(DEFAULT_TEMPO))
+	}
+}
+
+struct Reader<'a> {
+	data: &'a [u8],
+	pos: usize
+}
+
+impl<'a> Reader<'a> {
+	fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+		if self.data.len() - self.pos < n {
+			return Err(format!("Unexpected end of MIDI data at offset {}", self.pos));
+		}
+		let bytes = &self.data[self.pos..self.pos + n];
+		self.pos += n;
+		Ok(bytes)
+	}
+
+	fn byte(&mut self) -> Result<u8, String> {
+		self.bytes(1).map(|b| b[0])
+	}
+
+	fn u16(&mut self) -> Result<u16, String> {
+		self.bytes(2).map(|b| u16::from(b[0]) << 8 | u16::from(b[1]))
+	}
+
+	fn u32(&mut self) -> Result<u32, String> {
+		self.bytes(4).map(|b| b.iter().fold(0, |v, &b| v << 8 | u32::from(b)))
+	}
+
+	fn varlen(&mut self) -> Result<u32, String> {
+		let mut value = 0u32;
+		for _ in 0..4 {
+			let b = self.byte()?;
+			value = value << 7 | u32::from(b & 0x7F);
+			if b & 0x80 == 0 {
+				return Ok(value);
+			}
+		}
+		Err(format!("Malformed variable-length number at offset {}", self.pos))
+	}
+}
+
+fn parse_track(data: &[u8]) -> Result<MidiTrack, String> {
+	let mut reader = Reader { data: data, pos: 0 };
+	let mut track = MidiTrack { name: None, events: Vec::new(), end: 0 };
+	let mut tick = 0u32;
+	let mut running_status = None;
+	while reader.pos < data.len() {
+		tick += reader.varlen()?;
+		let mut status = reader.byte()?;
+		match status {
+			0xFF => {
+				let meta_type = reader.byte()?;
+				let length = reader.varlen()? as usize;
+				let meta = reader.bytes(length)?;
+				match meta_type {
+					0x03 if track.name.is_none() => track.name = Some(String::from_utf8_lossy(meta).trim().to_string()),
+					0x2F => break,
+					0x51 if length == 3 => track.events.push(MidiEvent {
+						tick: tick,
+						kind: MidiEventKind::Tempo(meta.iter().fold(0, |v, &b| v << 8 | u32::from(b)))
+					}),
+					_ => {}
+				}
+			},
+			0xF0 | 0xF7 => {
+				let length = reader.varlen()? as usize;
+				reader.bytes(length)?;
+			},
+			_ => {
+				if status < 0x80 {
+					status = running_status.ok_or(format!("Data byte without status at offset {}", reader.pos - 1))?;
+					reader.pos -= 1;
+				}
+				running_status = Some(status);
+				let channel = status & 0x0F;
+				let kind = match status & 0xF0 {
+					0x80 => {
+						let key = reader.byte()?;
+						reader.byte()?;
+						Some(MidiEventKind::NoteOff { channel: channel, key: key })
+					},
+					0x90 => {
+						let key = reader.byte()?;
+						let velocity = reader.byte()?;
+						Some(if velocity == 0 {
+							MidiEventKind::NoteOff { channel: channel, key: key }
+						} else {
+							MidiEventKind::NoteOn { channel: channel, key: key, velocity: velocity }
+						})
+					},
+					0xC0 | 0xD0 => { reader.byte()?; None },
+					_ => { reader.bytes(2)?; None }
+				};
+				if let Some(kind) = kind {
+					track.events.push(MidiEvent { tick: tick, kind: kind });
+				}
+			}
+		}
+	}
+	track.end = tick;
+	Ok(track)
+}
+
+/// Parse a Standard MIDI File of format 0 or 1.
+pub fn parse_midi(data: &[u8]) -> Result<MidiFile, String> {
+	let mut reader = Reader { data: data, pos: 0 };
+	if reader.bytes(4).ok() != Some(b"MThd") {
+		return Err("Not a Standard MIDI File".to_string());
+	}
+	let header_length = reader.u32()? as usize;
+	let format = reader.u16()?;
+	let ntracks = reader.u16()?;
+	let division = reader.u16()?;
+	reader.bytes(header_length.saturating_sub(6))?;
+	if format > 1 {
+		return Err(format!("MIDI file format {} is not supported", format));
+	}
+	if division & 0x8000 != 0 || division == 0 {
+		return Err("SMPTE time division is not supported".to_string());
+	}
+
+	let mut tracks = Vec::new();
+	while tracks.len() < ntracks as usize {
+		let chunk_type = reader.bytes(4)?;
+		let length = reader.u32()? as usize;
+		let chunk = reader.bytes(length)?;
+		if chunk_type == b"MTrk" {
+			tracks.push(parse_track(chunk).map_err(|e| format!("Track {}: {}", tracks.len(), e))?);
+		}
+	}
+	Ok(MidiFile {
+		division: division,
+		tracks: tracks
+	})
+}
+
+#[cfg(test)]
+pub fn write_test_midi(division: u16, tracks: &[Vec<(u32, Vec<u8>)>]) -> Vec<u8> {
+	let mut data = b"MThd".to_vec();
+	data.extend(&[0, 0, 0, 6, 0, 1, 0, tracks.len() as u8, (division >> 8) as u8, division as u8]);
+	for events in tracks {
+		let mut chunk = Vec::new();
+		for &(delta, ref bytes) in events {
+			let mut varlen = vec![(delta & 0x7F) as u8];
+			let mut rest = delta >> 7;
+			while rest > 0 {
+				varlen.insert(0, (rest & 0x7F) as u8 | 0x80);
+				rest >>= 7;
+			}
+			chunk.extend(varlen);
+			chunk.extend(bytes);
+		}
+		data.extend(b"MTrk");
+		data.extend(&(chunk.len() as u32).to_be_bytes());
+		data.extend(chunk);
+	}
+	data
+}
+
+#[test]
+fn test_parse_midi() {
+	let data = write_test_midi(96, &[
+		vec![(0, vec![0xFF, 0x51, 3, 0x07, 0xA1, 0x20]), (0, vec![0xFF, 0x2F, 0])],
+		vec![
+			(0, vec![0xFF, 0x03, 4, b'B', b'a', b's', b's']),
+			(0, vec![0x91, 36, 100]),
+			(200, vec![40, 90]),
+			(0, vec![0xB1, 7, 100]),
+			(0, vec![0x81, 36, 0]),
+			(96, vec![0x91, 40, 0]),
+			(24, vec![0xFF, 0x2F, 0])
+		]
+	]);
+	let midi = parse_midi(&data).unwrap();
+	assert_eq!(midi.division, 96);
+	assert_eq!(midi.tempo(), Ok(500000));
+	assert_eq!(midi.tracks.len(), 2);
+	let track = &midi.tracks[1];
+	assert_eq!(track.name.as_ref().unwrap(), "Bass");
+	assert_eq!(track.end, 320);
+	assert_eq!(track.events, vec![
+		MidiEvent { tick: 0, kind: MidiEventKind::NoteOn { channel: 1, key: 36, velocity: 100 } },
+		MidiEvent { tick: 200, kind: MidiEventKind::NoteOn { channel: 1, key: 40, velocity: 90 } },
+		MidiEvent { tick: 200, kind: MidiEventKind::NoteOff { channel: 1, key: 36 } },
+		MidiEvent { tick: 296, kind: MidiEventKind::NoteOff { channel: 1, key: 40 } }
+	]);
+
+	assert!(parse_midi(b"RIFF").is_err());
+	assert!(parse_midi(&data[..data.len() - 3]).is_err());
+}
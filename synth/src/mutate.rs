@@ -0,0 +1,128 @@
+use generate::{ParameterGroup, SoundGenerator, SoundParameters};
+#[cfg(test)] use oidos_generate::{OidosRandomData, OidosSoundGenerator, OidosSoundParameters, MODES, Q_GAIN, SEED};
+
+
+/// Number of candidates tried before giving up on finding a usable variation
+const MAX_ATTEMPTS: usize = 100;
+
+/// Whether the randomizer may change parameters in the group. Quantization
+/// parameters are left alone, as they are set when finalizing an instrument.
+/// Velocity parameters do not affect the full-velocity sound the randomizer judges,
+/// and the reference pitch is a property of the music rather than of the sound.
+pub fn is_mutable(group: ParameterGroup) -> bool {
+	match group {
+		ParameterGroup::Partials | ParameterGroup::Decay | ParameterGroup::Filter | ParameterGroup::Amplitude => true,
+		ParameterGroup::Velocity | ParameterGroup::Tuning | ParameterGroup::Quantization | ParameterGroup::Unused => false
+	}
+}
+
+/// Indices of the parameters the randomizer may change.
+pub fn mutable_parameters<S: SoundParameters>() -> Vec<usize> {
+	S::schema().iter().filter(|desc| is_mutable(desc.group)).map(|desc| desc.index).collect()
+}
+
+/// Random variations of patches. The random sequence is determined by the seed,
+/// so a set of variations can be reproduced.
+#[derive(Clone)]
+pub struct Mutator {
+	state: u32
+}
+
+impl Mutator {
+	pub fn new(seed: u32) -> Mutator {
+		Mutator {
+			// Xorshift gets stuck at zero
+			state: if seed == 0 { 0x9E3779B9 } else { seed }
+		}
+	}
+
+	fn next_unit(&mut self) -> f32 {
+		self.state ^= self.state << 13;
+		self.state ^= self.state >> 17;
+		self.state ^= self.state << 5;
+		(self.state >> 8) as f32 / (1 << 24) as f32
+	}
+
+	/// Move each unlocked, mutable parameter towards a random value. At strength 1,
+	/// the new values are uniformly random; at strength 0, nothing changes.
+	/// Parameters with integer steps stay on their steps.
+	pub fn mutate<S: SoundParameters>(&mut self, values: &[f32], locks: &[bool], strength: f32) -> Vec<f32> {
+		let mut result = values.to_vec();
+		for desc in S::schema() {
+			if !is_mutable(desc.group) || locks[desc.index] {
+				continue;
+			}
+			let target = self.next_unit();
+			let mut value = values[desc.index] + (target - values[desc.index]) * strength;
+			if let Some(steps) = desc.steps {
+				let steps = steps as f32;
+				value = (value * steps).round().max(desc.range.0) / steps;
+			}
+			result[desc.index] = value.clamp(0.0, 1.0);
+		}
+		result
+	}
+
+	/// Combine two patches, taking each unlocked, mutable parameter from either
+	/// of them at random. Other parameters are taken from `a`.
+	pub fn crossover<S: SoundParameters>(&mut self, a: &[f32], b: &[f32], locks: &[bool]) -> Vec<f32> {
+		let mut result = a.to_vec();
+		for desc in S::schema() {
+			if is_mutable(desc.group) && !locks[desc.index] && self.next_unit() < 0.5 {
+				result[desc.index] = b[desc.index];
+			}
+		}
+		result
+	}
+
+	/// Mutate the patch until the result has a finite duration and is audible at the
+	/// given tone. Returns `None` if no such variation was found.
+	pub fn mutate_usable<G: SoundGenerator>(&mut self, values: &[f32], locks: &[bool], strength: f32,
+	                                        tone: u8, sample_rate: f32, global: &G::Global) -> Option<Vec<f32>> {
+		for _ in 0..MAX_ATTEMPTS {
+			let candidate = self.mutate::<G::Parameters>(values, locks, strength);
+			let param = G::Parameters::build(&candidate, sample_rate);
+			if param.duration().is_some() && G::is_audible(&param, tone, global) {
+				return Some(candidate);
+			}
+		}
+		None
+	}
+}
+
+#[test]
+fn test_mutate() {
+	let schema = OidosSoundParameters::schema();
+	let values = OidosSoundParameters::default_values();
+	let mut locks = vec![false; values.len()];
+	locks[SEED] = true;
+
+	let mut mutator = Mutator::new(42);
+	assert_eq!(mutator.mutate::<OidosSoundParameters>(&values, &locks, 0.0), values);
+	for _ in 0..20 {
+		let mutated = mutator.mutate::<OidosSoundParameters>(&values, &locks, 1.0);
+		assert_eq!(mutated[SEED], values[SEED]);
+		assert_eq!(mutated[Q_GAIN], values[Q_GAIN]);
+		assert!(mutated[MODES] >= 0.01);
+		for desc in schema {
+			assert!(mutated[desc.index] >= 0.0 && mutated[desc.index] <= 1.0);
+			if let Some(steps) = desc.steps {
+				let scaled = mutated[desc.index] * steps as f32;
+				assert!((scaled - scaled.round()).abs() < 0.001);
+			}
+		}
+	}
+
+	let first = Mutator::new(7).mutate::<OidosSoundParameters>(&values, &locks, 0.5);
+	let second = Mutator::new(7).mutate::<OidosSoundParameters>(&values, &locks, 0.5);
+	assert_eq!(first, second);
+
+	// The default patch has infinite duration, so it can only be used if decay changes.
+	let random = OidosRandomData::default();
+	let usable = mutator.mutate_usable::<OidosSoundGenerator>(&values, &locks, 0.5, 60, 44100.0, &random).unwrap();
+	assert!(OidosSoundParameters::build(&usable, 44100.0).duration().is_some());
+	for &index in &mutable_parameters::<OidosSoundParameters>() {
+		locks[index] = true;
+	}
+	assert!(mutator.mutate_usable::<OidosSoundGenerator>(&values, &locks, 0.5, 60, 44100.0, &random).is_none());
+}
@@ -0,0 +1,460 @@
+use std::{f32, f64};
+use std::sync::Arc;
+
+use generate::{ParameterDescriptor, SoundGenerator, SoundParameters};
+#[cfg(test)] use generate::ParameterGroup;
+use oidos_generate::{quantize, OidosRandomData};
+use tuning::Tuning;
+
+
+const TARGET_SAMPLE_RATE: f32 = 44100.0;
+const DECAY_TIME: f32 = 4096.0 / TARGET_SAMPLE_RATE;
+
+const DIAGNOSTIC_NAMES: &'static [&'static str] = &[
+	"lint_duration",
+	"lint_peak"
+];
+
+pub const SEED: usize = 0;
+pub const FILTER: usize = 1;
+pub const RESONANCE: usize = 2;
+pub const SHAPE: usize = 3;
+pub const PITCHENV: usize = 4;
+pub const PITCHDECAY: usize = 5;
+pub const DECAY: usize = 6;
+pub const GAIN: usize = 7;
+pub const ATTACK: usize = 8;
+pub const RELEASE: usize = 9;
+pub const Q_FILTER: usize = 10;
+pub const Q_RESONANCE: usize = 11;
+pub const Q_SHAPE: usize = 12;
+pub const Q_PITCHENV: usize = 13;
+pub const Q_PITCHDECAY: usize = 14;
+pub const Q_DECAY: usize = 15;
+pub const Q_GAIN: usize = 16;
+pub const Q_ATTACK: usize = 17;
+pub const Q_RELEASE: usize = 18;
+
+const Q_RANGE: (f32, f32) = (0.0, 31.0);
+/// Distance in the random data between the noise of consecutive seeds
+pub const SEED_SPACING: usize = 2048;
+/// Largest distance of the filter from the played tone, in semitones
+const FILTER_RANGE: f32 = 72.0;
+/// Filter resonance, as the quality factor, at parameter values 0 and 1
+const RESONANCE_RANGE: (f32, f32) = (0.5, 50.0);
+/// Largest start offset of the filter pitch envelope, in semitones
+const PITCHENV_RANGE: f32 = 96.0;
+/// Longest half-times of the pitch envelope and the amplitude decay, in seconds
+const PITCHDECAY_RANGE: f32 = 1.0;
+const DECAY_RANGE: f32 = 2.0;
+/// Highest filter frequency, in radians per sample, keeping the filter clear of the Nyquist frequency
+const MAX_FILTER_FREQ: f64 = 0.98 * f64::consts::PI;
+/// Length of the start of the sound searched for its peak, in seconds
+const PEAK_WINDOW: f32 = 0.5;
+const SILENCE_WARNING_DB: f32 = -60.0;
+
+const SCHEMA: &'static [ParameterDescriptor] = &[
+	param!(SEED,          "seed",          0.5,  (0.0, 100.0),     "",       Some(100), Partials,     &[]),
+	param!(FILTER,        "filter",        0.5,  (-FILTER_RANGE, FILTER_RANGE), "ST", None, Filter,  &[Q_FILTER]),
+	param!(RESONANCE,     "resonance",     0.25, RESONANCE_RANGE,  "",       None,      Filter,       &[Q_RESONANCE]),
+	param!(SHAPE,         "shape",         0.5,  (-100.0, 100.0),  "%",      None,      Filter,       &[Q_SHAPE]),
+	param!(PITCHENV,      "pitchenv",      0.5,  (-PITCHENV_RANGE, PITCHENV_RANGE), "ST", None, Filter, &[Q_PITCHENV]),
+	param!(PITCHDECAY,    "pitchdecay",    0.2,  (0.0, 1000.0 * PITCHDECAY_RANGE), "ms half", None, Filter, &[Q_PITCHDECAY]),
+	param!(DECAY,         "decay",         0.2,  (0.0, 1000.0 * DECAY_RANGE), "ms half", None, Decay,   &[Q_DECAY]),
+	param!(GAIN,          "gain",          0.25, (0.125, 512.0),   "",       None,      Amplitude,    &[Q_GAIN]),
+	param!(ATTACK,        "attack",        0.0,  (0.0, 1000.0),    "ms",     None,      Amplitude,    &[Q_ATTACK]),
+	param!(RELEASE,       "release",       0.5,  (0.0, 1.0),       "s",      None,      Amplitude,    &[Q_RELEASE]),
+	param!(Q_FILTER,      "q_filter",      0.0,  Q_RANGE,          "ST",     Some(31),  Quantization, &[FILTER]),
+	param!(Q_RESONANCE,   "q_resonance",   0.0,  Q_RANGE,          "",       Some(31),  Quantization, &[RESONANCE]),
+	param!(Q_SHAPE,       "q_shape",       0.0,  Q_RANGE,          "%",      Some(31),  Quantization, &[SHAPE]),
+	param!(Q_PITCHENV,    "q_pitchenv",    0.0,  Q_RANGE,          "ST",     Some(31),  Quantization, &[PITCHENV]),
+	param!(Q_PITCHDECAY,  "q_pitchdecay",  0.0,  Q_RANGE,          "ms half",Some(31),  Quantization, &[PITCHDECAY]),
+	param!(Q_DECAY,       "q_decay",       0.0,  Q_RANGE,          "ms half",Some(31),  Quantization, &[DECAY]),
+	param!(Q_GAIN,        "q_gain",        0.0,  Q_RANGE,          "",       Some(31),  Quantization, &[GAIN]),
+	param!(Q_ATTACK,      "q_attack",      0.0,  Q_RANGE,          "ms",     Some(31),  Quantization, &[ATTACK]),
+	param!(Q_RELEASE,     "q_release",     0.0,  Q_RANGE,          "s",      Some(31),  Quantization, &[RELEASE])
+];
+
+
+/// Decay factor over `DECAY_TIME` of a half-time parameter, zero for an immediate decay.
+pub fn decay_factor(p: &[f32], index: usize) -> f32 {
+	let half_time = p[index] * p[index] * SCHEMA[index].range.1 / 1000.0;
+	if half_time == 0.0 { 0.0 } else { 0.5f32.powf(DECAY_TIME / half_time) }
+}
+
+/// Half-time, in milliseconds, of a decay factor over `DECAY_TIME`.
+fn half_time(factor: f32) -> f32 {
+	1000.0 * -DECAY_TIME / factor.log2()
+}
+
+#[derive(Clone, PartialEq)]
+pub struct NoiseSoundParameters {
+	seed: u8,
+
+	/// Distance of the filter from the played tone, in semitones
+	filter: f32,
+	/// Damping of the filter, the inverse of its quality factor
+	damping: f32,
+	/// Filter output, from low pass at -1 through band pass at 0 to high pass at 1
+	shape: f32,
+	/// Start offset of the filter pitch envelope, in semitones
+	pitch_env: f32,
+	/// Decay factors of the pitch envelope and the amplitude over `DECAY_TIME`
+	pitch_decay: f32,
+	decay: f32,
+
+	gain: f32,
+
+	sample_rate: f32,
+	base_freq: f32,
+	tuning: Tuning
+}
+
+impl SoundParameters for NoiseSoundParameters {
+	fn schema() -> &'static [ParameterDescriptor] {
+		&SCHEMA
+	}
+
+	fn display(&self, index: usize, p: &[f32]) -> String {
+		match index {
+			SEED => format!("{}", self.seed),
+			FILTER | Q_FILTER => format!("{:+.1}", self.filter),
+			RESONANCE | Q_RESONANCE => format!("{:.2}", 1.0 / self.damping),
+			SHAPE | Q_SHAPE => format!("{:+.0}", self.shape * 100.0),
+			PITCHENV | Q_PITCHENV => format!("{:+.1}", self.pitch_env),
+			PITCHDECAY | Q_PITCHDECAY => format!("{:.1}", half_time(self.pitch_decay)),
+			DECAY | Q_DECAY => format!("{:.1}", half_time(self.decay)),
+			GAIN | Q_GAIN => format!("{:.2}", self.gain),
+			ATTACK | Q_ATTACK => format!("{:.1}", 1000.0 / (NoiseSoundParameters::attack(p, self.sample_rate) * self.sample_rate)),
+			RELEASE | Q_RELEASE => format!("{:.2}", 1.0 / (NoiseSoundParameters::release(p, self.sample_rate) * self.sample_rate)),
+			_ => "-".to_string()
+		}
+	}
+
+	fn to_display(index: usize, value: f32) -> f32 {
+		match index {
+			SEED => SCHEMA[index].integer_value(value) as f32,
+			FILTER => (value * 2.0 - 1.0) * FILTER_RANGE,
+			RESONANCE => RESONANCE_RANGE.0 * (RESONANCE_RANGE.1 / RESONANCE_RANGE.0).powf(value),
+			SHAPE => (value * 2.0 - 1.0) * 100.0,
+			PITCHENV => (value * 2.0 - 1.0) * PITCHENV_RANGE,
+			PITCHDECAY => 1000.0 * PITCHDECAY_RANGE * value * value,
+			DECAY => 1000.0 * DECAY_RANGE * value * value,
+			GAIN => 4096f32.powf(value - 0.25),
+			ATTACK => 1000.0 * value * value,
+			RELEASE => value,
+			_ => (value * 31.0).floor()
+		}
+	}
+
+	fn from_display(index: usize, display: f32) -> f32 {
+		let value = match index {
+			SEED => display.round().max(SCHEMA[index].range.0) / 100.0,
+			FILTER => (display / FILTER_RANGE + 1.0) / 2.0,
+			RESONANCE => (display / RESONANCE_RANGE.0).ln() / (RESONANCE_RANGE.1 / RESONANCE_RANGE.0).ln(),
+			SHAPE => (display / 100.0 + 1.0) / 2.0,
+			PITCHENV => (display / PITCHENV_RANGE + 1.0) / 2.0,
+			PITCHDECAY => (display.max(0.0) / (1000.0 * PITCHDECAY_RANGE)).sqrt(),
+			DECAY => (display.max(0.0) / (1000.0 * DECAY_RANGE)).sqrt(),
+			GAIN => display.ln() / 4096f32.ln() + 0.25,
+			ATTACK => (display / 1000.0).sqrt(),
+			RELEASE => display,
+			// Quantization parameters are entered as the number of bits rounded off
+			_ => (display.round() + 0.5) / 31.0
+		};
+		value.max(0.0).min(1.0)
+	}
+
+	fn build(p: &[f32], sample_rate: f32) -> NoiseSoundParameters {
+		let mut params = NoiseSoundParameters {
+			seed:        SCHEMA[SEED].integer_value(p[SEED]) as u8,
+
+			filter:      (p[FILTER] * 2.0 - 1.0) * FILTER_RANGE,
+			damping:     1.0 / NoiseSoundParameters::to_display(RESONANCE, p[RESONANCE]),
+			shape:       p[SHAPE] * 2.0 - 1.0,
+			pitch_env:   (p[PITCHENV] * 2.0 - 1.0) * PITCHENV_RANGE,
+			pitch_decay: decay_factor(p, PITCHDECAY),
+			decay:       decay_factor(p, DECAY),
+
+			gain:        4096f32.powf(p[GAIN] - 0.25),
+
+			sample_rate: sample_rate,
+			base_freq:   440.0 * 2f32.powf(-57.0 / 12.0) / sample_rate * 2.0 * f32::consts::PI,
+			tuning:      Tuning::default()
+		};
+
+		params.filter = quantize(params.filter, p[Q_FILTER]);
+		params.damping = quantize(params.damping, p[Q_RESONANCE]);
+		params.shape = quantize(params.shape, p[Q_SHAPE]);
+		params.pitch_env = quantize(params.pitch_env, p[Q_PITCHENV]);
+		params.pitch_decay = quantize(params.pitch_decay, p[Q_PITCHDECAY]);
+		params.decay = quantize(params.decay, p[Q_DECAY]);
+		params.gain = quantize(params.gain, p[Q_GAIN]);
+
+		params
+	}
+
+	fn attack(p: &[f32], sample_rate: f32) -> f32 {
+		let attack = p[ATTACK];
+		quantize(if attack == 0.0 {
+			2.0
+		} else {
+			1.0 / (attack * attack * sample_rate)
+		}, p[Q_ATTACK])
+	}
+
+	fn release(p: &[f32], sample_rate: f32) -> f32 {
+		let release = p[RELEASE];
+		quantize(if release == 0.0 {
+			2.0
+		} else {
+			1.0 / (release * sample_rate)
+		}, p[Q_RELEASE])
+	}
+
+	fn tune(&mut self, tuning: &Tuning) {
+		self.tuning = tuning.clone();
+	}
+
+	fn duration(&self) -> Option<usize> {
+		// Same computation as maxsamples in the player data, scaled to the sample rate.
+		if self.decay >= 1.0 {
+			return None;
+		}
+		let decaytime = 0.01f32.ln() / self.decay.ln() * DECAY_TIME * TARGET_SAMPLE_RATE;
+		let maxsamples = (decaytime + 65535.0) as usize & !65535;
+		Some((maxsamples as f32 * self.sample_rate / TARGET_SAMPLE_RATE).ceil() as usize)
+	}
+}
+
+impl NoiseSoundParameters {
+	/// Tone played by a key, according to the tuning.
+	pub fn tuned_tone(&self, key: u8) -> f64 {
+		self.tuning.tone(key)
+	}
+
+	/// Decay factor per sample for a decay factor over `DECAY_TIME`.
+	fn per_sample(&self, factor: f32) -> f64 {
+		(factor as f64).powf(1.0 / (DECAY_TIME * self.sample_rate) as f64)
+	}
+
+	/// Peak level of the sound played at the given key, in dB, found within the start of the sound.
+	pub fn peak_level(&self, key: u8, random: &Arc<OidosRandomData>) -> f32 {
+		let window = (PEAK_WINDOW * self.sample_rate) as usize;
+		let length = self.duration().map_or(window, |d| d.min(window));
+		let mut generator = NoiseSoundGenerator::new(self, key, 0, random);
+		let peak = (0..length).map(|_| generator.produce_sample().abs()).fold(0.0, f32::max);
+		20.0 * peak.log10()
+	}
+}
+
+#[test]
+fn test_noise_schema() {
+	for (index, desc) in SCHEMA.iter().enumerate() {
+		assert_eq!(desc.index, index);
+	}
+	let values = NoiseSoundParameters::default_values();
+	let param = NoiseSoundParameters::build(&values, 44100.0);
+	assert_eq!(param.display(FILTER, &values), "+0.0");
+	assert_eq!(param.display(RESONANCE, &values), "1.58");
+	assert_eq!(param.display(DECAY, &values), "80.0");
+	assert_eq!(param.duration(), Some(65536));
+}
+
+#[test]
+fn test_noise_parse() {
+	let values = NoiseSoundParameters::default_values();
+	let param = NoiseSoundParameters::build(&values, 44100.0);
+	for desc in SCHEMA {
+		for &value in &[0.0, 0.13, 0.5, 0.71, 1.0] {
+			let display = NoiseSoundParameters::to_display(desc.index, value);
+			let parsed = NoiseSoundParameters::from_display(desc.index, display);
+			match desc.steps {
+				Some(_) => assert_eq!(NoiseSoundParameters::to_display(desc.index, parsed), display, "{} {}", desc.name, value),
+				None => assert!((parsed - value).abs() < 1e-4, "{} {} {} {}", desc.name, value, display, parsed)
+			}
+		}
+		if desc.group != ParameterGroup::Unused {
+			// Parsing a displayed value gives a value with the same display
+			let text = param.display(desc.index, &values);
+			let mut parsed_values = values.clone();
+			parsed_values[desc.index] = NoiseSoundParameters::parse_in_patch(desc.index, &format!("{} {}", text, desc.unit), &values, 44100.0).unwrap();
+			let parsed_param = NoiseSoundParameters::build(&parsed_values, 44100.0);
+			assert_eq!(parsed_param.display(desc.index, &parsed_values), text, "{}", desc.name);
+		}
+	}
+
+	let parse = |index, text| NoiseSoundParameters::parse(index, text).unwrap();
+	assert_eq!(parse(FILTER, "+36 ST"), 0.75);
+	assert_eq!(parse(SHAPE, "-100 %"), 0.0);
+	assert!((parse(RESONANCE, "5") - 0.5).abs() < 1e-6);
+	assert!((parse(DECAY, "500 ms half") - 0.5).abs() < 1e-6);
+}
+
+#[test]
+fn test_noise_duration() {
+	let mut values = NoiseSoundParameters::default_values();
+	// log(0.01, 0.5) * 1 s = 6.64 s, rounded up to a multiple of 65536 samples
+	values[DECAY] = NoiseSoundParameters::parse(DECAY, "1000 ms half").unwrap();
+	let param = NoiseSoundParameters::build(&values, 44100.0);
+	assert_eq!(param.duration(), Some(327680));
+	let param = NoiseSoundParameters::build(&values, 88200.0);
+	assert_eq!(param.duration(), Some(655360));
+
+	// A decay factor quantized to above 1 never ends
+	values[Q_DECAY] = NoiseSoundParameters::parse(Q_DECAY, "30").unwrap();
+	let param = NoiseSoundParameters::build(&values, 44100.0);
+	assert_eq!(param.duration(), None);
+}
+
+
+pub struct NoiseSoundGenerator {
+	random: Arc<OidosRandomData>,
+	random_index: usize,
+
+	/// Integrator states of the filter
+	ic1: f64,
+	ic2: f64,
+	damping: f64,
+	shape: f64,
+
+	/// Resting tone of the filter and the current offset of the pitch envelope
+	tone: f64,
+	pitch: f64,
+	pitch_mul: f64,
+	base_freq: f64,
+
+	amp: f64,
+	amp_mul: f64,
+
+	gain: f64
+}
+
+impl SoundGenerator for NoiseSoundGenerator {
+	type Parameters = NoiseSoundParameters;
+	type Output = f32;
+	type Global = Arc<OidosRandomData>;
+
+	fn new(param: &NoiseSoundParameters, tone: u8, time: usize, random: &Arc<OidosRandomData>) -> NoiseSoundGenerator {
+		let mut gen = NoiseSoundGenerator {
+			random:       Arc::clone(random),
+			random_index: param.seed as usize * SEED_SPACING,
+
+			ic1:          0.0,
+			ic2:          0.0,
+			damping:      param.damping as f64,
+			shape:        param.shape as f64,
+
+			tone:         param.tuned_tone(tone) + param.filter as f64,
+			pitch:        param.pitch_env as f64,
+			pitch_mul:    param.per_sample(param.pitch_decay),
+			base_freq:    param.base_freq as f64,
+
+			amp:          1.0,
+			amp_mul:      param.per_sample(param.decay),
+
+			gain:         param.gain as f64
+		};
+
+		// The filter state depends on all the noise before, so run through it
+		for _ in 0..time {
+			gen.produce_sample();
+		}
+
+		gen
+	}
+
+	fn produce_sample(&mut self) -> f32 {
+		let x = self.random.value(self.random_index);
+		self.random_index += 1;
+
+		// Trapezoidal state variable filter, stable at all frequencies
+		let freq = (self.base_freq * 2f64.powf((self.tone + self.pitch) / 12.0)).min(MAX_FILTER_FREQ);
+		let g = (freq / 2.0).tan();
+		let k = self.damping;
+		let a1 = 1.0 / (1.0 + g * (g + k));
+		let a2 = g * a1;
+		let a3 = g * a2;
+		let v3 = x - self.ic2;
+		let v1 = a1 * self.ic1 + a2 * v3;
+		let v2 = self.ic2 + a2 * self.ic1 + a3 * v3;
+		self.ic1 = 2.0 * v1 - self.ic1;
+		self.ic2 = 2.0 * v2 - self.ic2;
+		self.pitch *= self.pitch_mul;
+
+		// The band pass output is scaled to unit gain at the filter frequency
+		let (low, band, high) = (v2, k * v1, x - k * v1 - v2);
+		let filtered = if self.shape < 0.0 {
+			band + (low - band) * -self.shape
+		} else {
+			band + (high - band) * self.shape
+		};
+
+		let s = filtered * self.amp;
+		self.amp *= self.amp_mul;
+		(s * (self.gain / (1.0 + (self.gain - 1.0) * s * s)).sqrt()) as f32
+	}
+
+	fn diagnostic_names() -> &'static [&'static str] {
+		&DIAGNOSTIC_NAMES
+	}
+
+	fn diagnostics(param: &NoiseSoundParameters, tone: u8, random: &Arc<OidosRandomData>) -> Vec<(String, String)> {
+		let peak = param.peak_level(tone, random);
+		vec![
+			match param.duration() {
+				Some(d) => (format!("{:.2}", d as f32 / param.sample_rate), "s".to_string()),
+				None => ("INFINITE".to_string(), "(!)".to_string())
+			},
+			(format!("{:+.1}", peak), if peak < SILENCE_WARNING_DB { "dB (!)" } else { "dB" }.to_string())
+		]
+	}
+
+	fn is_audible(param: &NoiseSoundParameters, tone: u8, random: &Arc<OidosRandomData>) -> bool {
+		param.peak_level(tone, random) >= SILENCE_WARNING_DB
+	}
+}
+
+#[test]
+fn test_noise_generator() {
+	let random = Arc::new(OidosRandomData::default());
+	let mut values = NoiseSoundParameters::default_values();
+	values[PITCHENV] = NoiseSoundParameters::parse(PITCHENV, "+24 ST").unwrap();
+	values[PITCHDECAY] = NoiseSoundParameters::parse(PITCHDECAY, "100 ms half").unwrap();
+	let param = NoiseSoundParameters::build(&values, 44100.0);
+	assert!(NoiseSoundGenerator::is_audible(&param, 60, &random));
+
+	// The pitch envelope and amplitude halve over their half-times
+	let mut gen = NoiseSoundGenerator::new(&param, 60, 0, &random);
+	assert_eq!((gen.tone, gen.pitch), (60.0, 24.0));
+	for _ in 0..4410 {
+		gen.produce_sample();
+	}
+	assert!((gen.pitch - 12.0).abs() < 1e-3);
+	assert!((gen.amp - 0.5f64.powf(100.0 / 80.0)).abs() < 1e-4);
+
+	// A generator started later continues the sound of one started at the beginning
+	let mut later = NoiseSoundGenerator::new(&param, 60, 4410, &random);
+	for _ in 0..100 {
+		assert_eq!(gen.produce_sample(), later.produce_sample());
+	}
+
+	// The seed selects the noise
+	let mut seeded = values.clone();
+	seeded[SEED] = 0.51;
+	let render = |values: &[f32]| {
+		let mut gen = NoiseSoundGenerator::new(&NoiseSoundParameters::build(values, 44100.0), 60, 0, &random);
+		(0..100).map(|_| gen.produce_sample()).collect::<Vec<_>>()
+	};
+	assert_eq!(render(&values), render(&values));
+	assert!(render(&values) != render(&seeded));
+
+	// A filter far below the tone lets the high pass output through and silences the low pass output
+	values[FILTER] = 0.0;
+	values[PITCHENV] = 0.5;
+	values[SHAPE] = 1.0;
+	let high = NoiseSoundParameters::build(&values, 44100.0).peak_level(60, &random);
+	values[SHAPE] = 0.0;
+	let low = NoiseSoundParameters::build(&values, 44100.0).peak_level(60, &random);
+	assert!(high > -6.0 && low < high - 20.0, "{} {}", high, low);
+}
@@ -0,0 +1,143 @@
+use std::f64;
+#[cfg(test)] use std::sync::Arc;
+
+use generate::SoundParameters;
+use noise_generate::*;
+use oidos_generate::quantize;
+use oidos_player::{OidosInstrumentContext, PlayerValue};
+#[cfg(test)] use generate::SoundGenerator;
+#[cfg(test)] use oidos_generate::OidosRandomData;
+#[cfg(test)] use OidosEngine::noise::{make_noise_tone, NoiseParams};
+#[cfg(test)] use OidosEngine::random::{fill_random_data, RANDOM_DATA_SIZE};
+
+
+const SAMPLERATE: f64 = 44100.0;
+
+/// Index of the maxsamples value in the noise parameter block.
+pub const NOISE_BLOCK_MAXSAMPLES: usize = 8;
+
+/// Encode the plugin parameters of an OidosNoise instrument into a parameter block for the player.
+/// Of the context, only the note length, volume, velocity quantum and panning apply.
+///
+/// The block is read by `OidosEngine::noise`. The asm player has no noise generator yet,
+/// so the converter rejects OidosNoise instruments.
+///
+/// The block holds the start of the noise in the random data, the distance of the filter from
+/// the played tone in semitones, the filter damping and shape, the start of the pitch envelope in
+/// semitones, the decay factors of the pitch envelope and amplitude over 4096 samples, the gain,
+/// the number of samples of the sound, the release and attack rates, the volume and the panning.
+pub fn make_noise_param_block(p: &[f32], context: &OidosInstrumentContext) -> Result<Vec<PlayerValue>, String> {
+	let display = |index: usize| NoiseSoundParameters::to_display(index, p[index]) as f64;
+	let q = |value: f64, q_index: usize| quantize(value as f32, p[q_index]) as f64;
+
+	let random_offset = display(SEED) as usize * SEED_SPACING;
+	let filter = q(display(FILTER), Q_FILTER);
+	let damping = q(1.0 / display(RESONANCE), Q_RESONANCE);
+	let shape = q(display(SHAPE) / 100.0, Q_SHAPE);
+	let pitchenv = q(display(PITCHENV), Q_PITCHENV);
+	let pitchdecay = q(decay_factor(p, PITCHDECAY) as f64, Q_PITCHDECAY);
+	let decay = q(decay_factor(p, DECAY) as f64, Q_DECAY);
+	let gain = q(display(GAIN), Q_GAIN);
+	let attack = NoiseSoundParameters::attack(p, SAMPLERATE as f32) as f64;
+	let release = -NoiseSoundParameters::release(p, SAMPLERATE as f32) as f64;
+
+	let releasetime = if release != 0.0 { context.max_note_length * SAMPLERATE + 1.0 / -release } else { f64::INFINITY };
+	let decaytime = if decay < 1.0 { 0.01f64.ln() / decay.ln() * 4096.0 } else { f64::INFINITY };
+	if releasetime.is_infinite() && decaytime.is_infinite() {
+		return Err("Instrument has infinite duration".to_string());
+	}
+	let maxsamples = (releasetime.min(decaytime) + 65535.0) as i64 & -65536;
+
+	let left_volume = context.volume[0] * context.velocity_quantum as f64 * 128.0;
+	let right_volume = context.volume[1] * context.velocity_quantum as f64 * 128.0;
+	let volume = (left_volume + right_volume) / 2.0;
+	let pan = right_volume / volume - 1.0;
+
+	let mut block = vec![
+		PlayerValue::Int(random_offset as i32),
+		PlayerValue::Float(filter as f32),
+		PlayerValue::Float(damping as f32),
+		PlayerValue::Float(shape as f32),
+		PlayerValue::Float(pitchenv as f32),
+		PlayerValue::Float(pitchdecay as f32),
+		PlayerValue::Float(decay as f32),
+		PlayerValue::Float(gain as f32),
+		PlayerValue::Int(maxsamples as i32),
+		PlayerValue::Float(release as f32),
+		PlayerValue::Float(attack as f32),
+		PlayerValue::Float(quantize(volume as f32, 0.65))
+	];
+	if context.uses_panning {
+		block.push(PlayerValue::Float(quantize(pan as f32, 0.55)));
+	}
+
+	Ok(block)
+}
+
+#[test]
+fn test_noise_param_block() {
+	let mut values = NoiseSoundParameters::default_values();
+	values[SEED] = 0.03;
+	values[FILTER] = NoiseSoundParameters::parse(FILTER, "+36 ST").unwrap();
+	values[RESONANCE] = NoiseSoundParameters::parse(RESONANCE, "5").unwrap();
+	values[Q_RESONANCE] = NoiseSoundParameters::parse(Q_RESONANCE, "20").unwrap();
+	values[SHAPE] = NoiseSoundParameters::parse(SHAPE, "-50 %").unwrap();
+	values[PITCHENV] = NoiseSoundParameters::parse(PITCHENV, "+24 ST").unwrap();
+	values[DECAY] = NoiseSoundParameters::parse(DECAY, "500 ms half").unwrap();
+	let context = OidosInstrumentContext { max_note_length: 10.0, .. OidosInstrumentContext::default() };
+	let block = make_noise_param_block(&values, &context).unwrap();
+	assert_eq!(block.len(), 12);
+	assert_eq!(&block[0..5], &[
+		PlayerValue::Int(3 * 2048),
+		PlayerValue::Float(36.0),
+		PlayerValue::Float(quantize(0.2, values[Q_RESONANCE])),
+		PlayerValue::Float(-0.5),
+		PlayerValue::Float(24.0)
+	]);
+	assert_eq!(block[5], PlayerValue::Float(decay_factor(&values, PITCHDECAY)));
+	assert_eq!(block[6], PlayerValue::Float(decay_factor(&values, DECAY)));
+	// log(0.01, 0.5) * 500 ms = 3.32 s, rounded up to a multiple of 65536 samples
+	assert_eq!(block[NOISE_BLOCK_MAXSAMPLES], PlayerValue::Int(196608));
+	// Full volume at a velocity quantum of 128
+	assert_eq!(block[11], PlayerValue::Float(16384.0));
+
+	let panned = OidosInstrumentContext { volume: [0.5, 1.5], uses_panning: true, .. context };
+	let block = make_noise_param_block(&values, &panned).unwrap();
+	assert_eq!(block[12], PlayerValue::Float(0.5));
+
+	// A decay which never ends needs a release
+	values[Q_DECAY] = NoiseSoundParameters::parse(Q_DECAY, "30").unwrap();
+	assert!(make_noise_param_block(&values, &context).is_ok());
+	values[Q_RELEASE] = 1.0;
+	assert!(make_noise_param_block(&values, &context).is_err());
+}
+
+#[test]
+fn test_noise_param_block_sound() {
+	// The engine plays the sound of the plugin from the parameter block
+	let mut random = vec![0u32; RANDOM_DATA_SIZE];
+	fill_random_data(&mut random);
+	let random_data = Arc::new(OidosRandomData::default());
+	let settings: &[&[(usize, &str)]] = &[
+		&[],
+		&[(SEED, "42"), (FILTER, "+24 ST"), (RESONANCE, "10"), (SHAPE, "-50 %"), (PITCHENV, "+24 ST"), (PITCHDECAY, "100 ms half")],
+		&[(FILTER, "-36 ST"), (SHAPE, "+100 %"), (GAIN, "16"), (Q_DECAY, "10")]
+	];
+	for setting in settings {
+		let mut values = NoiseSoundParameters::default_values();
+		for &(index, text) in *setting {
+			values[index] = NoiseSoundParameters::parse(index, text).unwrap();
+		}
+		let context = OidosInstrumentContext { uses_panning: true, .. OidosInstrumentContext::default() };
+		let words: Vec<u32> = make_noise_param_block(&values, &context).unwrap().iter().map(PlayerValue::to_bits).collect();
+		let params = NoiseParams::from_words(&words, true);
+		assert_eq!(params.panning, 0.0);
+
+		let mut out = vec![0.0; 20000];
+		make_noise_tone(&params, 60.0, &random, &mut out);
+		let mut gen = NoiseSoundGenerator::new(&NoiseSoundParameters::build(&values, 44100.0), 60, 0, &random_data);
+		for (t, &sample) in out.iter().enumerate() {
+			assert!((gen.produce_sample() as f64 - sample).abs() < 1e-4, "{:?} sample {}", setting, t);
+		}
+	}
+}
@@ -1,9 +1,10 @@
 
 use std::{f32, f64};
-use std::ops::{Index};
-#[cfg(test)] use std::collections::HashMap;
 
-use generate::{SoundGenerator, SoundParameters};
+use generate::{layer_velocity, Envelope, ParameterDescriptor, SoundGenerator, SoundParameters};
+#[cfg(test)] use generate::ParameterGroup;
+use oidos_lint::{diagnose, note_name};
+use tuning::Tuning;
 
 
 const TOTAL_SEMITONES: f32 = 120f32;
@@ -11,40 +12,175 @@ const NOISESIZE: usize = 64;
 const TARGET_SAMPLE_RATE: f32 = 44100.0;
 const DECAY_TIME: f32 = 4096.0 / TARGET_SAMPLE_RATE;
 
-const NAMES: &'static [&'static str] = &[
-	"seed",
-	"modes",
-	"fat",
-	"width",
-	"overtones",
-	"sharpness",
-	"harmonicity",
-	"decaylow",
-	"decayhigh",
-	"filterlow",
-	"fslopelow",
-	"fsweeplow",
-	"filterhigh",
-	"fslopehigh",
-	"fsweephigh",
-	"gain",
-	"attack",
-	"release",
-	"-",
-	"q_decaydiff",
-	"q_decaylow",
-	"q_harmonicity",
-	"q_sharpness",
-	"q_width",
-	"q_f_low",
-	"q_fs_low",
-	"q_fsw_low",
-	"q_f_high",
-	"q_fs_high",
-	"q_fsw_high",
-	"q_gain",
-	"q_attack",
-	"q_release"
+const DIAGNOSTIC_NAMES: &'static [&'static str] = &[
+	"lint_duration",
+	"lint_partials",
+	"lint_aliasing",
+	"lint_peak",
+	"lint_saturation",
+	"lint_precalc"
+];
+
+pub const SEED: usize = 0;
+pub const MODES: usize = 1;
+pub const FAT: usize = 2;
+pub const WIDTH: usize = 3;
+pub const OVERTONES: usize = 4;
+pub const SHARPNESS: usize = 5;
+pub const HARMONICITY: usize = 6;
+pub const DECAYLOW: usize = 7;
+pub const DECAYHIGH: usize = 8;
+pub const FILTERLOW: usize = 9;
+pub const FSLOPELOW: usize = 10;
+pub const FSWEEPLOW: usize = 11;
+pub const FILTERHIGH: usize = 12;
+pub const FSLOPEHIGH: usize = 13;
+pub const FSWEEPHIGH: usize = 14;
+pub const GAIN: usize = 15;
+pub const ATTACK: usize = 16;
+pub const RELEASE: usize = 17;
+pub const UNUSED: usize = 18;
+pub const Q_DECAYDIFF: usize = 19;
+pub const Q_DECAYLOW: usize = 20;
+pub const Q_HARMONICITY: usize = 21;
+pub const Q_SHARPNESS: usize = 22;
+pub const Q_WIDTH: usize = 23;
+pub const Q_F_LOW: usize = 24;
+pub const Q_FS_LOW: usize = 25;
+pub const Q_FSW_LOW: usize = 26;
+pub const Q_F_HIGH: usize = 27;
+pub const Q_FS_HIGH: usize = 28;
+pub const Q_FSW_HIGH: usize = 29;
+pub const Q_GAIN: usize = 30;
+pub const Q_ATTACK: usize = 31;
+pub const Q_RELEASE: usize = 32;
+pub const VELSHARPNESS: usize = 33;
+pub const VELFILTER: usize = 34;
+pub const VELDECAY: usize = 35;
+pub const VELLAYERS: usize = 36;
+pub const REFPITCH: usize = 37;
+pub const HOLD: usize = 38;
+pub const ENVDECAY: usize = 39;
+pub const SUSTAIN: usize = 40;
+pub const ATTACKCURVE: usize = 41;
+pub const DECAYCURVE: usize = 42;
+pub const RELEASECURVE: usize = 43;
+pub const DECAYTRACK: usize = 44;
+pub const FILTERTRACK: usize = 45;
+pub const TRACKPIVOT: usize = 46;
+pub const SWELL: usize = 47;
+pub const SWELLSLOPE: usize = 48;
+pub const SWELLSPREAD: usize = 49;
+pub const FORMANT1: usize = 50;
+pub const FWIDTH1: usize = 51;
+pub const FGAIN1: usize = 52;
+pub const FORMANT2: usize = 53;
+pub const FWIDTH2: usize = 54;
+pub const FGAIN2: usize = 55;
+pub const FORMANTSWEEP: usize = 56;
+pub const Q_FORMANT: usize = 57;
+pub const Q_FWIDTH: usize = 58;
+pub const Q_FGAIN: usize = 59;
+pub const STIFFNESS: usize = 60;
+pub const Q_STIFFNESS: usize = 61;
+
+const INF: f32 = f32::INFINITY;
+const Q_RANGE: (f32, f32) = (0.0, 31.0);
+/// Sharpness reduction, high filter reduction and decay time reduction at the lowest velocity
+const VELSHARPNESS_RANGE: f32 = 24.0;
+const VELFILTER_RANGE: f32 = 60.0;
+const VELDECAY_RANGE: f32 = 100.0;
+/// Reference pitch of A at parameter values 0 and 1. Zero, as in patches from
+/// before the parameter existed, means 440 Hz.
+const REFPITCH_RANGE: (f32, f32) = (415.0, 465.0);
+/// Longest hold and envelope decay, in seconds
+const HOLD_RANGE: f32 = 1.0;
+const ENVDECAY_RANGE: f32 = 10.0;
+/// Envelope curves at parameter values 0 and 1, in percent. Zero, as in patches
+/// from before the parameters existed, means a linear stage.
+const CURVE_RANGE: (f32, f32) = (-100.0, 100.0);
+/// Decay key tracking at parameter value 1, in percent. At 100%, the decay
+/// half-times are halved for every octave above the pivot tone.
+const DECAYTRACK_RANGE: f32 = 100.0;
+/// Longest swell, in seconds
+const SWELL_RANGE: f32 = 2.0;
+/// Offset of the random values for the swell of the partials from those for their width,
+/// keeping the random values of patches without swell unchanged
+pub const SWELL_RANDOM_OFFSET: usize = 0x10000;
+/// Widest formant peak, as the distance from its center to where it has no effect, in semitones.
+/// A width of zero turns the peak off.
+const FWIDTH_RANGE: f32 = 48.0;
+/// Largest boost or cut at the center of a formant peak, in dB
+const FGAIN_RANGE: f32 = 24.0;
+/// Fastest formant movement, in semitones per second. Zero, as in patches
+/// from before the parameter existed, means no movement.
+const FORMANTSWEEP_RANGE: f32 = 120.0;
+/// Largest inharmonicity coefficient, stretching harmonic n to n * sqrt(1 + B n^2)
+const STIFFNESS_RANGE: f32 = 0.1;
+
+const SCHEMA: &'static [ParameterDescriptor] = &[
+	param!(SEED,          "seed",          0.5,  (0.0, 100.0),     "",       Some(100), Partials,     &[]),
+	param!(MODES,         "modes",         0.40, (1.0, 100.0),     "",       Some(100), Partials,     &[]),
+	param!(FAT,           "fat",           0.10, (1.0, 100.0),     "",       Some(100), Partials,     &[]),
+	param!(WIDTH,         "width",         0.34, (0.0, 100.0),     "ST",     None,      Partials,     &[Q_WIDTH]),
+	param!(OVERTONES,     "overtones",     0.27, (0.0, 100.0),     "ST",     Some(100), Partials,     &[]),
+	param!(SHARPNESS,     "sharpness",     0.9,  (-24.1, 6.0),     "dB/oct", None,      Partials,     &[Q_SHARPNESS]),
+	param!(HARMONICITY,   "harmonicity",   1.0,  (-1.0, 1.0),      "",       None,      Partials,     &[Q_HARMONICITY]),
+	param!(DECAYLOW,      "decaylow",      1.0,  (0.0, INF),       "ms half",None,      Decay,        &[Q_DECAYDIFF, Q_DECAYLOW]),
+	param!(DECAYHIGH,     "decayhigh",     1.0,  (0.0, INF),       "ms half",None,      Decay,        &[Q_DECAYDIFF]),
+	param!(FILTERLOW,     "filterlow",     0.0,  (-120.0, 120.0),  "ST",     None,      Filter,       &[Q_F_LOW]),
+	param!(FSLOPELOW,     "fslopelow",     0.0,  (1.0, INF),       "ST",     None,      Filter,       &[Q_FS_LOW]),
+	param!(FSWEEPLOW,     "fsweeplow",     0.5,  (-1500.0, 1500.0),"ST/s",   None,      Filter,       &[Q_FSW_LOW]),
+	param!(FILTERHIGH,    "filterhigh",    1.0,  (-120.0, 120.0),  "ST",     None,      Filter,       &[Q_F_HIGH]),
+	param!(FSLOPEHIGH,    "fslopehigh",    0.0,  (1.0, INF),       "ST",     None,      Filter,       &[Q_FS_HIGH]),
+	param!(FSWEEPHIGH,    "fsweephigh",    0.5,  (-1500.0, 1500.0),"ST/s",   None,      Filter,       &[Q_FSW_HIGH]),
+	param!(GAIN,          "gain",          0.25, (0.125, 512.0),   "",       None,      Amplitude,    &[Q_GAIN]),
+	param!(ATTACK,        "attack",        0.25, (0.0, 1000.0),    "ms",     None,      Amplitude,    &[Q_ATTACK]),
+	param!(RELEASE,       "release",       0.5,  (0.0, 1.0),       "s",      None,      Amplitude,    &[Q_RELEASE]),
+	param!(UNUSED,        "-",             0.0,  (0.0, 1.0),       "",       None,      Unused,       &[]),
+	param!(Q_DECAYDIFF,   "q_decaydiff",   0.0,  Q_RANGE,          "ms half",Some(31),  Quantization, &[DECAYHIGH]),
+	param!(Q_DECAYLOW,    "q_decaylow",    0.0,  Q_RANGE,          "ms half",Some(31),  Quantization, &[DECAYLOW, DECAYHIGH, Q_DECAYDIFF]),
+	param!(Q_HARMONICITY, "q_harmonicity", 0.0,  Q_RANGE,          "",       Some(31),  Quantization, &[HARMONICITY]),
+	param!(Q_SHARPNESS,   "q_sharpness",   0.0,  Q_RANGE,          "dB/oct", Some(31),  Quantization, &[SHARPNESS]),
+	param!(Q_WIDTH,       "q_width",       0.0,  Q_RANGE,          "ST",     Some(31),  Quantization, &[WIDTH]),
+	param!(Q_F_LOW,       "q_f_low",       0.0,  Q_RANGE,          "ST",     Some(31),  Quantization, &[FILTERLOW]),
+	param!(Q_FS_LOW,      "q_fs_low",      0.0,  Q_RANGE,          "ST",     Some(31),  Quantization, &[FSLOPELOW]),
+	param!(Q_FSW_LOW,     "q_fsw_low",     0.0,  Q_RANGE,          "ST/s",   Some(31),  Quantization, &[FSWEEPLOW]),
+	param!(Q_F_HIGH,      "q_f_high",      0.0,  Q_RANGE,          "ST",     Some(31),  Quantization, &[FILTERHIGH]),
+	param!(Q_FS_HIGH,     "q_fs_high",     0.0,  Q_RANGE,          "ST",     Some(31),  Quantization, &[FSLOPEHIGH]),
+	param!(Q_FSW_HIGH,    "q_fsw_high",    0.0,  Q_RANGE,          "ST/s",   Some(31),  Quantization, &[FSWEEPHIGH]),
+	param!(Q_GAIN,        "q_gain",        0.0,  Q_RANGE,          "",       Some(31),  Quantization, &[GAIN]),
+	param!(Q_ATTACK,      "q_attack",      0.0,  Q_RANGE,          "ms",     Some(31),  Quantization, &[ATTACK]),
+	param!(Q_RELEASE,     "q_release",     0.0,  Q_RANGE,          "s",      Some(31),  Quantization, &[RELEASE]),
+	param!(VELSHARPNESS,  "velsharpness",  0.0,  (0.0, VELSHARPNESS_RANGE), "dB/oct", None, Velocity, &[]),
+	param!(VELFILTER,     "velfilter",     0.0,  (0.0, VELFILTER_RANGE),    "ST",     None, Velocity, &[]),
+	param!(VELDECAY,      "veldecay",      0.0,  (0.0, VELDECAY_RANGE),     "%",      None, Velocity, &[]),
+	param!(VELLAYERS,     "vellayers",     0.0,  (1.0, 16.0),      "",       Some(16),  Velocity,     &[]),
+	param!(REFPITCH,      "refpitch",      0.0,  REFPITCH_RANGE,   "Hz",     None,      Tuning,       &[]),
+	param!(HOLD,          "hold",          0.0,  (0.0, 1000.0),    "ms",     None,      Amplitude,    &[]),
+	param!(ENVDECAY,      "envdecay",      0.0,  (0.0, 10000.0),   "ms",     None,      Amplitude,    &[]),
+	param!(SUSTAIN,       "sustain",       0.0,  (0.0, -INF),      "dB",     None,      Amplitude,    &[]),
+	param!(ATTACKCURVE,   "attackcurve",   0.0,  CURVE_RANGE,      "%",      None,      Amplitude,    &[]),
+	param!(DECAYCURVE,    "decaycurve",    0.0,  CURVE_RANGE,      "%",      None,      Amplitude,    &[]),
+	param!(RELEASECURVE,  "releasecurve",  0.0,  CURVE_RANGE,      "%",      None,      Amplitude,    &[]),
+	param!(DECAYTRACK,    "decaytrack",    0.0,  (0.0, DECAYTRACK_RANGE), "%", None,      Decay,        &[]),
+	param!(FILTERTRACK,   "filtertrack",   0.0,  (100.0, 0.0),     "%",      None,      Filter,       &[]),
+	param!(TRACKPIVOT,    "trackpivot",    60.0 / 127.0, (0.0, 127.0), "",   Some(127), Decay,        &[]),
+	param!(SWELL,         "swell",         0.0,  (0.0, 2000.0),    "ms",     None,      Amplitude,    &[]),
+	param!(SWELLSLOPE,    "swellslope",    0.0,  (0.0, 100.0),     "%/oct",  None,      Amplitude,    &[]),
+	param!(SWELLSPREAD,   "swellspread",   0.0,  (0.0, 100.0),     "%",      None,      Amplitude,    &[]),
+	param!(FORMANT1,      "formant1",      0.5,  (0.0, 127.0),     "ST",     None,      Filter,       &[Q_FORMANT]),
+	param!(FWIDTH1,       "fwidth1",       0.0,  (0.0, FWIDTH_RANGE), "ST",  None,      Filter,       &[Q_FWIDTH]),
+	param!(FGAIN1,        "fgain1",        0.5,  (-FGAIN_RANGE, FGAIN_RANGE), "dB", None, Filter,     &[Q_FGAIN]),
+	param!(FORMANT2,      "formant2",      0.5,  (0.0, 127.0),     "ST",     None,      Filter,       &[Q_FORMANT]),
+	param!(FWIDTH2,       "fwidth2",       0.0,  (0.0, FWIDTH_RANGE), "ST",  None,      Filter,       &[Q_FWIDTH]),
+	param!(FGAIN2,        "fgain2",        0.5,  (-FGAIN_RANGE, FGAIN_RANGE), "dB", None, Filter,     &[Q_FGAIN]),
+	param!(FORMANTSWEEP,  "formantsweep",  0.0,  (-FORMANTSWEEP_RANGE, FORMANTSWEEP_RANGE), "ST/s", None, Filter, &[]),
+	param!(Q_FORMANT,     "q_formant",     0.0,  Q_RANGE,          "ST",     Some(31),  Quantization, &[FORMANT1, FORMANT2]),
+	param!(Q_FWIDTH,      "q_fwidth",      0.0,  Q_RANGE,          "ST",     Some(31),  Quantization, &[FWIDTH1, FWIDTH2]),
+	param!(Q_FGAIN,       "q_fgain",       0.0,  Q_RANGE,          "dB",     Some(31),  Quantization, &[FGAIN1, FGAIN2]),
+	param!(STIFFNESS,     "stiffness",     0.0,  (0.0, STIFFNESS_RANGE), "",  None,      Partials,     &[Q_STIFFNESS]),
+	param!(Q_STIFFNESS,   "q_stiffness",   0.0,  Q_RANGE,          "",       Some(31),  Quantization, &[STIFFNESS])
 ];
 
 
@@ -73,16 +209,25 @@ impl Default for OidosRandomData {
 	}
 }
 
+impl OidosRandomData {
+	/// Random value between -1 and 1, wrapping around at the end of the data.
+	pub fn value(&self, index: usize) -> f64 {
+		self.data[index % self.data.len()] as i32 as f64 / 0x80000000u32 as f64
+	}
+}
+
 #[test]
 fn test_random_data() {
 	let random = OidosRandomData::default();
 	assert_eq!(random.data.len(), NOISESIZE*NOISESIZE*NOISESIZE);
 	assert_eq!(*random.data.first().unwrap(), 0xCAADAA7B);
 	assert_eq!(*random.data.last().unwrap(),  0xB08A4BA7);
+	assert_eq!(random.value(NOISESIZE*NOISESIZE*NOISESIZE), random.value(0));
+	assert_eq!(random.value(0), 0xCAADAA7Bu32 as i32 as f64 / 2147483648.0);
 }
 
 
-fn quantize(value: f32, level: f32) -> f32 {
+pub fn quantize(value: f32, level: f32) -> f32 {
 	let bit = 1 << ((level * 31.0).floor() as i32);
 	let mask = !bit + 1;
 	let add = bit >> 1;
@@ -104,6 +249,8 @@ pub struct OidosSoundParameters {
 	decaylow: f32,
 	decaydiff: f32,
 	harmonicity: f32,
+	/// Inharmonicity coefficient of the partial frequencies
+	stiffness: f32,
 	sharpness: f32,
 	width: f32,
 
@@ -116,199 +263,765 @@ pub struct OidosSoundParameters {
 
 	gain: f32,
 
+	/// Halvings of the decay half-times per octave above the pivot tone
+	decay_track: f32,
+	/// Fraction of the distance from the pivot tone not followed by the filter limits
+	filter_fixed: f32,
+	track_pivot: f32,
+
+	/// Increase of the swell level per sample, infinite for no swell
+	swell_rate: f32,
+	/// Doublings of the swell time per octave of the partials above the played tone
+	swell_slope: f32,
+	/// Largest random change of the swell time of a partial, in twelfths of a doubling
+	swell_spread: f32,
+
+	/// Center tones of the formant peaks
+	formant_center: [f32; 2],
+	/// Inverse widths of the formant peaks, zero for a peak which is turned off
+	formant_slope: [f32; 2],
+	/// Gains at the centers of the formant peaks, minus one
+	formant_gain: [f32; 2],
+	/// Movement of the formant peaks, in semitones per second
+	formant_sweep: f32,
+
+	/// Time after which the envelope is silent, in samples
+	envelope_end: Option<f32>,
+
 	sample_rate: f32,
-	base_freq: f32
+	base_freq: f32,
+
+	/// Offset of all tones given by the reference pitch, in semitones
+	transpose: f32,
+	tuning: Tuning
 }
 
 impl SoundParameters for OidosSoundParameters {
-	fn names() -> &'static [&'static str] {
-		&NAMES
-	}
-
-	fn default_value(name: &str) -> f32 {
-		match name {
-			"filterlow" | "fslopelow" | "fslopehigh" => 0.0,
-			"fat" => 0.10,
-			"gain" | "attack" => 0.25,
-			"overtones" => 0.27,
-			"width" => 0.34,
-			"modes" => 0.40,
-			"seed" | "fsweeplow" | "fsweephigh" | "release" => 0.5,
-			"sharpness" => 0.9,
-			"harmonicity" | "decaylow" | "decayhigh" | "filterhigh" => 1.0,
-			_ => 0.0
-		}
+	fn schema() -> &'static [ParameterDescriptor] {
+		&SCHEMA
 	}
 
-	fn display<P: Index<&'static str, Output = f32>>(&self, name: &'static str, p: &P) -> (String, String) {
+	fn display(&self, index: usize, p: &[f32]) -> String {
 		let decaylow = -DECAY_TIME / (self.decaylow).log2();
 		let decayhigh = -DECAY_TIME / (self.decaylow + self.decaydiff).log2();
 
-		let pname = match name {
-			"q_decaydiff" => "decaydiff",
-			"q_decaylow" => "decaylow",
-			"q_harmonicity" => "harmonicity",
-			"q_sharpness" => "sharpness",
-			"q_width" => "width",
-			"q_f_low" => "filterlow",
-			"q_fs_low" => "fslopelow",
-			"q_fsw_low" => "fsweeplow",
-			"q_f_high" => "filterhigh",
-			"q_fs_high" => "fslopehigh",
-			"q_fsw_high" => "fsweephigh",
-			"q_gain" => "gain",
-			"q_attack" => "attack",
-			"q_release" => "release",
-			n => n
-		};
-
-		let value = match pname {
-			"seed" => format!("{}", self.seed),
-			"modes" => format!("{}", self.modes),
-			"fat" => format!("{}", self.fat),
-			"width" => format!("{:.3}", self.width),
-			"overtones" => format!("{:.0}", self.overtones),
-			"sharpness" => format!("{:+.1}", self.sharpness * 20.0 * 2f32.log10()),
-			"harmonicity" => format!("{:.2}", self.harmonicity),
-			"decaylow" => format!("{:.0}", 1000.0 * decaylow),
-			"decayhigh" => format!("{:.0}", 1000.0 * decayhigh),
-			"decaydiff" => format!("{:+.0}", 1000.0 * (decayhigh - decaylow)),
-			"filterlow" => format!("{:+.0}", self.f_low),
-			"fslopelow" => format!("{:.1}", 1.0 / self.f_slopelow),
-			"fsweeplow" => format!("{:+.1}", self.f_sweeplow),
-			"filterhigh" => format!("{:+.0}", self.f_high),
-			"fslopehigh" => format!("{:.1}", 1.0 / self.f_slopehigh),
-			"fsweephigh" => format!("{:+.1}", self.f_sweephigh),
-			"gain" => format!("{:.2}", self.gain),
-			"attack" => format!("{:.1}", 1000.0 / (OidosSoundParameters::attack(p, self.sample_rate) * self.sample_rate)),
-			"release" => format!("{:.2}", 1.0 / (OidosSoundParameters::release(p, self.sample_rate) * self.sample_rate)),
+		match index {
+			SEED => format!("{}", self.seed),
+			MODES => format!("{}", self.modes),
+			FAT => format!("{}", self.fat),
+			WIDTH | Q_WIDTH => format!("{:.3}", self.width),
+			OVERTONES => format!("{:.0}", self.overtones),
+			SHARPNESS | Q_SHARPNESS => format!("{:+.1}", self.sharpness * 20.0 * 2f32.log10()),
+			HARMONICITY | Q_HARMONICITY => format!("{:.2}", self.harmonicity),
+			STIFFNESS | Q_STIFFNESS => format!("{:.2e}", self.stiffness),
+			DECAYLOW | Q_DECAYLOW => format!("{:.0}", 1000.0 * decaylow),
+			DECAYHIGH => format!("{:.0}", 1000.0 * decayhigh),
+			Q_DECAYDIFF => format!("{:+.0}", 1000.0 * (decayhigh - decaylow)),
+			FILTERLOW | Q_F_LOW => format!("{:+.0}", self.f_low),
+			FSLOPELOW | Q_FS_LOW => format!("{:.1}", 1.0 / self.f_slopelow),
+			FSWEEPLOW | Q_FSW_LOW => format!("{:+.1}", self.f_sweeplow),
+			FILTERHIGH | Q_F_HIGH => format!("{:+.0}", self.f_high),
+			FSLOPEHIGH | Q_FS_HIGH => format!("{:.1}", 1.0 / self.f_slopehigh),
+			FSWEEPHIGH | Q_FSW_HIGH => format!("{:+.1}", self.f_sweephigh),
+			GAIN | Q_GAIN => format!("{:.2}", self.gain),
+			ATTACK | Q_ATTACK => format!("{:.1}", 1000.0 / (OidosSoundParameters::attack(p, self.sample_rate) * self.sample_rate)),
+			RELEASE | Q_RELEASE => format!("{:.2}", 1.0 / (OidosSoundParameters::release(p, self.sample_rate) * self.sample_rate)),
+			VELSHARPNESS => format!("{:.1}", OidosSoundParameters::to_display(index, p[index])),
+			VELFILTER | VELDECAY => format!("{:.0}", OidosSoundParameters::to_display(index, p[index])),
+			VELLAYERS => format!("{}", OidosSoundParameters::velocity_layers(p)),
+			REFPITCH | HOLD | SUSTAIN => format!("{:.1}", OidosSoundParameters::to_display(index, p[index])),
+			ENVDECAY => format!("{:.0}", OidosSoundParameters::to_display(index, p[index])),
+			ATTACKCURVE | DECAYCURVE | RELEASECURVE => format!("{:+.0}", OidosSoundParameters::to_display(index, p[index])),
+			DECAYTRACK | FILTERTRACK => format!("{:.0}", OidosSoundParameters::to_display(index, p[index])),
+			TRACKPIVOT => format!("{}", self.track_pivot),
+			SWELL => format!("{:.1}", OidosSoundParameters::to_display(index, p[index])),
+			SWELLSLOPE | SWELLSPREAD => format!("{:.0}", OidosSoundParameters::to_display(index, p[index])),
+			FORMANT1 | Q_FORMANT => format!("{:.1}", self.formant_center[0]),
+			FORMANT2 => format!("{:.1}", self.formant_center[1]),
+			FWIDTH1 | Q_FWIDTH => format!("{:.1}", self.formant_width(0)),
+			FWIDTH2 => format!("{:.1}", self.formant_width(1)),
+			FGAIN1 | Q_FGAIN => format!("{:+.1}", 20.0 * (1.0 + self.formant_gain[0]).log10()),
+			FGAIN2 => format!("{:+.1}", 20.0 * (1.0 + self.formant_gain[1]).log10()),
+			FORMANTSWEEP => format!("{:+.1}", self.formant_sweep),
 			_ => "-".to_string()
-		};
-		let label = match pname {
-			"sharpness" => "dB/oct",
-			"width" | "overtones" | "filterlow" | "fslopelow" | "filterhigh" | "fslopehigh" => "ST",
-			"fsweeplow" | "fsweephigh" => "ST/s",
-			"decaylow" | "decayhigh" | "decaydiff" => "ms half",
-			"attack" => "ms",
-			"release" => "s",
-			_ => ""
-		}.to_string();
-
-		(value, label)
-	}
-
-	fn influence(name: &'static str) -> Vec<&'static str> {
-		match name {
-			"width"         => vec!["q_width"],
-			"sharpness"     => vec!["q_sharpness"],
-			"harmonicity"   => vec!["q_harmonicity"],
-			"decaylow"      => vec!["q_decaydiff", "q_decaylow"],
-			"decayhigh"     => vec!["q_decaydiff"],
-			"filterlow"     => vec!["q_f_low"],
-			"fslopelow"     => vec!["q_fs_low"],
-			"fsweeplow"     => vec!["q_fsw_low"],
-			"filterhigh"    => vec!["q_f_high"],
-			"fslopehigh"    => vec!["q_fs_high"],
-			"fsweephigh"    => vec!["q_fsw_high"],
-			"gain"          => vec!["q_gain"],
-			"attack"        => vec!["q_attack"],
-			"release"       => vec!["q_release"],
-
-			"q_decaydiff"   => vec!["decayhigh"],
-			"q_decaylow"    => vec!["decaylow", "decayhigh", "q_decaydiff"],
-			"q_harmonicity" => vec!["harmonicity"],
-			"q_sharpness"   => vec!["sharpness"],
-			"q_width"       => vec!["width"],
-			"q_f_low"       => vec!["filterlow"],
-			"q_fs_low"      => vec!["fslopelow"],
-			"q_fsw_low"     => vec!["fsweeplow"],
-			"q_f_high"      => vec!["filterhigh"],
-			"q_fs_high"     => vec!["fslopehigh"],
-			"q_fsw_high"    => vec!["fsweephigh"],
-			"q_gain"        => vec!["gain"],
-			"q_attack"      => vec!["attack"],
-			"q_release"     => vec!["release"],
-
-			_               => vec![]
 		}
 	}
 
-	fn build<P: Index<&'static str, Output = f32>>(p: &P, sample_rate: f32) -> OidosSoundParameters {
-		let mut params = OidosSoundParameters {
-			modes:       (p["modes"]     * 100.0 + 0.5).floor().max(1.0) as u8,
-			fat:         (p["fat"]       * 100.0 + 0.5).floor().max(1.0) as u8,
-			seed:        (p["seed"]      * 100.0 + 0.5).floor() as u8,
-			overtones:   (p["overtones"] * 100.0 + 0.5).floor() as u8,
-
-			decaylow:    p["decaylow"],
-			decaydiff:   p["decayhigh"] - p["decaylow"],
-			harmonicity: p["harmonicity"] * 2.0 - 1.0,
-			sharpness:   p["sharpness"] * 5.0 - 4.0,
-			width:       p["width"].powi(5) * 100.0,
-
-			f_low:       (p["filterlow"] * 2.0 - 1.0)    * TOTAL_SEMITONES,
-			f_slopelow:  (1.0 - p["fslopelow"]).powi(3),
-			f_sweeplow:  (p["fsweeplow"] - 0.5).powi(3)  * TOTAL_SEMITONES * 100.0,
-			f_high:      (p["filterhigh"] * 2.0 - 1.0)   * TOTAL_SEMITONES,
-			f_slopehigh: (1.0 - p["fslopehigh"]).powi(3),
-			f_sweephigh: (p["fsweephigh"] - 0.5).powi(3) * TOTAL_SEMITONES * 100.0,
-
-			gain:        4096f32.powf(p["gain"] - 0.25),
+	fn to_display(index: usize, value: f32) -> f32 {
+		match index {
+			SEED | MODES | FAT | OVERTONES => SCHEMA[index].integer_value(value) as f32,
+			WIDTH => value.powi(5) * 100.0,
+			SHARPNESS => (value * 5.0 - 4.0) * 20.0 * 2f32.log10(),
+			HARMONICITY => value * 2.0 - 1.0,
+			DECAYLOW | DECAYHIGH => 1000.0 * -DECAY_TIME / value.log2(),
+			FILTERLOW | FILTERHIGH => (value * 2.0 - 1.0) * TOTAL_SEMITONES,
+			FSLOPELOW | FSLOPEHIGH => 1.0 / (1.0 - value).powi(3),
+			FSWEEPLOW | FSWEEPHIGH => (value - 0.5).powi(3) * TOTAL_SEMITONES * 100.0,
+			GAIN => 4096f32.powf(value - 0.25),
+			ATTACK => 1000.0 * value * value,
+			RELEASE => value,
+			UNUSED => value,
+			VELSHARPNESS => value * VELSHARPNESS_RANGE,
+			VELFILTER => value * VELFILTER_RANGE,
+			VELDECAY => value * VELDECAY_RANGE,
+			VELLAYERS => SCHEMA[index].integer_value(value) as f32,
+			REFPITCH if value == 0.0 => 440.0,
+			REFPITCH => REFPITCH_RANGE.0 + value * (REFPITCH_RANGE.1 - REFPITCH_RANGE.0),
+			HOLD => 1000.0 * HOLD_RANGE * value * value,
+			ENVDECAY => 1000.0 * ENVDECAY_RANGE * value * value,
+			SUSTAIN => 20.0 * (1.0 - value).log10(),
+			ATTACKCURVE | DECAYCURVE | RELEASECURVE if value == 0.0 => 0.0,
+			ATTACKCURVE | DECAYCURVE | RELEASECURVE => CURVE_RANGE.0 + value * (CURVE_RANGE.1 - CURVE_RANGE.0),
+			DECAYTRACK => value * DECAYTRACK_RANGE,
+			FILTERTRACK => 100.0 * (1.0 - value),
+			TRACKPIVOT => SCHEMA[index].integer_value(value) as f32,
+			SWELL => 1000.0 * SWELL_RANGE * value * value,
+			SWELLSLOPE | SWELLSPREAD => value * 100.0,
+			FORMANT1 | FORMANT2 => value * 127.0,
+			FWIDTH1 | FWIDTH2 => value * FWIDTH_RANGE,
+			FGAIN1 | FGAIN2 => (value * 2.0 - 1.0) * FGAIN_RANGE,
+			FORMANTSWEEP if value == 0.0 => 0.0,
+			FORMANTSWEEP => (value * 2.0 - 1.0).powi(3) * FORMANTSWEEP_RANGE,
+			STIFFNESS => value.powi(4) * STIFFNESS_RANGE,
+			_ => (value * 31.0).floor()
+		}
+	}
 
-			sample_rate: sample_rate,
-			base_freq:   440.0 * 2f32.powf(-57.0 / 12.0) / sample_rate * 2.0 * f32::consts::PI
+	fn from_display(index: usize, display: f32) -> f32 {
+		let value = match index {
+			SEED | MODES | FAT | OVERTONES => display.round().max(SCHEMA[index].range.0) / 100.0,
+			WIDTH => (display / 100.0).powf(0.2),
+			SHARPNESS => (display / (20.0 * 2f32.log10()) + 4.0) / 5.0,
+			HARMONICITY => (display + 1.0) / 2.0,
+			// Infinite decay is displayed as -inf
+			DECAYLOW | DECAYHIGH if display.is_infinite() => 1.0,
+			DECAYLOW | DECAYHIGH => 2f32.powf(-DECAY_TIME * 1000.0 / display.max(0.0)),
+			FILTERLOW | FILTERHIGH => (display / TOTAL_SEMITONES + 1.0) / 2.0,
+			FSLOPELOW | FSLOPEHIGH => 1.0 - (1.0 / display).cbrt(),
+			FSWEEPLOW | FSWEEPHIGH => 0.5 + (display / (TOTAL_SEMITONES * 100.0)).cbrt(),
+			GAIN => display.ln() / 4096f32.ln() + 0.25,
+			ATTACK => (display / 1000.0).sqrt(),
+			RELEASE => display,
+			UNUSED => display,
+			VELSHARPNESS => display / VELSHARPNESS_RANGE,
+			VELFILTER => display / VELFILTER_RANGE,
+			VELDECAY => display / VELDECAY_RANGE,
+			VELLAYERS => display.round().max(1.0) / 16.0,
+			// Stay clear of zero, which means 440 Hz
+			REFPITCH => ((display - REFPITCH_RANGE.0) / (REFPITCH_RANGE.1 - REFPITCH_RANGE.0)).max(f32::MIN_POSITIVE),
+			HOLD => (display / (1000.0 * HOLD_RANGE)).sqrt(),
+			ENVDECAY => (display / (1000.0 * ENVDECAY_RANGE)).sqrt(),
+			SUSTAIN => 1.0 - 10f32.powf(display / 20.0),
+			// Stay clear of zero, which means a linear stage
+			ATTACKCURVE | DECAYCURVE | RELEASECURVE => ((display - CURVE_RANGE.0) / (CURVE_RANGE.1 - CURVE_RANGE.0)).max(f32::MIN_POSITIVE),
+			DECAYTRACK => display / DECAYTRACK_RANGE,
+			FILTERTRACK => 1.0 - display / 100.0,
+			TRACKPIVOT => display.round() / 127.0,
+			SWELL => (display / (1000.0 * SWELL_RANGE)).sqrt(),
+			SWELLSLOPE | SWELLSPREAD => display / 100.0,
+			FORMANT1 | FORMANT2 => display / 127.0,
+			FWIDTH1 | FWIDTH2 => display / FWIDTH_RANGE,
+			FGAIN1 | FGAIN2 => (display / FGAIN_RANGE + 1.0) / 2.0,
+			// Stay clear of zero, which means no movement
+			FORMANTSWEEP => (0.5 + (display / FORMANTSWEEP_RANGE).cbrt() / 2.0).max(f32::MIN_POSITIVE),
+			STIFFNESS => (display.max(0.0) / STIFFNESS_RANGE).powf(0.25),
+			// Quantization parameters are entered as the number of bits rounded off
+			_ => (display.round() + 0.5) / 31.0
 		};
+		value.max(0.0).min(1.0)
+	}
 
-		params.decaylow = quantize(params.decaylow, p["q_decaylow"]);
-		params.decaydiff = quantize(params.decaydiff, p["q_decaydiff"]);
-		params.harmonicity = quantize(params.harmonicity, p["q_harmonicity"]);
-		params.sharpness = quantize(params.sharpness, p["q_sharpness"]);
-		params.width = quantize(params.width, p["q_width"]);
+	fn build(p: &[f32], sample_rate: f32) -> OidosSoundParameters {
+		let mut params = OidosSoundParameters {
+			modes:       SCHEMA[MODES].integer_value(p[MODES]) as u8,
+			fat:         SCHEMA[FAT].integer_value(p[FAT]) as u8,
+			seed:        SCHEMA[SEED].integer_value(p[SEED]) as u8,
+			overtones:   SCHEMA[OVERTONES].integer_value(p[OVERTONES]) as u8,
+
+			decaylow:    p[DECAYLOW],
+			decaydiff:   p[DECAYHIGH] - p[DECAYLOW],
+			harmonicity: p[HARMONICITY] * 2.0 - 1.0,
+			stiffness:   p[STIFFNESS].powi(4) * STIFFNESS_RANGE,
+			sharpness:   p[SHARPNESS] * 5.0 - 4.0,
+			width:       p[WIDTH].powi(5) * 100.0,
+
+			f_low:       (p[FILTERLOW] * 2.0 - 1.0)    * TOTAL_SEMITONES,
+			f_slopelow:  (1.0 - p[FSLOPELOW]).powi(3),
+			f_sweeplow:  (p[FSWEEPLOW] - 0.5).powi(3)  * TOTAL_SEMITONES * 100.0,
+			f_high:      (p[FILTERHIGH] * 2.0 - 1.0)   * TOTAL_SEMITONES,
+			f_slopehigh: (1.0 - p[FSLOPEHIGH]).powi(3),
+			f_sweephigh: (p[FSWEEPHIGH] - 0.5).powi(3) * TOTAL_SEMITONES * 100.0,
+
+			gain:        4096f32.powf(p[GAIN] - 0.25),
+
+			decay_track: p[DECAYTRACK],
+			filter_fixed: p[FILTERTRACK],
+			track_pivot: SCHEMA[TRACKPIVOT].integer_value(p[TRACKPIVOT]) as f32,
+
+			swell_rate:  if p[SWELL] == 0.0 { INF } else { 1.0 / (p[SWELL] * p[SWELL] * SWELL_RANGE * sample_rate) },
+			swell_slope: p[SWELLSLOPE],
+			swell_spread: p[SWELLSPREAD] * 12.0,
+
+			formant_center: [p[FORMANT1] * 127.0, p[FORMANT2] * 127.0],
+			formant_slope: [OidosSoundParameters::formant_slope(p[FWIDTH1]), OidosSoundParameters::formant_slope(p[FWIDTH2])],
+			formant_gain: [10f32.powf((p[FGAIN1] * 2.0 - 1.0) * FGAIN_RANGE / 20.0) - 1.0,
+			               10f32.powf((p[FGAIN2] * 2.0 - 1.0) * FGAIN_RANGE / 20.0) - 1.0],
+			formant_sweep: OidosSoundParameters::to_display(FORMANTSWEEP, p[FORMANTSWEEP]),
+
+			envelope_end: OidosSoundParameters::envelope(p, sample_rate).silence_time(),
 
-		params.f_low = quantize(params.f_low, p["q_f_low"]);
-		params.f_slopelow = quantize(params.f_slopelow, p["q_fs_low"]);
-		params.f_sweeplow = quantize(params.f_sweeplow / TARGET_SAMPLE_RATE, p["q_fsw_low"]) * TARGET_SAMPLE_RATE;
-		params.f_high = quantize(params.f_high, p["q_f_high"]);
-		params.f_slopehigh = quantize(params.f_slopehigh, p["q_fs_high"]);
-		params.f_sweephigh = quantize(params.f_sweephigh / TARGET_SAMPLE_RATE, p["q_fsw_high"]) * TARGET_SAMPLE_RATE;
+			sample_rate: sample_rate,
+			base_freq:   440.0 * 2f32.powf(-57.0 / 12.0) / sample_rate * 2.0 * f32::consts::PI,
 
-		params.gain = quantize(params.gain, p["q_gain"]);
+			transpose:   12.0 * (OidosSoundParameters::to_display(REFPITCH, p[REFPITCH]) / 440.0).log2(),
+			tuning:      Tuning::default()
+		};
+
+		params.decaylow = quantize(params.decaylow, p[Q_DECAYLOW]);
+		params.decaydiff = quantize(params.decaydiff, p[Q_DECAYDIFF]);
+		params.harmonicity = quantize(params.harmonicity, p[Q_HARMONICITY]);
+		params.stiffness = quantize(params.stiffness, p[Q_STIFFNESS]);
+		params.sharpness = quantize(params.sharpness, p[Q_SHARPNESS]);
+		params.width = quantize(params.width, p[Q_WIDTH]);
+
+		params.f_low = quantize(params.f_low, p[Q_F_LOW]);
+		params.f_slopelow = quantize(params.f_slopelow, p[Q_FS_LOW]);
+		params.f_sweeplow = quantize(params.f_sweeplow / TARGET_SAMPLE_RATE, p[Q_FSW_LOW]) * TARGET_SAMPLE_RATE;
+		params.f_high = quantize(params.f_high, p[Q_F_HIGH]);
+		params.f_slopehigh = quantize(params.f_slopehigh, p[Q_FS_HIGH]);
+		params.f_sweephigh = quantize(params.f_sweephigh / TARGET_SAMPLE_RATE, p[Q_FSW_HIGH]) * TARGET_SAMPLE_RATE;
+
+		params.gain = quantize(params.gain, p[Q_GAIN]);
+
+		for k in 0..2 {
+			params.formant_center[k] = quantize(params.formant_center[k], p[Q_FORMANT]);
+			params.formant_slope[k] = quantize(params.formant_slope[k], p[Q_FWIDTH]);
+			params.formant_gain[k] = quantize(params.formant_gain[k], p[Q_FGAIN]);
+		}
 
 		params
 	}
 
-	fn attack<P: Index<&'static str, Output = f32>>(p: &P, sample_rate: f32) -> f32 {
-		let attack = p["attack"];
+	fn attack(p: &[f32], sample_rate: f32) -> f32 {
+		let attack = p[ATTACK];
 		quantize(if attack == 0.0 {
 			2.0
 		} else {
 			1.0 / (attack * attack * sample_rate)
-		}, p["q_attack"])
+		}, p[Q_ATTACK])
 	}
 
-	fn release<P: Index<&'static str, Output = f32>>(p: &P, sample_rate: f32) -> f32 {
-		let release = p["release"];
+	fn release(p: &[f32], sample_rate: f32) -> f32 {
+		let release = p[RELEASE];
 		quantize(if release == 0.0 {
 			2.0
 		} else {
 			1.0 / (release * sample_rate)
-		}, p["q_release"])
+		}, p[Q_RELEASE])
+	}
+
+	fn envelope(p: &[f32], sample_rate: f32) -> Envelope {
+		let curve = |index: usize| OidosSoundParameters::to_display(index, p[index]) / 100.0;
+		let decay = p[ENVDECAY];
+		Envelope {
+			attack: OidosSoundParameters::attack(p, sample_rate),
+			attack_curve: curve(ATTACKCURVE),
+			hold: p[HOLD] * p[HOLD] * HOLD_RANGE * sample_rate,
+			decay: if decay == 0.0 { 2.0 } else { 1.0 / (decay * decay * ENVDECAY_RANGE * sample_rate) },
+			decay_curve: curve(DECAYCURVE),
+			sustain: 1.0 - p[SUSTAIN],
+			release: OidosSoundParameters::release(p, sample_rate),
+			release_curve: curve(RELEASECURVE)
+		}
+	}
+
+	fn velocity_layers(p: &[f32]) -> usize {
+		SCHEMA[VELLAYERS].integer_value(p[VELLAYERS]) as usize
+	}
+
+	fn layer_values(p: &[f32], layer: usize) -> Vec<f32> {
+		let mut values = p.to_vec();
+		// Lower layers are softer, from 0 at the top layer towards 1 at the lowest velocities
+		let softness = 1.0 - layer_velocity(layer, OidosSoundParameters::velocity_layers(p)) as f32 / 127.0;
+		if softness == 0.0 {
+			return values;
+		}
+		let sharpness = p[VELSHARPNESS] * VELSHARPNESS_RANGE / (5.0 * 20.0 * 2f32.log10());
+		values[SHARPNESS] = (p[SHARPNESS] - sharpness * softness).max(0.0);
+		let filter = p[VELFILTER] * VELFILTER_RANGE / (2.0 * TOTAL_SEMITONES);
+		values[FILTERHIGH] = (p[FILTERHIGH] - filter * softness).max(0.0);
+		if p[VELDECAY] > 0.0 {
+			// Scale the half-times of the decay
+			let scale = (1.0 - p[VELDECAY] * softness).max(0.01);
+			values[DECAYLOW] = p[DECAYLOW].powf(1.0 / scale);
+			values[DECAYHIGH] = p[DECAYHIGH].powf(1.0 / scale);
+		}
+		values
+	}
+
+	fn tune(&mut self, tuning: &Tuning) {
+		self.tuning = tuning.clone();
+	}
+
+	fn duration(&self) -> Option<usize> {
+		// With key tracking, the decay is longest at the lowest tone
+		let lowest_tone = (0..128).map(|key| self.tuned_tone(key)).fold(f64::INFINITY, f64::min);
+		self.duration_from(lowest_tone)
 	}
+}
 
+/// Default values with some parameters set from their displayed values.
+#[cfg(test)]
+fn test_parse_values(settings: &[(usize, &str)]) -> Vec<f32> {
+	let mut values = OidosSoundParameters::default_values();
+	for &(index, text) in settings {
+		values[index] = OidosSoundParameters::parse(index, text).unwrap();
+	}
+	values
+}
+
+/// Check that a generator started later continues the sound of one started at the beginning.
+#[cfg(test)]
+fn test_continuation(param: &OidosSoundParameters, random: &OidosRandomData) {
+	let mut from_start = OidosSoundGenerator::new(param, 60, 0, random);
+	for _ in 0..5000 {
+		from_start.produce_sample();
+	}
+	let mut later = OidosSoundGenerator::new(param, 60, 5000, random);
+	for _ in 0..100 {
+		assert!((from_start.produce_sample() - later.produce_sample()).abs() < 1e-5);
+	}
 }
 
 #[test]
 fn test_oidos_sound_parameters() {
-	let names = OidosSoundParameters::names();
-	let mut map = HashMap::new();
-	for name in names {
-		map.insert(*name, OidosSoundParameters::default_value(name));
-	}
-	let param = OidosSoundParameters::build(&map, 44100.0);
+	let values = OidosSoundParameters::default_values();
+	let param = OidosSoundParameters::build(&values, 44100.0);
 	assert_eq!(param.base_freq, 0.00232970791933f32);
+	assert_eq!(param.duration(), None);
+}
+
+#[test]
+fn test_oidos_layer_values() {
+	let mut values = OidosSoundParameters::default_values();
+	values[DECAYLOW] = 0.5;
+	values[DECAYHIGH] = 0.8;
+	assert_eq!(OidosSoundParameters::velocity_layers(&values), 1);
+	assert_eq!(OidosSoundParameters::layer_values(&values, 0), values);
+
+	values[VELLAYERS] = OidosSoundParameters::parse(VELLAYERS, "4").unwrap();
+	values[VELSHARPNESS] = OidosSoundParameters::parse(VELSHARPNESS, "12 dB/oct").unwrap();
+	values[VELFILTER] = OidosSoundParameters::parse(VELFILTER, "30 ST").unwrap();
+	values[VELDECAY] = OidosSoundParameters::parse(VELDECAY, "50 %").unwrap();
+	assert_eq!(OidosSoundParameters::velocity_layers(&values), 4);
+	assert_eq!(OidosSoundParameters::layer_values(&values, 3), values);
+
+	// The lowest layer plays velocity 31, at a softness of 96/127
+	let softness = 96.0 / 127.0;
+	let layer = OidosSoundParameters::layer_values(&values, 0);
+	let base = OidosSoundParameters::build(&values, 44100.0);
+	let soft = OidosSoundParameters::build(&layer, 44100.0);
+	let display = |param: &OidosSoundParameters, values: &[f32], index| param.display(index, values).parse::<f32>().unwrap();
+	assert!((display(&base, &values, SHARPNESS) - display(&soft, &layer, SHARPNESS) - 12.0 * softness).abs() < 0.1);
+	assert!((display(&base, &values, FILTERHIGH) - display(&soft, &layer, FILTERHIGH) - 30.0 * softness).abs() < 1.0);
+	let halftime = |param: &OidosSoundParameters, values: &[f32], index| display(param, values, index) * (1.0 - 0.5 * softness);
+	assert!((halftime(&base, &values, DECAYLOW) - display(&soft, &layer, DECAYLOW)).abs() < 1.0);
+	assert!((halftime(&base, &values, DECAYHIGH) - display(&soft, &layer, DECAYHIGH)).abs() < 1.0);
+}
+
+#[test]
+fn test_oidos_tuning() {
+	let mut values = OidosSoundParameters::default_values();
+	let mut param = OidosSoundParameters::build(&values, 44100.0);
+	assert_eq!(param.tuned_tone(60), 60.0);
+	let mut tuning = Tuning::default();
+	tuning.set_tone(60, 60.5);
+	param.tune(&tuning);
+	assert_eq!(param.tuned_tone(60), 60.5);
+	assert_eq!(param.tuned_tone(61), 61.0);
+
+	values[REFPITCH] = OidosSoundParameters::parse(REFPITCH, "415.3 Hz").unwrap();
+	let param = OidosSoundParameters::build(&values, 44100.0);
+	assert!((param.tuned_tone(60) - 59.0).abs() < 1e-3);
+	assert_eq!(OidosSoundParameters::parse(REFPITCH, "415 Hz").map(|v| v > 0.0), Some(true));
+}
+
+#[test]
+fn test_oidos_schema() {
+	for (index, desc) in SCHEMA.iter().enumerate() {
+		assert_eq!(desc.index, index);
+	}
+
+	// The converter has its own copy of the parameter names
+	let converter = include_str!("../../convert/OidosConvert.py");
+	let names_start = converter.find("class Instrument:").unwrap();
+	let names_start = names_start + converter[names_start..].find('[').unwrap() + 1;
+	let names_end = names_start + converter[names_start..].find(']').unwrap();
+	let converter_names: Vec<&str> = converter[names_start..names_end].split(',')
+		.map(|n| n.trim().trim_matches('"'))
+		.map(|n| if n == "dummy" { "-" } else { n })
+		.collect();
+	let names: Vec<&str> = SCHEMA.iter().map(|desc| desc.name).collect();
+	assert_eq!(converter_names, names);
+}
+
+#[test]
+fn test_oidos_parse() {
+	let mut values = OidosSoundParameters::default_values();
+	// Finite decays, so that q_decaydiff shows a number
+	values[DECAYLOW] = OidosSoundParameters::from_display(DECAYLOW, 500.0);
+	values[DECAYHIGH] = OidosSoundParameters::from_display(DECAYHIGH, 250.0);
+	let param = OidosSoundParameters::build(&values, 44100.0);
+	for desc in SCHEMA {
+		for &value in &[0.0, 0.13, 0.5, 0.71, 1.0] {
+			if [REFPITCH, ATTACKCURVE, DECAYCURVE, RELEASECURVE, FORMANTSWEEP].contains(&desc.index) && value == 0.0 {
+				// Zero displays as the neutral value in the middle of the range
+				continue;
+			}
+			let display = OidosSoundParameters::to_display(desc.index, value);
+			let parsed = OidosSoundParameters::from_display(desc.index, display);
+			match desc.steps {
+				Some(_) => assert_eq!(OidosSoundParameters::to_display(desc.index, parsed), display, "{} {}", desc.name, value),
+				None => assert!((parsed - value).abs() < 1e-4, "{} {} {} {}", desc.name, value, display, parsed)
+			}
+		}
+		if desc.group != ParameterGroup::Unused {
+			// Parsing a displayed value gives a value with the same display
+			let text = param.display(desc.index, &values);
+			let mut parsed_values = values.clone();
+			parsed_values[desc.index] = OidosSoundParameters::parse_in_patch(desc.index, &format!("{} {}", text, desc.unit), &values, 44100.0).unwrap();
+			let parsed_param = OidosSoundParameters::build(&parsed_values, 44100.0);
+			assert_eq!(parsed_param.display(desc.index, &parsed_values), text, "{}", desc.name);
+		}
+	}
+
+	let parse = |index, text| OidosSoundParameters::parse(index, text).unwrap();
+	assert_eq!(parse(MODES, "17"), 0.17);
+	assert_eq!(parse(FAT, "0"), 0.01);
+	assert_eq!(parse(FILTERHIGH, "+3 ST"), (3.0 / 120.0 + 1.0) / 2.0);
+	assert!((parse(WIDTH, "3.2 ST") - 0.032f32.powf(0.2)).abs() < 1e-6);
+	assert!((parse(DECAYLOW, "250 ms half") - 0.5f32.powf(4096.0 / 44100.0 / 0.25)).abs() < 1e-6);
+	assert_eq!(parse(DECAYHIGH, "inf"), 1.0);
+	assert!((parse(FSLOPELOW, "8") - 0.5).abs() < 1e-6);
+	assert!((parse(FSWEEPHIGH, "-12") - 0.4).abs() < 1e-6);
+	assert!((parse(ATTACK, "250ms") - 0.5).abs() < 1e-6);
+	assert_eq!(parse(Q_GAIN, "16"), 16.5 / 31.0);
+	assert_eq!(OidosSoundParameters::parse(GAIN, "loud"), None);
+}
+
+#[test]
+fn test_oidos_parse_quantization() {
+	let mut values = OidosSoundParameters::default_values();
+	values[GAIN] = 0.4;
+	let parse = |text, values: &[f32]| OidosSoundParameters::parse_in_patch(Q_GAIN, text, values, 44100.0).unwrap();
+	let display = |values: &[f32]| OidosSoundParameters::build(values, 44100.0).display(Q_GAIN, values);
+	assert_eq!(parse("16 bits", &values), 16.5 / 31.0);
+
+	// A typed value picks the coarsest level showing the nearest value
+	let unquantized = display(&values);
+	values[Q_GAIN] = parse("20 bits", &values);
+	let text = display(&values);
+	assert_ne!(text, unquantized);
+	values[Q_GAIN] = parse(&text, &values);
+	assert_eq!(display(&values), text);
+	assert!(OidosSoundParameters::to_display(Q_GAIN, values[Q_GAIN]) >= 20.0);
+	assert_eq!(OidosSoundParameters::parse_in_patch(Q_GAIN, "loud", &values, 44100.0), None);
+}
+
+#[test]
+fn test_oidos_duration() {
+	let mut values = OidosSoundParameters::default_values();
+	values[DECAYLOW] = 0.9;
+	values[DECAYHIGH] = 0.95;
+	// log(0.01, 0.95) * 4096 = 367771 samples, rounded up to a multiple of 65536
+	let param = OidosSoundParameters::build(&values, 44100.0);
+	assert_eq!(param.duration(), Some(393216));
+	let param = OidosSoundParameters::build(&values, 88200.0);
+	assert_eq!(param.duration(), Some(786432));
+
+	// An envelope decaying to silence ends the sound earlier
+	values[SUSTAIN] = 1.0;
+	values[ENVDECAY] = 0.1;
+	let param = OidosSoundParameters::build(&values, 44100.0);
+	assert_eq!(param.duration(), Some(65536));
+}
+
+#[test]
+fn test_oidos_envelope() {
+	let values = OidosSoundParameters::default_values();
+	let attack = OidosSoundParameters::attack(&values, 44100.0);
+	let release = OidosSoundParameters::release(&values, 44100.0);
+	assert_eq!(OidosSoundParameters::envelope(&values, 44100.0), Envelope::linear(attack, release));
+
+	let values = test_parse_values(&[(HOLD, "250 ms"), (ENVDECAY, "100 ms"), (SUSTAIN, "-6 dB"), (DECAYCURVE, "+50 %")]);
+	let envelope = OidosSoundParameters::envelope(&values, 44100.0);
+	assert!((envelope.hold - 11025.0).abs() < 0.1);
+	assert!((envelope.decay * 4410.0 - 1.0).abs() < 1e-4);
+	assert!((envelope.sustain - 0.501).abs() < 1e-3);
+	assert_eq!(envelope.decay_curve, 0.5);
+	assert_eq!(OidosSoundParameters::to_display(RELEASECURVE, 0.0), 0.0);
+	assert!(OidosSoundParameters::parse(RELEASECURVE, "-100 %").unwrap() > 0.0);
+}
+
+
+#[test]
+fn test_oidos_keytrack() {
+	let mut values = OidosSoundParameters::default_values();
+	values[DECAYLOW] = 0.5;
+	values[DECAYHIGH] = 0.5;
+	let param = OidosSoundParameters::build(&values, 44100.0);
+	assert_eq!((param.decay_scale(84.0), param.filter_start(72, 72.0)), (1.0, param.filter_start(60, 60.0)));
+	assert_eq!(param.key_duration(36), param.key_duration(84));
+
+	// Half-times halved for every octave above the pivot, filters following the tone half way
+	values[DECAYTRACK] = OidosSoundParameters::parse(DECAYTRACK, "100 %").unwrap();
+	values[FILTERTRACK] = OidosSoundParameters::parse(FILTERTRACK, "50 %").unwrap();
+	values[FILTERHIGH] = 0.5;
+	values[FSLOPEHIGH] = 0.5;
+	let param = OidosSoundParameters::build(&values, 44100.0);
+	assert_eq!((param.decay_scale(48.0), param.decay_scale(60.0), param.decay_scale(84.0)), (0.5, 1.0, 4.0));
+	// The high limit is 6 semitones above the pivot at tone 72, with a slope of 8 semitones
+	assert_eq!(param.filter_start(72, 64.0).1, 1.25);
+	assert_eq!(param.key_duration(48), Some(65536));
+	assert_eq!(param.key_duration(36), Some(131072));
+	assert_eq!(param.duration(), Some(917504));
+}
+
+#[test]
+fn test_oidos_swell() {
+	let random = OidosRandomData::default();
+	let param = OidosSoundParameters::build(&OidosSoundParameters::default_values(), 44100.0);
+	param.for_each_partial(60, &random, |partial| assert!(partial.swell.is_infinite()));
+
+	// Swell of 500 ms, twice as long for every octave of the partials above the tone
+	let values = test_parse_values(&[(SWELL, "500 ms"), (SWELLSLOPE, "100 %/oct")]);
+	let param = OidosSoundParameters::build(&values, 44100.0);
+	param.for_each_partial(60, &random, |partial| {
+		let swell_time = 22050.0 * 2f64.powf((partial.tone - 60.0) / 12.0);
+		assert!((partial.swell * swell_time - 1.0).abs() < 1e-4);
+	});
+
+	// The sound starts silent, and a generator started later continues the swell
+	assert_eq!(OidosSoundGenerator::new(&param, 60, 0, &random).produce_sample(), 0.0);
+	test_continuation(&param, &random);
+}
+
+#[test]
+fn test_oidos_formants() {
+	let random = OidosRandomData::default();
+	let param = OidosSoundParameters::build(&OidosSoundParameters::default_values(), 44100.0);
+	for &ptone in &[0.0, 60.0, 127.0] {
+		assert_eq!(OidosSoundParameters::formant_factor(&param.formant_start(ptone)), 1.0);
+	}
+
+	// A 6 dB peak at tone 72, falling off over 12 semitones and moving up an octave per second
+	let values = test_parse_values(&[(FORMANT1, "72 ST"), (FWIDTH1, "12 ST"), (FGAIN1, "+6 dB"), (FORMANTSWEEP, "+12 ST/s")]);
+	let param = OidosSoundParameters::build(&values, 44100.0);
+	let factor = |ptone: f64| OidosSoundParameters::formant_factor(&param.formant_start(ptone));
+	let peak = 10f64.powf(6.0 / 20.0);
+	assert!((factor(72.0) - peak).abs() < 1e-3);
+	assert!((factor(66.0) - (1.0 + peak) / 2.0).abs() < 1e-3);
+	assert_eq!((factor(60.0), factor(84.0)), (1.0, 1.0));
+	let (ramp, add, _) = param.formant_start(84.0)[0];
+	assert!((ramp + add * 44100.0 - 1.0).abs() < 1e-3);
+
+	// A generator started later continues the formant movement
+	test_continuation(&param, &random);
+}
+
+#[test]
+fn test_oidos_stiffness() {
+	let random = OidosRandomData::default();
+	let mut values = OidosSoundParameters::default_values();
+	values[WIDTH] = 0.0;
+	values[OVERTONES] = 0.48;
+	let is_harmonic = |values: &[f32], b: f64| {
+		let mut harmonic = true;
+		OidosSoundParameters::build(values, 44100.0).for_each_partial(60, &random, |partial| {
+			// Find the harmonic stretched to the frequency of the partial
+			let relfreq = 2f64.powf((partial.tone - 60.0) / 12.0);
+			let n = (1..=16).map(|n| n as f64).find(|n| (n * (1.0 + b * n * n).sqrt() - relfreq).abs() < 1e-6);
+			harmonic &= n.is_some();
+		});
+		harmonic
+	};
+	assert!(is_harmonic(&values, 0.0));
+
+	values[STIFFNESS] = OidosSoundParameters::parse(STIFFNESS, "1e-3").unwrap();
+	let param = OidosSoundParameters::build(&values, 44100.0);
+	assert!((param.stiffness - 1e-3).abs() < 1e-9);
+	assert_eq!(param.display(STIFFNESS, &values), "1.00e-3");
+	assert!(is_harmonic(&values, param.stiffness as f64));
+	assert!(!is_harmonic(&values, 0.0));
 }
 
 
+/// Placement of a single partial in the sound.
+pub struct OidosPartial {
+	/// Absolute tone of the partial, in semitones
+	pub tone: f64,
+	/// Initial amplitude, before filtering
+	pub amp: f64,
+	/// Amplitude decay factor per sample
+	pub ampmul: f64,
+	/// Initial phase, in units of pi
+	pub phase: f64,
+	/// Increase of the swell level per sample, infinite for a partial at full level from the start
+	pub swell: f64
+}
+
+impl OidosSoundParameters {
+	/// Number of partials in the sound.
+	pub fn partial_count(&self) -> usize {
+		self.modes as usize * self.fat as usize
+	}
+
+	/// Tone played by a key, according to the tuning and reference pitch.
+	pub fn tuned_tone(&self, key: u8) -> f64 {
+		self.tuning.tone(key) + self.transpose as f64
+	}
+
+	/// Number of samples after which the sound played at or above the given tone can be cut off.
+	fn duration_from(&self, lowest_tone: f64) -> Option<usize> {
+		// Same computation as maxsamples in the converter, scaled to the sample rate.
+		let maxdecay = self.decaylow.max(self.decaylow + self.decaydiff);
+		let decaytime = if maxdecay < 1.0 {
+			0.01f32.ln() / maxdecay.ln() * DECAY_TIME * TARGET_SAMPLE_RATE / self.decay_scale(lowest_tone) as f32
+		} else {
+			INF
+		};
+		let envelopetime = self.envelope_end.map_or(INF, |end| end * TARGET_SAMPLE_RATE / self.sample_rate);
+		if decaytime.is_infinite() && envelopetime.is_infinite() {
+			return None;
+		}
+		let maxsamples = (decaytime.min(envelopetime) + 65535.0) as usize & !65535;
+		Some((maxsamples as f32 * self.sample_rate / TARGET_SAMPLE_RATE).ceil() as usize)
+	}
+
+	/// Number of samples after which the sound played at the given key can be cut off.
+	pub fn key_duration(&self, key: u8) -> Option<usize> {
+		self.duration_from(self.tuned_tone(key))
+	}
+
+	/// Exponent applied to the decay factors of the partials at a tone, given by the decay key tracking.
+	pub fn decay_scale(&self, tone: f64) -> f64 {
+		2f64.powf((tone - self.track_pivot as f64) * self.decay_track as f64 / 12.0)
+	}
+
+	/// Call `f` for each partial of the sound played at the given key,
+	/// in the order in which the player generates them.
+	pub fn for_each_partial<F: FnMut(&OidosPartial)>(&self, key: u8, random: &OidosRandomData, mut f: F) {
+		let tone = self.tuned_tone(key);
+		let decay_scale = self.decay_scale(tone);
+		for m in 0..self.modes as usize {
+			let mut random_index = m * 256 + self.seed as usize;
+			let mut getrandom = || {
+				let r = random.data[random_index];
+				random_index += 1;
+				r as i32 as f64 / 0x80000000u32 as f64
+			};
+
+			let subtone = getrandom().abs();
+			let reltone = subtone * self.overtones as f64;
+			let decay = self.decaylow as f64 + subtone * self.decaydiff as f64;
+			let ampmul = decay.powf((1.0 / DECAY_TIME / self.sample_rate) as f64 * decay_scale);
+
+			let relfreq = 2f64.powf(reltone / 12.0);
+			let relfreq_ot = (relfreq + 0.5).floor();
+			let relfreq_h = relfreq + (relfreq_ot - relfreq) * self.harmonicity as f64;
+			// Stretched like the overtones of a stiff string
+			let relfreq_h = relfreq_h * (1.0 + self.stiffness as f64 * relfreq_h * relfreq_h).sqrt();
+			let reltone = relfreq_h.log2() * 12.0;
+			let mtone = tone + reltone;
+			let mamp = getrandom() * 2f64.powf(reltone * self.sharpness as f64 / 12.0);
+
+			for p in 0..self.fat as usize {
+				let ptone = mtone + getrandom() * self.width as f64;
+				let phase = getrandom();
+				// Longer swell for partials further above the tone, randomly spread
+				let swell_random = random.data[m * 256 + self.seed as usize + 2 + p * 2 + SWELL_RANDOM_OFFSET];
+				let swell_random = swell_random as i32 as f64 / 0x80000000u32 as f64;
+				let swell_tone = (ptone - tone) * self.swell_slope as f64 + swell_random * self.swell_spread as f64;
+				f(&OidosPartial {
+					tone:   ptone,
+					amp:    mamp,
+					ampmul: ampmul,
+					phase:  phase,
+					swell:  self.swell_rate as f64 * 2f64.powf(-swell_tone / 12.0)
+				});
+			}
+		}
+	}
+
+	/// Initial values of the low and high filter ramps for a partial of the sound played at the given key.
+	/// The partial is audible where both are positive and fully audible where both are at least 1.
+	pub fn filter_start(&self, key: u8, ptone: f64) -> (f64, f64) {
+		// The limits follow the tone, except for the part of its distance from the pivot tone kept fixed
+		let tone = self.tuned_tone(key);
+		let offset = tone - (tone - self.track_pivot as f64) * self.filter_fixed as f64;
+		let f_lowlimit = self.f_low as f64 + offset;
+		let f_highlimit = self.f_high as f64 + offset;
+		let f_startlow = 1.0 - (f_lowlimit - ptone) * self.f_slopelow as f64;
+		let f_starthigh = 1.0 - (ptone - f_highlimit) * self.f_slopehigh as f64;
+		(f_startlow, f_starthigh)
+	}
+
+	/// Inverse width of a formant peak for a width parameter value, zero for a peak which is turned off.
+	fn formant_slope(width: f32) -> f32 {
+		if width == 0.0 { 0.0 } else { 1.0 / (width * FWIDTH_RANGE) }
+	}
+
+	/// Width of a formant peak, in semitones.
+	fn formant_width(&self, k: usize) -> f32 {
+		if self.formant_slope[k] == 0.0 { 0.0 } else { 1.0 / self.formant_slope[k] }
+	}
+
+	/// Initial values and increases per sample of the formant peak ramps for a partial,
+	/// together with the gains of the peaks minus one. The ramp of a peak is 1 at its center
+	/// and 0 or 2 at its edges. A peak which is turned off has a constant ramp of 1 and no gain.
+	pub fn formant_start(&self, ptone: f64) -> [(f64, f64, f64); 2] {
+		let formant = |k: usize| {
+			let slope = self.formant_slope[k] as f64;
+			let gain = if slope == 0.0 { 0.0 } else { self.formant_gain[k] as f64 };
+			let start = 1.0 - (ptone - self.formant_center[k] as f64) * slope;
+			(start, self.formant_sweep as f64 * slope / self.sample_rate as f64, gain)
+		};
+		[formant(0), formant(1)]
+	}
+
+	/// Factor applied to a partial by the formant peaks, for their ramps at some point in time.
+	pub fn formant_factor(ramps: &[(f64, f64, f64); 2]) -> f64 {
+		ramps.iter().map(|&(ramp, _, gain)| 1.0 + gain * ramp.min(2.0 - ramp).min(1.0).max(0.0)).product()
+	}
+
+	/// Sample rate the parameters were built for.
+	pub fn sample_rate(&self) -> f32 {
+		self.sample_rate
+	}
+
+	/// Saturation gain applied to the sum of the partials.
+	pub fn gain(&self) -> f32 {
+		self.gain
+	}
+
+	/// Angular frequency, in radians per sample, of the given tone.
+	pub fn tone_frequency(&self, tone: f64) -> f64 {
+		self.base_freq as f64 * 2f64.powf(tone / 12.0)
+	}
+}
+
+
+/// Layout of the shape values of a pair of partials in the additive cores, as offsets of the first of two values
+const SHAPE_LEVEL: usize = 0;
+const SHAPE_ADD: usize = 2;
+const SHAPE_FORMANT1: usize = 4;
+const SHAPE_FORMANT2: usize = 10;
+const SHAPE_SIZE: usize = 16;
+/// Offsets within the values of a formant peak
+const FORMANT_RAMP: usize = 0;
+const FORMANT_ADD: usize = 2;
+const FORMANT_GAIN: usize = 4;
+
 pub struct OidosSoundGenerator {
 	n_partials:  usize,
 
@@ -318,6 +1031,9 @@ pub struct OidosSoundGenerator {
 	step_im:     Vec<f64>,
 	filter_low:  Vec<f64>,
 	filter_high: Vec<f64>,
+	/// Swell levels and formant ramps of pairs of partials, laid out as
+	/// [level, add, ramp1, add1, gain1, ramp2, add2, gain2] with two values each
+	shape:       Vec<f64>,
 
 	f_add_low:   f64,
 	f_add_high:  f64,
@@ -333,7 +1049,7 @@ impl SoundGenerator for OidosSoundGenerator {
 	type Global = OidosRandomData;
 
 	fn new(param: &OidosSoundParameters, tone: u8, time: usize, random: &OidosRandomData) -> OidosSoundGenerator {
-		let n_partials = param.modes as usize * param.fat as usize;
+		let n_partials = param.partial_count();
 		let n_partials_in_array = (n_partials + 3) & !3;
 		let mut gen = OidosSoundGenerator {
 			n_partials:   n_partials,
@@ -344,6 +1060,7 @@ impl SoundGenerator for OidosSoundGenerator {
 			step_im:      Vec::with_capacity(n_partials_in_array),
 			filter_low:   Vec::with_capacity(n_partials_in_array),
 			filter_high:  Vec::with_capacity(n_partials_in_array),
+			shape:        vec![0.0; n_partials_in_array * SHAPE_SIZE / 2],
 
 			f_add_low:    (-param.f_sweeplow * param.f_slopelow / param.sample_rate) as f64,
 			f_add_high:   (param.f_sweephigh * param.f_slopehigh / param.sample_rate) as f64,
@@ -353,46 +1070,32 @@ impl SoundGenerator for OidosSoundGenerator {
 			avx_support:  unsafe { supports_avx() }
 		};
 
-		let f_lowlimit = param.f_low as f64 + tone as f64;
-		let f_highlimit = param.f_high as f64 + tone as f64;
-
-		for m in 0..param.modes as usize {
-			let mut random_index = m * 256 + param.seed as usize;
-			let mut getrandom = || {
-				let r = random.data[random_index];
-				random_index += 1;
-				r as i32 as f64 / 0x80000000u32 as f64
-			};
-
-			let subtone = getrandom().abs();
-			let reltone = subtone * param.overtones as f64;
-			let decay = param.decaylow as f64 + subtone * param.decaydiff as f64;
-			let ampmul = decay.powf((1.0 / DECAY_TIME / param.sample_rate) as f64);
-
-			let relfreq = 2f64.powf(reltone / 12.0);
-			let relfreq_ot = (relfreq + 0.5).floor();
-			let relfreq_h = relfreq + (relfreq_ot - relfreq) * param.harmonicity as f64;
-			let reltone = relfreq_h.log2() * 12.0;
-			let mtone = tone as f64 + reltone;
-			let mamp = getrandom() * 2f64.powf(reltone * param.sharpness as f64 / 12.0);
-
-			for _ in 0..param.fat as usize {
-				let ptone = mtone + getrandom() * param.width as f64;
-				let phase = param.base_freq as f64 * 2f64.powf(ptone / 12.0);
-				gen.step_re.push(ampmul * phase.cos());
-				gen.step_im.push(ampmul * phase.sin());
-
-				let angle = getrandom() * f64::consts::PI + phase * time as f64;
-				let amp = mamp * ampmul.powi(time as i32);
-				gen.state_re.push(amp * angle.cos());
-				gen.state_im.push(amp * angle.sin());
-
-				let f_startlow = 1.0 - (f_lowlimit - ptone) * param.f_slopelow as f64;
-				let f_starthigh = 1.0 - (ptone - f_highlimit) * param.f_slopehigh as f64;
-				gen.filter_low.push(f_startlow + gen.f_add_low * time as f64);
-				gen.filter_high.push(f_starthigh + gen.f_add_high * time as f64);
+		param.for_each_partial(tone, random, |partial| {
+			let phase = param.tone_frequency(partial.tone);
+			gen.step_re.push(partial.ampmul * phase.cos());
+			gen.step_im.push(partial.ampmul * phase.sin());
+
+			let angle = partial.phase * f64::consts::PI + phase * time as f64;
+			let amp = partial.amp * partial.ampmul.powi(time as i32);
+			gen.state_re.push(amp * angle.cos());
+			gen.state_im.push(amp * angle.sin());
+
+			let (f_startlow, f_starthigh) = param.filter_start(tone, partial.tone);
+			gen.filter_low.push(f_startlow + gen.f_add_low * time as f64);
+			gen.filter_high.push(f_starthigh + gen.f_add_high * time as f64);
+
+			let (level, add) = if partial.swell.is_infinite() { (1.0, 0.0) } else { (partial.swell * time as f64, partial.swell) };
+			let i = gen.state_re.len() - 1;
+			let shape = &mut gen.shape[i / 2 * SHAPE_SIZE + i % 2..];
+			shape[SHAPE_LEVEL] = level;
+			shape[SHAPE_ADD] = add;
+			for (k, &(ramp, add, gain)) in param.formant_start(partial.tone).iter().enumerate() {
+				let formant = SHAPE_FORMANT1 + k * (SHAPE_FORMANT2 - SHAPE_FORMANT1);
+				shape[formant + FORMANT_RAMP] = ramp + add * time as f64;
+				shape[formant + FORMANT_ADD] = add;
+				shape[formant + FORMANT_GAIN] = gain;
 			}
-		}
+		});
 
 		for _ in n_partials..n_partials_in_array {
 			gen.state_re.push(0.0);
@@ -407,26 +1110,59 @@ impl SoundGenerator for OidosSoundGenerator {
 	}
 
 	fn produce_sample(&mut self) -> f32 {
-		let s = unsafe {
-			if self.avx_support {
+		let avx = self.avx_support;
+		let s = self.vector_core(avx);
+		(s * (self.gain / (self.n_partials as f64 + (self.gain - 1.0) * s * s)).sqrt()) as f32
+	}
+
+	fn diagnostic_names() -> &'static [&'static str] {
+		&DIAGNOSTIC_NAMES
+	}
+
+	fn diagnostics(param: &OidosSoundParameters, tone: u8, random: &OidosRandomData) -> Vec<(String, String)> {
+		let diag = diagnose(param, tone, random);
+		let warn = |problem: bool| if problem { " (!)" } else { "" };
+		vec![
+			match diag.duration {
+				Some(d) => (format!("{:.2}", d), "s".to_string()),
+				None => ("INFINITE".to_string(), "(!)".to_string())
+			},
+			(format!("{}", diag.partials), "".to_string()),
+			(format!("{}", diag.aliasing_partials), format!("at {}{}", note_name(tone), warn(diag.aliasing_partials > 0))),
+			(format!("{:+.1}", diag.peak_level), format!("dB{}", warn(diag.is_silent()))),
+			(format!("{:.1}", diag.saturation), format!("dB{}", warn(diag.is_saturating()))),
+			match diag.precalc_time {
+				Some(t) => (format!("{:.2}", t), "s/tone".to_string()),
+				None => ("-".to_string(), "".to_string())
+			}
+		]
+	}
+
+	fn is_audible(param: &OidosSoundParameters, tone: u8, random: &OidosRandomData) -> bool {
+		!diagnose(param, tone, random).is_silent()
+	}
+}
+
+impl OidosSoundGenerator {
+	/// Sum of the partials for the next sample, computed by the AVX or SSE2 asm version.
+	fn vector_core(&mut self, avx: bool) -> f64 {
+		unsafe {
+			if avx {
 				additive_core_avx(self.state_re.as_mut_ptr(), self.state_im.as_mut_ptr(),
 				                  self.step_re.as_ptr(), self.step_im.as_ptr(),
 				                  self.filter_low.as_mut_ptr(), self.filter_high.as_mut_ptr(),
-				                  self.f_add_low, self.f_add_high, self.n_partials)
+				                  self.f_add_low, self.f_add_high, self.n_partials, self.shape.as_mut_ptr())
 			} else {
 				additive_core_sse2(self.state_re.as_mut_ptr(), self.state_im.as_mut_ptr(),
 				                   self.step_re.as_ptr(), self.step_im.as_ptr(),
 				                   self.filter_low.as_mut_ptr(), self.filter_high.as_mut_ptr(),
-				                   self.f_add_low, self.f_add_high, self.n_partials)
+				                   self.f_add_low, self.f_add_high, self.n_partials, self.shape.as_mut_ptr())
 			}
-		};
-		(s * (self.gain / (self.n_partials as f64 + (self.gain - 1.0) * s * s)).sqrt()) as f32
+		}
 	}
-}
 
-impl OidosSoundGenerator {
 	/// Functionally equivalent to the vectorized asm versions, but much slower.
-	#[allow(unused)]
+	#[cfg(test)]
 	fn additive_core(&mut self) -> f64 {
 		let mut s = 0f64;
 		for i in 0..self.n_partials {
@@ -435,9 +1171,16 @@ impl OidosSoundGenerator {
 			self.state_re[i] = re;
 			self.state_im[i] = im;
 
-			let f = self.filter_low[i].min(self.filter_high[i]).min(1.0).max(0.0);
+			let shape = &mut self.shape[i / 2 * SHAPE_SIZE + i % 2..];
+			let mut f = self.filter_low[i].min(self.filter_high[i]).min(shape[SHAPE_LEVEL]).min(1.0).max(0.0);
 			self.filter_low[i] += self.f_add_low;
 			self.filter_high[i] += self.f_add_high;
+			shape[SHAPE_LEVEL] += shape[SHAPE_ADD];
+			for &formant in &[SHAPE_FORMANT1, SHAPE_FORMANT2] {
+				let ramp = shape[formant + FORMANT_RAMP];
+				f *= 1.0 + shape[formant + FORMANT_GAIN] * ramp.min(2.0 - ramp).min(1.0).max(0.0);
+				shape[formant + FORMANT_RAMP] += shape[formant + FORMANT_ADD];
+			}
 
 			s += re * f;
 		}
@@ -446,10 +1189,41 @@ impl OidosSoundGenerator {
 	}
 }
 
+#[test]
+fn test_oidos_vector_core() {
+	// The asm versions compute the same samples as the Rust version, with swell,
+	// formants and filter sweeps
+	let random = OidosRandomData::default();
+	let avx_support = unsafe { supports_avx() };
+	let cases = [
+		test_parse_values(&[]),
+		test_parse_values(&[(SWELL, "500 ms"), (SWELLSLOPE, "100 %/oct")]),
+		test_parse_values(&[(FORMANT1, "72 ST"), (FWIDTH1, "12 ST"), (FGAIN1, "+6 dB"), (FORMANTSWEEP, "+12 ST/s")]),
+		test_parse_values(&[(FORMANT2, "48 ST"), (FWIDTH2, "6 ST"), (FGAIN2, "-6 dB")]),
+		test_parse_values(&[(FSWEEPLOW, "+12 ST/s"), (FSWEEPHIGH, "-12 ST/s")])
+	];
+	for values in &cases {
+		let param = OidosSoundParameters::build(values, 44100.0);
+		let mut rust = OidosSoundGenerator::new(&param, 60, 0, &random);
+		let mut sse2 = OidosSoundGenerator::new(&param, 60, 0, &random);
+		let mut avx = OidosSoundGenerator::new(&param, 60, 0, &random);
+		let tolerance = 1e-9 * rust.n_partials as f64;
+		for i in 0..10000 {
+			let expected = rust.additive_core();
+			assert!((sse2.vector_core(false) - expected).abs() < tolerance, "SSE2 sample {}", i);
+			if avx_support {
+				assert!((avx.vector_core(true) - expected).abs() < tolerance, "AVX sample {}", i);
+			}
+		}
+	}
+}
+
 extern "cdecl" {
 	fn supports_avx() -> bool;
 	fn additive_core_sse2(state_re: *mut f64, state_im: *mut f64, step_re: *const f64, step_im: *const f64,
-	                      filter_low: *mut f64, filter_high: *mut f64, f_add_low: f64, f_add_high: f64, n: usize) -> f64;
+	                      filter_low: *mut f64, filter_high: *mut f64, f_add_low: f64, f_add_high: f64, n: usize,
+	                      shape: *mut f64) -> f64;
 	fn additive_core_avx(state_re: *mut f64, state_im: *mut f64, step_re: *const f64, step_im: *const f64,
-	                     filter_low: *mut f64, filter_high: *mut f64, f_add_low: f64, f_add_high: f64, n: usize) -> f64;
+	                     filter_low: *mut f64, filter_high: *mut f64, f_add_low: f64, f_add_high: f64, n: usize,
+	                     shape: *mut f64) -> f64;
 }
@@ -0,0 +1,146 @@
+use std::f64;
+
+#[cfg(test)] use generate::SoundParameters;
+use oidos_generate::{OidosRandomData, OidosSoundParameters};
+#[cfg(test)] use oidos_generate::{DECAYHIGH, DECAYLOW, FILTERHIGH, GAIN, OVERTONES};
+
+
+const TARGET_SAMPLE_RATE: f64 = 44100.0;
+// Partial-seconds per second of precalc, as estimated by the converter
+const BURDEN_PER_SECOND: f64 = 5000.0;
+// Typical ratio between peak and RMS level for a sum of many partials
+const CREST_FACTOR: f64 = 3.0;
+
+const SATURATION_WARNING_DB: f32 = 6.0;
+const SILENCE_WARNING_DB: f32 = -60.0;
+
+const NOTE_NAMES: [&'static str; 12] = ["C-", "C#", "D-", "D#", "E-", "F-", "F#", "G-", "G#", "A-", "A#", "B-"];
+
+pub fn note_name(tone: u8) -> String {
+	format!("{}{}", NOTE_NAMES[tone as usize % 12], tone / 12)
+}
+
+/// Potential problems with an instrument, as detected by `diagnose`.
+pub struct OidosDiagnostics {
+	/// Tone the sound was analyzed at
+	pub tone: u8,
+	/// Length of the sound at the tone in the player, in seconds, or `None` if infinite
+	pub duration: Option<f32>,
+	/// Total number of partials
+	pub partials: usize,
+	/// Number of audible partials above the Nyquist frequency of the player
+	pub aliasing_partials: usize,
+	/// Estimated peak level of the sound at full velocity, in dB
+	pub peak_level: f32,
+	/// Estimated amount of level reduction caused by the gain saturation, in dB
+	pub saturation: f32,
+	/// Estimated precalc time in the player for each tone used, in seconds
+	pub precalc_time: Option<f32>
+}
+
+/// Analyze an instrument played at the given tone.
+pub fn diagnose(param: &OidosSoundParameters, tone: u8, random: &OidosRandomData) -> OidosDiagnostics {
+	let partials = param.partial_count();
+	let duration = param.key_duration(tone).map(|d| d as f32 / param.sample_rate());
+
+	// The player always runs at 44100Hz, regardless of the rate the parameters were built for.
+	let rate_scale = param.sample_rate() as f64 / TARGET_SAMPLE_RATE;
+	let mut aliasing_partials = 0;
+	let mut sum_abs = 0f64;
+	let mut sum_squares = 0f64;
+	param.for_each_partial(tone, random, |partial| {
+		let (f_startlow, f_starthigh) = param.filter_start(tone, partial.tone);
+		let filter = f_startlow.min(f_starthigh).min(1.0).max(0.0);
+		if filter > 0.0 && param.tone_frequency(partial.tone) * rate_scale > f64::consts::PI {
+			aliasing_partials += 1;
+		}
+		let formant = OidosSoundParameters::formant_factor(&param.formant_start(partial.tone));
+		let amp = (partial.amp * filter * formant).abs();
+		sum_abs += amp;
+		sum_squares += amp * amp;
+	});
+
+	// Same saturation formula as the generator
+	let gain = param.gain() as f64;
+	let n = partials as f64;
+	let s = sum_abs.min((sum_squares / 2.0).sqrt() * CREST_FACTOR);
+	let linear = s * (gain / n).sqrt();
+	let saturated = s * (gain / (n + (gain - 1.0) * s * s)).sqrt();
+
+	OidosDiagnostics {
+		tone: tone,
+		duration: duration,
+		partials: partials,
+		aliasing_partials: aliasing_partials,
+		peak_level: decibel(saturated),
+		saturation: if saturated > 0.0 { decibel(linear / saturated) } else { 0.0 },
+		precalc_time: duration.map(|d| (n * d as f64 / BURDEN_PER_SECOND) as f32)
+	}
+}
+
+fn decibel(amp: f64) -> f32 {
+	(20.0 * amp.log10()) as f32
+}
+
+impl OidosDiagnostics {
+	/// Whether the gain saturation is strong enough to noticeably distort the sound.
+	pub fn is_saturating(&self) -> bool {
+		self.saturation > SATURATION_WARNING_DB
+	}
+
+	/// Whether the sound is too quiet to be heard.
+	pub fn is_silent(&self) -> bool {
+		!(self.peak_level > SILENCE_WARNING_DB)
+	}
+
+	/// Human-readable descriptions of the detected problems.
+	pub fn warnings(&self) -> Vec<String> {
+		let mut warnings = Vec::new();
+		if self.duration.is_none() {
+			warnings.push("Instrument has infinite duration".to_string());
+		}
+		if self.aliasing_partials > 0 {
+			warnings.push(format!("{} of {} partials are above the Nyquist frequency at {}",
+			                      self.aliasing_partials, self.partials, note_name(self.tone)));
+		}
+		if self.is_silent() {
+			warnings.push(format!("Instrument is silent at {}", note_name(self.tone)));
+		}
+		if self.is_saturating() {
+			warnings.push(format!("Gain saturates the sound by {:.1} dB", self.saturation));
+		}
+		warnings
+	}
+}
+
+#[test]
+fn test_diagnose() {
+	let mut values = OidosSoundParameters::default_values();
+	let random = OidosRandomData::default();
+
+	let param = OidosSoundParameters::build(&values, 44100.0);
+	let diag = diagnose(&param, 60, &random);
+	assert_eq!(diag.partials, 40 * 10);
+	assert_eq!(diag.aliasing_partials, 0);
+	assert_eq!(diag.duration, None);
+	assert_eq!(diag.precalc_time, None);
+	assert_eq!(diag.warnings().len(), 1);
+
+	values[DECAYLOW] = 0.9;
+	values[DECAYHIGH] = 0.9;
+	values[OVERTONES] = 1.0;
+	values[GAIN] = 1.0;
+	let param = OidosSoundParameters::build(&values, 44100.0);
+	let diag = diagnose(&param, 120, &random);
+	assert!(diag.duration.is_some());
+	assert!(diag.aliasing_partials > 0);
+	assert!(diag.is_saturating());
+	assert_eq!(diag.warnings().len(), 2);
+	assert_eq!(diagnose(&param, 0, &random).aliasing_partials, 0);
+
+	values[FILTERHIGH] = 0.0;
+	let param = OidosSoundParameters::build(&values, 44100.0);
+	let diag = diagnose(&param, 60, &random);
+	assert!(diag.is_silent());
+	assert_eq!(diag.warnings().len(), 1);
+}
@@ -0,0 +1,523 @@
+use std::f64;
+use std::fmt;
+
+/// Names and default values of the OidosReverb parameters, in plugin order.
+pub use OidosReverb::{DEFAULT_VALUES as REVERB_DEFAULT_VALUES, PARAMETER_NAMES as REVERB_PARAMETER_NAMES};
+
+use generate::SoundParameters;
+use oidos_generate::*;
+
+
+const TOTAL_SEMITONES: f64 = 120.0;
+const SAMPLERATE: f64 = 44100.0;
+
+/// Index of the maxsamples value in the parameter block.
+pub const BLOCK_MAXSAMPLES: usize = 16;
+
+/// A value in the `dd` parameter block of an instrument in the player.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlayerValue {
+	Int(i32),
+	Float(f32)
+}
+
+impl PlayerValue {
+	pub fn to_bits(&self) -> u32 {
+		match *self {
+			PlayerValue::Int(i) => i as u32,
+			PlayerValue::Float(f) => f.to_bits()
+		}
+	}
+}
+
+impl fmt::Display for PlayerValue {
+	/// Formatted the way the converter writes it.
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			PlayerValue::Int(i) => write!(f, "{}", i),
+			PlayerValue::Float(v) => write!(f, "0x{:08X}", v.to_bits())
+		}
+	}
+}
+
+/// Information about how an instrument is used in the music,
+/// needed to encode its parameters for the player.
+pub struct OidosInstrumentContext {
+	/// Length of the longest note played by the instrument, in seconds
+	pub max_note_length: f64,
+	/// Left and right volume of the instrument, including track and master volume
+	pub volume: [f64; 2],
+	/// Granularity of the velocities used with the instrument (a power of two up to 128)
+	pub velocity_quantum: u32,
+	/// Whether any instrument in the music is panned
+	pub uses_panning: bool,
+	/// Whether any instrument in the music has more than a linear attack and release
+	pub uses_envelope: bool,
+	/// Whether any instrument in the music uses key tracking
+	pub uses_keytrack: bool,
+	/// Whether any instrument in the music lets its partials swell
+	pub uses_swell: bool,
+	/// Whether any instrument in the music has formant peaks
+	pub uses_formants: bool,
+	/// Whether any instrument in the music has stretched partials
+	pub uses_stiffness: bool,
+	/// Lowest tone played by the instrument, including tuning
+	pub lowest_tone: f64
+}
+
+impl Default for OidosInstrumentContext {
+	fn default() -> OidosInstrumentContext {
+		OidosInstrumentContext {
+			max_note_length: 0.0,
+			volume: [1.0, 1.0],
+			velocity_quantum: 128,
+			uses_panning: false,
+			uses_envelope: false,
+			uses_keytrack: false,
+			uses_swell: false,
+			uses_formants: false,
+			uses_stiffness: false,
+			lowest_tone: 0.0
+		}
+	}
+}
+
+/// Whether an instrument needs the envelope parameters of the player:
+/// a hold, a decay to a sustain level below full or a curved envelope stage.
+pub fn uses_envelope(p: &[f32]) -> bool {
+	[HOLD, SUSTAIN, ATTACKCURVE, DECAYCURVE, RELEASECURVE].iter().any(|&index| p[index] != 0.0)
+}
+
+/// Whether an instrument needs the key tracking parameters of the player:
+/// a decay or filter which does not follow the tone in the usual way.
+pub fn uses_keytrack(p: &[f32]) -> bool {
+	[DECAYTRACK, FILTERTRACK].iter().any(|&index| p[index] != 0.0)
+}
+
+/// Whether an instrument needs the swell parameters of the player.
+pub fn uses_swell(p: &[f32]) -> bool {
+	p[SWELL] != 0.0
+}
+
+/// Whether an instrument needs the formant parameters of the player.
+pub fn uses_formants(p: &[f32]) -> bool {
+	[FWIDTH1, FWIDTH2].iter().any(|&index| p[index] != 0.0)
+}
+
+/// Whether an instrument needs the stiffness parameter of the player.
+pub fn uses_stiffness(p: &[f32]) -> bool {
+	p[STIFFNESS] != 0.0
+}
+
+/// Encode the plugin parameters of an instrument into the parameter block used by
+/// the player, exactly as done by `makeParamBlock` in the converter.
+pub fn make_param_block(p: &[f32], context: &OidosInstrumentContext) -> Result<Vec<PlayerValue>, String> {
+	let v = |index: usize| p[index] as f64;
+	let q = |value: f64, q_index: usize| quantize(value as f32, p[q_index]) as f64;
+
+	let modes = (0.5 + v(MODES) * 100.0).floor().max(1.0);
+	let fat = (0.5 + v(FAT) * 100.0).floor().max(1.0);
+	let seed = (0.5 + v(SEED) * 100.0).floor();
+	let overtones = (0.5 + v(OVERTONES) * 100.0).floor();
+
+	let decaydiff = q(v(DECAYHIGH) - v(DECAYLOW), Q_DECAYDIFF);
+	let decaylow = q(v(DECAYLOW), Q_DECAYLOW);
+	let harmonicity = q(v(HARMONICITY) * 2.0 - 1.0, Q_HARMONICITY);
+	let stiffness = q(v(STIFFNESS).powi(4) * 0.1, Q_STIFFNESS);
+	let sharpness = q(v(SHARPNESS) * 5.0 - 4.0, Q_SHARPNESS);
+	let width = q(100.0 * v(WIDTH).powi(5), Q_WIDTH);
+
+	let filterlow = q((v(FILTERLOW) * 2.0 - 1.0) * TOTAL_SEMITONES, Q_F_LOW);
+	let fslopelow = q((1.0 - v(FSLOPELOW)).powi(3), Q_FS_LOW);
+	let fsweeplow = q(-(v(FSWEEPLOW) - 0.5).powi(3) * 100.0 * TOTAL_SEMITONES / SAMPLERATE, Q_FSW_LOW);
+	let filterhigh = q((v(FILTERHIGH) * 2.0 - 1.0) * TOTAL_SEMITONES, Q_F_HIGH);
+	let fslopehigh = q(-(1.0 - v(FSLOPEHIGH)).powi(3), Q_FS_HIGH);
+	let fsweephigh = q(-(v(FSWEEPHIGH) - 0.5).powi(3) * 100.0 * TOTAL_SEMITONES / SAMPLERATE, Q_FSW_HIGH);
+
+	let gain = q(4096f64.powf(v(GAIN) - 0.25), Q_GAIN);
+	let attack = q(if v(ATTACK) == 0.0 { 2.0 } else { 1.0 / (v(ATTACK) * v(ATTACK)) / SAMPLERATE }, Q_ATTACK);
+	let release = q(-(if v(RELEASE) == 0.0 { 2.0 } else { 1.0 / v(RELEASE) / SAMPLERATE }), Q_RELEASE);
+
+	let curve = |index: usize| OidosSoundParameters::to_display(index, p[index]) as f64 / 100.0;
+	let hold = v(HOLD) * v(HOLD) * SAMPLERATE;
+	let decay = if v(ENVDECAY) == 0.0 { 2.0 } else { 1.0 / (v(ENVDECAY) * v(ENVDECAY) * 10.0) / SAMPLERATE };
+	let sustain = 1.0 - v(SUSTAIN);
+	let decaystart = 1.0 / attack + hold;
+
+	let trackpivot = (0.5 + v(TRACKPIVOT) * 127.0).floor();
+	let decaytrack = v(DECAYTRACK);
+	let filtertrack = v(FILTERTRACK);
+	// The player raises tracked decay factors to a power through their logarithm,
+	// which needs them to be above zero. Untracked decay factors are left as they are.
+	if decaytrack != 0.0 && decaylow.min(decaylow + decaydiff) <= 0.0 {
+		return Err("Decay key tracking needs decay times above zero".to_string());
+	}
+	// The decay is longest at the lowest tone
+	let decayscale = 2f64.powf((context.lowest_tone - trackpivot) * decaytrack / 12.0);
+
+	// Zero words make the partials start at full level
+	let swelling = v(SWELL) != 0.0;
+	let swell = if swelling { 1.0 / (v(SWELL) * v(SWELL) * 2.0) / SAMPLERATE } else { 0.0 };
+	let swellslope = if swelling { v(SWELLSLOPE) } else { 0.0 };
+	let swellspread = if swelling { v(SWELLSPREAD) * 12.0 } else { 0.0 };
+
+	// Center, inverse width and gain minus one of a formant peak, turned off at zero width
+	let formant = |center: usize, width: usize, fgain: usize| {
+		if v(width) == 0.0 {
+			return [q(v(center) * 127.0, Q_FORMANT), 0.0, 0.0];
+		}
+		[
+			q(v(center) * 127.0, Q_FORMANT),
+			q(1.0 / (v(width) * 48.0), Q_FWIDTH),
+			q(10f64.powf((v(fgain) * 2.0 - 1.0) * 24.0 / 20.0) - 1.0, Q_FGAIN)
+		]
+	};
+	let formant1 = formant(FORMANT1, FWIDTH1, FGAIN1);
+	let formant2 = formant(FORMANT2, FWIDTH2, FGAIN2);
+	let formantsweep = OidosSoundParameters::to_display(FORMANTSWEEP, p[FORMANTSWEEP]) as f64 / SAMPLERATE;
+
+	let maxdecay = decaylow.max(decaylow + decaydiff);
+	let releasetime = if release != 0.0 { context.max_note_length * SAMPLERATE + 1.0 / -release } else { f64::INFINITY };
+	let decaytime = if maxdecay < 1.0 { 0.01f64.ln() / maxdecay.ln() * 4096.0 / decayscale } else { f64::INFINITY };
+	let envelopetime = if sustain == 0.0 { decaystart + 1.0 / decay } else { f64::INFINITY };
+	if releasetime.is_infinite() && decaytime.is_infinite() && envelopetime.is_infinite() {
+		return Err("Instrument has infinite duration".to_string());
+	}
+	let maxsamples = (releasetime.min(decaytime).min(envelopetime) + 65535.0) as i64 & -65536;
+
+	let left_volume = context.volume[0] * context.velocity_quantum as f64 * 128.0;
+	let right_volume = context.volume[1] * context.velocity_quantum as f64 * 128.0;
+	let volume = (left_volume + right_volume) / 2.0;
+	let pan = right_volume / volume - 1.0;
+
+	let mut block = vec![
+		PlayerValue::Int(modes as i32),
+		PlayerValue::Int(fat as i32),
+		PlayerValue::Int(seed as i32),
+		PlayerValue::Int(overtones as i32),
+		PlayerValue::Float(decaydiff as f32),
+		PlayerValue::Float(decaylow as f32),
+		PlayerValue::Float(harmonicity as f32),
+		PlayerValue::Float(sharpness as f32),
+		PlayerValue::Float(width as f32),
+		PlayerValue::Float(filterlow as f32),
+		PlayerValue::Float(filterhigh as f32),
+		PlayerValue::Float(fslopelow as f32),
+		PlayerValue::Float(fslopehigh as f32),
+		PlayerValue::Float(fsweeplow as f32),
+		PlayerValue::Float(fsweephigh as f32),
+		PlayerValue::Float(gain as f32),
+		PlayerValue::Int(maxsamples as i32),
+		PlayerValue::Float(release as f32),
+		PlayerValue::Float(attack as f32),
+		PlayerValue::Float(quantize(volume as f32, 0.65))
+	];
+	if context.uses_panning {
+		block.push(PlayerValue::Float(quantize(pan as f32, 0.55)));
+	}
+	if context.uses_envelope {
+		// The player computes the decay state from the number of samples left of the
+		// sound, so store the state at the end of the sound. It is 1 at the decay start.
+		let decaybase = 1.0 + (decaystart - maxsamples as f64) * decay;
+		block.extend_from_slice(&[
+			PlayerValue::Float(curve(RELEASECURVE) as f32),
+			PlayerValue::Float(curve(ATTACKCURVE) as f32),
+			PlayerValue::Float(decaybase as f32),
+			PlayerValue::Float(decay as f32),
+			PlayerValue::Float(curve(DECAYCURVE) as f32),
+			PlayerValue::Float(sustain as f32)
+		]);
+	}
+	if context.uses_keytrack {
+		block.extend_from_slice(&[
+			PlayerValue::Float(trackpivot as f32),
+			PlayerValue::Float(decaytrack as f32),
+			PlayerValue::Float(filtertrack as f32)
+		]);
+	}
+	if context.uses_swell {
+		block.extend_from_slice(&[
+			PlayerValue::Float(swell as f32),
+			PlayerValue::Float(swellslope as f32),
+			PlayerValue::Float(swellspread as f32)
+		]);
+	}
+	if context.uses_formants {
+		// The values of the two peaks are interleaved, so the player can process them together
+		for &value in formant1.iter().zip(&formant2).flat_map(|(a, b)| [a, b]).chain(&[formantsweep]) {
+			block.push(PlayerValue::Float(value as f32));
+		}
+	}
+	if context.uses_stiffness {
+		block.push(PlayerValue::Float(stiffness as f32));
+	}
+
+	Ok(block)
+}
+
+/// Format a parameter block as a line of assembly data, as written by the converter.
+pub fn format_param_block(block: &[PlayerValue]) -> String {
+	let values: Vec<String> = block.iter().map(|v| v.to_string()).collect();
+	format!("\tdd\t{}\n", values.join(","))
+}
+
+/// Reverb settings for the player, computed from the OidosReverb parameters
+/// exactly as done by the `Reverb` class in the converter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OidosReverbDefines {
+	pub num_delays: i64,
+	pub min_delay: i64,
+	pub max_delay: i64,
+	pub add_delay: i64,
+	pub seed: i64,
+	pub max_decay: f64,
+	pub decay_mul: f64,
+	pub filter_high: f32,
+	pub filter_low: f32,
+	pub dampen_high: f32,
+	pub dampen_low: f32,
+	pub volumes: [f32; 2],
+	/// Time for the reverb to decay to half, in seconds
+	pub halftime: f64
+}
+
+impl OidosReverbDefines {
+	pub fn new(p: &[f32]) -> OidosReverbDefines {
+		let v = |index: usize| p[index] as f64;
+		let step = |index: usize| (v(index) * 100.0 + 0.5).floor() as i64;
+		let filter = |index: usize, q_index: usize| quantize((v(index) * v(index)) as f32, p[q_index]).min(1.0);
+
+		let num_delays = step(10) * 2;
+		let max_delay = step(3) * 256;
+		let decay_mul = 2f64.powf(1.0 / (v(5) * SAMPLERATE));
+		let mix = v(0) * 10.0 / (num_delays as f64).sqrt();
+		let volume = |s: f64| quantize((mix * (1.0 + s - 2.0 * s * v(1)).sqrt()) as f32, p[15]);
+		OidosReverbDefines {
+			num_delays: num_delays,
+			min_delay: step(2) * 256,
+			max_delay: max_delay,
+			add_delay: step(4) * 256,
+			seed: step(11) * 2048,
+			max_decay: decay_mul.powf(-max_delay as f64),
+			decay_mul: decay_mul,
+			filter_high: filter(7, 17),
+			filter_low: filter(6, 16),
+			dampen_high: filter(9, 19),
+			dampen_low: filter(8, 18),
+			volumes: [volume(1.0), volume(-1.0)],
+			halftime: v(5)
+		}
+	}
+
+	/// The `%define` lines for the reverb, as written by the converter.
+	pub fn format(&self) -> String {
+		let float = |name: &str, value: f32| format!("%define {:<19} 0x{:08X} ; {:.9}\n", name, value.to_bits(), value as f64);
+		let mut text = String::new();
+		text += &format!("%define REVERB_NUM_DELAYS   {}\n", self.num_delays);
+		text += &format!("%define REVERB_MIN_DELAY    {}\n", self.min_delay);
+		text += &format!("%define REVERB_MAX_DELAY    {}\n", self.max_delay);
+		text += &format!("%define REVERB_ADD_DELAY    {}\n", self.add_delay);
+		text += &format!("%define REVERB_RANDOMSEED   {}\n", self.seed);
+		text += &format!("%define REVERB_MAX_DECAY    {:.9}\n", self.max_decay);
+		text += &format!("%define REVERB_DECAY_MUL    {:.9}\n", self.decay_mul);
+		text += &float("REVERB_FILTER_HIGH", self.filter_high);
+		text += &float("REVERB_FILTER_LOW", self.filter_low);
+		text += &float("REVERB_DAMPEN_HIGH", self.dampen_high);
+		text += &float("REVERB_DAMPEN_LOW", self.dampen_low);
+		text += &float("REVERB_VOLUME_LEFT", self.volumes[0]);
+		text += &float("REVERB_VOLUME_RIGHT", self.volumes[1]);
+		text
+	}
+}
+
+#[cfg(test)]
+fn test_param_values(overrides: &[(usize, f32)]) -> Vec<f32> {
+	let mut values = OidosSoundParameters::default_values();
+	for &(index, value) in overrides {
+		values[index] = value;
+	}
+	values
+}
+
+/// Encode the parameter block with the given context, check that the block without the optional
+/// words is the block of a context using none of them, and return the block and its optional words.
+#[cfg(test)]
+fn test_optional_words(values: &[f32], context: &OidosInstrumentContext) -> (Vec<PlayerValue>, Vec<f32>) {
+	let block = make_param_block(values, context).unwrap();
+	let plain = OidosInstrumentContext {
+		uses_envelope: false,
+		uses_keytrack: false,
+		uses_swell: false,
+		uses_formants: false,
+		uses_stiffness: false,
+		.. *context
+	};
+	assert_eq!(make_param_block(values, &plain).unwrap()[..], block[..20]);
+	let words = block[20..].iter().map(|v| f32::from_bits(v.to_bits())).collect();
+	(block, words)
+}
+
+#[test]
+fn test_param_block_defaults() {
+	let values = test_param_values(&[]);
+	let context = OidosInstrumentContext { max_note_length: 1.5, .. OidosInstrumentContext::default() };
+	let block = make_param_block(&values, &context).unwrap();
+	let bits: Vec<u32> = block.iter().map(|v| v.to_bits()).collect();
+	assert_eq!(bits, vec![
+		40, 10, 50, 27,
+		0x00000000, 0x3F800000, 0x3F800000, 0x3EFFFFFC, 0x3EE8A11F,
+		0xC2F00000, 0x42F00000, 0x3F800000, 0xBF800000, 0x00000000, 0x00000000,
+		0x3F800000, 131072, 0xB83E37C6, 0x39BE37C6,
+		0x46800000
+	]);
+	assert_eq!(block[BLOCK_MAXSAMPLES], PlayerValue::Int(131072));
+}
+
+#[test]
+fn test_param_block_quantized() {
+	let values = test_param_values(&[
+		(SEED, 0.17), (MODES, 0.12), (FAT, 0.03), (WIDTH, 0.41),
+		(OVERTONES, 0.66), (SHARPNESS, 0.73), (HARMONICITY, 0.81),
+		(DECAYLOW, 0.93), (DECAYHIGH, 0.88),
+		(FILTERLOW, 0.47), (FSLOPELOW, 0.6), (FSWEEPLOW, 0.62),
+		(FILTERHIGH, 0.71), (FSLOPEHIGH, 0.35), (FSWEEPHIGH, 0.29),
+		(GAIN, 0.4), (ATTACK, 0.12), (RELEASE, 0.3),
+		(Q_DECAYDIFF, 0.5), (Q_DECAYLOW, 0.6), (Q_HARMONICITY, 0.7), (Q_SHARPNESS, 0.55),
+		(Q_WIDTH, 0.45), (Q_F_LOW, 0.6), (Q_FS_LOW, 0.4), (Q_FSW_LOW, 0.5),
+		(Q_F_HIGH, 0.65), (Q_FS_HIGH, 0.35), (Q_FSW_HIGH, 0.5),
+		(Q_GAIN, 0.7), (Q_ATTACK, 0.6), (Q_RELEASE, 0.6)
+	]);
+	let context = OidosInstrumentContext {
+		max_note_length: 0.25,
+		volume: [0.5, 0.8],
+		velocity_quantum: 16,
+		uses_panning: true,
+		uses_envelope: false,
+		uses_keytrack: false,
+		uses_swell: false,
+		uses_formants: false,
+		uses_stiffness: false,
+		lowest_tone: 0.0
+	};
+	let block = make_param_block(&values, &context).unwrap();
+	let bits: Vec<u32> = block.iter().map(|v| v.to_bits()).collect();
+	assert_eq!(bits, vec![
+		12, 3, 17, 66,
+		0xBD4D0000, 0x3F700000, 0x3F200000, 0xBEB40000, 0x3F944000,
+		0xC0E80000, 0x42500000, 0x3D831000, 0xBE8C9C00, 0xB9F68000, 0x3B250000,
+		0x40600000, 65536, 0xB8A00000, 0x3AD00000,
+		0x44A00000, 0x3E6C0000
+	]);
+	assert_eq!(format_param_block(&block[..4]), "\tdd\t12,3,17,66\n");
+}
+
+#[test]
+fn test_param_block_envelope() {
+	let values = test_param_values(&[(HOLD, 0.5), (ENVDECAY, 0.1), (SUSTAIN, 1.0), (ATTACKCURVE, 0.75), (RELEASECURVE, 0.25)]);
+	assert!(uses_envelope(&values) && !uses_envelope(&test_param_values(&[(ENVDECAY, 0.1)])));
+	let context = OidosInstrumentContext { max_note_length: 1.5, uses_envelope: true, .. OidosInstrumentContext::default() };
+	let (block, floats) = test_optional_words(&values, &context);
+	// The sound is silent after the attack of 2756.25 samples, the hold of 11025 and the decay of 4410
+	assert_eq!(block[BLOCK_MAXSAMPLES], PlayerValue::Int(65536));
+	assert_eq!((floats[0], floats[1], floats[4], floats[5]), (-0.5, 0.5, 0.0, 0.0));
+	assert!((floats[3] * 4410.0 - 1.0).abs() < 1e-6);
+	assert!((floats[2] - (1.0 + (13781.25 - 65536.0) / 4410.0)).abs() < 1e-4);
+}
+
+#[test]
+fn test_param_block_keytrack() {
+	// Decay half-time of 4096 samples at the pivot, halved for every octave above it
+	let values = test_param_values(&[(DECAYLOW, 0.5), (DECAYHIGH, 0.5), (RELEASE, 0.0), (Q_RELEASE, 1.0),
+	                                 (DECAYTRACK, 1.0), (FILTERTRACK, 0.25), (TRACKPIVOT, 60.0 / 127.0)]);
+	assert!(uses_keytrack(&values) && !uses_keytrack(&test_param_values(&[(TRACKPIVOT, 0.5)])));
+	let context = OidosInstrumentContext { uses_keytrack: true, lowest_tone: 60.0, .. OidosInstrumentContext::default() };
+	let (block, _) = test_optional_words(&values, &context);
+	assert_eq!(block[BLOCK_MAXSAMPLES], PlayerValue::Int(65536));
+	assert_eq!(block[20..], [PlayerValue::Float(60.0), PlayerValue::Float(1.0), PlayerValue::Float(0.25)]);
+
+	// Two octaves below the pivot, the sound lasts four times as long
+	let low = OidosInstrumentContext { lowest_tone: 36.0, .. context };
+	assert_eq!(make_param_block(&values, &low).unwrap()[BLOCK_MAXSAMPLES], PlayerValue::Int(131072));
+	let silent = test_param_values(&[(DECAYLOW, 0.0), (DECAYTRACK, 1.0)]);
+	assert!(make_param_block(&silent, &context).is_err());
+}
+
+#[test]
+fn test_param_block_swell() {
+	// Swell of 500 ms, twice as long for every octave above the tone, spread by half an octave
+	let values = test_param_values(&[(SWELL, 0.5), (SWELLSLOPE, 1.0), (SWELLSPREAD, 0.5)]);
+	assert!(uses_swell(&values) && !uses_swell(&test_param_values(&[(SWELLSLOPE, 1.0)])));
+	let context = OidosInstrumentContext { max_note_length: 1.5, uses_swell: true, .. OidosInstrumentContext::default() };
+	let (_, floats) = test_optional_words(&values, &context);
+	assert!((floats[0] * 22050.0 - 1.0).abs() < 1e-6);
+	assert_eq!((floats[1], floats[2]), (1.0, 6.0));
+
+	// Without swell, the words are zero, whatever the slope and spread
+	let plain = make_param_block(&test_param_values(&[(SWELLSLOPE, 1.0), (SWELLSPREAD, 0.5)]), &context).unwrap();
+	assert_eq!(plain[20..23], [PlayerValue::Float(0.0); 3]);
+}
+
+#[test]
+fn test_param_block_formants() {
+	// A 6 dB peak 12 semitones wide at tone 95.25, moving up 15 semitones per second
+	let values = test_param_values(&[(FORMANT1, 0.75), (FWIDTH1, 0.25), (FGAIN1, 0.625), (FORMANTSWEEP, 0.75)]);
+	assert!(uses_formants(&values) && !uses_formants(&test_param_values(&[(FGAIN1, 1.0)])));
+	let context = OidosInstrumentContext { max_note_length: 1.5, uses_formants: true, .. OidosInstrumentContext::default() };
+	let (block, floats) = test_optional_words(&values, &context);
+	assert_eq!(floats.len(), 7);
+	assert_eq!((floats[0], floats[2]), (95.25, 1.0 / 12.0));
+	assert!((floats[4] - (10f32.powf(0.3) - 1.0)).abs() < 1e-6);
+	assert!((floats[6] * 44100.0 - 15.0).abs() < 1e-4);
+	let bits: Vec<u32> = block[20..].iter().map(|v| v.to_bits()).collect();
+	assert_eq!(bits, vec![0x42BE8000, 0x427E0000, 0x3DAAAAAB, 0, 0x3F7EC983, 0, 0x39B2544A]);
+
+	// A peak of zero width has no effect, whatever its gain
+	assert_eq!((floats[1], floats[3], floats[5]), (63.5, 0.0, 0.0));
+}
+
+#[test]
+fn test_param_block_stiffness() {
+	// Stiffness of 0.1 * 0.5^4, stretching harmonic n to n * sqrt(1 + n^2 / 160)
+	let values = test_param_values(&[(STIFFNESS, 0.5), (Q_STIFFNESS, 0.6)]);
+	assert!(uses_stiffness(&values) && !uses_stiffness(&test_param_values(&[(Q_STIFFNESS, 0.6)])));
+	let context = OidosInstrumentContext { max_note_length: 1.5, uses_stiffness: true, .. OidosInstrumentContext::default() };
+	let (block, _) = test_optional_words(&values, &context);
+	assert_eq!(block.len(), 21);
+	assert_eq!(block[20], PlayerValue::Float(quantize(0.1 / 16.0, 0.6)));
+	assert_eq!(block[20].to_bits(), 0x3BCC0000);
+
+	// The stiffness word follows the formant words
+	let all = OidosInstrumentContext { uses_formants: true, .. context };
+	assert_eq!(make_param_block(&values, &all).unwrap()[27], block[20]);
+}
+
+#[test]
+fn test_param_block_infinite() {
+	let values = test_param_values(&[(RELEASE, 0.0), (Q_RELEASE, 1.0)]);
+	assert!(make_param_block(&values, &OidosInstrumentContext::default()).is_err());
+}
+
+#[test]
+fn test_reverb_defines() {
+	let mut values = REVERB_DEFAULT_VALUES;
+	values[15..20].copy_from_slice(&[0.2, 0.3, 0.4, 0.5, 0.6]);
+	let defines = OidosReverbDefines::new(&values);
+	assert_eq!(defines.num_delays, 64);
+	assert_eq!((defines.min_delay, defines.max_delay, defines.add_delay), (7 * 256, 13 * 256, 0));
+	assert_eq!(defines.seed, 32 * 2048);
+	// Values computed by the converter
+	assert_eq!(defines.format(), "\
+		%define REVERB_NUM_DELAYS   64\n\
+		%define REVERB_MIN_DELAY    1792\n\
+		%define REVERB_MAX_DELAY    3328\n\
+		%define REVERB_ADD_DELAY    0\n\
+		%define REVERB_RANDOMSEED   65536\n\
+		%define REVERB_MAX_DECAY    0.900669864\n\
+		%define REVERB_DECAY_MUL    1.000031436\n\
+		%define REVERB_FILTER_HIGH  0x3EB85000 ; 0.359985352\n\
+		%define REVERB_FILTER_LOW   0x3C23D800 ; 0.010000229\n\
+		%define REVERB_DAMPEN_HIGH  0x3EFC0000 ; 0.492187500\n\
+		%define REVERB_DAMPEN_LOW   0x3C240000 ; 0.010009766\n\
+		%define REVERB_VOLUME_LEFT  0x3E000000 ; 0.125000000\n\
+		%define REVERB_VOLUME_RIGHT 0x3E000000 ; 0.125000000\n");
+}
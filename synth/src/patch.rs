@@ -0,0 +1,156 @@
+#[cfg(test)] use oidos_generate::{OidosSoundParameters, GAIN};
+
+use generate::{ParameterGroup, SoundParameters};
+
+
+/// Parse a patch in the text format written by `format_patch`: one `name value`
+/// line per parameter, with values in the 0-1 range of the plugin parameters.
+/// Parameters not mentioned get their default values. Text after `#` is ignored.
+pub fn parse_patch<S: SoundParameters>(text: &str) -> Result<Vec<f32>, String> {
+	let names: Vec<&str> = S::schema().iter().map(|desc| desc.name).collect();
+	parse_named_values(text, &names, S::default_values())
+}
+
+/// Parse a patch text for a plugin with the given parameter names, starting
+/// from the given default values.
+pub fn parse_named_values(text: &str, names: &[&str], defaults: Vec<f32>) -> Result<Vec<f32>, String> {
+	let mut values = defaults;
+	for (line_index, line) in text.lines().enumerate() {
+		let line = line.split('#').next().unwrap().trim();
+		if line.is_empty() {
+			continue;
+		}
+		let mut words = line.split_whitespace();
+		let name = words.next().unwrap();
+		let index = match names.iter().position(|&n| n == name) {
+			Some(index) => index,
+			None => return Err(format!("Line {}: Unknown parameter '{}'", line_index + 1, name))
+		};
+		let value = match (words.next().map(|w| w.parse::<f32>()), words.next()) {
+			(Some(Ok(value)), None) => value,
+			_ => return Err(format!("Line {}: Expected a single number after '{}'", line_index + 1, name))
+		};
+		if !(0.0..=1.0).contains(&value) {
+			return Err(format!("Line {}: Value {} for '{}' is outside the range 0 to 1", line_index + 1, value, name));
+		}
+		values[index] = value;
+	}
+	Ok(values)
+}
+
+/// Big-endian word at a position in a preset file.
+fn fxp_word(data: &[u8], pos: usize) -> Option<u32> {
+	data.get(pos..pos + 4).map(|b| b.iter().fold(0, |v, &b| v << 8 | u32::from(b)))
+}
+
+/// Whether a VST preset file stores the plugin state as a chunk rather than a parameter list.
+pub fn is_fxp_chunk(data: &[u8]) -> bool {
+	data.get(0..4) == Some(b"CcnK") && data.get(8..12) == Some(b"FPCh")
+}
+
+/// Read the parameter values from a VST preset file (`.fxp`) saved by a host
+/// as a parameter list, as done for the reverb and for synth presets saved
+/// before the synth stored its state as a chunk. Parameters beyond `count`,
+/// such as the read-only diagnostic parameters, are ignored.
+pub fn parse_fxp(data: &[u8], count: usize) -> Result<Vec<f32>, String> {
+	let word = |pos: usize| fxp_word(data, pos);
+	if data.get(0..4) != Some(b"CcnK") {
+		return Err("Not a VST preset file".to_string());
+	}
+	if data.get(8..12) != Some(b"FxCk") {
+		return Err("Only presets with a parameter list or a chunk are supported".to_string());
+	}
+	let num_params = word(24).ok_or("Truncated preset file")? as usize;
+	if num_params < count {
+		return Err(format!("Preset has {} parameters, expected at least {}", num_params, count));
+	}
+	(0..count).map(|i| {
+		let value = word(56 + i * 4).map(f32::from_bits).ok_or("Truncated preset file")?;
+		if !(0.0..=1.0).contains(&value) {
+			return Err(format!("Preset value {} is outside the range 0 to 1", value));
+		}
+		Ok(value)
+	}).collect()
+}
+
+/// Read the chunk from a VST preset file (`.fxp`) storing the plugin state as a chunk.
+pub fn parse_fxp_chunk(data: &[u8]) -> Result<&[u8], String> {
+	if !is_fxp_chunk(data) {
+		return Err("Not a VST preset file with a chunk".to_string());
+	}
+	let size = fxp_word(data, 56).ok_or("Truncated preset file")? as usize;
+	data.get(60..60 + size).ok_or("Truncated preset file".to_string())
+}
+
+/// Write a VST preset file (`.fxp`) storing the state of the plugin with the given ID as a chunk.
+pub fn format_fxp_chunk(plugin_id: u32, plugin_version: u32, name: &str, chunk: &[u8]) -> Vec<u8> {
+	let mut data = b"CcnK".to_vec();
+	data.extend(&(52 + chunk.len() as u32).to_be_bytes());
+	data.extend(b"FPCh");
+	data.extend(&1u32.to_be_bytes());
+	data.extend(&plugin_id.to_be_bytes());
+	data.extend(&plugin_version.to_be_bytes());
+	// Number of parameters, which is not needed for a chunk
+	data.extend(&0u32.to_be_bytes());
+	let mut program_name = [0u8; 28];
+	for (dst, &src) in program_name[..27].iter_mut().zip(name.as_bytes()) {
+		*dst = src;
+	}
+	data.extend(&program_name);
+	data.extend(&(chunk.len() as u32).to_be_bytes());
+	data.extend(chunk);
+	data
+}
+
+/// Write parameter values as a patch text.
+pub fn format_patch<S: SoundParameters>(values: &[f32]) -> String {
+	let mut text = String::new();
+	for (desc, value) in S::schema().iter().zip(values) {
+		if desc.group != ParameterGroup::Unused {
+			text += &format!("{} {}\n", desc.name, value);
+		}
+	}
+	text
+}
+
+#[test]
+fn test_patch_roundtrip() {
+	let schema = OidosSoundParameters::schema();
+	let values: Vec<f32> = schema.iter().map(|desc| if desc.group == ParameterGroup::Unused { 0.0 } else { desc.index as f32 / 64.0 }).collect();
+	let text = format_patch::<OidosSoundParameters>(&values);
+	assert_eq!(parse_patch::<OidosSoundParameters>(&text), Ok(values));
+
+	let defaults = parse_patch::<OidosSoundParameters>("# Nothing but defaults\n\n").unwrap();
+	assert_eq!(defaults[GAIN], 0.25);
+	assert!(parse_patch::<OidosSoundParameters>("gain 2").is_err());
+	assert!(parse_patch::<OidosSoundParameters>("gainz 0.5").is_err());
+	assert!(parse_patch::<OidosSoundParameters>("gain").is_err());
+}
+
+#[test]
+fn test_parse_fxp() {
+	let values = [0.25f32, 1.0, 0.0];
+	let mut data = b"CcnK".to_vec();
+	data.extend(&(48 + 4 * values.len() as u32).to_be_bytes());
+	data.extend(b"FxCk");
+	data.extend(&[0, 0, 0, 1, b'O', b'i', b'd', b'o', 0, 0, 0, 1, 0, 0, 0, values.len() as u8]);
+	data.extend(&[0u8; 28]);
+	for v in &values {
+		data.extend(&v.to_bits().to_be_bytes());
+	}
+	assert_eq!(parse_fxp(&data, 2), Ok(vec![0.25, 1.0]));
+	assert!(parse_fxp(&data, 4).is_err());
+	assert!(parse_fxp(&data[..data.len() - 2], 3).is_err());
+	assert!(parse_fxp(b"gain 0.5", 1).is_err());
+}
+
+#[test]
+fn test_fxp_chunk() {
+	let data = format_fxp_chunk(0x50D10, 2100, "Bass", b"gain 0.5\n");
+	assert_eq!(data.len(), 60 + 9);
+	assert!(is_fxp_chunk(&data));
+	assert_eq!(parse_fxp_chunk(&data), Ok(&b"gain 0.5\n"[..]));
+	assert!(parse_fxp_chunk(&data[..data.len() - 1]).is_err());
+	assert!(parse_fxp(&data, 1).is_err());
+	assert!(parse_fxp_chunk(b"gain 0.5").is_err());
+}
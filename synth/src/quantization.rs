@@ -0,0 +1,159 @@
+use OidosReverb::{render_impulse_response, QUANTIZATION_PARAMETERS as REVERB_QUANTIZATION_PARAMETERS};
+
+use fit::SoundProfile;
+use generate::{ParameterGroup, Sample, SoundGenerator, SoundParameters};
+use render::render_note;
+#[cfg(test)] use patch::{format_patch, parse_patch};
+#[cfg(test)] use OidosReverb::{format_patch as format_reverb_patch, parse_patch as parse_reverb_patch, DEFAULT_VALUES as REVERB_DEFAULT_VALUES};
+#[cfg(test)] use oidos_generate::{OidosRandomData, OidosSoundGenerator, OidosSoundParameters, DECAYHIGH, DECAYLOW, FAT, MODES, Q_GAIN};
+
+
+/// Sample rate at which reverb impulse responses are compared
+const REVERB_SAMPLE_RATE: f32 = 44100.0;
+/// Number of steps of the reverb quantization parameters
+const REVERB_QUANTIZATION_STEPS: u32 = 31;
+
+/// Quantization level chosen by `optimize_quantization` for a quantization parameter.
+pub struct QuantizationLevel {
+	pub index: usize,
+	/// Number of bits rounded off in the original patch
+	pub original_bits: u32,
+	/// Number of bits rounded off in the optimized patch
+	pub bits: u32,
+	/// Spectral error of the optimized patch so far, in dB
+	pub error: f32
+}
+
+fn bits_of(steps: u32, value: f32) -> u32 {
+	(value * steps as f32).floor() as u32
+}
+
+fn value_of(steps: u32, bits: u32) -> f32 {
+	// All bits rounded off is the top of the range
+	((bits as f32 + 0.5) / steps as f32).min(1.0)
+}
+
+/// Find the coarsest levels of the quantization parameters for which the sound stays
+/// within `max_error` (RMS difference in dB of the spectral envelope and decay)
+/// of the unquantized sound, played at the given tone for `length` samples and
+/// released halfway, so the attack and release are included.
+/// The parameters are optimized one at a time in the order of the schema, each
+/// taking the error of the ones before into account.
+/// Returns the optimized patch and the chosen levels.
+pub fn optimize_quantization<G: SoundGenerator>(values: &[f32], tone: u8, length: usize, max_error: f32,
+                                                sample_rate: f32, global: &G::Global) -> (Vec<f32>, Vec<QuantizationLevel>) {
+	let quantization: Vec<(usize, u32)> = G::Parameters::schema().iter()
+		.filter(|desc| desc.group == ParameterGroup::Quantization)
+		.map(|desc| (desc.index, desc.steps.unwrap_or(1)))
+		.collect();
+	optimize_levels(values, &quantization, max_error, |values| {
+		vec![SoundProfile::analyze(&render_note::<G>(values, tone, 127, length / 2, length, sample_rate, global), sample_rate)]
+	})
+}
+
+/// Find the coarsest levels of the quantization parameters of an OidosReverb patch for
+/// which the impulse response of the reverb, `length` samples long, stays within
+/// `max_error` of the unquantized reverb, as for `optimize_quantization`.
+/// The channels are compared separately, so the panning counts.
+pub fn optimize_reverb_quantization(values: &[f32], length: usize, max_error: f32) -> (Vec<f32>, Vec<QuantizationLevel>) {
+	let quantization: Vec<(usize, u32)> = REVERB_QUANTIZATION_PARAMETERS.map(|index| (index, REVERB_QUANTIZATION_STEPS)).collect();
+	optimize_levels(values, &quantization, max_error, |values| {
+		let response = render_impulse_response(values, length);
+		(0..2).map(|channel| {
+			let sound: Vec<Sample> = response.iter().map(|s| Sample::from(s[channel])).collect();
+			SoundProfile::analyze(&sound, REVERB_SAMPLE_RATE)
+		}).collect()
+	})
+}
+
+/// Binary search for the coarsest level of each of the given parameters, with their
+/// numbers of steps, keeping the profiles of the sound given by `analyze` within
+/// `max_error` of those of the patch with all of the parameters at zero.
+fn optimize_levels<F>(values: &[f32], quantization: &[(usize, u32)], max_error: f32, analyze: F) -> (Vec<f32>, Vec<QuantizationLevel>)
+	where F: Fn(&[f32]) -> Vec<SoundProfile>
+{
+	let mut result = values.to_vec();
+	for &(index, _) in quantization {
+		result[index] = 0.0;
+	}
+	let reference = analyze(&result);
+	let error = |values: &[f32]| {
+		let sum: f32 = reference.iter().zip(&analyze(values)).map(|(r, p)| r.distance(p)).sum();
+		(sum / reference.len().max(1) as f32).sqrt()
+	};
+
+	let mut levels = Vec::new();
+	for &(index, steps) in quantization {
+		// Binary search for the largest number of bits within the error
+		let (mut good, mut bad) = (0, steps + 1);
+		let mut good_error = levels.last().map(|l: &QuantizationLevel| l.error).unwrap_or(0.0);
+		while bad - good > 1 {
+			let bits = (good + bad) / 2;
+			result[index] = value_of(steps, bits);
+			let e = error(&result);
+			if e <= max_error {
+				good = bits;
+				good_error = e;
+			} else {
+				bad = bits;
+			}
+		}
+		result[index] = if good == 0 { 0.0 } else { value_of(steps, good) };
+		levels.push(QuantizationLevel {
+			index: index,
+			original_bits: bits_of(steps, values[index]),
+			bits: good,
+			error: good_error
+		});
+	}
+	(result, levels)
+}
+
+#[test]
+fn test_optimize_quantization() {
+	let random = OidosRandomData::default();
+	let mut values = OidosSoundParameters::default_values();
+	values[MODES] = 0.05;
+	values[FAT] = 0.02;
+	values[DECAYLOW] = 0.9;
+	values[DECAYHIGH] = 0.85;
+	let length = 4096;
+
+	let (exact, levels) = optimize_quantization::<OidosSoundGenerator>(&values, 60, length, 0.0, 22050.0, &random);
+	assert!(levels.iter().all(|l| l.error == 0.0));
+	assert_eq!(exact[MODES], values[MODES]);
+
+	let (quantized, levels) = optimize_quantization::<OidosSoundGenerator>(&values, 60, length, 1.0, 22050.0, &random);
+	assert_eq!(levels.len(), 18);
+	assert!(levels.iter().all(|l| l.error <= 1.0));
+	assert!(levels.iter().any(|l| l.bits > 10));
+	let gain = levels.iter().find(|l| l.index == Q_GAIN).unwrap();
+	assert_eq!(bits_of(31, quantized[Q_GAIN]), gain.bits);
+
+	// Rounding off every bit still gives a valid patch
+	let (coarsest, levels) = optimize_quantization::<OidosSoundGenerator>(&values, 60, length, f32::INFINITY, 22050.0, &random);
+	assert!(levels.iter().any(|l| l.bits == 31));
+	for level in &levels {
+		assert_eq!(bits_of(31, coarsest[level.index]), level.bits);
+	}
+	assert_eq!(parse_patch::<OidosSoundParameters>(&format_patch::<OidosSoundParameters>(&coarsest)), Ok(coarsest));
+}
+
+#[test]
+fn test_optimize_reverb_quantization() {
+	let length = 8192;
+	let (_, levels) = optimize_reverb_quantization(&REVERB_DEFAULT_VALUES, length, 0.0);
+	assert!(levels.iter().all(|l| l.error == 0.0));
+
+	let (quantized, levels) = optimize_reverb_quantization(&REVERB_DEFAULT_VALUES, length, 1.0);
+	assert_eq!(levels.len(), 5);
+	assert!(levels.iter().all(|l| l.error <= 1.0 && l.bits > 0));
+	for level in &levels {
+		assert_eq!(bits_of(REVERB_QUANTIZATION_STEPS, quantized[level.index]), level.bits);
+	}
+
+	// Rounding off every bit still gives a valid patch
+	let (coarsest, levels) = optimize_reverb_quantization(&REVERB_DEFAULT_VALUES, length, f32::INFINITY);
+	assert!(levels.iter().all(|l| l.bits == 31));
+	assert_eq!(parse_reverb_patch(&format_reverb_patch(&coarsest)), Ok(coarsest));
+}
@@ -0,0 +1,209 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+
+use cache::SoundCache;
+use generate::{velocity_layer, Sample, SoundGenerator, SoundParameters};
+use synth::Note;
+use tuning::Tuning;
+
+
+/// Render a single note the way the plugin plays it: the sound of the generator
+/// of the velocity layer of the note, shaped by the envelope with a release after `hold`
+/// samples, and by the note velocity. Rendering stops when the release has finished, the sound has ended or
+/// `max_length` samples have been rendered.
+pub fn render_note<G: SoundGenerator>(values: &[f32], tone: u8, velocity: u8, hold: usize, max_length: usize,
+                                      sample_rate: f32, global: &G::Global) -> Vec<Sample> {
+	let layer = velocity_layer(velocity, G::Parameters::velocity_layers(values));
+	let param = G::Parameters::build(&G::Parameters::layer_values(values, layer), sample_rate);
+	let envelope = G::Parameters::envelope(values, sample_rate);
+	let end = param.duration().unwrap_or(usize::MAX).min(max_length);
+
+	let mut generator = G::new(&param, tone, 0, global);
+	let mut samples = Vec::new();
+	for time in 0..end {
+		if envelope.is_released(time, hold) {
+			break;
+		}
+		let sample: Sample = generator.produce_sample().into();
+		samples.push(sample * (envelope.amplitude(time, Some(hold)) * (velocity as f32 / 127.0)));
+	}
+	samples
+}
+
+/// Render a key at several velocities through the sound cache and note envelope
+/// of the plugin, so the sound of the key is calculated once for each velocity
+/// layer. The key plays the tone given by the tuning. Each note is
+/// released after `hold` samples and ends when the release has finished, at the
+/// analytic length of the sound, after a second of silence or after `max_length`
+/// samples, whichever comes first.
+#[allow(clippy::too_many_arguments)]
+pub fn render_velocity_layers<G: SoundGenerator>(values: &[f32], tuning: &Tuning, tone: u8, velocities: &[u8], hold: usize, max_length: usize,
+                                                 sample_rate: f32, global: &G::Global) -> Vec<Vec<Sample>> {
+	let layers = G::Parameters::velocity_layers(values);
+	let params: Vec<G::Parameters> = (0..layers).map(|layer| {
+		let mut param = G::Parameters::build(&G::Parameters::layer_values(values, layer), sample_rate);
+		param.tune(tuning);
+		param
+	}).collect();
+	let envelope = G::Parameters::envelope(values, sample_rate);
+	let mut cache: Vec<Vec<SoundCache<G>>> = (0..layers).map(|_| (0..tone as usize + 1).map(|t| SoundCache::new(t as u8)).collect()).collect();
+
+	velocities.iter().map(|&velocity| {
+		let layer = velocity_layer(velocity, layers);
+		let mut note = Note::new(tone, layer, velocity, envelope, params[layer].duration(), Some(sample_rate as usize));
+		let mut samples = Vec::new();
+		while note.is_alive() && samples.len() < max_length {
+			if samples.len() == hold {
+				note.release(0);
+			}
+			samples.push(note.produce_sample(&mut cache, &params, global));
+		}
+		samples
+	}).collect()
+}
+
+/// Remove the trailing samples that are silent when written as 16-bit samples.
+pub fn trim_silence(samples: &mut Vec<Sample>) {
+	let end = samples.iter().rposition(|s| s.left.abs() >= 0.5 / 32767.0 || s.right.abs() >= 0.5 / 32767.0);
+	samples.truncate(end.map_or(0, |e| e + 1));
+}
+
+/// Write stereo samples to a 16-bit WAV file, clipping samples outside the -1 to 1 range.
+pub fn write_wav(filename: &str, samples: &[Sample], sample_rate: u32) -> io::Result<()> {
+	let mut out = BufWriter::new(File::create(filename)?);
+	let data_size = samples.len() as u32 * 4;
+	out.write_all(b"RIFF")?;
+	out.write_all(&(36 + data_size).to_le_bytes())?;
+	out.write_all(b"WAVEfmt ")?;
+	out.write_all(&16u32.to_le_bytes())?;
+	out.write_all(&1u16.to_le_bytes())?;
+	out.write_all(&2u16.to_le_bytes())?;
+	out.write_all(&sample_rate.to_le_bytes())?;
+	out.write_all(&(sample_rate * 4).to_le_bytes())?;
+	out.write_all(&4u16.to_le_bytes())?;
+	out.write_all(&16u16.to_le_bytes())?;
+	out.write_all(b"data")?;
+	out.write_all(&data_size.to_le_bytes())?;
+	for sample in samples {
+		for &s in &[sample.left, sample.right] {
+			let value = (s.clamp(-1.0, 1.0) * 32767.0).round() as i16;
+			out.write_all(&value.to_le_bytes())?;
+		}
+	}
+	out.flush()
+}
+
+/// Read a 16-bit integer or 32-bit float WAV file with one or two channels.
+/// Returns the samples and the sample rate.
+pub fn read_wav(filename: &str) -> Result<(Vec<Sample>, u32), String> {
+	let mut data = Vec::new();
+	File::open(filename).and_then(|mut f| f.read_to_end(&mut data)).map_err(|e| e.to_string())?;
+	if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+		return Err(format!("{} is not a WAV file", filename));
+	}
+	let u16_at = |pos: usize| u16::from_le_bytes([data[pos], data[pos + 1]]);
+	let u32_at = |pos: usize| u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]);
+
+	let mut format = None;
+	let mut pos = 12;
+	while pos + 8 <= data.len() {
+		let size = u32_at(pos + 4) as usize;
+		let body = pos + 8;
+		let end = (body + size).min(data.len());
+		match &data[pos..pos + 4] {
+			b"fmt " if size >= 16 => {
+				if body + 16 > data.len() {
+					return Err(format!("{} has a truncated format chunk", filename));
+				}
+				format = Some((u16_at(body), u16_at(body + 2), u32_at(body + 4), u16_at(body + 14)));
+			},
+			b"data" => {
+				let (tag, channels, sample_rate, bits) = format.ok_or("WAV data before format")?;
+				if channels != 1 && channels != 2 {
+					return Err(format!("Unsupported number of channels: {}", channels));
+				}
+				let read: fn(&[u8]) -> f32 = match (tag, bits) {
+					(1, 16) => |b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+					(3, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+					_ => return Err(format!("Unsupported WAV format {} with {} bits", tag, bits))
+				};
+				let bytes = bits as usize / 8;
+				let frame_bytes = bytes * channels as usize;
+				let samples = match channels {
+					1 => data[body..end].chunks_exact(frame_bytes).map(|f| Sample::from(read(f))).collect(),
+					_ => data[body..end].chunks_exact(frame_bytes).map(|f| Sample { left: read(f), right: read(&f[bytes..]) }).collect()
+				};
+				return Ok((samples, sample_rate));
+			},
+			_ => {}
+		}
+		// Chunks are padded to even sizes
+		pos = body + size + (size & 1);
+	}
+	Err(format!("{} contains no sound data", filename))
+}
+
+#[test]
+fn test_wav_roundtrip() {
+	let samples: Vec<Sample> = (0..100).map(|i| Sample { left: i as f32 / 100.0, right: -0.5 }).collect();
+	let filename = std::env::temp_dir().join("oidos_test_wav_roundtrip.wav");
+	let filename = filename.to_str().unwrap();
+	write_wav(filename, &samples, 22050).unwrap();
+	let (read, sample_rate) = read_wav(filename).unwrap();
+	std::fs::remove_file(filename).unwrap();
+	assert_eq!(sample_rate, 22050);
+	assert_eq!(read.len(), samples.len());
+	for (a, b) in read.iter().zip(&samples) {
+		assert!((a.left - b.left).abs() < 0.0001 && (a.right - b.right).abs() < 0.0001);
+	}
+}
+
+#[test]
+fn test_read_wav_errors() {
+	let filename = std::env::temp_dir().join("oidos_test_read_wav_errors.wav");
+	let filename = filename.to_str().unwrap();
+	let read = |chunks: &[u8]| {
+		let mut data = b"RIFF\0\0\0\0WAVE".to_vec();
+		data.extend_from_slice(chunks);
+		std::fs::write(filename, &data).unwrap();
+		read_wav(filename)
+	};
+	// Format chunk of a mono 16-bit file
+	let mut fmt = b"fmt \x10\0\0\0\x01\0\x01\0\x44\xAC\0\0\x88\x58\x01\0\x02\0\x10\0".to_vec();
+	let sound = b"data\x04\0\0\0\0\x40\0\xC0";
+	assert_eq!(read(&[&fmt[..], sound].concat()).unwrap().0.len(), 2);
+	// Declared larger than the file
+	assert!(read(&fmt[..20]).is_err());
+	// No channels
+	fmt[10] = 0;
+	assert!(read(&[&fmt[..], sound].concat()).is_err());
+	std::fs::remove_file(filename).unwrap();
+}
+
+#[test]
+fn test_render_velocity_layers() {
+	use oidos_generate::{OidosRandomData, OidosSoundGenerator, OidosSoundParameters};
+
+	let mut values = OidosSoundParameters::default_values();
+	let names: Vec<&str> = OidosSoundParameters::schema().iter().map(|desc| desc.name).collect();
+	values[names.iter().position(|&n| n == "modes").unwrap()] = 0.1;
+	let random = OidosRandomData::default();
+	let layers = render_velocity_layers::<OidosSoundGenerator>(&values, &Tuning::default(), 60, &[127, 64], 1000, 3000, 4000.0, &random);
+	let single = render_note::<OidosSoundGenerator>(&values, 60, 64, 1000, 3000, 4000.0, &random);
+	assert_eq!(layers.len(), 2);
+	assert_eq!(layers[1].len(), single.len());
+	assert!(layers[1].iter().zip(&single).all(|(a, b)| a.left == b.left && a.right == b.right));
+	assert!(layers[0].iter().zip(&layers[1]).all(|(a, b)| (a.left * 64.0 - b.left * 127.0).abs() < 1e-4));
+
+	// Velocity layers play different sounds
+	values[names.iter().position(|&n| n == "vellayers").unwrap()] = 2.0 / 16.0;
+	values[names.iter().position(|&n| n == "velsharpness").unwrap()] = 1.0;
+	let layers = render_velocity_layers::<OidosSoundGenerator>(&values, &Tuning::default(), 60, &[127, 64, 63], 1000, 3000, 4000.0, &random);
+	let single = render_note::<OidosSoundGenerator>(&values, 60, 63, 1000, 3000, 4000.0, &random);
+	assert!(layers[1].iter().zip(&layers[2]).any(|(a, b)| (a.left * 63.0 - b.left * 64.0).abs() > 1e-4));
+	assert!(layers[2].iter().zip(&single).all(|(a, b)| a.left == b.left && a.right == b.right));
+
+	let mut samples = vec![Sample::from(0.5), Sample::from(-0.001), Sample::from(0.00001), Sample::from(0.0)];
+	trim_silence(&mut samples);
+	assert_eq!(samples.len(), 2);
+}
@@ -1,8 +1,10 @@
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::VecDeque;
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use vst::api::{Events, Supported};
 use vst::buffer::AudioBuffer;
@@ -11,7 +13,12 @@ use vst::host::Host;
 use vst::plugin::{CanDo, Category, HostCallback, Info, Plugin, PluginParameters};
 
 use cache::SoundCache;
-use generate::{Sample, SoundGenerator, SoundParameters};
+use controls::{Controls, DEFAULT_MORPH_SWITCH, DEFAULT_MUTATION_STRENGTH};
+use generate::{velocity_layer, Envelope, Sample, SoundGenerator, SoundParameters};
+use morph::morph;
+use mutate::{mutable_parameters, Mutator};
+use patch::{format_patch, parse_patch};
+use tuning::Tuning;
 
 
 #[allow(dead_code)]
@@ -43,36 +50,40 @@ pub struct TimedMidiCommand {
 	command: MidiCommand,
 }
 
-struct Note {
+pub(crate) struct Note {
 	time: usize,
+	end_time: Option<usize>,
 	dead_time: usize,
 	max_dead_time: Option<usize>,
 	tone: u8,
+	/// Velocity layer whose sound the note plays
+	layer: usize,
 	velocity: u8,
-	attack: f32,
-	release: f32,
+	envelope: Envelope,
 
 	release_time: Option<usize>
 }
 
 impl Note {
-	fn new(tone: u8, velocity: u8, attack: f32, release: f32, max_dead_time: Option<usize>) -> Note {
+	pub(crate) fn new(tone: u8, layer: usize, velocity: u8, envelope: Envelope, end_time: Option<usize>, max_dead_time: Option<usize>) -> Note {
 		Note {
 			time: 0,
+			end_time: end_time,
 			dead_time: 0,
 			max_dead_time: max_dead_time,
 			tone: tone,
+			layer: layer,
 			velocity: velocity,
-			attack: attack,
-			release: release,
+			envelope: envelope,
 
 			release_time: None
 		}
 	}
 
-	fn produce_sample<G: SoundGenerator>(&mut self, cache: &mut Vec<SoundCache<G>>, param: &G::Parameters, global: &G::Global) -> Sample {
-		let wave = cache[self.tone as usize].get_sample(self.time, param, global);
-		let amp = self.attack_amp().min(self.release_amp()) * (self.velocity as f32 / 127.0);
+	/// Produce the next sample of the note from the caches and sound parameters of each velocity layer.
+	pub(crate) fn produce_sample<G: SoundGenerator>(&mut self, cache: &mut [Vec<SoundCache<G>>], params: &[G::Parameters], global: &G::Global) -> Sample {
+		let wave = cache[self.layer][self.tone as usize].get_sample(self.time, &params[self.layer], global);
+		let amp = self.envelope.amplitude(self.time, self.release_time) * (self.velocity as f32 / 127.0);
 		let sample = wave * amp;
 		self.time += 1;
 
@@ -85,18 +96,7 @@ impl Note {
 		sample
 	}
 
-	fn attack_amp(&self) -> f32 {
-		(self.time as f32 * self.attack).min(1.0)
-	}
-
-	fn release_amp(&self) -> f32 {
-		match self.release_time {
-			None => 1.0,
-			Some(t) => (1.0 - (self.time - t) as f32 * self.release).max(0.0)
-		}
-	}
-
-	fn release(&mut self, _velocity: u8) {
+	pub(crate) fn release(&mut self, _velocity: u8) {
 		self.release_time = Some(self.time);
 	}
 
@@ -104,13 +104,22 @@ impl Note {
 		self.release_time.is_some()
 	}
 
-	fn is_alive(&self) -> bool {
-		if let Some(max_dead_time) = self.max_dead_time {
-			if self.dead_time > max_dead_time {
+	pub(crate) fn is_alive(&self) -> bool {
+		match self.end_time {
+			Some(end_time) => if self.time >= end_time {
 				return false;
+			},
+			// Fall back to silence detection for sounds that never end
+			None => if let Some(max_dead_time) = self.max_dead_time {
+				if self.dead_time > max_dead_time {
+					return false;
+				}
 			}
 		}
-		self.release_amp() > 0.0
+		match self.release_time {
+			Some(t) => !self.envelope.is_released(self.time, t),
+			None => true
+		}
 	}
 }
 
@@ -125,9 +134,10 @@ pub struct SynthPlugin<G: SoundGenerator + 'static, S: SynthInfo> {
 	notes: Vec<Note>,
 	events: VecDeque<TimedMidiCommand>,
 
-	cache: Vec<SoundCache<G>>,
-	cached_sound_params: G::Parameters,
-	global: G::Global,
+	/// Sounds of each velocity layer and tone
+	cache: Vec<Vec<SoundCache<G>>>,
+	cached_sound_params: Vec<G::Parameters>,
+	global: Arc<G::Global>,
 
 	params: Arc<RwLockWrapper<SynthPluginParameters<G>>>,
 
@@ -137,14 +147,58 @@ pub struct SynthPlugin<G: SoundGenerator + 'static, S: SynthInfo> {
 struct SynthPluginParameters<G: SoundGenerator> {
 	host: Option<HostCallback>,
 	values: Vec<f32>,
-	map: HashMap<&'static str, f32>,
 	sound_params: G::Parameters,
+	/// Sound parameters of each velocity layer, the top layer having `sound_params`
+	layer_params: Vec<G::Parameters>,
 	sample_rate: f32,
+	tuning: Tuning,
+
+	global: Arc<G::Global>,
+	/// Last tone played, set by the audio thread without waiting for the diagnostics
+	played_tone: AtomicU8,
+	/// Tone the diagnostics were computed for
+	diagnostic_tone: u8,
+	diagnostics: Vec<(String, String)>,
+
+	mutator: Mutator,
+	mutate_trigger: f32,
+	mutation_strength: f32,
+	mutation_failed: bool,
+	locks: Vec<bool>,
+
+	snapshots: [Option<Vec<f32>>; 2],
+	snapshot_triggers: [f32; 2],
+	morph_amount: f32,
+	morph_switch: f32,
+	commit_trigger: f32,
+	/// Morphed patch replacing the current patch while morphing
+	morphed: Option<Vec<f32>>,
 }
 
+/// Meaning of a plugin parameter index. The sound parameters come first, then the
+/// read-only diagnostics, then the controls for the randomizer.
+enum ParameterKind {
+	Sound(usize),
+	Diagnostic(usize),
+	Mutate,
+	MutationStrength,
+	/// Lock of the parameter with the given index
+	Lock(usize),
+	/// Store the sound as snapshot A (0) or B (1)
+	StoreSnapshot(usize),
+	Morph,
+	MorphSwitch,
+	CommitMorph
+}
+
+const MUTATION_CONTROLS: usize = 2;
+const MORPH_CONTROLS: usize = 5;
+
 // Work around orphan rule
 struct RwLockWrapper<T> {
-	inner: RwLock<T>
+	inner: RwLock<T>,
+	/// Indices of the parameters with a lock control, in the order of the controls
+	lockable: Vec<usize>
 }
 
 impl<T> Deref for RwLockWrapper<T> {
@@ -155,32 +209,48 @@ impl<T> Deref for RwLockWrapper<T> {
 	}
 }
 
-fn make_param_map(param_names: &[&'static str], param_values: &[f32]) -> HashMap<&'static str, f32> {
-	let mut param_map = HashMap::new();
-	for (s, v) in param_names.iter().zip(param_values) {
-		param_map.insert(*s, *v);
-	}
-	param_map
-}
-
 impl<G: SoundGenerator, S: SynthInfo> Default for SynthPlugin<G, S> {
 	fn default() -> Self {
-		let param_values: Vec<f32> = G::Parameters::names().iter().map(|s| G::Parameters::default_value(s)).collect();
-		let param_map = make_param_map(G::Parameters::names(), &param_values);
+		let param_values = G::Parameters::default_values();
 
-		let cache = (0..128).map(|tone| SoundCache::new(tone)).collect();
+		let cache = vec![(0..128).map(|tone| SoundCache::new(tone)).collect()];
 
 		let sample_rate = 44100.0;
 
-		let sound_params = G::Parameters::build(&param_map, sample_rate);
+		let sound_params = G::Parameters::build(&param_values, sample_rate);
+
+		let global = Arc::new(G::Global::default());
 
-		let params = SynthPluginParameters {
+		let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+		let n_sound_params = param_values.len();
+
+		let mut params = SynthPluginParameters {
 			host: None,
 			values: param_values,
-			map: param_map,
 			sound_params: sound_params.clone(),
+			layer_params: vec![sound_params.clone()],
 			sample_rate: sample_rate,
+			tuning: Tuning::default(),
+
+			global: Arc::clone(&global),
+			played_tone: AtomicU8::new(60),
+			diagnostic_tone: 60,
+			diagnostics: Vec::new(),
+
+			mutator: Mutator::new(seed),
+			mutate_trigger: 0.0,
+			mutation_strength: DEFAULT_MUTATION_STRENGTH,
+			mutation_failed: false,
+			locks: vec![false; n_sound_params],
+
+			snapshots: [None, None],
+			snapshot_triggers: [0.0; 2],
+			morph_amount: 0.0,
+			morph_switch: DEFAULT_MORPH_SWITCH,
+			commit_trigger: 0.0,
+			morphed: None,
 		};
+		params.update_diagnostics();
 
 		SynthPlugin {
 			sample_rate: sample_rate,
@@ -189,10 +259,10 @@ impl<G: SoundGenerator, S: SynthInfo> Default for SynthPlugin<G, S> {
 			events: VecDeque::new(),
 			cache: cache,
 
-			cached_sound_params: sound_params,
-			params: Arc::new(RwLockWrapper { inner: RwLock::new(params) }),
+			cached_sound_params: vec![sound_params],
+			params: Arc::new(RwLockWrapper { inner: RwLock::new(params), lockable: mutable_parameters::<G::Parameters>() }),
 
-			global: G::Global::default(),
+			global: global,
 
 			phantom: PhantomData
 		}
@@ -210,11 +280,12 @@ impl<G: SoundGenerator, S: SynthInfo> Plugin for SynthPlugin<G, S> {
 	fn get_info(&self) -> Info {
 		Info {
 			presets: 0,
-			parameters: G::Parameters::names().len() as i32,
+			parameters: parameter_count::<G>() as i32,
 			inputs: 0,
 			outputs: 2,
 			category: Category::Synth,
 			f64_precision: false,
+			preset_chunks: true,
 
 			.. S::get_info()
 		}
@@ -271,38 +342,212 @@ impl<G: SoundGenerator, S: SynthInfo> Plugin for SynthPlugin<G, S> {
 
 impl<G: SoundGenerator> PluginParameters for RwLockWrapper<SynthPluginParameters<G>> {
 	fn get_parameter_name(&self, index: i32) -> String {
-		G::Parameters::names()[index as usize].to_string()
+		match parameter_kind::<G>(index, &self.lockable) {
+			ParameterKind::Sound(p)         => G::Parameters::schema()[p].name.to_string(),
+			ParameterKind::Diagnostic(d)    => G::diagnostic_names()[d].to_string(),
+			ParameterKind::Mutate           => "mutate".to_string(),
+			ParameterKind::MutationStrength => "mutate_strength".to_string(),
+			ParameterKind::Lock(p)          => format!("lock_{}", G::Parameters::schema()[p].name),
+			ParameterKind::StoreSnapshot(s) => ["snapshot_a", "snapshot_b"][s].to_string(),
+			ParameterKind::Morph            => "morph".to_string(),
+			ParameterKind::MorphSwitch      => "morph_switch".to_string(),
+			ParameterKind::CommitMorph      => "commit_morph".to_string()
+		}
 	}
 
 	fn get_parameter_text(&self, index: i32) -> String {
+		if let ParameterKind::Diagnostic(_) = parameter_kind::<G>(index, &self.lockable) {
+			self.follow_played_tone();
+		}
 		let params: &SynthPluginParameters<G> = &self.read().unwrap();
-		params.sound_params.display(G::Parameters::names()[index as usize], &params.map).0
+		match parameter_kind::<G>(index, &self.lockable) {
+			ParameterKind::Sound(p)         => params.sound_params.display(p, params.sound_values()),
+			ParameterKind::Diagnostic(d)    => params.diagnostics[d].0.clone(),
+			ParameterKind::Mutate           => if params.mutation_failed { "Failed" } else { "Ready" }.to_string(),
+			ParameterKind::MutationStrength => format!("{:.0}", params.mutation_strength * 100.0),
+			ParameterKind::Lock(p)          => if params.locks[p] { "Locked" } else { "Free" }.to_string(),
+			ParameterKind::StoreSnapshot(s) => if params.snapshots[s].is_some() { "Stored" } else { "Empty" }.to_string(),
+			ParameterKind::Morph            => format!("{:.0}", params.morph_amount * 100.0),
+			ParameterKind::MorphSwitch      => format!("{:.0}", params.morph_switch * 100.0),
+			ParameterKind::CommitMorph      => if params.morphed.is_some() { "Ready" } else { "-" }.to_string()
+		}
 	}
 
 	fn get_parameter_label(&self, index: i32) -> String {
+		if let ParameterKind::Diagnostic(_) = parameter_kind::<G>(index, &self.lockable) {
+			self.follow_played_tone();
+		}
 		let params: &SynthPluginParameters<G> = &self.read().unwrap();
-		params.sound_params.display(G::Parameters::names()[index as usize], &params.map).1
+		match parameter_kind::<G>(index, &self.lockable) {
+			ParameterKind::Sound(p)         => G::Parameters::schema()[p].unit.to_string(),
+			ParameterKind::Diagnostic(d)    => params.diagnostics[d].1.clone(),
+			ParameterKind::MutationStrength |
+			ParameterKind::Morph |
+			ParameterKind::MorphSwitch      => "%".to_string(),
+			_                               => "".to_string()
+		}
 	}
 
 	fn get_parameter(&self, index: i32) -> f32 {
 		let params: &SynthPluginParameters<G> = &self.read().unwrap();
-		params.values[index as usize]
+		match parameter_kind::<G>(index, &self.lockable) {
+			ParameterKind::Sound(p)         => params.values[p],
+			ParameterKind::Diagnostic(_)    => 0.0,
+			ParameterKind::Mutate           => params.mutate_trigger,
+			ParameterKind::MutationStrength => params.mutation_strength,
+			ParameterKind::Lock(p)          => if params.locks[p] { 1.0 } else { 0.0 },
+			ParameterKind::StoreSnapshot(s) => params.snapshot_triggers[s],
+			ParameterKind::Morph            => params.morph_amount,
+			ParameterKind::MorphSwitch      => params.morph_switch,
+			ParameterKind::CommitMorph      => params.commit_trigger
+		}
+	}
+
+	fn can_be_automated(&self, index: i32) -> bool {
+		match parameter_kind::<G>(index, &self.lockable) {
+			ParameterKind::Sound(_) | ParameterKind::MutationStrength | ParameterKind::Morph | ParameterKind::MorphSwitch => true,
+			// Diagnostics are read-only, and the triggers and locks act on the
+			// patch rather than being part of the sound
+			_ => false
+		}
+	}
+
+	fn string_to_parameter(&self, index: i32, text: String) -> bool {
+		match parameter_kind::<G>(index, &self.lockable) {
+			ParameterKind::Sound(p) => {
+				let parsed = {
+					let params = self.read().unwrap();
+					G::Parameters::parse_in_patch(p, &text, &params.values, params.sample_rate)
+				};
+				match parsed {
+					Some(value) => {
+						self.set_parameter(index, value);
+						true
+					},
+					None => false
+				}
+			},
+			_ => false
+		}
 	}
 
 	fn set_parameter(&self, index: i32, value: f32) {
+		let kind = parameter_kind::<G>(index, &self.lockable);
+		if let ParameterKind::Mutate = kind {
+			self.pull_mutate_trigger(value);
+			return;
+		}
 		let params: &mut SynthPluginParameters<G> = &mut self.write().unwrap();
-		params.values[index as usize] = value;
+		match kind {
+			ParameterKind::Sound(p) => {
+				// Editing the patch ends morphing
+				params.morphed = None;
+				params.values[p] = value;
+
+				if let Some(ref mut host) = params.host {
+					for &i in G::Parameters::schema()[p].influences {
+						params.values[i] = infinitesimal_change(params.values[i]).min(1.0);
+						host.automate(i as i32, params.values[i]);
+					}
+				}
 
-		if let Some(ref mut host) = params.host {
-			for name in G::Parameters::influence(G::Parameters::names()[index as usize]) {
-				if let Some(p) = G::Parameters::names().iter().position(|n| *n == name) {
-					params.values[p] = infinitesimal_change(params.values[p]).min(1.0);
-					host.automate(p as i32, params.values[p]);
+				params.build_sound_params();
+			},
+			// Diagnostics are read-only
+			ParameterKind::Diagnostic(_) => {},
+			// Mutation is handled above, outside the lock
+			ParameterKind::Mutate => {},
+			ParameterKind::MutationStrength => params.mutation_strength = value,
+			ParameterKind::Lock(p) => params.locks[p] = value >= 0.5,
+			ParameterKind::StoreSnapshot(s) => {
+				if value >= 0.5 && params.snapshot_triggers[s] < 0.5 {
+					params.snapshots[s] = Some(params.sound_values().to_vec());
+				}
+				params.snapshot_triggers[s] = value;
+			},
+			ParameterKind::Morph => {
+				params.morph_amount = value;
+				params.update_morph();
+			},
+			ParameterKind::MorphSwitch => {
+				params.morph_switch = value;
+				if params.morphed.is_some() {
+					params.update_morph();
 				}
+			},
+			ParameterKind::CommitMorph => {
+				if value >= 0.5 && params.commit_trigger < 0.5 {
+					params.commit_morph();
+				}
+				params.commit_trigger = value;
 			}
 		}
+	}
 
-		params.build_sound_params();
+	fn get_preset_data(&self) -> Vec<u8> {
+		self.read().unwrap().state_text().into_bytes()
+	}
+
+	fn get_bank_data(&self) -> Vec<u8> {
+		self.get_preset_data()
+	}
+
+	fn load_preset_data(&self, data: &[u8]) {
+		let params: &mut SynthPluginParameters<G> = &mut self.write().unwrap();
+		if let Ok(text) = String::from_utf8(data.to_vec()) {
+			// Keep the current state if the data is not understood
+			let _ = params.load_state_text(&text);
+		}
+	}
+
+	fn load_bank_data(&self, data: &[u8]) {
+		self.load_preset_data(data);
+	}
+}
+
+impl<G: SoundGenerator> RwLockWrapper<SynthPluginParameters<G>> {
+	/// Update the diagnostics for the last tone played. The sound is analyzed
+	/// without holding the lock, so the audio thread is not kept waiting.
+	fn follow_played_tone(&self) {
+		let (tone, sound_params, global) = {
+			let params = self.read().unwrap();
+			let tone = params.played_tone.load(Ordering::Relaxed);
+			if tone == params.diagnostic_tone {
+				return;
+			}
+			(tone, params.sound_params.clone(), Arc::clone(&params.global))
+		};
+		let diagnostics = G::diagnostics(&sound_params, tone, &global);
+		let params: &mut SynthPluginParameters<G> = &mut self.write().unwrap();
+		params.diagnostic_tone = tone;
+		if params.sound_params == sound_params {
+			params.diagnostics = diagnostics;
+		} else {
+			// The sound changed in the meantime
+			params.update_diagnostics();
+		}
+	}
+
+	/// Mutate once each time the trigger is pulled up. The candidates are
+	/// tried without holding the lock, since that can take a while.
+	fn pull_mutate_trigger(&self, value: f32) {
+		let (mut mutator, values, locks, strength, tone, sample_rate, global) = {
+			let params: &mut SynthPluginParameters<G> = &mut self.write().unwrap();
+			let pulled = value >= 0.5 && params.mutate_trigger < 0.5;
+			params.mutate_trigger = value;
+			if !pulled {
+				return;
+			}
+			(params.mutator.clone(), params.values.clone(), params.locks.clone(), params.mutation_strength,
+			 params.played_tone.load(Ordering::Relaxed), params.sample_rate, Arc::clone(&params.global))
+		};
+		let mutated = mutator.mutate_usable::<G>(&values, &locks, strength, tone, sample_rate, &global);
+		let params: &mut SynthPluginParameters<G> = &mut self.write().unwrap();
+		params.mutator = mutator;
+		// Drop the variation if the patch was edited in the meantime
+		if params.values == values {
+			params.apply_mutation(mutated);
+		}
 	}
 }
 
@@ -311,9 +556,12 @@ impl<G: SoundGenerator, S: SynthInfo> SynthPlugin<G, S> {
 		let params: &SynthPluginParameters<G> = &self.params.read().unwrap();
 		match event.command {
 			MidiCommand::NoteOn { key, velocity, .. } => {
-				let attack = G::Parameters::attack(&params.map, self.sample_rate);
-				let release = G::Parameters::release(&params.map, self.sample_rate);
-				let note = Note::new(key, velocity, attack, release, Some(self.sample_rate as usize));
+				// The diagnostics follow the tone when they are next shown
+				params.played_tone.store(key, Ordering::Relaxed);
+				let envelope = G::Parameters::envelope(params.sound_values(), self.sample_rate);
+				let layer = velocity_layer(velocity, params.layer_params.len());
+				let end_time = params.layer_params[layer].duration();
+				let note = Note::new(key, layer, velocity, envelope, end_time, Some(self.sample_rate as usize));
 				self.notes.push(note);
 			},
 			MidiCommand::NoteOff { key, velocity, .. } => {
@@ -352,19 +600,155 @@ impl<G: SoundGenerator, S: SynthInfo> SynthPlugin<G, S> {
 
 	fn update_cache(&mut self) {
 		let params: &SynthPluginParameters<G> = &self.params.read().unwrap();
-		if params.sound_params != self.cached_sound_params {
-			self.cached_sound_params = params.sound_params.clone();
-			for c in &mut self.cache {
-				c.invalidate();
+		if params.layer_params != self.cached_sound_params {
+			let layers = params.layer_params.len();
+			self.cache.resize_with(layers, || (0..128).map(|tone| SoundCache::new(tone)).collect());
+			for (layer, cache) in self.cache.iter_mut().enumerate() {
+				if self.cached_sound_params.get(layer) != Some(&params.layer_params[layer]) {
+					for c in cache {
+						c.invalidate();
+					}
+				}
 			}
+			// Notes of removed layers continue with the sound of the top layer
+			for note in &mut self.notes {
+				note.layer = note.layer.min(layers - 1);
+			}
+			self.cached_sound_params = params.layer_params.clone();
 		}
 	}
 }
 
 impl<G: SoundGenerator> SynthPluginParameters<G> {
+	/// Parameter values of the sound being played: the morphed patch while
+	/// morphing, otherwise the current patch.
+	fn sound_values(&self) -> &[f32] {
+		self.morphed.as_ref().unwrap_or(&self.values)
+	}
+
 	fn build_sound_params(&mut self) {
-		self.map = make_param_map(G::Parameters::names(), &self.values);
-		self.sound_params = G::Parameters::build(&self.map, self.sample_rate);
+		let sample_rate = self.sample_rate;
+		let values = self.sound_values();
+		let tuning = &self.tuning;
+		let build = |values: &[f32]| {
+			let mut params = G::Parameters::build(values, sample_rate);
+			params.tune(tuning);
+			params
+		};
+		let layers = G::Parameters::velocity_layers(values);
+		let layer_params = (0..layers).map(|layer| build(&G::Parameters::layer_values(values, layer))).collect();
+		self.sound_params = build(values);
+		self.layer_params = layer_params;
+		self.update_diagnostics();
+	}
+
+	/// The state saved by the host: the patch text of the current patch, followed by
+	/// the tuning lines if the tuning is not equal-tempered and the lines of the
+	/// controls not in their default state.
+	fn state_text(&self) -> String {
+		let controls = Controls {
+			mutation_strength: self.mutation_strength,
+			locks: self.locks.clone(),
+			snapshots: self.snapshots.clone(),
+			morph_amount: self.morph_amount,
+			morph_switch: self.morph_switch,
+			morphing: self.morphed.is_some()
+		};
+		format_patch::<G::Parameters>(&self.values) + &self.tuning.format() + &controls.format::<G::Parameters>()
+	}
+
+	fn load_state_text(&mut self, text: &str) -> Result<(), String> {
+		let names: Vec<&str> = G::Parameters::schema().iter().map(|desc| desc.name).collect();
+		let (patch, tuning) = Tuning::split_patch(text)?;
+		let (patch, controls) = Controls::split_patch(&patch, &names, &G::Parameters::default_values())?;
+		let values = parse_patch::<G::Parameters>(&patch)?;
+		self.values = values;
+		self.tuning = tuning;
+		self.mutation_strength = controls.mutation_strength;
+		self.locks = controls.locks;
+		self.snapshots = controls.snapshots;
+		self.morph_amount = controls.morph_amount;
+		self.morph_switch = controls.morph_switch;
+		self.morphed = match self.snapshots {
+			[Some(ref a), Some(ref b)] if controls.morphing => Some(morph::<G::Parameters>(a, b, self.morph_amount, self.morph_switch)),
+			_ => None
+		};
+		self.build_sound_params();
+		Ok(())
+	}
+
+	fn update_diagnostics(&mut self) {
+		self.diagnostics = G::diagnostics(&self.sound_params, self.diagnostic_tone, &self.global);
+	}
+
+	fn apply_mutation(&mut self, mutated: Option<Vec<f32>>) {
+		self.mutation_failed = mutated.is_none();
+		if let Some(values) = mutated {
+			self.morphed = None;
+			if let Some(ref mut host) = self.host {
+				for (index, &value) in values.iter().enumerate() {
+					if value != self.values[index] {
+						host.automate(index as i32, value);
+					}
+				}
+			}
+			self.values = values;
+			self.build_sound_params();
+		}
+	}
+
+	fn update_morph(&mut self) {
+		if let [Some(ref a), Some(ref b)] = self.snapshots {
+			self.morphed = Some(morph::<G::Parameters>(a, b, self.morph_amount, self.morph_switch));
+			self.build_sound_params();
+		}
+	}
+
+	/// Make the morphed patch the current patch.
+	fn commit_morph(&mut self) {
+		if let Some(values) = self.morphed.take() {
+			if let Some(ref mut host) = self.host {
+				for (index, &value) in values.iter().enumerate() {
+					if value != self.values[index] {
+						host.automate(index as i32, value);
+					}
+				}
+			}
+			self.values = values;
+			self.build_sound_params();
+		}
+	}
+}
+
+fn parameter_count<G: SoundGenerator>() -> usize {
+	G::Parameters::schema().len() + G::diagnostic_names().len() +
+		MUTATION_CONTROLS + mutable_parameters::<G::Parameters>().len() + MORPH_CONTROLS
+}
+
+fn parameter_kind<G: SoundGenerator>(index: i32, lockable: &[usize]) -> ParameterKind {
+	let index = index as usize;
+	let n_sound = G::Parameters::schema().len();
+	let n_diagnostics = G::diagnostic_names().len();
+	if index < n_sound {
+		ParameterKind::Sound(index)
+	} else if index < n_sound + n_diagnostics {
+		ParameterKind::Diagnostic(index - n_sound)
+	} else if index == n_sound + n_diagnostics {
+		ParameterKind::Mutate
+	} else if index == n_sound + n_diagnostics + 1 {
+		ParameterKind::MutationStrength
+	} else {
+		let lock_index = index - n_sound - n_diagnostics - MUTATION_CONTROLS;
+		if lock_index < lockable.len() {
+			return ParameterKind::Lock(lockable[lock_index]);
+		}
+		match lock_index - lockable.len() {
+			0 => ParameterKind::StoreSnapshot(0),
+			1 => ParameterKind::StoreSnapshot(1),
+			2 => ParameterKind::Morph,
+			3 => ParameterKind::MorphSwitch,
+			_ => ParameterKind::CommitMorph
+		}
 	}
 }
 
@@ -0,0 +1,288 @@
+// Microtuning: the pitch of each of the 128 keys, loaded from Scala files
+
+/// Key whose equal-tempered tone is A at 440 Hz
+const A440_KEY: u8 = 57;
+/// Key playing the first degree of the scale when no keyboard mapping is given
+const DEFAULT_MIDDLE_KEY: u8 = 60;
+/// First word of the lines holding the tuning in a patch text
+const TUNE_KEYWORD: &str = "tune";
+
+/// Tuning table mapping each key to the tone it plays, in equal-tempered
+/// semitones relative to A440 at key 57. Keys map to themselves by default.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tuning {
+	tones: Vec<f64>
+}
+
+impl Default for Tuning {
+	fn default() -> Tuning {
+		Tuning {
+			tones: (0..128).map(|key| key as f64).collect()
+		}
+	}
+}
+
+/// Pitch in cents of a line of a Scala scale: cents if the number has a period, otherwise a ratio.
+fn parse_scala_pitch(word: &str) -> Option<f64> {
+	if word.contains('.') {
+		return word.parse::<f64>().ok().filter(|c| c.is_finite());
+	}
+	let (num, den) = match word.split_once('/') {
+		Some((num, den)) => (num.parse::<f64>().ok()?, den.parse::<f64>().ok()?),
+		None => (word.parse::<f64>().ok()?, 1.0)
+	};
+	if num > 0.0 && den > 0.0 { Some(1200.0 * (num / den).log2()) } else { None }
+}
+
+/// Non-comment lines of a Scala file, with their line numbers.
+fn scala_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+	text.lines().enumerate().filter(|(_, line)| !line.starts_with('!')).map(|(i, line)| (i + 1, line.trim()))
+}
+
+/// Keyboard mapping as read from a Scala `.kbm` file.
+struct KeyboardMapping {
+	first_key: u8,
+	last_key: u8,
+	middle_key: u8,
+	reference_key: u8,
+	reference_freq: f64,
+	/// Scale degree of the formal octave
+	octave_degree: usize,
+	/// Scale degree of each key in the mapping pattern, or `None` for unmapped keys.
+	/// Empty for a linear mapping.
+	map: Vec<Option<usize>>
+}
+
+impl KeyboardMapping {
+	fn linear(degrees: usize) -> KeyboardMapping {
+		// The reference keeps A440 where the equal-tempered tuning has it
+		KeyboardMapping {
+			first_key: 0,
+			last_key: 127,
+			middle_key: DEFAULT_MIDDLE_KEY,
+			reference_key: A440_KEY,
+			reference_freq: 440.0,
+			octave_degree: degrees,
+			map: vec![]
+		}
+	}
+
+	fn parse(text: &str) -> Result<KeyboardMapping, String> {
+		let mut lines = scala_lines(text).filter(|(_, line)| !line.is_empty());
+		let mut next = |what: &str| -> Result<(usize, String), String> {
+			lines.next().map(|(n, line)| (n, line.split_whitespace().next().unwrap().to_string()))
+				.ok_or(format!("Keyboard mapping ends before the {}", what))
+		};
+		let mut number = |what: &str| -> Result<f64, String> {
+			let (n, word) = next(what)?;
+			word.parse::<f64>().ok().filter(|v| v.is_finite()).ok_or(format!("Line {}: Illegal {} '{}'", n, what, word))
+		};
+		let key = |value: f64, what: &str| -> Result<u8, String> {
+			if (0.0..128.0).contains(&value) && value.fract() == 0.0 { Ok(value as u8) } else { Err(format!("Illegal {} {}", what, value)) }
+		};
+		let size = number("map size")?;
+		let first_key = key(number("first key")?, "first key")?;
+		let last_key = key(number("last key")?, "last key")?;
+		let middle_key = key(number("middle key")?, "middle key")?;
+		let reference_key = key(number("reference key")?, "reference key")?;
+		let reference_freq = number("reference frequency")?;
+		let octave_degree = number("octave degree")?;
+		if size < 0.0 || size.fract() != 0.0 || reference_freq <= 0.0 || octave_degree < 0.0 || octave_degree.fract() != 0.0 {
+			return Err("Illegal keyboard mapping header".to_string());
+		}
+		let mut map = Vec::new();
+		for _ in 0..size as usize {
+			let (n, word) = next("end of the mapping")?;
+			map.push(match word.as_str() {
+				"x" | "X" => None,
+				_ => Some(word.parse::<usize>().map_err(|_| format!("Line {}: Illegal scale degree '{}'", n, word))?)
+			});
+		}
+		Ok(KeyboardMapping {
+			first_key,
+			last_key,
+			middle_key,
+			reference_key,
+			reference_freq,
+			octave_degree: octave_degree as usize,
+			map
+		})
+	}
+
+	/// Scale degree played by a key, counted from the middle key, or `None` if the key is unmapped.
+	fn degree(&self, key: u8) -> Option<i64> {
+		let offset = key as i64 - self.middle_key as i64;
+		if self.map.is_empty() {
+			return Some(offset);
+		}
+		let size = self.map.len() as i64;
+		let degree = self.map[offset.rem_euclid(size) as usize]? as i64;
+		Some(degree + offset.div_euclid(size) * self.octave_degree as i64)
+	}
+}
+
+impl Tuning {
+	/// Tuning given by a Scala scale (`.scl`) and optionally a keyboard mapping (`.kbm`).
+	/// Without a keyboard mapping, the scale starts at key 60 and is placed such that key 57
+	/// keeps its frequency of 440 Hz. Keys outside the mapped range or unmapped in the
+	/// mapping keep their equal-tempered tones.
+	pub fn from_scala(scl: &str, kbm: Option<&str>) -> Result<Tuning, String> {
+		let mut lines = scala_lines(scl);
+		// The first line is the description, which may be empty
+		lines.next().ok_or("Scale has no description line")?;
+		let mut lines = lines.filter(|(_, line)| !line.is_empty());
+		let (n, count) = lines.next().ok_or("Scale has no note count")?;
+		let count = count.split_whitespace().next().unwrap().parse::<usize>()
+			.map_err(|_| format!("Line {}: Illegal note count '{}'", n, count))?;
+		// Cents of each degree, starting at the implicit 1/1 and ending at the period
+		let mut cents = vec![0.0];
+		for (n, line) in lines.take(count) {
+			let word = line.split_whitespace().next().unwrap();
+			cents.push(parse_scala_pitch(word).ok_or(format!("Line {}: Illegal pitch '{}'", n, word))?);
+		}
+		if count == 0 || cents.len() != count + 1 {
+			return Err(format!("Scale has {} pitches, expected {}", cents.len() - 1, count));
+		}
+
+		let mapping = match kbm {
+			Some(text) => KeyboardMapping::parse(text)?,
+			None => KeyboardMapping::linear(count)
+		};
+		if mapping.map.iter().flatten().any(|&degree| degree > count) {
+			return Err("Keyboard mapping refers to degrees beyond the scale".to_string());
+		}
+		let degree_cents = |degree: i64| {
+			let period = degree.div_euclid(count as i64);
+			period as f64 * cents[count] + cents[degree.rem_euclid(count as i64) as usize]
+		};
+		let reference_cents = match mapping.degree(mapping.reference_key) {
+			Some(degree) => degree_cents(degree),
+			None => return Err("Reference key of the keyboard mapping is unmapped".to_string())
+		};
+		let reference_tone = A440_KEY as f64 + 12.0 * (mapping.reference_freq / 440.0).log2();
+
+		let mut tuning = Tuning::default();
+		for key in mapping.first_key..=mapping.last_key {
+			if let Some(degree) = mapping.degree(key) {
+				tuning.tones[key as usize] = reference_tone + (degree_cents(degree) - reference_cents) / 100.0;
+			}
+		}
+		Ok(tuning)
+	}
+
+	/// Whether all keys play their equal-tempered tones.
+	pub fn is_equal_tempered(&self) -> bool {
+		*self == Tuning::default()
+	}
+
+	/// Tone played by a key, in semitones.
+	pub fn tone(&self, key: u8) -> f64 {
+		self.tones[key as usize]
+	}
+
+	/// Set the tone played by a key.
+	pub fn set_tone(&mut self, key: u8, tone: f64) {
+		self.tones[key as usize] = tone;
+	}
+
+	/// Write the tuning as patch text lines: a `tune <key> <tone>` line for each key
+	/// not playing its equal-tempered tone.
+	pub fn format(&self) -> String {
+		let mut text = String::new();
+		for (key, &tone) in self.tones.iter().enumerate() {
+			if tone != key as f64 {
+				text += &format!("{} {} {}\n", TUNE_KEYWORD, key, tone);
+			}
+		}
+		text
+	}
+
+	/// Separate the tuning lines written by `format` from the rest of a patch text.
+	/// The tuning lines are blanked out in the returned text to keep the line numbers.
+	pub fn split_patch(text: &str) -> Result<(String, Tuning), String> {
+		let mut tuning = Tuning::default();
+		let mut rest = String::new();
+		for (line_index, line) in text.lines().enumerate() {
+			let mut words = line.split('#').next().unwrap().split_whitespace();
+			if words.next() != Some(TUNE_KEYWORD) {
+				rest += line;
+				rest += "\n";
+				continue;
+			}
+			match (words.next().map(|w| w.parse::<u8>()), words.next().map(|w| w.parse::<f64>()), words.next()) {
+				(Some(Ok(key)), Some(Ok(tone)), None) if key < 128 && tone.is_finite() => tuning.set_tone(key, tone),
+				_ => return Err(format!("Line {}: Expected a key from 0 to 127 and a tone after '{}'", line_index + 1, TUNE_KEYWORD))
+			}
+			rest += "\n";
+		}
+		Ok((rest, tuning))
+	}
+}
+
+#[cfg(test)]
+const TEST_SCALE: &str = "\
+! meantone.scl
+!
+1/4-comma meantone
+ 12
+!
+ 76.04900
+ 193.15686
+ 310.26303
+ 5/4
+ 503.42157
+ 579.47057
+ 696.57843
+ 25/16
+ 889.73529
+ 1006.84314
+ 1082.89214
+ 2/1
+";
+
+#[test]
+fn test_scala_default_mapping() {
+	let equal = "12-TET\n12\n100.0\n200.\n300.0\n400.0\n500.0\n600.0\n700.0\n800.0\n900.0\n1000.0\n1100.0\n2\n";
+	assert!(Tuning::from_scala(equal, None).unwrap().tones.iter().enumerate().all(|(key, &tone)| (tone - key as f64).abs() < 1e-9));
+
+	let tuning = Tuning::from_scala(TEST_SCALE, None).unwrap();
+	assert!(!tuning.is_equal_tempered());
+	assert!((tuning.tone(57) - 57.0).abs() < 1e-9);
+	// A major third above the start of the scale, an octave up
+	let third = 12.0 * (5.0f64 / 4.0).log2();
+	assert!((tuning.tone(76) - tuning.tone(60) - 12.0 - third).abs() < 1e-9);
+	assert!((tuning.tone(48) - tuning.tone(60) + 12.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_scala_keyboard_mapping() {
+	// A pentatonic mapping on the white keys from C to A, with 1/1 at key 60 and 261.6256 Hz
+	let kbm = "! pentatonic.kbm\n12\n48\n72\n60\n60\n261.625565\n12\n0\nx\n2\nx\n4\nx\nx\n7\nx\n9\nx\nx\n";
+	let tuning = Tuning::from_scala(TEST_SCALE, Some(kbm)).unwrap();
+	let middle = 57.0 + 12.0 * (261.625565f64 / 440.0).log2();
+	assert!((tuning.tone(60) - middle).abs() < 1e-6);
+	assert!((tuning.tone(64) - middle - 386.31371 / 100.0).abs() < 1e-6);
+	assert!((tuning.tone(72) - middle - 12.0).abs() < 1e-6);
+	// Unmapped keys and keys outside the range keep their tones
+	assert_eq!(tuning.tone(61), 61.0);
+	assert_eq!(tuning.tone(47), 47.0);
+	assert_eq!(tuning.tone(73), 73.0);
+
+	assert!(Tuning::from_scala(TEST_SCALE, Some("12\n0\n127\n60\n61\n440.0\n12\n0\nx\n")).is_err());
+	assert!(Tuning::from_scala(TEST_SCALE, Some("1\n0\n127\n60\n60\n440.0\n12\n13\n")).is_err());
+	assert!(Tuning::from_scala("Too short\n3\n100.0\n200.0\n", None).is_err());
+	assert!(Tuning::from_scala("Bad ratio\n1\n-3/2\n", None).is_err());
+}
+
+#[test]
+fn test_tuning_patch_text() {
+	let tuning = Tuning::from_scala(TEST_SCALE, None).unwrap();
+	let text = format!("gain 0.5\n{}# Comment\n", tuning.format());
+	let (rest, parsed) = Tuning::split_patch(&text).unwrap();
+	assert_eq!(parsed, tuning);
+	assert_eq!(rest.lines().count(), text.lines().count());
+	assert_eq!(rest.lines().filter(|l| !l.is_empty()).collect::<Vec<_>>(), vec!["gain 0.5", "# Comment"]);
+	assert_eq!(Tuning::default().format(), "");
+	assert!(Tuning::split_patch("tune 128 3.0").is_err());
+	assert!(Tuning::split_patch("tune 60").is_err());
+}